@@ -0,0 +1,483 @@
+//! Subcommands of the binary. `watch` runs the bot, the others are tools
+//! around it; only `watch`, `quote`, `sandwich`, `record` and `index` talk to
+//! a node.
+
+use crate::constants;
+use crate::env_store::{get_env_or, EnvError};
+use crate::utils::backtest::{
+    self, Archive, ArchiveWriter, BacktestConfig, Backtester, BlockRecord, PendingRecord, Record,
+    Snapshot,
+};
+use crate::utils::detector;
+use crate::utils::multicall::{CallBatcher, MulticallConfig};
+use crate::utils::pools::PoolIndex;
+use crate::utils::swaps;
+use crate::utils::tokens::{self, TokenRegistry};
+use crate::utils::univ2::{self, Dex, IUniswapV2Pair};
+use crate::utils::universal_router;
+use crate::IUniswapV2Router02Calls;
+
+use clap::{Args, Parser, Subcommand};
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::{abi::AbiDecode, prelude::*, utils::rlp::Rlp};
+use eyre::{eyre, Result};
+use log::{error, info};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "UniswapV2 sandwich bot and tools")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Watch the mempool and evaluate router swaps (default)
+    Watch {
+        /// Show the terminal dashboard instead of log output
+        #[arg(long)]
+        tui: bool,
+    },
+    /// Decode a raw signed transaction or router calldata
+    Decode {
+        /// hex, with or without 0x
+        input: String,
+    },
+    /// Pair address of two tokens
+    Pair {
+        token_a: Address,
+        token_b: Address,
+        #[arg(long, default_value = "uniswapv2", value_parser = parse_dex)]
+        dex: Dex,
+    },
+    /// Quote a swap along a path with current reserves
+    Quote(QuoteArgs),
+    /// Evaluate a pending transaction, read from a JSON file, as a victim
+    Sandwich {
+        #[arg(long)]
+        victim: PathBuf,
+    },
+    /// Append blocks, Sync logs and router txs to an archive directory
+    Record {
+        #[arg(long, default_value = "archive")]
+        out: PathBuf,
+        /// Also record block receipts, for `detect`
+        #[arg(long)]
+        receipts: bool,
+    },
+    /// Backtest the strategy on an archive directory
+    #[command(alias = "backtest")]
+    Replay { dir: PathBuf },
+    /// Find sandwiches mined in an archive directory
+    Detect { dir: PathBuf },
+    /// Walk the factories' pairs into the pool index, see `POOL_INDEX_PATH`
+    Index {
+        #[arg(long, value_parser = parse_dex)]
+        dex: Option<Dex>,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct QuoteArgs {
+    /// Comma separated token addresses
+    #[arg(long, value_delimiter = ',', required = true)]
+    pub path: Vec<Address>,
+    #[arg(long, value_parser = parse_amount, conflicts_with = "amount_out", required_unless_present = "amount_out")]
+    pub amount_in: Option<U256>,
+    #[arg(long, value_parser = parse_amount)]
+    pub amount_out: Option<U256>,
+    #[arg(long, default_value = "uniswapv2", value_parser = parse_dex)]
+    pub dex: Dex,
+    /// Print the raw quote as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+fn parse_dex(name: &str) -> Result<Dex, String> {
+    Dex::from_name(name).ok_or_else(|| format!("unknown dex {}, try uniswapv2 or sushiswap", name))
+}
+
+// decimal, like the amounts the router prints
+fn parse_amount(amount: &str) -> Result<U256, String> {
+    U256::from_dec_str(amount).map_err(|e| format!("{:?}", e))
+}
+
+/// Settings shared by every subcommand, from the environment and `.env`.
+#[derive(Debug, Clone)]
+pub struct Config {
+    ws_url: Option<String>,
+    pub router: Address,
+    // SwapRouter and SwapRouter02, whose swaps are decoded but not sandwiched
+    pub v3_routers: Vec<Address>,
+    // Universal Routers, their V2 swaps are sandwiched like the router's
+    pub universal_routers: Vec<Address>,
+    pub weth: Address,
+}
+
+impl Config {
+    pub fn load() -> Result<Self, EnvError> {
+        dotenv::dotenv().ok();
+        Ok(Self {
+            ws_url: std::env::var("INFURA_MAINNET_WS").ok(),
+            router: get_env_or(
+                "UNIV2_ROUTE",
+                constants::UNIV2_ROUTER_ADDRESS.parse::<Address>().unwrap(),
+            )?,
+            v3_routers: [
+                constants::UNIV3_SWAP_ROUTER_ADDRESS,
+                constants::UNIV3_SWAP_ROUTER02_ADDRESS,
+            ]
+            .iter()
+            .map(|router| router.parse::<Address>().unwrap())
+            .collect(),
+            universal_routers: [
+                constants::UNIVERSAL_ROUTER_ADDRESS,
+                constants::UNIVERSAL_ROUTER_V1_ADDRESS,
+            ]
+            .iter()
+            .map(|router| router.parse::<Address>().unwrap())
+            .collect(),
+            weth: constants::WETH_ADDRESS.parse::<Address>().unwrap(),
+        })
+    }
+
+    /// Node connection, only the commands needing one call this.
+    pub async fn provider(&self) -> Result<Arc<Provider<Ws>>> {
+        let url = self
+            .ws_url
+            .as_deref()
+            .ok_or_else(|| eyre!("INFURA_MAINNET_WS is not set"))?;
+        Ok(Arc::new(Provider::<Ws>::connect(url).await?))
+    }
+
+    /// Bundled and cached token metadata, see `TOKEN_CACHE_PATH`.
+    pub fn tokens(&self) -> Result<TokenRegistry> {
+        TokenRegistry::from_env().map_err(|e| eyre!("{:?}", e))
+    }
+}
+
+fn parse_hex(input: &str) -> Result<Vec<u8>> {
+    Ok(hex::decode(input.trim().trim_start_matches("0x"))?)
+}
+
+fn describe_calldata(data: &[u8], value: U256) -> Result<String> {
+    if let Ok(call) = IUniswapV2Router02Calls::decode(data) {
+        let mut out = format!("{:#?}", call);
+        if let Some(swap) = swaps::decode_v2(data, value) {
+            out.push_str(&format!("\n{:#?}", swap));
+        }
+        return Ok(out);
+    }
+    if let Ok(execution) = universal_router::decode_execute(data) {
+        return Ok(format!("{:#?}", execution));
+    }
+    let swaps = swaps::decode_v3(data);
+    if swaps.is_empty() {
+        return Err(eyre!(
+            "unknown router call, selector 0x{}",
+            hex::encode(data.get(..4).unwrap_or(data))
+        ));
+    }
+    Ok(format!("{:#?}", swaps))
+}
+
+/// Human readable form of a raw signed transaction or of router calldata.
+pub fn describe(input: &[u8]) -> Result<String> {
+    let Ok((tx, signature)) = TypedTransaction::decode_signed(&Rlp::new(input)) else {
+        return describe_calldata(input, U256::zero());
+    };
+    let from = signature.recover(tx.sighash())?;
+    let mut out = format!(
+        "hash  {:?}\nfrom  {:?}\nto    {:?}\nnonce {}\nvalue {}\n",
+        H256::from(ethers::utils::keccak256(input)),
+        from,
+        tx.to_addr(),
+        tx.nonce().copied().unwrap_or_default(),
+        tx.value().copied().unwrap_or_default(),
+    );
+    let data = tx.data().cloned().unwrap_or_default();
+    if !data.is_empty() {
+        out.push_str(&describe_calldata(
+            &data,
+            tx.value().copied().unwrap_or_default(),
+        )?);
+    }
+    Ok(out)
+}
+
+pub fn decode(input: &str) -> Result<()> {
+    println!("{}", describe(&parse_hex(input)?)?);
+    Ok(())
+}
+
+pub fn pair(config: &Config, token_a: Address, token_b: Address, dex: Dex) -> Result<()> {
+    let tokens = config.tokens()?;
+    let (token0, token1) = univ2::sort_token(token_a, token_b);
+    println!("{} pair {:?}", dex.name, dex.pair_address(token_a, token_b));
+    for (label, token) in [("token0", token0), ("token1", token1)] {
+        println!("{} {:?} {}", label, token, tokens.info(token).symbol);
+    }
+    Ok(())
+}
+
+/// `getReserves` of every pair along `path`.
+async fn fetch_reserves(
+    provider: &Arc<Provider<Ws>>,
+    dex: &Dex,
+    path: &[Address],
+) -> Result<HashMap<Address, (U256, U256)>> {
+    let mut reserves = HashMap::new();
+    for hop in path.windows(2) {
+        let pair = dex.pair_address(hop[0], hop[1]);
+        let (reserve0, reserve1, _timestamp) = IUniswapV2Pair::new(pair, Arc::clone(provider))
+            .get_reserves()
+            .call()
+            .await?;
+        reserves.insert(pair, (reserve0.into(), reserve1.into()));
+    }
+    Ok(reserves)
+}
+
+pub async fn index(config: &Config, dex: Option<Dex>) -> Result<()> {
+    let provider = config.provider().await?;
+    let index = PoolIndex::from_env().map_err(|e| eyre!("{:?}", e))?;
+    let multicall = MulticallConfig::from_env().map_err(|e| eyre!("{:?}", e))?;
+    let batcher = CallBatcher::new(Arc::clone(&provider), multicall);
+    let dexes = match dex {
+        Some(dex) => vec![dex],
+        None => vec![Dex::uniswap_v2(), Dex::sushiswap()],
+    };
+    for dex in dexes {
+        let added = index
+            .sync(Arc::clone(&provider), &batcher, dex.factory)
+            .await
+            .map_err(|e| eyre!("{:?}", e))?;
+        println!(
+            "{} {} pairs, {} new",
+            dex.name,
+            index.walked(dex.factory),
+            added
+        );
+    }
+    Ok(())
+}
+
+pub async fn quote(config: &Config, args: QuoteArgs) -> Result<()> {
+    let provider = config.provider().await?;
+    let reserves = fetch_reserves(&provider, &args.dex, &args.path).await?;
+    let quote = match (args.amount_in, args.amount_out) {
+        (Some(amount_in), _) => univ2::get_amounts_out(amount_in, &args.path, &args.dex, &reserves),
+        (None, Some(amount_out)) => {
+            univ2::get_amounts_in(amount_out, &args.path, &args.dex, &reserves)
+        }
+        (None, None) => return Err(eyre!("--amount-in or --amount-out is required")),
+    }
+    .map_err(|e| eyre!("{}", e))?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&quote)?);
+        return Ok(());
+    }
+
+    let tokens = config.tokens()?;
+    for token in &args.path {
+        tokens.resolve(provider.as_ref(), *token).await;
+    }
+    for hop in &quote.hops {
+        println!(
+            "{:?} {} -> {}",
+            hop.pair,
+            tokens.format(hop.token_in, hop.amount_in),
+            tokens.format(hop.token_out, hop.amount_out)
+        );
+    }
+    Ok(())
+}
+
+async fn head_block(provider: &Provider<Ws>) -> Result<Block<TxHash>> {
+    provider
+        .get_block(BlockNumber::Latest)
+        .await?
+        .ok_or_else(|| eyre!("node returned no latest block"))
+}
+
+fn block_record(block: &Block<TxHash>) -> BlockRecord {
+    BlockRecord {
+        number: block.number.unwrap_or_default(),
+        timestamp: block.timestamp,
+        base_fee: block.base_fee_per_gas.unwrap_or_default(),
+        transactions: block.transactions.clone(),
+    }
+}
+
+pub async fn sandwich(config: &Config, victim: &Path) -> Result<()> {
+    let tx: Transaction = serde_json::from_str(&std::fs::read_to_string(victim)?)?;
+    let swap = backtest::decode_victim(&tx)
+        .ok_or_else(|| eyre!("{:?} is not an ETH-in router swap", tx.hash))?;
+    let backtest_config = BacktestConfig::from_env(config.weth).map_err(|e| eyre!("{}", e))?;
+
+    let provider = config.provider().await?;
+    let block = block_record(&head_block(&provider).await?);
+    let reserves = fetch_reserves(&provider, &backtest_config.dex, &swap.path).await?;
+    let tokens = config.tokens()?;
+    let token_out = swap.path.get(1).copied().unwrap_or_default();
+    let token = tokens.resolve(provider.as_ref(), token_out).await;
+    println!(
+        "victim {:?} buys {} with {} ETH",
+        tx.hash,
+        token.symbol,
+        tokens::format_units(tx.value, 18)
+    );
+    match Backtester::new(backtest_config).evaluate(&tx, &block, &reserves) {
+        None => println!("{:?} does not buy with WETH", tx.hash),
+        Some(Ok(state)) => {
+            let eth = |wei| format!("{} ETH", tokens::format_units(wei, 18));
+            println!("frontrun {}", eth(state.optimal_sandwich_weth_in));
+            println!("revenue  {}", eth(state.revenue));
+            println!("gas      {}", eth(state.gas_cost));
+            println!(
+                "profit   {} ETH",
+                tokens::format_signed_units(state.profit, 18)
+            );
+        }
+        Some(Err(rejection)) => println!("rejected [{}]: {}", rejection.reason(), rejection),
+    }
+    Ok(())
+}
+
+/// Record until interrupted, one file per session.
+pub async fn record(config: &Config, out: &Path, receipts: bool) -> Result<()> {
+    let provider = config.provider().await?;
+    let started = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let name = format!("record-{}", started);
+    let mut writer = ArchiveWriter::create(out, &name).map_err(|e| eyre!("{:?}", e))?;
+    info!("recording to {}", writer.path().display());
+
+    let dex = Dex::uniswap_v2();
+    let sync_topic = H256::from(ethers::utils::keccak256("Sync(uint112,uint112)"));
+    let mut head = head_block(&provider).await?.number.unwrap_or_default();
+    let mut blocks = provider.subscribe_blocks().await?;
+    let mut pending = provider.subscribe_pending_txs().await?;
+
+    loop {
+        tokio::select! {
+            Some(header) = blocks.next() => {
+                let Some(number) = header.number else { continue };
+                head = number;
+                let Some(block) = provider.get_block(number).await? else { continue };
+                let mut records = vec![Record::Block(block_record(&block))];
+                let filter = Filter::new().at_block_hash(block.hash.unwrap_or_default()).topic0(sync_topic);
+                records.extend(provider.get_logs(&filter).await?.into_iter().map(Record::Log));
+                if receipts {
+                    records.extend(
+                        provider
+                            .get_block_receipts(number)
+                            .await?
+                            .into_iter()
+                            .map(|receipt| Record::Receipt(Box::new(receipt))),
+                    );
+                }
+                for record in &records {
+                    writer.write(record).map_err(|e| eyre!("{:?}", e))?;
+                }
+            }
+            Some(tx_hash) = pending.next() => {
+                let Ok(Some(tx)) = provider.get_transaction(tx_hash).await else { continue };
+                if tx.to != Some(config.router) {
+                    continue;
+                }
+                // reserves of the victim pairs, Sync logs keep them current
+                if let Some(swap) = backtest::decode_victim(&tx) {
+                    match fetch_reserves(&provider, &dex, &swap.path).await {
+                        Ok(reserves) => writer
+                            .write(&Record::Snapshot(Snapshot { block: head, reserves }))
+                            .map_err(|e| eyre!("{:?}", e))?,
+                        Err(e) => error!("reserves of {:?} failed: {:?}", tx.hash, e),
+                    }
+                }
+                writer
+                    .write(&Record::Pending(Box::new(PendingRecord { seen_at: head, tx })))
+                    .map_err(|e| eyre!("{:?}", e))?;
+            }
+            else => return Err(eyre!("node subscriptions ended")),
+        }
+    }
+}
+
+pub fn replay(config: &Config, dir: &Path) -> Result<()> {
+    let backtest_config = BacktestConfig::from_env(config.weth).map_err(|e| eyre!("{:?}", e))?;
+    let archive = Archive::load(dir).map_err(|e| eyre!("{:?}", e))?;
+    let report = Backtester::new(backtest_config).run(&archive);
+    println!("{}", report);
+    Ok(())
+}
+
+pub fn detect(dir: &Path) -> Result<()> {
+    let archive = Archive::load(dir).map_err(|e| eyre!("{:?}", e))?;
+    for sandwich in detector::detect(&archive) {
+        println!("{}", sandwich);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SwapExactETHForTokensCall;
+    use ethers::abi::AbiEncode;
+
+    #[test]
+    fn test_parse_commands() {
+        let cli = Cli::try_parse_from(["unibot-rs"]).unwrap();
+        assert!(cli.command.is_none());
+
+        let cli = Cli::try_parse_from([
+            "unibot-rs",
+            "quote",
+            "--path",
+            "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2,0x6B175474E89094C44Da98b954EedeAC495271d0F",
+            "--amount-in",
+            "1000000000000000000",
+            "--dex",
+            "sushiswap",
+        ])
+        .unwrap();
+        let Some(Command::Quote(args)) = cli.command else {
+            panic!("not a quote");
+        };
+        assert_eq!(2, args.path.len());
+        assert_eq!(Some(U256::exp10(18)), args.amount_in);
+        assert_eq!(Dex::sushiswap(), args.dex);
+
+        // exactly one amount
+        assert!(Cli::try_parse_from(["unibot-rs", "quote", "--path", "0x01,0x02"]).is_err());
+        assert!(Cli::try_parse_from(["unibot-rs", "pair", "0x01", "0x02", "--dex", "x"]).is_err());
+        assert!(matches!(
+            Cli::try_parse_from(["unibot-rs", "backtest", "archive"])
+                .unwrap()
+                .command,
+            Some(Command::Replay { .. })
+        ));
+    }
+
+    #[test]
+    fn test_describe_calldata() {
+        let call = SwapExactETHForTokensCall {
+            amount_out_min: U256::from(42),
+            path: vec![Address::repeat_byte(1), Address::repeat_byte(2)],
+            to: Address::repeat_byte(3),
+            deadline: U256::from(7),
+        };
+        let described = describe(&call.encode()).unwrap();
+        assert!(described.contains("SwapExactETHForTokens"));
+        assert!(described.contains("amount_out_min: 42"));
+
+        assert!(describe(&[0xde, 0xad, 0xbe, 0xef])
+            .unwrap_err()
+            .to_string()
+            .contains("0xdeadbeef"));
+    }
+}
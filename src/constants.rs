@@ -1,3 +1,17 @@
 pub const UNIV2_ROUTER02_ADDRESS: &str = "5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f";
 
 pub const TESTNUM: u8 = 8;
+
+pub const WETH_ADDRESS: &str = "C02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2";
+
+pub const UNIV2_ROUTER_ADDRESS: &str = "7a250d5630B4cF539739dF2C5dAcb4c659F2488D";
+
+pub const UNIV3_SWAP_ROUTER_ADDRESS: &str = "E592427A0AEce92De3Edee1F18E0157C05861564";
+
+pub const UNIV3_SWAP_ROUTER02_ADDRESS: &str = "68b3465833fb72A70ecDF485E0e4C7bD8665Fc45";
+
+pub const UNIVERSAL_ROUTER_ADDRESS: &str = "3fC91A3afd70395Cd496C647d5a6CC9D4B2b7FAD";
+
+pub const UNIVERSAL_ROUTER_V1_ADDRESS: &str = "Ef1c6E67703c7BD7107eed8303Fbe6EC2554BF6B";
+
+pub const MULTICALL3_ADDRESS: &str = "cA11bde05977b3631167028862bE2a173976CA11";
@@ -1,5 +1,6 @@
 use std::error::Error;
 use std::fmt;
+use std::str::FromStr;
 
 #[derive(Debug, Clone)]
 pub struct EnvError {
@@ -24,6 +25,12 @@ impl From<()> for EnvError {
     }
 }
 
+impl From<String> for EnvError {
+    fn from(details: String) -> Self {
+        EnvError { details }
+    }
+}
+
 impl Error for EnvError {}
 
 fn get_env_var(var: &str) -> Result<String, EnvError> {
@@ -32,6 +39,17 @@ fn get_env_var(var: &str) -> Result<String, EnvError> {
     })
 }
 
+/// Optional tuning knob: falls back to `default` when the variable is unset,
+/// but refuses a value that does not parse.
+pub fn get_env_or<T: FromStr>(var: &str, default: T) -> Result<T, EnvError> {
+    match std::env::var(var) {
+        Ok(val) => val.trim().parse::<T>().map_err(|_| EnvError {
+            details: format!("invalid value for {}: {}", var, val),
+        }),
+        Err(_) => Ok(default),
+    }
+}
+
 // FIXME: handle error details
 fn convert_val_to_key<const LEN: usize>(val: String) -> Result<[u8; LEN], EnvError> {
     Ok(hex::decode(&val).map_err(|e| {})?[..]
@@ -43,6 +61,7 @@ fn convert_val_to_key<const LEN: usize>(val: String) -> Result<[u8; LEN], EnvErr
 pub struct EnvStore {
     ws_url: String,
     eth_private_key: [u8; 32],
+    signer_pool_keys: Vec<[u8; 32]>,
 }
 
 impl EnvStore {
@@ -50,9 +69,23 @@ impl EnvStore {
         Ok(EnvStore {
             ws_url: get_env_var(ws_url_var)?,
             eth_private_key: convert_val_to_key(get_env_var(eth_private_key_var)?).unwrap(),
+            signer_pool_keys: vec![],
         })
     }
 
+    /// Extra signing accounts, read from a comma separated list of hex keys.
+    /// The variable is optional, without it the bot signs with a single key.
+    pub fn with_signer_pool(mut self, signer_pool_var: &str) -> Result<EnvStore, EnvError> {
+        if let Ok(keys) = get_env_var(signer_pool_var) {
+            for key in keys.split(',').map(str::trim).filter(|k| !k.is_empty()) {
+                self.signer_pool_keys.push(convert_val_to_key(
+                    key.trim_start_matches("0x").to_string(),
+                )?);
+            }
+        }
+        Ok(self)
+    }
+
     pub fn get_ws_url(&self) -> &str {
         &self.ws_url
     }
@@ -60,4 +93,8 @@ impl EnvStore {
     pub fn get_private_key(&self) -> &[u8] {
         &self.eth_private_key
     }
+
+    pub fn get_signer_pool_keys(&self) -> &[[u8; 32]] {
+        &self.signer_pool_keys
+    }
 }
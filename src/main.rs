@@ -1,15 +1,25 @@
-use dotenv::dotenv;
+use clap::Parser;
 use ethers::{abi::AbiDecode, prelude::*};
 use eyre::Result;
 use log::{debug, error, info, log_enabled, Level};
-use std::env;
 use std::sync::Arc;
 
 pub mod utils;
+use utils::api::ApiState;
+use utils::arbitrage::SwapIntent;
 use utils::client::*;
+use utils::events::{self, ArbitrageEvent, BotEvent, CycleEvent, VictimEvent};
+use utils::rejection::Rejection;
+use utils::swaps::{self, Protocol, SwapAmount};
+use utils::tui;
+use utils::univ2::{Dex, MinRecv, SandWichState};
+use utils::universal_router;
+use utils::validator::TokenIn;
 
+mod cli;
 mod constants;
 mod env_store;
+use cli::{Cli, Command, Config};
 
 abigen!(
     IUniswapV2Router02,
@@ -20,6 +30,7 @@ abigen!(
         swapTokensForExactETH(uint amountOut, uint amountInMax, address[] calldata path, address to, uint deadline)])
         swapExactTokensForETH(uint amountIn, uint amountOutMin, address[] calldata path, address to, uint deadline)])
         swapETHForExactTokens(uint amountOut, address[] calldata path, address to, uint deadline)])
+        swapExactETHForTokensSupportingFeeOnTransferTokens(uint amountOutMin, address[] calldata path, address to, uint deadline)
     ]"#,
 );
 
@@ -27,39 +38,182 @@ abigen!(
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Watch { tui: false });
+    // the dashboard owns the terminal, log lines would tear it
+    if !matches!(command, Command::Watch { tui: true }) {
+        env_logger::init();
+    }
     debug!("this is a debug {}", "message");
 
-    dotenv().ok();
+    let config = Config::load()?;
+    match command {
+        Command::Watch { tui } => watch(config, tui).await,
+        Command::Decode { input } => cli::decode(&input),
+        Command::Pair {
+            token_a,
+            token_b,
+            dex,
+        } => cli::pair(&config, token_a, token_b, dex),
+        Command::Quote(args) => cli::quote(&config, args).await,
+        Command::Sandwich { victim } => cli::sandwich(&config, &victim).await,
+        Command::Record { out, receipts } => cli::record(&config, &out, receipts).await,
+        Command::Replay { dir } => cli::replay(&config, &dir),
+        Command::Detect { dir } => cli::detect(&dir),
+        Command::Index { dex } => cli::index(&config, dex).await,
+    }
+}
 
-    let infura_url: String = env::var("INFURA_MAINNET_WS").unwrap();
-    let univ2_router: Address = env::var("UNIV2_ROUTE").unwrap().parse().unwrap();
+async fn watch(config: Config, dashboard: bool) -> Result<()> {
+    let univ2_router = config.router;
+    let v3_routers = config.v3_routers.clone();
+    let universal_routers = config.universal_routers.clone();
 
-    let envstore = env_store::EnvStore::new("INFURA_MAINNET_WS", "ETH_PRIVATE_KEY")?;
+    let envstore = env_store::EnvStore::new("INFURA_MAINNET_WS", "ETH_PRIVATE_KEY")?
+        .with_signer_pool("ETH_SIGNER_POOL_KEYS")?;
 
-    let client = UniswapV2Client::new(envstore).await.unwrap();
+    let client = UniswapV2Client::new(envstore, config.router, config.weth)
+        .await
+        .unwrap();
     let client = Arc::new(client);
+    if let Err(e) = client.nonce_manager().sync().await {
+        error!("nonce sync failed: {:?}", e);
+    }
+
+    let metrics_addr: std::net::SocketAddr =
+        env_store::get_env_or("METRICS_ADDR", ([127, 0, 0, 1], 9100).into())
+            .map_err(|e| eyre::eyre!("{:?}", e))?;
+    let (metrics_addr, _) = utils::metrics::serve(Arc::clone(client.metrics()), metrics_addr)?;
+    info!("serving metrics on http://{}/metrics", metrics_addr);
+
+    let api_addr: std::net::SocketAddr =
+        env_store::get_env_or("API_ADDR", ([127, 0, 0, 1], 9101).into())
+            .map_err(|e| eyre::eyre!("{:?}", e))?;
+    let api_max_weth_in: u128 =
+        env_store::get_env_or("API_MAX_WETH_IN_WEI", 10_000_000_000_000_000_000)
+            .map_err(|e| eyre::eyre!("{:?}", e))?;
+    let api_state = ApiState {
+        weth: config.weth,
+        reserves: Arc::clone(client.reserve_cache()),
+        opportunities: Arc::clone(client.opportunities()),
+        fee_policy: Arc::clone(client.fee_policy()),
+        max_weth_in: api_max_weth_in.into(),
+    };
+    let (api_addr, _) = utils::api::serve(Arc::new(api_state), api_addr)?;
+    info!("serving api on http://{}", api_addr);
+
+    events::spawn_sink(client.events(), Arc::clone(client.opportunities()));
+
+    let index_client = Arc::clone(&client);
+    tokio::spawn(async move { index_client.watch_pair_created().await });
+    let index_client = Arc::clone(&client);
+    tokio::spawn(async move { index_client.index_pairs().await });
+
+    let block_client = Arc::clone(&client);
+    tokio::spawn(async move {
+        let mut blocks = block_client.get_new_blocks().await;
+        while let Some(block) = blocks.next().await {
+            if let Some(number) = block.number {
+                block_client.events().publish(BotEvent::NewBlock {
+                    number,
+                    timestamp: block.timestamp,
+                });
+            }
+            block_client.fee_policy().on_block(&block);
+            block_client.clear_reserve_cache();
+            block_client.refresh_reference_pools().await;
+            if let (Some(number), Some(hash)) = (block.number, block.hash) {
+                block_client.sync_pool_graph(hash).await;
+                block_client.sync_pending(hash, number).await;
+                report_cycles(&block_client, number);
+            }
+            if let Some(number) = block.number {
+                reconcile_nonces(&block_client, number).await;
+                if let Err(e) = block_client.inventory().refresh(number).await {
+                    if let Some(call) = e.rpc_call() {
+                        block_client.metrics().record_rpc_error(call);
+                    }
+                    error!("inventory refresh failed: {:?}", e);
+                }
+            }
+        }
+    });
+
+    if dashboard {
+        let receiver = client.events().subscribe();
+        let weth = config.weth;
+        let tokens = Arc::clone(client.tokens());
+        let dashboard = tokio::task::spawn_blocking(move || tui::run(receiver, weth, tokens));
+        tokio::select! {
+            _ = watch_pending(
+                Arc::clone(&client),
+                univ2_router,
+                &v3_routers,
+                &universal_routers,
+            ) => {}
+            result = dashboard => result??,
+        }
+    } else {
+        events::spawn_sink(
+            client.events(),
+            events::LogSink {
+                tokens: Arc::clone(client.tokens()),
+            },
+        );
+        watch_pending(client, univ2_router, &v3_routers, &universal_routers).await;
+    }
+    Ok(())
+}
 
+async fn watch_pending(
+    client: Arc<UniswapV2Client>,
+    univ2_router: Address,
+    v3_routers: &[Address],
+    universal_routers: &[Address],
+) {
     let mut stream = client.get_pending_txs().await;
 
     while let Some(tx_hash) = stream.next().await {
+        client.events().publish(BotEvent::PendingTx(tx_hash));
         let tx = client.get_transaction(tx_hash).await;
 
         if tx.is_some() {
             let tx = tx.unwrap();
-            parse_tx(Arc::clone(&client), &tx, &univ2_router).await;
+            parse_tx(
+                Arc::clone(&client),
+                &tx,
+                &univ2_router,
+                v3_routers,
+                universal_routers,
+            )
+            .await;
         } else {
             continue;
         }
     }
-    Ok(())
+    client.events().publish(BotEvent::Disconnected(
+        "pending tx subscription ended".to_string(),
+    ));
 }
 
-async fn parse_tx(client: Arc<UniswapV2Client>, tx: &Transaction, router: &Address) {
-    if tx.to.unwrap() == *router {
-        println!("Uni transaction founded: tx={:?}", tx.hash);
+async fn parse_tx(
+    client: Arc<UniswapV2Client>,
+    tx: &Transaction,
+    router: &Address,
+    v3_routers: &[Address],
+    universal_routers: &[Address],
+) {
+    // contract creations go to no router
+    let Some(to) = tx.to else { return };
+    if to == *router {
+        debug!("Uni transaction founded: tx={:?}", tx.hash);
+        // into the overlay before it is evaluated, it orders the victim too
+        if let Some(swap) = swaps::decode_v2(&tx.input, tx.value) {
+            client.track_pending_swaps(tx, &[swap]);
+        }
 
         if let Ok(decoded) = SwapExactTokensForTokensCall::decode(&tx.input) {
+            count_router_tx(&client, tx, "swapExactTokensForTokens");
             let amount_in = decoded.amount_in;
             let amount_out_min = decoded.amount_out_min;
             let mut path = decoded.path.into_iter();
@@ -67,38 +221,109 @@ async fn parse_tx(client: Arc<UniswapV2Client>, tx: &Transaction, router: &Addre
             let to = path.next().unwrap();
             let address_to = decoded.to;
             let deadline = decoded.deadline;
-            let pair_address = client.get_uni_pair_address(from, to);
-            client.get_univ2_reserve(pair_address, from, to).await;
+            let token_in = TokenIn {
+                token: from,
+                amount: amount_in,
+            };
+            if let Err(rejection) = client.validate_victim(tx, deadline, Some(token_in)).await {
+                report_victim(&client, tx, from, to, amount_in, Err(rejection)).await;
+                return;
+            }
+            client.get_pair_reserves(from, to).await;
+            report_arbitrage(&client, tx, from, to, amount_in).await;
         } else if let Ok(decoded) = SwapTokensForExactTokensCall::decode(&tx.input) {
+            count_router_tx(&client, tx, "swapTokensForExactTokens");
             let amount_out = decoded.amount_out;
             let amount_in_max = decoded.amount_in_max;
+            let amount_in =
+                quote_amount_in(&client, amount_out, amount_in_max, &decoded.path).await;
             let mut path = decoded.path.into_iter();
             let from = path.next().unwrap();
             let to = path.next().unwrap();
             let address_to = decoded.to;
             let deadline = decoded.deadline;
-            let pair_address = client.get_uni_pair_address(from, to);
-            client.get_univ2_reserve(pair_address, from, to).await;
+            let token_in = TokenIn {
+                token: from,
+                amount: amount_in,
+            };
+            if let Err(rejection) = client.validate_victim(tx, deadline, Some(token_in)).await {
+                report_victim(&client, tx, from, to, amount_in, Err(rejection)).await;
+                return;
+            }
+            client.get_pair_reserves(from, to).await;
         } else if let Ok(decoded) = SwapExactETHForTokensCall::decode(&tx.input) {
+            count_router_tx(&client, tx, "swapExactETHForTokens");
             //// only implements this abi
             let amount_out_min = decoded.amount_out_min;
             let path = decoded.path;
             let address_to = decoded.to;
             let deadline = decoded.deadline;
+            let (from, to) = path_ends(&path);
 
-            client.swap_eth_for_exact_tokens(tx.value, amount_out_min, path, address_to, deadline);
+            let outcome = client
+                .swap_eth_for_exact_tokens(
+                    tx,
+                    tx.value,
+                    MinRecv::PairOut(amount_out_min),
+                    path,
+                    address_to,
+                    deadline,
+                )
+                .await;
+            let lands = !matches!(&outcome, Err(rejection) if !rejection.victim_may_land());
+            report_victim(&client, tx, from, to, tx.value, outcome).await;
+            if lands {
+                report_arbitrage(&client, tx, from, to, tx.value).await;
+            }
+        } else if let Ok(decoded) =
+            SwapExactETHForTokensSupportingFeeOnTransferTokensCall::decode(&tx.input)
+        {
+            count_router_tx(
+                &client,
+                tx,
+                "swapExactETHForTokensSupportingFeeOnTransferTokens",
+            );
+            // the router checks amountOutMin against what the recipient
+            // received, after the buy tax, unlike the plain variant
+            let (from, to) = path_ends(&decoded.path);
+            let outcome = client
+                .swap_eth_for_exact_tokens(
+                    tx,
+                    tx.value,
+                    MinRecv::Received(decoded.amount_out_min),
+                    decoded.path,
+                    decoded.to,
+                    decoded.deadline,
+                )
+                .await;
+            let lands = !matches!(&outcome, Err(rejection) if !rejection.victim_may_land());
+            report_victim(&client, tx, from, to, tx.value, outcome).await;
+            if lands {
+                report_arbitrage(&client, tx, from, to, tx.value).await;
+            }
         } else if let Ok(decoded) = SwapTokensForExactETHCall::decode(&tx.input) {
+            count_router_tx(&client, tx, "swapTokensForExactETH");
             let amount_out = decoded.amount_out;
             let amount_in_max = decoded.amount_in_max;
+            let amount_in =
+                quote_amount_in(&client, amount_out, amount_in_max, &decoded.path).await;
             let mut path = decoded.path.into_iter();
             let from = path.next().unwrap();
             let to = path.next().unwrap();
             let address_to = decoded.to;
             let deadline = decoded.deadline;
+            let token_in = TokenIn {
+                token: from,
+                amount: amount_in,
+            };
+            if let Err(rejection) = client.validate_victim(tx, deadline, Some(token_in)).await {
+                report_victim(&client, tx, from, to, amount_in, Err(rejection)).await;
+                return;
+            }
 
-            let pair_address = client.get_uni_pair_address(from, to);
-            client.get_univ2_reserve(pair_address, from, to).await;
+            client.get_pair_reserves(from, to).await;
         } else if let Ok(decoded) = SwapExactTokensForETHCall::decode(&tx.input) {
+            count_router_tx(&client, tx, "swapExactTokensForETH");
             let amount_in = decoded.amount_in;
             let amount_out_min = decoded.amount_out_min;
             let mut path = decoded.path.into_iter();
@@ -106,23 +331,222 @@ async fn parse_tx(client: Arc<UniswapV2Client>, tx: &Transaction, router: &Addre
             let to = path.next().unwrap();
             let address_to = decoded.to;
             let deadline = decoded.deadline;
+            let token_in = TokenIn {
+                token: from,
+                amount: amount_in,
+            };
+            if let Err(rejection) = client.validate_victim(tx, deadline, Some(token_in)).await {
+                report_victim(&client, tx, from, to, amount_in, Err(rejection)).await;
+                return;
+            }
 
-            let pair_address = client.get_uni_pair_address(from, to);
-            client.get_univ2_reserve(pair_address, from, to).await;
+            client.get_pair_reserves(from, to).await;
+            report_arbitrage(&client, tx, from, to, amount_in).await;
         } else if let Ok(decoded) = SwapETHForExactTokensCall::decode(&tx.input) {
+            count_router_tx(&client, tx, "swapETHForExactTokens");
             let amount_out = decoded.amount_out;
             let mut path = decoded.path.into_iter();
             let from = path.next().unwrap();
             let to = path.next().unwrap();
             let address_to = decoded.to;
             let deadline = decoded.deadline;
+            if let Err(rejection) = client.validate_victim(tx, deadline, None).await {
+                report_victim(&client, tx, from, to, tx.value, Err(rejection)).await;
+                return;
+            }
 
-            let pair_address = client.get_uni_pair_address(from, to);
-            client.get_univ2_reserve(pair_address, from, to).await;
+            client.get_pair_reserves(from, to).await;
         } else {
-            println!("AbiError");
+            client.events().publish(BotEvent::DecodeFailure(tx.hash));
+            debug!("AbiError");
         }
+    } else if v3_routers.contains(&to) {
+        count_v3_swaps(&client, tx);
+    } else if universal_routers.contains(&to) {
+        parse_universal_router_tx(&client, tx, to).await;
     } else {
-        println!("from:  {:?} -> {:?}", tx.from, tx.to)
+        debug!("from:  {:?} -> {:?}", tx.from, to)
+    }
+}
+
+// V3 swaps are only counted, the sandwich math covers V2 pairs
+fn count_v3_swaps(client: &UniswapV2Client, tx: &Transaction) {
+    let swaps = swaps::decode_v3(&tx.input);
+    if swaps.is_empty() {
+        client.events().publish(BotEvent::DecodeFailure(tx.hash));
+        return;
+    }
+    for swap in swaps {
+        count_router_tx(client, tx, swap.function);
+        debug!("V3 swap {:?}: {:?}", tx.hash, swap);
+    }
+}
+
+// Universal Router swaps paying ETH into a V2 pair run through the same
+// evaluation as swapExactETHForTokens, the others are only counted
+async fn parse_universal_router_tx(client: &UniswapV2Client, tx: &Transaction, router: Address) {
+    let execution = match universal_router::decode_execute(&tx.input) {
+        Ok(execution) => execution,
+        Err(e) => {
+            client.events().publish(BotEvent::DecodeFailure(tx.hash));
+            debug!("Universal Router {:?}: {:?}", tx.hash, e);
+            return;
+        }
+    };
+    let weth = client.prices().config().weth;
+    let swaps = universal_router::normalize(&execution, tx.from, router, tx.value, weth);
+    client.track_pending_swaps(tx, &swaps);
+    for swap in swaps {
+        count_router_tx(client, tx, swap.function);
+        let SwapAmount::ExactIn {
+            amount_in,
+            amount_out_min,
+        } = swap.amount
+        else {
+            continue;
+        };
+        if swap.protocol != Protocol::V2 || swap.token_in() != weth || amount_in > tx.value {
+            continue;
+        }
+        let (from, to) = path_ends(&swap.path);
+        let outcome = client
+            .swap_eth_for_exact_tokens(
+                tx,
+                amount_in,
+                // V2_SWAP_EXACT_IN checks the recipient's balance increase
+                MinRecv::Received(amount_out_min),
+                swap.path,
+                swap.recipient,
+                swap.deadline.unwrap_or(U256::MAX),
+            )
+            .await;
+        let lands = !matches!(&outcome, Err(rejection) if !rejection.victim_may_land());
+        report_victim(client, tx, from, to, amount_in, outcome).await;
+        if lands {
+            report_arbitrage(client, tx, from, to, amount_in).await;
+        }
+    }
+}
+
+fn count_router_tx(client: &UniswapV2Client, tx: &Transaction, function: &'static str) {
+    let mut selector = [0u8; 4];
+    if let Some(input) = tx.input.get(..4) {
+        selector.copy_from_slice(input);
+    }
+    client.events().publish(BotEvent::RouterTx {
+        tx_hash: tx.hash,
+        selector,
+        function,
+    });
+}
+
+/// What an exact-output swap pulls from the sender at the current reserves.
+/// The router reverts above `amount_in_max`, so that is the most it can take.
+async fn quote_amount_in(
+    client: &UniswapV2Client,
+    amount_out: U256,
+    amount_in_max: U256,
+    path: &[Address],
+) -> U256 {
+    match client
+        .get_amounts_in(amount_out, path, &Dex::uniswap_v2())
+        .await
+    {
+        Ok(quote) => quote.amounts[0].min(amount_in_max),
+        Err(e) => {
+            debug!("quote for {} out failed: {:?}", amount_out, e);
+            amount_in_max
+        }
+    }
+}
+
+// first hop of a router path, default addresses when it is too short
+fn path_ends(path: &[Address]) -> (Address, Address) {
+    (
+        path.first().copied().unwrap_or_default(),
+        path.get(1).copied().unwrap_or_default(),
+    )
+}
+
+async fn report_victim(
+    client: &UniswapV2Client,
+    tx: &Transaction,
+    token_in: Address,
+    token_out: Address,
+    amount_in: U256,
+    outcome: Result<SandWichState, Rejection>,
+) {
+    // sinks render amounts with the registry, fill it before they look
+    client.resolve_tokens(&[token_in, token_out]).await;
+    let victim = VictimEvent::new(tx.hash, token_in, token_out, amount_in, outcome)
+        .valued(client.prices(), client.reserve_cache().as_ref());
+    client.events().publish(BotEvent::Victim(Box::new(victim)));
+}
+
+/// Settle the signer nonces against the new head: confirmed ones leave the
+/// in-flight set, bundles that missed their block give their nonces back.
+async fn reconcile_nonces(client: &UniswapV2Client, block: U64) {
+    let reports = match client.nonce_manager().reconcile(block).await {
+        Ok(reports) => reports,
+        Err(e) => {
+            client.metrics().record_rpc_error("eth_getTransactionCount");
+            error!("nonce reconcile failed: {:?}", e);
+            return;
+        }
+    };
+    for report in reports {
+        for (nonce, tx_hash) in &report.dropped {
+            info!(
+                "{:?} nonce {} dropped ({:?}), next {}",
+                report.account, nonce, tx_hash, report.next
+            );
+        }
+    }
+}
+
+/// Publish the WETH loops the last reserve updates opened.
+fn report_cycles(client: &UniswapV2Client, block: U64) {
+    for opportunity in client.pool_graph().profitable_cycles() {
+        let profit_value = Some(client.prices().value_wei(
+            I256::from_raw(opportunity.profit),
+            client.reserve_cache().as_ref(),
+        ));
+        client
+            .events()
+            .publish(BotEvent::Cycle(Box::new(CycleEvent {
+                block,
+                opportunity,
+                profit_value,
+            })));
+    }
+}
+
+/// Publish the cross-dex backruns `amount_in` of `token_in` leaves open.
+async fn report_arbitrage(
+    client: &UniswapV2Client,
+    tx: &Transaction,
+    token_in: Address,
+    token_out: Address,
+    amount_in: U256,
+) {
+    let intent = SwapIntent {
+        dex: Dex::uniswap_v2(),
+        token_in,
+        token_out,
+        amount_in,
+    };
+    for opportunity in client.find_arbitrage(&intent).await {
+        let profit_value = client.prices().value_token(
+            opportunity.base,
+            opportunity.profit,
+            client.reserve_cache().as_ref(),
+        );
+        client
+            .events()
+            .publish(BotEvent::Arbitrage(Box::new(ArbitrageEvent {
+                tx_hash: tx.hash,
+                opportunity,
+                profit_value,
+            })));
     }
 }
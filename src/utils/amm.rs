@@ -0,0 +1,223 @@
+use ethers::prelude::*;
+use std::fmt;
+
+pub const FEE_DENOMINATOR: u64 = 1000;
+// 0.3% swap fee of UniswapV2 and most of its forks
+pub const DEFAULT_FEE_NUMERATOR: u64 = 997;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmmError {
+    ZeroInput,
+    ZeroOutput,
+    InsufficientLiquidity,
+    Overflow,
+}
+
+impl fmt::Display for AmmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AmmError::ZeroInput => write!(f, "INSUFFICIENT_INPUT_AMOUNT"),
+            AmmError::ZeroOutput => write!(f, "INSUFFICIENT_OUTPUT_AMOUNT"),
+            AmmError::InsufficientLiquidity => write!(f, "INSUFFICIENT_LIQUIDITY"),
+            AmmError::Overflow => write!(f, "OVERFLOW"),
+        }
+    }
+}
+
+/// A single constant-product swap and the pair reserves right after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwapOutcome {
+    pub amount_in: U256,
+    pub amount_out: U256,
+    pub new_reserve_in: U256,
+    pub new_reserve_out: U256,
+}
+
+// pairs store reserves as uint112 and revert with OVERFLOW beyond it
+fn max_reserve() -> U256 {
+    (U256::one() << 112) - 1
+}
+
+fn check_reserves(reserve_in: U256, reserve_out: U256) -> Result<(), AmmError> {
+    if reserve_in.is_zero() || reserve_out.is_zero() {
+        return Err(AmmError::InsufficientLiquidity);
+    }
+    if reserve_in > max_reserve() || reserve_out > max_reserve() {
+        return Err(AmmError::Overflow);
+    }
+    Ok(())
+}
+
+fn outcome(
+    amount_in: U256,
+    amount_out: U256,
+    reserve_in: U256,
+    reserve_out: U256,
+) -> Result<SwapOutcome, AmmError> {
+    let new_reserve_in = reserve_in
+        .checked_add(amount_in)
+        .filter(|r| *r <= max_reserve())
+        .ok_or(AmmError::Overflow)?;
+    let new_reserve_out = reserve_out
+        .checked_sub(amount_out)
+        .filter(|r| !r.is_zero())
+        .ok_or(AmmError::InsufficientLiquidity)?;
+
+    Ok(SwapOutcome {
+        amount_in,
+        amount_out,
+        new_reserve_in,
+        new_reserve_out,
+    })
+}
+
+/// `getAmountOut`: what `amount_in` buys from the pair.
+pub fn swap_given_in(
+    amount_in: U256,
+    reserve_in: U256,
+    reserve_out: U256,
+    fee_numerator: u64,
+) -> Result<SwapOutcome, AmmError> {
+    if amount_in.is_zero() {
+        return Err(AmmError::ZeroInput);
+    }
+    check_reserves(reserve_in, reserve_out)?;
+
+    let amount_in_with_fee = amount_in
+        .checked_mul(fee_numerator.into())
+        .ok_or(AmmError::Overflow)?;
+    let numerator = amount_in_with_fee
+        .checked_mul(reserve_out)
+        .ok_or(AmmError::Overflow)?;
+    let denominator = reserve_in
+        .checked_mul(FEE_DENOMINATOR.into())
+        .and_then(|r| r.checked_add(amount_in_with_fee))
+        .ok_or(AmmError::Overflow)?;
+
+    outcome(amount_in, numerator / denominator, reserve_in, reserve_out)
+}
+
+/// `getAmountIn`: the smallest input that buys `amount_out` from the pair.
+pub fn swap_given_out(
+    amount_out: U256,
+    reserve_in: U256,
+    reserve_out: U256,
+    fee_numerator: u64,
+) -> Result<SwapOutcome, AmmError> {
+    if amount_out.is_zero() {
+        return Err(AmmError::ZeroOutput);
+    }
+    check_reserves(reserve_in, reserve_out)?;
+    if amount_out >= reserve_out {
+        return Err(AmmError::InsufficientLiquidity);
+    }
+
+    let numerator = reserve_in
+        .checked_mul(amount_out)
+        .and_then(|n| n.checked_mul(FEE_DENOMINATOR.into()))
+        .ok_or(AmmError::Overflow)?;
+    // non zero: amount_out < reserve_out and fee_numerator > 0
+    let denominator = (reserve_out - amount_out)
+        .checked_mul(fee_numerator.into())
+        .ok_or(AmmError::Overflow)?;
+    if denominator.is_zero() {
+        return Err(AmmError::InsufficientLiquidity);
+    }
+
+    outcome(
+        numerator / denominator + 1,
+        amount_out,
+        reserve_in,
+        reserve_out,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn k(reserve_in: U256, reserve_out: U256) -> U256 {
+        reserve_in * reserve_out
+    }
+
+    #[test]
+    fn test_swap_given_in() {
+        let out = swap_given_in(
+            U256::from(1_000),
+            U256::from(1_000_000),
+            U256::from(2_000_000),
+            DEFAULT_FEE_NUMERATOR,
+        )
+        .unwrap();
+        assert_eq!(U256::from(1_992), out.amount_out);
+        assert_eq!(U256::from(1_001_000), out.new_reserve_in);
+        assert_eq!(U256::from(1_998_008), out.new_reserve_out);
+    }
+
+    #[test]
+    fn test_swap_errors() {
+        let fee = DEFAULT_FEE_NUMERATOR;
+        assert_eq!(
+            Err(AmmError::ZeroInput),
+            swap_given_in(U256::zero(), U256::one(), U256::one(), fee)
+        );
+        assert_eq!(
+            Err(AmmError::ZeroOutput),
+            swap_given_out(U256::zero(), U256::one(), U256::one(), fee)
+        );
+        assert_eq!(
+            Err(AmmError::InsufficientLiquidity),
+            swap_given_in(U256::one(), U256::zero(), U256::one(), fee)
+        );
+        assert_eq!(
+            Err(AmmError::InsufficientLiquidity),
+            swap_given_out(U256::from(10), U256::from(10), U256::from(10), fee)
+        );
+        assert_eq!(
+            Err(AmmError::Overflow),
+            swap_given_in(U256::MAX, U256::from(10), U256::from(10), fee)
+        );
+        assert_eq!(
+            Err(AmmError::Overflow),
+            swap_given_in(max_reserve(), U256::from(10), U256::from(10), fee)
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn prop_given_in_keeps_k(
+            amount_in in 1u128..(1u128 << 100),
+            reserve_in in 1u128..(1u128 << 100),
+            reserve_out in 1u128..(1u128 << 100),
+        ) {
+            let (amount_in, reserve_in, reserve_out) =
+                (U256::from(amount_in), U256::from(reserve_in), U256::from(reserve_out));
+            if let Ok(out) = swap_given_in(amount_in, reserve_in, reserve_out, DEFAULT_FEE_NUMERATOR) {
+                prop_assert!(out.amount_out < reserve_out);
+                prop_assert!(k(out.new_reserve_in, out.new_reserve_out) >= k(reserve_in, reserve_out));
+                prop_assert_eq!(reserve_in + amount_in, out.new_reserve_in);
+            }
+        }
+
+        #[test]
+        fn prop_given_out_keeps_k(
+            amount_out in 1u128..(1u128 << 100),
+            reserve_in in 1u128..(1u128 << 100),
+            reserve_out in 1u128..(1u128 << 100),
+        ) {
+            let (amount_out, reserve_in, reserve_out) =
+                (U256::from(amount_out), U256::from(reserve_in), U256::from(reserve_out));
+            match swap_given_out(amount_out, reserve_in, reserve_out, DEFAULT_FEE_NUMERATOR) {
+                Ok(out) => {
+                    prop_assert!(out.amount_out < reserve_out);
+                    prop_assert!(k(out.new_reserve_in, out.new_reserve_out) >= k(reserve_in, reserve_out));
+                    // the quoted input really buys at least `amount_out`
+                    let back = swap_given_in(out.amount_in, reserve_in, reserve_out, DEFAULT_FEE_NUMERATOR).unwrap();
+                    prop_assert!(back.amount_out >= amount_out);
+                }
+                Err(e) => prop_assert!(amount_out >= reserve_out || e == AmmError::Overflow),
+            }
+        }
+    }
+}
@@ -0,0 +1,493 @@
+//! Read-only HTTP/JSON API for inspecting the running bot.
+//!
+//! - `GET /pair?token_a=..&token_b=..[&dex=..]`
+//! - `GET /quote?path=0x..,0x..&amount_in=..` (or `amount_out=..`)
+//! - `GET /sandwich?token=..&amount_in=..&amount_out_min=..[&max_weth_in=..]`
+//! - `GET /opportunities[?limit=..]`
+//!
+//! Amounts are decimal wei, quotes only use reserves already cached this
+//! block.
+
+use crate::utils::fees::FeePolicy;
+use crate::utils::pricing::Valuation;
+use crate::utils::rejection::Rejection;
+use crate::utils::univ2::{
+    self, Dex, MinRecv, QuoteError, ReserveCache, Reserves, SandWichState, TokenTaxes,
+};
+
+use ethers::prelude::*;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Opportunity {
+    pub tx_hash: H256,
+    // unix seconds
+    pub found_at: u64,
+    pub state: SandWichState,
+    pub profit_value: Option<Valuation>,
+}
+
+/// The last `capacity` opportunities found, oldest dropped first.
+pub struct OpportunityLog {
+    capacity: usize,
+    entries: RwLock<VecDeque<Opportunity>>,
+}
+
+impl OpportunityLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: RwLock::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn push(&self, tx_hash: H256, state: SandWichState, profit_value: Option<Valuation>) {
+        let found_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let mut entries = self.entries.write().unwrap();
+        // a log of capacity 0 still keeps the newest entry
+        while entries.len() >= self.capacity.max(1) {
+            entries.pop_front();
+        }
+        entries.push_back(Opportunity {
+            tx_hash,
+            found_at,
+            state,
+            profit_value,
+        });
+    }
+
+    /// Up to `n` opportunities, newest first.
+    pub fn latest(&self, n: usize) -> Vec<Opportunity> {
+        self.entries
+            .read()
+            .unwrap()
+            .iter()
+            .rev()
+            .take(n)
+            .cloned()
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiError {
+    NotFound,
+    MissingParam(&'static str),
+    BadParam(&'static str, String),
+    Quote(QuoteError),
+    Rejected(Rejection),
+}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::NotFound | ApiError::Quote(QuoteError::UnknownPair(_)) => {
+                StatusCode::NOT_FOUND
+            }
+            ApiError::MissingParam(_) | ApiError::BadParam(..) | ApiError::Quote(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            ApiError::Rejected(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::NotFound => write!(f, "not found"),
+            ApiError::MissingParam(name) => write!(f, "missing parameter {}", name),
+            ApiError::BadParam(name, e) => write!(f, "bad parameter {}: {}", name, e),
+            ApiError::Quote(QuoteError::UnknownPair(pair)) => {
+                write!(f, "no cached reserves for pair {:?}", pair)
+            }
+            ApiError::Quote(e) => write!(f, "quote failed: {}", e),
+            ApiError::Rejected(rejection) => write!(f, "{}", rejection),
+        }
+    }
+}
+
+/// Query string parameters, values are not percent-decoded.
+pub struct Params(HashMap<String, String>);
+
+impl Params {
+    pub fn parse(query: Option<&str>) -> Self {
+        Self(
+            query
+                .unwrap_or_default()
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
+    }
+
+    fn get<T>(
+        &self,
+        name: &'static str,
+        parse: impl Fn(&str) -> Result<T, String>,
+    ) -> Result<Option<T>, ApiError> {
+        self.0
+            .get(name)
+            .map(|value| parse(value).map_err(|e| ApiError::BadParam(name, e)))
+            .transpose()
+    }
+
+    fn require<T>(
+        &self,
+        name: &'static str,
+        parse: impl Fn(&str) -> Result<T, String>,
+    ) -> Result<T, ApiError> {
+        self.get(name, parse)?.ok_or(ApiError::MissingParam(name))
+    }
+}
+
+fn address(value: &str) -> Result<Address, String> {
+    value.parse::<Address>().map_err(|e| e.to_string())
+}
+
+fn amount(value: &str) -> Result<U256, String> {
+    U256::from_dec_str(value).map_err(|e| e.to_string())
+}
+
+fn dex(value: &str) -> Result<Dex, String> {
+    Dex::from_name(value).ok_or_else(|| format!("unknown dex {}", value))
+}
+
+fn number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse::<T>()
+        .map_err(|_| format!("not a number: {}", value))
+}
+
+fn tax_bps(value: &str) -> Result<u64, String> {
+    number(value).and_then(|bps: u64| {
+        if bps <= univ2::BPS {
+            Ok(bps)
+        } else {
+            Err(format!("{} bps is more than the whole amount", bps))
+        }
+    })
+}
+
+/// What the API reads, shared with the running bot.
+pub struct ApiState {
+    pub weth: Address,
+    pub reserves: Arc<ReserveCache>,
+    pub opportunities: Arc<OpportunityLog>,
+    pub fee_policy: Arc<FeePolicy>,
+    // frontrun budget for `/sandwich` when the request sets none
+    pub max_weth_in: U256,
+}
+
+impl ApiState {
+    pub fn pair(&self, params: &Params) -> Result<Value, ApiError> {
+        let token_a = params.require("token_a", address)?;
+        let token_b = params.require("token_b", address)?;
+        let dex = params.get("dex", dex)?.unwrap_or_else(Dex::uniswap_v2);
+        let (token0, token1) = univ2::sort_token(token_a, token_b);
+        let pair = dex.pair_address(token0, token1);
+        Ok(json!({
+            "dex": dex.name,
+            "pair": pair,
+            "token0": token0,
+            "token1": token1,
+            "reserves": self.reserves.reserves(pair),
+        }))
+    }
+
+    pub fn quote(&self, params: &Params) -> Result<Value, ApiError> {
+        let path = params.require("path", |value| {
+            value.split(',').map(address).collect::<Result<Vec<_>, _>>()
+        })?;
+        let dex = params.get("dex", dex)?.unwrap_or_else(Dex::uniswap_v2);
+
+        let quote = match (
+            params.get("amount_in", amount)?,
+            params.get("amount_out", amount)?,
+        ) {
+            (Some(amount_in), None) => {
+                univ2::get_amounts_out(amount_in, &path, &dex, self.reserves.as_ref())
+            }
+            (None, Some(amount_out)) => {
+                univ2::get_amounts_in(amount_out, &path, &dex, self.reserves.as_ref())
+            }
+            _ => {
+                return Err(ApiError::BadParam(
+                    "amount_in",
+                    "set exactly one of amount_in and amount_out".to_string(),
+                ))
+            }
+        }
+        .map_err(ApiError::Quote)?;
+        Ok(json!(quote))
+    }
+
+    pub fn sandwich(&self, params: &Params) -> Result<Value, ApiError> {
+        let token = params.require("token", address)?;
+        let amount_in = params.require("amount_in", amount)?;
+        // bound of a plain `swapExactETHForTokens`, on the pair's output
+        let amount_out_min = MinRecv::PairOut(params.require("amount_out_min", amount)?);
+        let max_weth_in = params
+            .get("max_weth_in", amount)?
+            .unwrap_or(self.max_weth_in);
+        let taxes = TokenTaxes {
+            buy_tax_bps: params.get("buy_tax_bps", tax_bps)?.unwrap_or_default(),
+            sell_tax_bps: params.get("sell_tax_bps", tax_bps)?.unwrap_or_default(),
+        };
+
+        let dex = Dex::uniswap_v2();
+        let pair = dex.pair_address(self.weth, token);
+        let (reserve0, reserve1) = self
+            .reserves
+            .reserves(pair)
+            .ok_or(ApiError::Quote(QuoteError::UnknownPair(pair)))?;
+        let (reserve_weth, reserve_token) = if univ2::sort_token(self.weth, token).0 == self.weth {
+            (reserve0, reserve1)
+        } else {
+            (reserve1, reserve0)
+        };
+
+        // a plain victim tx gets the minimum frontrun tip
+        let gas_cost = self
+            .fee_policy
+            .latest()
+            .and_then(|fees| {
+                self.fee_policy
+                    .sandwich_fees_at(&Transaction::default(), fees.next_base_fee)
+                    .ok()
+            })
+            .map(|fees| fees.gas_cost)
+            .unwrap_or_default();
+
+        let optimal_weth_in = univ2::calc_sandwich_optima_in(
+            amount_in,
+            amount_out_min,
+            reserve_weth,
+            reserve_token,
+            max_weth_in,
+            taxes,
+        );
+        let state = univ2::get_sandwitch_state(
+            optimal_weth_in,
+            amount_in,
+            amount_out_min,
+            reserve_weth,
+            reserve_token,
+            taxes,
+            gas_cost,
+        )
+        .map_err(ApiError::Rejected)?;
+        Ok(json!(state))
+    }
+
+    pub fn opportunities(&self, params: &Params) -> Result<Value, ApiError> {
+        let limit = params.get("limit", number)?.unwrap_or(20);
+        Ok(json!(self.opportunities.latest(limit)))
+    }
+
+    fn route(&self, method: &Method, path: &str, query: Option<&str>) -> Result<Value, ApiError> {
+        if method != Method::GET {
+            return Err(ApiError::NotFound);
+        }
+        let params = Params::parse(query);
+        match path {
+            "/pair" => self.pair(&params),
+            "/quote" => self.quote(&params),
+            "/sandwich" => self.sandwich(&params),
+            "/opportunities" => self.opportunities(&params),
+            _ => Err(ApiError::NotFound),
+        }
+    }
+}
+
+async fn handle(state: Arc<ApiState>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let (status, body) = match state.route(req.method(), req.uri().path(), req.uri().query()) {
+        Ok(body) => (StatusCode::OK, body),
+        Err(e) => (e.status(), json!({ "error": e.to_string() })),
+    };
+    Ok(Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap())
+}
+
+/// Serve the API on `addr`, returns the bound address and the server task.
+pub fn serve(
+    state: Arc<ApiState>,
+    addr: SocketAddr,
+) -> Result<(SocketAddr, JoinHandle<()>), hyper::Error> {
+    let make_service = make_service_fn(move |_conn| {
+        let state = Arc::clone(&state);
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(Arc::clone(&state), req))) }
+    });
+
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    let local_addr = server.local_addr();
+    let handle = tokio::spawn(async move {
+        if let Err(e) = server.await {
+            log::error!("api server stopped: {:?}", e);
+        }
+    });
+    Ok((local_addr, handle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::fees::FeeConfig;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    fn weth() -> Address {
+        "C02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"
+            .parse::<Address>()
+            .unwrap()
+    }
+
+    fn token() -> Address {
+        Address::repeat_byte(0xaa)
+    }
+
+    fn state() -> ApiState {
+        let reserves = ReserveCache::default();
+        // token < weth, reserve0 is the token
+        reserves.insert(
+            Dex::uniswap_v2().pair_address(weth(), token()),
+            (U256::exp10(24) * 2, U256::exp10(20)),
+        );
+        ApiState {
+            weth: weth(),
+            reserves: Arc::new(reserves),
+            opportunities: Arc::new(OpportunityLog::new(2)),
+            fee_policy: Arc::new(FeePolicy::new(FeeConfig::default())),
+            max_weth_in: U256::exp10(20),
+        }
+    }
+
+    async fn get(addr: SocketAddr, path: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn test_sandwich_and_opportunity_log() {
+        let state = state();
+        // the victim gets ~181k tokens for 10 ETH, 175k leaves room to frontrun
+        let params = Params::parse(Some(&format!(
+            "token={:?}&amount_in=10000000000000000000&amount_out_min=175000000000000000000000",
+            token()
+        )));
+        let value = state.sandwich(&params).unwrap();
+        assert!(!value["optimal_sandwich_weth_in"]
+            .as_str()
+            .unwrap()
+            .is_empty());
+
+        let sandwich = univ2::get_sandwitch_state(
+            U256::exp10(18),
+            U256::exp10(19),
+            MinRecv::PairOut(U256::zero()),
+            U256::exp10(20),
+            U256::exp10(24) * 2,
+            TokenTaxes::default(),
+            U256::zero(),
+        )
+        .unwrap();
+        for i in 1..=3 {
+            state
+                .opportunities
+                .push(H256::from_low_u64_be(i), sandwich.clone(), None);
+        }
+        let latest = state.opportunities.latest(5);
+        assert_eq!(2, latest.len());
+        assert_eq!(H256::from_low_u64_be(3), latest[0].tx_hash);
+        assert_eq!(H256::from_low_u64_be(2), latest[1].tx_hash);
+
+        let unsized_log = OpportunityLog::new(0);
+        for i in 1..=3 {
+            unsized_log.push(H256::from_low_u64_be(i), sandwich.clone(), None);
+        }
+        assert_eq!(1, unsized_log.latest(5).len());
+
+        let params = Params::parse(Some(&format!(
+            "token={:?}&amount_in=10000000000000000000&amount_out_min=0&buy_tax_bps=20000",
+            token()
+        )));
+        assert!(matches!(
+            state.sandwich(&params),
+            Err(ApiError::BadParam("buy_tax_bps", _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_http_api() {
+        let (addr, server) = serve(Arc::new(state()), ([127, 0, 0, 1], 0).into()).unwrap();
+
+        let (status, pair) = get(
+            addr,
+            &format!("/pair?token_a={:?}&token_b={:?}", weth(), token()),
+        )
+        .await;
+        assert_eq!(200, status);
+        assert_eq!(
+            json!(Dex::uniswap_v2().pair_address(weth(), token())),
+            pair["pair"]
+        );
+        assert_eq!(json!(token()), pair["token0"]);
+
+        let (status, quote) = get(
+            addr,
+            &format!(
+                "/quote?path={:?},{:?}&amount_in=1000000000000000000",
+                weth(),
+                token()
+            ),
+        )
+        .await;
+        assert_eq!(200, status);
+        assert_eq!(2, quote["amounts"].as_array().unwrap().len());
+
+        let (status, error) = get(
+            addr,
+            &format!("/quote?path={:?},{:?}&amount_in=1", weth(), Address::zero()),
+        )
+        .await;
+        assert_eq!(404, status);
+        assert!(error["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("no cached reserves"));
+
+        let (status, _) = get(addr, "/sandwich?amount_in=1").await;
+        assert_eq!(400, status);
+
+        let (status, opportunities) = get(addr, "/opportunities?limit=5").await;
+        assert_eq!(200, status);
+        assert_eq!(json!([]), opportunities);
+
+        server.abort();
+    }
+}
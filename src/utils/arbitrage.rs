@@ -0,0 +1,274 @@
+//! Backrun arbitrage between forks: a victim swap moves one pool, the same
+//! pair on another fork is left at the old price.
+//!
+//! Two pools chained `base -> bridge -> base` behave like a single pool
+//! `out(x) = A x / (B + C x)`, so the input maximising `out(x) - x` has a
+//! closed form, `x = (sqrt(A B) - B) / C`, and there is a profit only when
+//! `A > B`.
+
+use crate::utils::amm::{self, AmmError, FEE_DENOMINATOR};
+use crate::utils::univ2::{self, Dex, Reserves};
+
+use ethers::prelude::*;
+use serde::Serialize;
+
+/// A swap about to hit one pool, as decoded from a pending tx.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwapIntent {
+    pub dex: Dex,
+    pub token_in: Address,
+    pub token_out: Address,
+    pub amount_in: U256,
+}
+
+/// Reserves of one pool oriented for a swap, with its fee.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pool {
+    pub reserve_in: U256,
+    pub reserve_out: U256,
+    pub fee_numerator: u64,
+}
+
+impl Pool {
+    fn swap(&self, amount_in: U256) -> Result<U256, AmmError> {
+        amm::swap_given_in(
+            amount_in,
+            self.reserve_in,
+            self.reserve_out,
+            self.fee_numerator,
+        )
+        .map(|outcome| outcome.amount_out)
+    }
+}
+
+/// Input of `buy` (base -> bridge) then `sell` (bridge -> base) that
+/// returns the most base token, with what comes back. `None` when the loop
+/// loses money at any size.
+pub fn optimal_two_pool(buy: Pool, sell: Pool) -> Option<(U256, U256)> {
+    let denominator = U256::from(FEE_DENOMINATOR);
+    let (n1, n2) = (
+        U256::from(buy.fee_numerator),
+        U256::from(sell.fee_numerator),
+    );
+
+    let a = n1
+        .checked_mul(n2)?
+        .checked_mul(buy.reserve_out)?
+        .checked_mul(sell.reserve_out)?;
+    let b = (denominator * denominator)
+        .checked_mul(buy.reserve_in)?
+        .checked_mul(sell.reserve_in)?;
+    if a <= b {
+        return None;
+    }
+    let c = n1.checked_mul(
+        denominator
+            .checked_mul(sell.reserve_in)?
+            .checked_add(n2.checked_mul(buy.reserve_out)?)?,
+    )?;
+
+    let root = U256::try_from(a.full_mul(b).integer_sqrt()).ok()?;
+    let amount_in = (root - b) / c;
+    if amount_in.is_zero() {
+        return None;
+    }
+    let amount_out = sell.swap(buy.swap(amount_in).ok()?).ok()?;
+    (amount_out > amount_in).then_some((amount_in, amount_out))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ArbOpportunity {
+    // the loop starts and ends in `base`
+    pub base: Address,
+    pub bridge: Address,
+    pub buy_on: &'static str,
+    pub sell_on: &'static str,
+    pub amount_in: U256,
+    pub amount_out: U256,
+    // in `base`, before gas
+    pub profit: U256,
+}
+
+// `pair` reserves as (reserve of `token_in`, reserve of `token_out`)
+fn oriented(
+    reserves: &impl Reserves,
+    dex: &Dex,
+    token_in: Address,
+    token_out: Address,
+) -> Option<(U256, U256)> {
+    let (reserve0, reserve1) = reserves.reserves(dex.pair_address(token_in, token_out))?;
+    if univ2::sort_token(token_in, token_out).0 == token_in {
+        Some((reserve0, reserve1))
+    } else {
+        Some((reserve1, reserve0))
+    }
+}
+
+/// Arbitrage between the pool `intent` trades on, once the swap landed, and
+/// the same pair on every other dex with cached reserves. Loops are counted
+/// in `intent.token_in`, most profitable first.
+pub fn find(intent: &SwapIntent, dexes: &[Dex], reserves: &impl Reserves) -> Vec<ArbOpportunity> {
+    let Some((reserve_in, reserve_out)) =
+        oriented(reserves, &intent.dex, intent.token_in, intent.token_out)
+    else {
+        return vec![];
+    };
+    let Ok(after) = amm::swap_given_in(
+        intent.amount_in,
+        reserve_in,
+        reserve_out,
+        intent.dex.fee_numerator,
+    ) else {
+        return vec![];
+    };
+    // the victim pool after the swap, token_in -> token_out and back
+    let victim_forward = Pool {
+        reserve_in: after.new_reserve_in,
+        reserve_out: after.new_reserve_out,
+        fee_numerator: intent.dex.fee_numerator,
+    };
+    let victim_backward = Pool {
+        reserve_in: after.new_reserve_out,
+        reserve_out: after.new_reserve_in,
+        fee_numerator: intent.dex.fee_numerator,
+    };
+
+    let mut found = Vec::new();
+    for dex in dexes.iter().filter(|dex| dex.name != intent.dex.name) {
+        let Some((other_in, other_out)) =
+            oriented(reserves, dex, intent.token_in, intent.token_out)
+        else {
+            continue;
+        };
+        let other_forward = Pool {
+            reserve_in: other_in,
+            reserve_out: other_out,
+            fee_numerator: dex.fee_numerator,
+        };
+        let other_backward = Pool {
+            reserve_in: other_out,
+            reserve_out: other_in,
+            fee_numerator: dex.fee_numerator,
+        };
+
+        // the victim made token_out dear on its pool, usually buying it on
+        // the other one pays; the reverse covers pools that were off already
+        for (buy, sell, buy_on, sell_on) in [
+            (other_forward, victim_backward, dex.name, intent.dex.name),
+            (victim_forward, other_backward, intent.dex.name, dex.name),
+        ] {
+            if let Some((amount_in, amount_out)) = optimal_two_pool(buy, sell) {
+                found.push(ArbOpportunity {
+                    base: intent.token_in,
+                    bridge: intent.token_out,
+                    buy_on,
+                    sell_on,
+                    amount_in,
+                    amount_out,
+                    profit: amount_out - amount_in,
+                });
+            }
+        }
+    }
+    found.sort_by_key(|arb| std::cmp::Reverse(arb.profit));
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn insert(
+        reserves: &mut HashMap<Address, (U256, U256)>,
+        dex: &Dex,
+        token_a: Address,
+        token_b: Address,
+        reserve_a: U256,
+        reserve_b: U256,
+    ) {
+        let value = if univ2::sort_token(token_a, token_b).0 == token_a {
+            (reserve_a, reserve_b)
+        } else {
+            (reserve_b, reserve_a)
+        };
+        reserves.insert(dex.pair_address(token_a, token_b), value);
+    }
+
+    fn profit(buy: Pool, sell: Pool, amount_in: U256) -> U256 {
+        sell.swap(buy.swap(amount_in).unwrap())
+            .unwrap()
+            .saturating_sub(amount_in)
+    }
+
+    #[test]
+    fn test_optimal_two_pool() {
+        let buy = Pool {
+            reserve_in: U256::exp10(21),
+            reserve_out: U256::exp10(24) * 2,
+            fee_numerator: 997,
+        };
+        let sell = Pool {
+            reserve_in: U256::exp10(24) * 2,
+            reserve_out: U256::exp10(21) * 11 / 10,
+            fee_numerator: 997,
+        };
+        let (amount_in, amount_out) = optimal_two_pool(buy, sell).unwrap();
+        let best = amount_out - amount_in;
+        assert_eq!(best, profit(buy, sell, amount_in));
+        assert!(best >= profit(buy, sell, amount_in * 9 / 10));
+        assert!(best >= profit(buy, sell, amount_in * 11 / 10));
+
+        // the same price on both sides never pays the fees
+        let flat = Pool {
+            reserve_in: U256::exp10(24) * 2,
+            reserve_out: U256::exp10(21),
+            fee_numerator: 997,
+        };
+        assert_eq!(None, optimal_two_pool(buy, flat));
+    }
+
+    #[test]
+    fn test_backrun_victim_on_other_fork() {
+        let weth = Address::repeat_byte(0xee);
+        let token = Address::repeat_byte(0xaa);
+        let (uniswap, sushiswap) = (Dex::uniswap_v2(), Dex::sushiswap());
+        let mut reserves = HashMap::new();
+        insert(
+            &mut reserves,
+            &uniswap,
+            weth,
+            token,
+            U256::exp10(21),
+            U256::exp10(24),
+        );
+        insert(
+            &mut reserves,
+            &sushiswap,
+            weth,
+            token,
+            U256::exp10(20) * 5,
+            U256::exp10(23) * 5,
+        );
+
+        let intent = SwapIntent {
+            dex: uniswap,
+            token_in: weth,
+            token_out: token,
+            amount_in: U256::exp10(20),
+        };
+        let found = find(&intent, &[uniswap, sushiswap], &reserves);
+        assert_eq!(1, found.len());
+        let arb = &found[0];
+        assert_eq!(("sushiswap", "uniswapv2"), (arb.buy_on, arb.sell_on));
+        assert_eq!((weth, token), (arb.base, arb.bridge));
+        assert!(arb.profit > U256::zero());
+
+        // a victim too small to beat two fees leaves nothing
+        let small = SwapIntent {
+            amount_in: U256::exp10(15),
+            ..intent
+        };
+        assert!(find(&small, &[uniswap, sushiswap], &reserves).is_empty());
+    }
+}
@@ -0,0 +1,777 @@
+//! Offline replay of recorded blocks and mempool traffic.
+//!
+//! An archive directory holds `*.jsonl` files of [`Record`]s, one per line,
+//! and optionally a `snapshots/` directory of [`Snapshot`] JSON files. Pair
+//! reserves are rebuilt block by block from snapshots and `Sync` logs, and
+//! every victim seen in the mempool before its inclusion block is evaluated
+//! against the reserves right before that block. Receipt records are only
+//! read by the on-chain sandwich detector.
+
+use crate::env_store::{get_env_or, EnvError};
+use crate::utils::deadline::{DeadlineGuard, FixedClock, SLOT_TIME};
+use crate::utils::fees::{FeeConfig, FeePolicy};
+use crate::utils::rejection::Rejection;
+use crate::utils::screener::ScreenerConfig;
+use crate::utils::tokens;
+use crate::utils::univ2::{self, Dex, MinRecv, QuoteError, Reserves, SandWichState, TokenTaxes};
+
+use ethers::{abi::AbiDecode, prelude::*};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+abigen!(
+    IRouterEthIn,
+    r#"[
+        function swapExactETHForTokens(uint amountOutMin, address[] calldata path, address to, uint deadline)
+        function swapExactETHForTokensSupportingFeeOnTransferTokens(uint amountOutMin, address[] calldata path, address to, uint deadline)
+    ]"#,
+);
+
+#[derive(Debug)]
+pub enum BacktestError {
+    IoError(PathBuf, std::io::Error),
+    JsonError {
+        file: PathBuf,
+        line: usize,
+        error: serde_json::Error,
+    },
+}
+
+/// A mined block, `base_fee` prices the bundle that would have landed in it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockRecord {
+    pub number: U64,
+    pub timestamp: U256,
+    pub base_fee: U256,
+    pub transactions: Vec<H256>,
+}
+
+/// A pending transaction, `seen_at` is the head when it showed up.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingRecord {
+    pub seen_at: U64,
+    pub tx: Transaction,
+}
+
+/// Pair reserves at the end of `block`, in `(reserve0, reserve1)` order.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub block: U64,
+    pub reserves: HashMap<Address, (U256, U256)>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Record {
+    Block(BlockRecord),
+    Pending(Box<PendingRecord>),
+    Log(Log),
+    Snapshot(Snapshot),
+    Receipt(Box<TransactionReceipt>),
+}
+
+/// Recorded data sorted by block.
+#[derive(Debug, Clone, Default)]
+pub struct Archive {
+    pub blocks: BTreeMap<U64, BlockRecord>,
+    pub logs: BTreeMap<U64, Vec<Log>>,
+    pub snapshots: BTreeMap<U64, Vec<Snapshot>>,
+    pub pending: Vec<PendingRecord>,
+    pub receipts: BTreeMap<U64, Vec<TransactionReceipt>>,
+}
+
+impl Archive {
+    pub fn push(&mut self, record: Record) {
+        match record {
+            Record::Block(block) => {
+                self.blocks.insert(block.number, block);
+            }
+            Record::Pending(pending) => self.pending.push(*pending),
+            Record::Log(log) => {
+                if let Some(number) = log.block_number {
+                    self.logs.entry(number).or_default().push(log);
+                }
+            }
+            Record::Snapshot(snapshot) => {
+                self.snapshots
+                    .entry(snapshot.block)
+                    .or_default()
+                    .push(snapshot);
+            }
+            Record::Receipt(receipt) => {
+                if let Some(number) = receipt.block_number {
+                    self.receipts.entry(number).or_default().push(*receipt);
+                }
+            }
+        }
+    }
+
+    /// Read every `*.jsonl` file of `dir` and the `snapshots/*.json` files.
+    pub fn load(dir: &Path) -> Result<Self, BacktestError> {
+        let mut archive = Archive::default();
+
+        for file in sorted_files(dir, "jsonl")? {
+            let content =
+                fs::read_to_string(&file).map_err(|e| BacktestError::IoError(file.clone(), e))?;
+            for (i, line) in content.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let record =
+                    serde_json::from_str(line).map_err(|error| BacktestError::JsonError {
+                        file: file.clone(),
+                        line: i + 1,
+                        error,
+                    })?;
+                archive.push(record);
+            }
+        }
+
+        let snapshot_dir = dir.join("snapshots");
+        if snapshot_dir.is_dir() {
+            for file in sorted_files(&snapshot_dir, "json")? {
+                let content = fs::read_to_string(&file)
+                    .map_err(|e| BacktestError::IoError(file.clone(), e))?;
+                let snapshot =
+                    serde_json::from_str(&content).map_err(|error| BacktestError::JsonError {
+                        file: file.clone(),
+                        line: 1,
+                        error,
+                    })?;
+                archive.push(Record::Snapshot(snapshot));
+            }
+        }
+
+        Ok(archive)
+    }
+}
+
+/// Appends [`Record`]s to a new `*.jsonl` file of an archive directory, in
+/// the layout [`Archive::load`] reads back.
+pub struct ArchiveWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl ArchiveWriter {
+    /// Start `<name>.jsonl` in `dir`, creating the directory when missing.
+    pub fn create(dir: &Path, name: &str) -> Result<Self, BacktestError> {
+        fs::create_dir_all(dir).map_err(|e| BacktestError::IoError(dir.to_path_buf(), e))?;
+        let path = dir.join(format!("{}.jsonl", name));
+        let file = File::create(&path).map_err(|e| BacktestError::IoError(path.clone(), e))?;
+        Ok(Self {
+            path,
+            writer: BufWriter::new(file),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write one record and flush it, an interrupted recording keeps every
+    /// line written so far.
+    pub fn write(&mut self, record: &Record) -> Result<(), BacktestError> {
+        let line = serde_json::to_string(record).map_err(|error| BacktestError::JsonError {
+            file: self.path.clone(),
+            line: 0,
+            error,
+        })?;
+        writeln!(self.writer, "{}", line)
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| BacktestError::IoError(self.path.clone(), e))
+    }
+}
+
+fn sorted_files(dir: &Path, extension: &str) -> Result<Vec<PathBuf>, BacktestError> {
+    let entries = fs::read_dir(dir).map_err(|e| BacktestError::IoError(dir.to_path_buf(), e))?;
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == extension))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// ETH-in router call the live bot sandwiches.
+#[derive(Debug, Clone)]
+pub struct VictimSwap {
+    pub amount_out_min: MinRecv,
+    pub path: Vec<Address>,
+    pub deadline: U256,
+}
+
+pub fn decode_victim(tx: &Transaction) -> Option<VictimSwap> {
+    if let Ok(call) = SwapExactETHForTokensCall::decode(&tx.input) {
+        return Some(VictimSwap {
+            amount_out_min: MinRecv::PairOut(call.amount_out_min),
+            path: call.path,
+            deadline: call.deadline,
+        });
+    }
+    if let Ok(call) = SwapExactETHForTokensSupportingFeeOnTransferTokensCall::decode(&tx.input) {
+        return Some(VictimSwap {
+            amount_out_min: MinRecv::Received(call.amount_out_min),
+            path: call.path,
+            deadline: call.deadline,
+        });
+    }
+    None
+}
+
+#[derive(Debug, Clone)]
+pub struct BacktestConfig {
+    pub weth: Address,
+    pub dex: Dex,
+    // most weth a single frontrun may use
+    pub capital: U256,
+    pub fees: FeeConfig,
+    pub tax_overrides: HashMap<Address, TokenTaxes>,
+}
+
+impl BacktestConfig {
+    pub fn from_env(weth: Address) -> Result<Self, EnvError> {
+        let capital: u128 = get_env_or("BACKTEST_CAPITAL_WEI", 10_000_000_000_000_000_000)?;
+        let dex_name: String = get_env_or("BACKTEST_DEX", "uniswapv2".to_string())?;
+        Ok(Self {
+            weth,
+            dex: Dex::from_name(&dex_name)
+                .ok_or_else(|| EnvError::from(format!("unknown dex {}", dex_name)))?,
+            capital: capital.into(),
+            fees: FeeConfig::from_env()?,
+            tax_overrides: ScreenerConfig::from_env()?.tax_overrides,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DayReport {
+    // UTC date, YYYY-MM-DD
+    pub day: String,
+    pub victims: usize,
+    pub hits: usize,
+    pub revenue: U256,
+    pub gas_cost: U256,
+    pub profit: I256,
+    // weth sent by all frontruns of the day, and the most in a single block
+    pub capital_used: U256,
+    pub peak_capital: U256,
+}
+
+impl DayReport {
+    pub fn hit_rate(&self) -> f64 {
+        if self.victims == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / self.victims as f64
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BacktestReport {
+    pub blocks: usize,
+    pub days: Vec<DayReport>,
+    pub rejections: BTreeMap<&'static str, usize>,
+    // pending txs that are not ETH-in router calls or never got mined
+    pub skipped: usize,
+}
+
+impl fmt::Display for BacktestReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} blocks replayed, {} pending txs skipped",
+            self.blocks, self.skipped
+        )?;
+        writeln!(
+            f,
+            "{:<10} {:>8} {:>6} {:>8} {:>24} {:>24} {:>24}",
+            "day",
+            "victims",
+            "hits",
+            "hit rate",
+            "profit (ETH)",
+            "capital used (ETH)",
+            "peak capital (ETH)"
+        )?;
+        for day in &self.days {
+            writeln!(
+                f,
+                "{:<10} {:>8} {:>6} {:>7.1}% {:>24} {:>24} {:>24}",
+                day.day,
+                day.victims,
+                day.hits,
+                day.hit_rate() * 100.0,
+                tokens::format_signed_units(day.profit, 18),
+                tokens::format_units(day.capital_used, 18),
+                tokens::format_units(day.peak_capital, 18)
+            )?;
+        }
+        for (reason, count) in &self.rejections {
+            writeln!(f, "rejected {:<24} {}", reason, count)?;
+        }
+        Ok(())
+    }
+}
+
+fn day_of(timestamp: U256) -> String {
+    chrono::DateTime::from_timestamp(timestamp.low_u64() as i64, 0)
+        .map(|date| date.format("%Y-%m-%d").to_string())
+        .unwrap_or_default()
+}
+
+pub struct Backtester {
+    config: BacktestConfig,
+    fee_policy: FeePolicy,
+}
+
+impl Backtester {
+    pub fn new(config: BacktestConfig) -> Self {
+        let fee_policy = FeePolicy::new(config.fees.clone());
+        Self { config, fee_policy }
+    }
+
+    /// Sandwich `victim` in `block`, `reserves` being the state right before
+    /// it. `None` when the tx isn't a victim we would look at.
+    pub fn evaluate(
+        &self,
+        victim: &Transaction,
+        block: &BlockRecord,
+        reserves: &impl Reserves,
+    ) -> Option<Result<SandWichState, Rejection>> {
+        let swap = decode_victim(victim)?;
+        if swap.path.len() < 2 || swap.path[0] != self.config.weth {
+            return None;
+        }
+        Some(self.evaluate_swap(victim, &swap, block, reserves))
+    }
+
+    fn evaluate_swap(
+        &self,
+        victim: &Transaction,
+        swap: &VictimSwap,
+        block: &BlockRecord,
+        reserves: &impl Reserves,
+    ) -> Result<SandWichState, Rejection> {
+        let clock = FixedClock {
+            number: block.number,
+            timestamp: block.timestamp,
+        };
+        DeadlineGuard::new(Arc::new(clock), SLOT_TIME).check_at(swap.deadline, block.number)?;
+
+        let dex = &self.config.dex;
+        let user_min_recv = if swap.path.len() == 2 {
+            swap.amount_out_min
+        } else {
+            MinRecv::PairOut(
+                univ2::get_amounts_in(swap.amount_out_min.amount(), &swap.path[1..], dex, reserves)
+                    .map_err(Rejection::Quote)?
+                    .amounts[0],
+            )
+        };
+
+        let (weth, token) = (swap.path[0], swap.path[1]);
+        let pair = dex.pair_address(weth, token);
+        let (reserve0, reserve1) = reserves
+            .reserves(pair)
+            .ok_or(Rejection::Quote(QuoteError::UnknownPair(pair)))?;
+        let (reserve_weth, reserve_token) = if univ2::sort_token(weth, token).0 == weth {
+            (reserve0, reserve1)
+        } else {
+            (reserve1, reserve0)
+        };
+
+        let taxes = self
+            .config
+            .tax_overrides
+            .get(&token)
+            .copied()
+            .unwrap_or_default();
+        let fees = self.fee_policy.sandwich_fees_at(victim, block.base_fee)?;
+
+        let optimal_weth_in = univ2::calc_sandwich_optima_in(
+            victim.value,
+            user_min_recv,
+            reserve_weth,
+            reserve_token,
+            self.config.capital,
+            taxes,
+        );
+        univ2::get_sandwitch_state(
+            optimal_weth_in,
+            victim.value,
+            user_min_recv,
+            reserve_weth,
+            reserve_token,
+            taxes,
+            fees.gas_cost,
+        )
+    }
+
+    pub fn run(&self, archive: &Archive) -> BacktestReport {
+        let mut report = BacktestReport::default();
+
+        // block and position in it
+        let inclusion: HashMap<H256, (U64, usize)> = archive
+            .blocks
+            .values()
+            .flat_map(|block| {
+                block
+                    .transactions
+                    .iter()
+                    .enumerate()
+                    .map(|(i, hash)| (*hash, (block.number, i)))
+            })
+            .collect();
+        let mut victims: BTreeMap<U64, Vec<(usize, &Transaction)>> = BTreeMap::new();
+        let mut seen = HashSet::new();
+        for pending in &archive.pending {
+            if !seen.insert(pending.tx.hash) {
+                continue;
+            }
+            match inclusion.get(&pending.tx.hash) {
+                Some((block, i)) if pending.seen_at < *block => {
+                    victims.entry(*block).or_default().push((*i, &pending.tx))
+                }
+                _ => report.skipped += 1,
+            }
+        }
+        // each hit moves the reserves the next victim trades against, so
+        // they are evaluated in the order they were mined, not seen
+        for block_victims in victims.values_mut() {
+            block_victims.sort_by_key(|(i, _)| *i);
+        }
+
+        let mut reserves: HashMap<Address, (U256, U256)> = HashMap::new();
+        let mut days: BTreeMap<String, DayReport> = BTreeMap::new();
+        // blocks with state left to apply, also those without a block record
+        let mut pending_state: BTreeSet<U64> = archive
+            .snapshots
+            .keys()
+            .chain(archive.logs.keys())
+            .copied()
+            .collect();
+        for (number, block) in &archive.blocks {
+            while let Some(state_block) = pending_state.first().copied() {
+                if state_block >= *number {
+                    break;
+                }
+                pending_state.pop_first();
+                apply_state(archive, state_block, &mut reserves);
+            }
+            let day = days
+                .entry(day_of(block.timestamp))
+                .or_insert_with_key(|day| DayReport {
+                    day: day.clone(),
+                    ..Default::default()
+                });
+
+            let mut block_capital = U256::zero();
+            for (_, victim) in victims.get(number).into_iter().flatten() {
+                let outcome = match self.evaluate(victim, block, &reserves) {
+                    Some(outcome) => outcome,
+                    None => {
+                        report.skipped += 1;
+                        continue;
+                    }
+                };
+                day.victims += 1;
+                match outcome {
+                    Ok(state) if state.profit > I256::zero() => {
+                        day.hits += 1;
+                        day.revenue += state.revenue;
+                        day.gas_cost += state.gas_cost;
+                        day.profit += state.profit;
+                        day.capital_used += state.optimal_sandwich_weth_in;
+                        block_capital += state.optimal_sandwich_weth_in;
+                        // the next victim on the pair trades after our bundle
+                        self.apply_sandwich(victim, &state, &mut reserves);
+                    }
+                    Ok(_) => {
+                        *report
+                            .rejections
+                            .entry(Rejection::Unprofitable.reason())
+                            .or_default() += 1
+                    }
+                    Err(rejection) => {
+                        *report.rejections.entry(rejection.reason()).or_default() += 1
+                    }
+                }
+            }
+            day.peak_capital = day.peak_capital.max(block_capital);
+            report.blocks += 1;
+        }
+
+        report.days = days.into_values().collect();
+        report
+    }
+
+    // reserves of the pair `victim` buys from once frontrun, victim and
+    // backrun are mined
+    fn apply_sandwich(
+        &self,
+        victim: &Transaction,
+        state: &SandWichState,
+        reserves: &mut HashMap<Address, (U256, U256)>,
+    ) {
+        let Some(swap) = decode_victim(victim) else {
+            return;
+        };
+        let (weth, token) = (swap.path[0], swap.path[1]);
+        // the backrun sells the token, its reserves are (token, weth)
+        let (reserve_token, reserve_weth) =
+            (state.back_run.new_reserve_a, state.back_run.new_reserve_b);
+        let pair_reserves = if univ2::sort_token(weth, token).0 == weth {
+            (reserve_weth, reserve_token)
+        } else {
+            (reserve_token, reserve_weth)
+        };
+        reserves.insert(self.config.dex.pair_address(weth, token), pair_reserves);
+    }
+}
+
+// snapshots taken at `block`, then its `Sync` logs in order
+fn apply_state(archive: &Archive, block: U64, reserves: &mut HashMap<Address, (U256, U256)>) {
+    for snapshot in archive.snapshots.get(&block).into_iter().flatten() {
+        reserves.extend(snapshot.reserves.iter().map(|(k, v)| (*k, *v)));
+    }
+    let mut logs: Vec<&Log> = archive.logs.get(&block).into_iter().flatten().collect();
+    logs.sort_by_key(|log| log.log_index);
+    for (pair, reserve0, reserve1) in logs.into_iter().filter_map(univ2::decode_sync) {
+        reserves.insert(pair, (reserve0, reserve1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::abi::AbiEncode;
+    use ethers::utils::keccak256;
+
+    fn weth() -> Address {
+        "C02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"
+            .parse::<Address>()
+            .unwrap()
+    }
+
+    fn config() -> BacktestConfig {
+        BacktestConfig {
+            weth: weth(),
+            dex: Dex::uniswap_v2(),
+            capital: U256::exp10(20),
+            fees: FeeConfig::default(),
+            tax_overrides: HashMap::new(),
+        }
+    }
+
+    fn sync_log(pair: Address, block: u64, reserve0: U256, reserve1: U256) -> Log {
+        let mut data = [0u8; 64];
+        reserve0.to_big_endian(&mut data[..32]);
+        reserve1.to_big_endian(&mut data[32..]);
+        Log {
+            address: pair,
+            topics: vec![H256::from(keccak256("Sync(uint112,uint112)"))],
+            data: data.to_vec().into(),
+            block_number: Some(block.into()),
+            log_index: Some(U256::zero()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_writer_round_trip() {
+        let dir = std::env::temp_dir().join(format!("unibot-archive-{}", std::process::id()));
+        let mut writer = ArchiveWriter::create(&dir, "session").unwrap();
+        writer
+            .write(&Record::Block(BlockRecord {
+                number: 7.into(),
+                timestamp: U256::from(84),
+                base_fee: U256::exp10(9),
+                transactions: vec![H256::repeat_byte(1)],
+            }))
+            .unwrap();
+        writer
+            .write(&Record::Log(sync_log(
+                Address::repeat_byte(1),
+                7,
+                5.into(),
+                9.into(),
+            )))
+            .unwrap();
+        drop(writer);
+
+        let archive = Archive::load(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            vec![H256::repeat_byte(1)],
+            archive.blocks[&U64::from(7)].transactions
+        );
+        assert_eq!(1, archive.logs[&U64::from(7)].len());
+    }
+
+    #[test]
+    fn test_run_sandwiches_victim_before_inclusion() {
+        let token = Address::repeat_byte(0xaa);
+        let pair = Dex::uniswap_v2().pair_address(weth(), token);
+        // token < weth, reserve0 is the token
+        let (reserve_token, reserve_weth) = (U256::exp10(24) * 2, U256::exp10(20));
+
+        let amount_in = U256::exp10(19);
+        let expected = univ2::get_univ2_data_given_in(amount_in, reserve_weth, reserve_token)
+            .unwrap()
+            .amount_out;
+        let victim = Transaction {
+            hash: H256::repeat_byte(7),
+            value: amount_in,
+            gas_price: Some(U256::exp10(10)),
+            input: SwapExactETHForTokensCall {
+                // 3% slippage
+                amount_out_min: expected * 97 / 100,
+                path: vec![weth(), token],
+                to: Address::repeat_byte(9),
+                deadline: U256::from(2_000),
+            }
+            .encode()
+            .into(),
+            ..Default::default()
+        };
+        let late = Transaction {
+            hash: H256::repeat_byte(8),
+            ..victim.clone()
+        };
+
+        let mut archive = Archive::default();
+        archive.push(Record::Log(sync_log(pair, 99, reserve_token, reserve_weth)));
+        for (number, transactions) in [(99u64, vec![]), (100, vec![victim.hash, late.hash])] {
+            archive.push(Record::Block(BlockRecord {
+                number: number.into(),
+                timestamp: U256::from(number * 12),
+                base_fee: U256::exp10(9),
+                transactions,
+            }));
+        }
+        archive.push(Record::Pending(Box::new(PendingRecord {
+            seen_at: 99.into(),
+            tx: victim,
+        })));
+        // seen only once mined, we never had a chance
+        archive.push(Record::Pending(Box::new(PendingRecord {
+            seen_at: 100.into(),
+            tx: late,
+        })));
+
+        let report = Backtester::new(config()).run(&archive);
+        assert_eq!(2, report.blocks);
+        assert_eq!(1, report.skipped);
+        assert_eq!(1, report.days.len());
+        let day = &report.days[0];
+        assert_eq!("1970-01-01", day.day);
+        assert_eq!((1, 1), (day.victims, day.hits));
+        assert!(day.profit > I256::zero());
+        assert_eq!(day.capital_used, day.peak_capital);
+    }
+
+    #[test]
+    fn test_run_applies_earlier_state_and_moves_hit_pairs() {
+        let token = Address::repeat_byte(0xaa);
+        let pair = Dex::uniswap_v2().pair_address(weth(), token);
+        let (reserve_token, reserve_weth) = (U256::exp10(24) * 2, U256::exp10(20));
+
+        let victim = |hash: u8, amount_in: U256| Transaction {
+            hash: H256::repeat_byte(hash),
+            value: amount_in,
+            gas_price: Some(U256::exp10(10)),
+            input: SwapExactETHForTokensCall {
+                amount_out_min: univ2::get_univ2_data_given_in(
+                    amount_in,
+                    reserve_weth,
+                    reserve_token,
+                )
+                .unwrap()
+                .amount_out
+                    * 97
+                    / 100,
+                path: vec![weth(), token],
+                to: Address::repeat_byte(9),
+                deadline: U256::from(2_000),
+            }
+            .encode()
+            .into(),
+            ..Default::default()
+        };
+        let (first, second) = (victim(7, U256::exp10(19)), victim(8, U256::exp10(19) / 2));
+
+        let mined = vec![first.hash, second.hash];
+        let archive = |pending: Vec<Transaction>| {
+            // the start snapshot has no block record of its own
+            let mut archive = Archive::default();
+            archive.push(Record::Snapshot(Snapshot {
+                block: 98.into(),
+                reserves: HashMap::from([(pair, (reserve_token, reserve_weth))]),
+            }));
+            archive.push(Record::Block(BlockRecord {
+                number: 100.into(),
+                timestamp: U256::from(1_200),
+                base_fee: U256::exp10(9),
+                transactions: mined.clone(),
+            }));
+            for tx in pending {
+                archive.push(Record::Pending(Box::new(PendingRecord {
+                    seen_at: 99.into(),
+                    tx,
+                })));
+            }
+            archive
+        };
+
+        // seen in the other order than mined
+        let report = Backtester::new(config()).run(&archive(vec![second.clone(), first.clone()]));
+        let day = &report.days[0];
+        // after the first sandwich the second victim's bound no longer holds
+        assert_eq!((2, 1), (day.victims, day.hits));
+        assert_eq!(
+            Some(&1),
+            report.rejections.get(Rejection::SanityCheckFailed.reason())
+        );
+        // and the hit is the victim mined first
+        let alone = Backtester::new(config()).run(&archive(vec![first]));
+        assert_eq!(alone.days[0].profit, day.profit);
+    }
+
+    #[test]
+    fn test_load_from_dir() {
+        let dir = std::env::temp_dir().join(format!("backtest-{}", std::process::id()));
+        fs::create_dir_all(dir.join("snapshots")).unwrap();
+
+        let block = Record::Block(BlockRecord {
+            number: 5.into(),
+            timestamp: U256::from(60),
+            base_fee: U256::one(),
+            transactions: vec![],
+        });
+        fs::write(
+            dir.join("blocks.jsonl"),
+            format!("{}\n\n", serde_json::to_string(&block).unwrap()),
+        )
+        .unwrap();
+        let snapshot = Snapshot {
+            block: 4.into(),
+            reserves: HashMap::from([(Address::repeat_byte(1), (U256::one(), U256::one()))]),
+        };
+        fs::write(
+            dir.join("snapshots").join("4.json"),
+            serde_json::to_string(&snapshot).unwrap(),
+        )
+        .unwrap();
+
+        let archive = Archive::load(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(1, archive.blocks.len());
+        assert_eq!(1, archive.snapshots[&U64::from(4)].len());
+    }
+}
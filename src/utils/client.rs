@@ -1,14 +1,32 @@
 use crate::constants::*;
-use crate::env_store::EnvStore;
+use crate::env_store::{get_env_or, EnvError, EnvStore};
+use crate::utils::api::OpportunityLog;
+use crate::utils::arbitrage::{self, ArbOpportunity, SwapIntent};
 use crate::utils::contract_abi::UniswapV2Router02;
+use crate::utils::cycles::{CycleConfig, PoolGraph};
+use crate::utils::deadline::{DeadlineGuard, SLOT_TIME};
+use crate::utils::events::EventBus;
+use crate::utils::fees::{self, FeeConfig, FeePolicy};
+use crate::utils::inventory::{Inventory, InventoryConfig};
+use crate::utils::metrics::Metrics;
+use crate::utils::multicall::{CallBatcher, MulticallConfig};
+use crate::utils::nonce::NonceManager;
+use crate::utils::pairs::PairValidator;
+use crate::utils::pending::{PendingOverlay, PendingSwap};
+use crate::utils::pools::{PoolIndex, PoolIndexError};
+use crate::utils::pricing::{PriceOracle, PricingConfig};
+use crate::utils::rejection::Rejection;
+use crate::utils::screener::{ScreenReason, ScreenerConfig, TokenScreener, TokenVerdict};
+use crate::utils::swaps::{NormalizedSwap, Protocol, SwapAmount};
+use crate::utils::tokens::{TokenError, TokenInfo, TokenRegistry};
 use crate::utils::univ2;
+use crate::utils::validator::{TokenIn, VictimCheck, VictimValidator};
 
 use ethers::prelude::*;
-use ethers::utils::keccak256;
-use log::{debug, info, warn};
-use std::env::VarError;
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant};
 
 use eyre::Result;
 
@@ -23,17 +41,22 @@ use ethers::{
 };
 
 use hex::FromHexError;
-use univ2::{PairState, SandWichState};
+use univ2::{
+    Dex, MinRecv, PathQuote, QuoteError, ReserveCache, Reserves, SandWichState, TokenTaxes,
+};
 
 #[derive(Debug)]
 pub enum UniswapV2Error {
     ClientError(WsClientError),
     ContractError(ContractError<UniswapV2Middleware>),
+    EnvError(EnvError),
     HexError(FromHexError),
     IntoError(String),
+    MetricsError(prometheus::Error),
+    PoolIndexError(PoolIndexError),
     ProviderError(ProviderError),
     SigningError(ethers::core::k256::ecdsa::Error),
-    VarError(VarError),
+    TokenError(TokenError),
 }
 
 pub type UniswapV2Middleware = SignerMiddleware<Provider<Ws>, Wallet<SigningKey>>;
@@ -42,22 +65,34 @@ pub struct UniswapV2Client {
     envstore: EnvStore,
     provider: Arc<UniswapV2Middleware>,
     router: UniswapV2Router02<UniswapV2Middleware>,
-}
-
-abigen!(
-    IUniswapV2Pair,
-    r#"[
-        function getReserves() external view returns (uint112 reserve0, uint112 reserve1, uint32 blockTimestampLast)
-    ]"#,
-);
-
-fn time() -> u64 {
-    let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-    return time.as_secs();
+    signers: Vec<LocalWallet>,
+    nonce_manager: NonceManager<Ws>,
+    fee_policy: Arc<FeePolicy>,
+    deadline_guard: DeadlineGuard,
+    inventory: Inventory<Ws>,
+    screener: TokenScreener<Ws>,
+    validator: VictimValidator<Ws>,
+    metrics: Arc<Metrics>,
+    reserve_cache: Arc<ReserveCache>,
+    opportunities: Arc<OpportunityLog>,
+    events: EventBus,
+    tokens: Arc<TokenRegistry>,
+    prices: Arc<PriceOracle>,
+    pool_graph: PoolGraph,
+    pool_index: Arc<PoolIndex>,
+    pairs: PairValidator<Ws>,
+    batcher: CallBatcher<UniswapV2Middleware>,
+    pending: PendingOverlay,
 }
 
 impl<'a> UniswapV2Client {
-    pub async fn new(env: EnvStore) -> Result<Self, UniswapV2Error> {
+    /// Client for the bot watching `router`, with the addresses from the
+    /// shared `cli::Config`.
+    pub async fn new(
+        env: EnvStore,
+        router: Address,
+        weth: Address,
+    ) -> Result<Self, UniswapV2Error> {
         let provider = Provider::new(
             Ws::connect(format!("{}", env.get_ws_url()))
                 .await
@@ -75,7 +110,61 @@ impl<'a> UniswapV2Client {
         )
         .with_chain_id(chain_id.as_u64());
 
+        let mut signers = vec![wallet.clone()];
+        for key in env.get_signer_pool_keys() {
+            signers.push(
+                LocalWallet::from(
+                    SigningKey::from_slice(key).map_err(UniswapV2Error::SigningError)?,
+                )
+                .with_chain_id(chain_id.as_u64()),
+            );
+        }
+
+        let nonce_manager = NonceManager::new(
+            Arc::new(provider.clone()),
+            signers.iter().map(|s| s.address()).collect(),
+        );
+
+        let executor =
+            get_env_or("EXECUTOR_ADDRESS", wallet.address()).map_err(UniswapV2Error::EnvError)?;
+        let multicall = MulticallConfig::from_env().map_err(UniswapV2Error::EnvError)?;
+        let inventory = Inventory::new(
+            Arc::new(provider.clone()),
+            InventoryConfig::from_env().map_err(UniswapV2Error::EnvError)?,
+            multicall.clone(),
+            executor,
+            weth,
+        );
+        let screener = TokenScreener::new(
+            Arc::new(provider.clone()),
+            ScreenerConfig::from_env().map_err(UniswapV2Error::EnvError)?,
+            executor,
+            weth,
+        );
+
+        let validator = VictimValidator::new(Arc::new(provider.clone()), router);
+
+        let fee_policy = Arc::new(FeePolicy::new(
+            FeeConfig::from_env().map_err(UniswapV2Error::EnvError)?,
+        ));
+        let deadline_guard = DeadlineGuard::new(fee_policy.clone(), SLOT_TIME);
+
+        let pool_index = Arc::new(PoolIndex::from_env().map_err(UniswapV2Error::PoolIndexError)?);
+        let pairs = PairValidator::new(
+            Arc::new(provider.clone()),
+            Arc::clone(&pool_index),
+            Duration::from_secs(
+                get_env_or("PAIR_MISSING_TTL_SECS", 600).map_err(UniswapV2Error::EnvError)?,
+            ),
+        );
+
         let provider = Arc::new(SignerMiddleware::new(provider.clone(), wallet));
+        let batcher = CallBatcher::new(Arc::clone(&provider), multicall);
+
+        let tokens = Arc::new(TokenRegistry::from_env().map_err(UniswapV2Error::TokenError)?);
+        let metrics = Arc::new(Metrics::new().map_err(UniswapV2Error::MetricsError)?);
+        let pool_graph =
+            PoolGraph::new(CycleConfig::from_env(weth).map_err(UniswapV2Error::EnvError)?);
 
         Ok(UniswapV2Client {
             envstore: env,
@@ -84,181 +173,605 @@ impl<'a> UniswapV2Client {
                 provider.clone(),
             ),
             provider: provider,
+            signers,
+            nonce_manager,
+            fee_policy,
+            deadline_guard,
+            inventory,
+            screener,
+            validator,
+            metrics: Arc::clone(&metrics),
+            reserve_cache: Arc::new(ReserveCache::default()),
+            opportunities: Arc::new(OpportunityLog::new(
+                get_env_or("OPPORTUNITY_LOG_SIZE", 100).map_err(UniswapV2Error::EnvError)?,
+            )),
+            events: EventBus::new(
+                get_env_or("EVENT_BUS_CAPACITY", 1024).map_err(UniswapV2Error::EnvError)?,
+                metrics,
+            ),
+            tokens: Arc::clone(&tokens),
+            prices: Arc::new(PriceOracle::new(
+                PricingConfig::from_env(weth).map_err(UniswapV2Error::EnvError)?,
+                tokens,
+            )),
+            pool_graph,
+            pool_index,
+            pairs,
+            batcher,
+            pending: PendingOverlay::new(
+                get_env_or("PENDING_MAX_AGE_BLOCKS", 3).map_err(UniswapV2Error::EnvError)?,
+            ),
         })
     }
 
+    pub fn screener(&self) -> &TokenScreener<Ws> {
+        &self.screener
+    }
+
+    pub fn inventory(&self) -> &Inventory<Ws> {
+        &self.inventory
+    }
+
+    pub fn fee_policy(&self) -> &Arc<FeePolicy> {
+        &self.fee_policy
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    pub fn opportunities(&self) -> &Arc<OpportunityLog> {
+        &self.opportunities
+    }
+
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    pub fn tokens(&self) -> &Arc<TokenRegistry> {
+        &self.tokens
+    }
+
+    /// Symbol and decimals of `token`, fetched once and then cached.
+    pub async fn resolve_token(&self, token: Address) -> TokenInfo {
+        self.tokens.resolve(self.provider.as_ref(), token).await
+    }
+
+    /// Fetch the metadata of every unknown token in one batch.
+    pub async fn resolve_tokens(&self, tokens: &[Address]) {
+        self.tokens.resolve_all(&self.batcher, tokens).await
+    }
+
+    pub fn prices(&self) -> &Arc<PriceOracle> {
+        &self.prices
+    }
+
+    /// Read the WETH/stablecoin pairs into the reserve cache, the USD price
+    /// comes from them.
+    pub async fn refresh_reference_pools(&self) {
+        let weth = self.prices.config().weth;
+        let pairs: Vec<Address> = self
+            .prices
+            .config()
+            .stablecoins
+            .iter()
+            .map(|stable| {
+                self.pool_graph.add_pool(Dex::uniswap_v2(), weth, *stable);
+                Dex::uniswap_v2().pair_address(weth, *stable)
+            })
+            .collect();
+        self.fetch_univ2_reserves(&pairs).await;
+    }
+
+    pub fn pool_graph(&self) -> &PoolGraph {
+        &self.pool_graph
+    }
+
+    /// Apply the `Sync` logs of `block` and the reserve cache to the pool
+    /// graph, returns how many tracked pairs moved. Indexed pairs they show
+    /// with reserves join the graph.
+    pub async fn sync_pool_graph(&self, block: H256) -> usize {
+        let filter =
+            Filter::new()
+                .at_block_hash(block)
+                .topic0(H256::from(ethers::utils::keccak256(
+                    "Sync(uint112,uint112)",
+                )));
+        let moved = match self.provider.get_logs(&filter).await {
+            Ok(logs) => {
+                for (pair, reserve0, reserve1) in logs.iter().filter_map(univ2::decode_sync) {
+                    add_indexed_pool(
+                        &self.pool_graph,
+                        &self.pool_index,
+                        pair,
+                        (reserve0, reserve1),
+                    );
+                }
+                self.pool_graph.apply_logs(&logs)
+            }
+            Err(e) => {
+                self.metrics.record_rpc_error("getLogs");
+                error!("Sync logs of {:?} failed: {:?}", block, e);
+                0
+            }
+        };
+        for (pair, reserves) in self.reserve_cache.entries() {
+            add_indexed_pool(&self.pool_graph, &self.pool_index, pair, reserves);
+        }
+        moved + self.pool_graph.sync(self.reserve_cache.as_ref())
+    }
+
+    pub fn pool_index(&self) -> &Arc<PoolIndex> {
+        &self.pool_index
+    }
+
+    /// Walk the factories' `allPairs` from where the index stopped.
+    pub async fn index_pairs(&self) {
+        for dex in [Dex::uniswap_v2(), Dex::sushiswap()] {
+            match self
+                .pool_index
+                .sync(Arc::clone(&self.provider), &self.batcher, dex.factory)
+                .await
+            {
+                Ok(added) => info!("{} pool index: {} new pairs", dex.name, added),
+                Err(e) => {
+                    self.metrics.record_rpc_error("allPairs");
+                    error!("{} pool index sync failed: {:?}", dex.name, e);
+                }
+            }
+        }
+    }
+
+    /// Add the pairs the factories create from now on to the index.
+    pub async fn watch_pair_created(&self) {
+        let factories = [Dex::uniswap_v2().factory, Dex::sushiswap().factory];
+        let mut logs = match self
+            .provider
+            .subscribe_logs(&PoolIndex::pair_created_filter(&factories))
+            .await
+        {
+            Ok(logs) => logs,
+            Err(e) => {
+                error!("PairCreated subscription failed: {:?}", e);
+                return;
+            }
+        };
+        while let Some(log) = logs.next().await {
+            if let Some(record) = self.pool_index.apply_log(&log) {
+                debug!("new pair {:?}", record);
+                if let Err(e) = self.pool_index.save() {
+                    error!("saving pool index failed: {:?}", e);
+                }
+            }
+        }
+    }
+
+    pub fn pending(&self) -> &PendingOverlay {
+        &self.pending
+    }
+
+    /// Track the first hop of every UniswapV2 swap of `tx` in the pending
+    /// overlay.
+    pub fn track_pending_swaps(&self, tx: &Transaction, swaps: &[NormalizedSwap]) {
+        let base_fee = self
+            .fee_policy
+            .latest()
+            .map(|fees| fees.next_base_fee)
+            .unwrap_or_default();
+        let priority_fee = fees::victim_priority_fee(tx, base_fee);
+        let legs: Vec<PendingSwap> = swaps
+            .iter()
+            .filter(|swap| swap.protocol == Protocol::V2 && swap.path.len() >= 2)
+            .map(|swap| PendingSwap {
+                hash: tx.hash,
+                sender: tx.from,
+                nonce: tx.nonce,
+                priority_fee,
+                pair: Dex::uniswap_v2().pair_address(swap.path[0], swap.path[1]),
+                token_in: swap.path[0],
+                amount: match swap.amount {
+                    amount if swap.path.len() == 2 => amount,
+                    SwapAmount::ExactIn { amount_in, .. } => SwapAmount::ExactIn {
+                        amount_in,
+                        amount_out_min: U256::zero(),
+                    },
+                    // the first hop takes at most the bound, the most the
+                    // swap can move its pair
+                    SwapAmount::ExactOut { amount_in_max, .. } => SwapAmount::ExactIn {
+                        amount_in: amount_in_max,
+                        amount_out_min: U256::zero(),
+                    },
+                },
+            })
+            .collect();
+        if let Some(replaced) = self.pending.insert(&legs).first() {
+            debug!("{:?} replaced pending swap {:?}", tx.hash, replaced.hash);
+        }
+    }
+
+    /// Drop the pending swaps mined in `block`, or waiting for too long.
+    pub async fn sync_pending(&self, block: H256, number: U64) {
+        match self.provider.get_block(block).await {
+            Ok(Some(block)) => {
+                let removed = self.pending.on_block(number, &block.transactions);
+                debug!(
+                    "{} pending swaps left, {} removed",
+                    self.pending.len(),
+                    removed
+                );
+            }
+            Ok(None) => {}
+            Err(e) => {
+                self.metrics.record_rpc_error("eth_getBlockByHash");
+                error!("block {:?} failed: {:?}", block, e);
+                // still expire the old ones
+                self.pending.on_block(number, &[]);
+            }
+        }
+    }
+
+    pub fn reserve_cache(&self) -> &Arc<ReserveCache> {
+        &self.reserve_cache
+    }
+
+    /// Forget reserves read during the previous block.
+    pub fn clear_reserve_cache(&self) {
+        self.reserve_cache.clear();
+    }
+
+    pub fn nonce_manager(&self) -> &NonceManager<Ws> {
+        &self.nonce_manager
+    }
+
+    /// Wallet of a pool account, used to sign with the nonce a lease was
+    /// issued for.
+    pub fn signer(&self, address: Address) -> Option<&LocalWallet> {
+        self.signers.iter().find(|s| s.address() == address)
+    }
+
     pub async fn get_pending_txs(&self) -> SubscriptionStream<'_, Ws, TxHash> {
         self.provider.subscribe_pending_txs().await.unwrap()
     }
 
+    pub async fn get_new_blocks(&self) -> SubscriptionStream<'_, Ws, Block<TxHash>> {
+        self.provider.subscribe_blocks().await.unwrap()
+    }
+
     pub async fn get_transaction(&self, tx: TxHash) -> Option<Transaction> {
-        self.provider.get_transaction(tx).await.unwrap()
+        match self.provider.get_transaction(tx).await {
+            Ok(tx) => tx,
+            Err(e) => {
+                self.metrics.record_rpc_error("eth_getTransactionByHash");
+                error!("get transaction {:?} failed: {:?}", tx, e);
+                None
+            }
+        }
+    }
+
+    /// Run the deadline and validity checks for a pending router call and
+    /// return the block a bundle around it would target. `token_in` is the
+    /// ERC20 side for swaps paying with tokens.
+    pub async fn validate_victim(
+        &self,
+        victim: &Transaction,
+        deadline: U256,
+        token_in: Option<TokenIn>,
+    ) -> Result<U64, Rejection> {
+        let target_block = self.deadline_guard.check(deadline)?;
+        let fees = self.fee_policy.latest().ok_or(Rejection::NoBlockYet)?;
+        self.validator
+            .validate(victim, fees.next_base_fee, token_in)
+            .await
+            .map_err(|check| {
+                if let VictimCheck::LookupFailed(call, _) = &check {
+                    self.metrics.record_rpc_error(call);
+                }
+                // it will not be mined, it only misplaces the others
+                if check.never_mined() {
+                    self.pending.remove(victim.hash);
+                }
+                Rejection::InvalidVictim(check)
+            })?;
+        Ok(target_block)
     }
 
     pub async fn swap_eth_for_exact_tokens(
         &self,
+        victim: &Transaction,
         user_amount_in: U256,
-        amount_out_min: U256,
+        amount_out_min: MinRecv,
         path: Vec<Address>,
         to: Address,
         deadline: U256,
-    ) {
-        if U256::from(time()) > deadline {
-            println!("deadline exceeded, can't mev op");
-            return;
-        }
-
-        let user_min_recv = self
-            .get_univ2_exact_weth_token_min_recv(amount_out_min, &path)
-            .await;
+    ) -> Result<SandWichState, Rejection> {
+        let target_block = self.validate_victim(victim, deadline, None).await?;
+        let fees = self.fee_policy.sandwich_fees(victim)?;
+
+        // past the first hop only the bound on the pair's output is known
+        let user_min_recv = match amount_out_min {
+            MinRecv::Received(_) if path.len() == 2 => amount_out_min,
+            _ => MinRecv::PairOut(
+                self.get_univ2_exact_weth_token_min_recv(amount_out_min.amount(), &path)
+                    .await
+                    .map_err(Rejection::Quote)?,
+            ),
+        };
 
         let weth = path[0];
         let token = path[1];
 
-        let pair_to_sandwich = self.get_uni_pair_address(weth, token);
+        let Some(pair_to_sandwich) = self
+            .get_existing_pair(&Dex::uniswap_v2(), weth, token)
+            .await
+        else {
+            let pair = self.get_uni_pair_address(weth, token);
+            return Err(Rejection::Quote(QuoteError::UnknownPair(pair)));
+        };
+        let taxes = match self.screener.screen(token, pair_to_sandwich).await {
+            TokenVerdict::Safe(taxes) => taxes,
+            TokenVerdict::Rejected(reason) => {
+                if let ScreenReason::RpcError(_) = reason {
+                    self.metrics.record_rpc_error("screener_fork");
+                }
+                return Err(Rejection::TokenScreen(reason));
+            }
+        };
+
         let (weth_reserve, token_reserve) =
             self.get_univ2_reserve(pair_to_sandwich, weth, token).await;
-
-        let optimal_weth_in = univ2::calc_sandwich_optima_in(
-            user_amount_in,
-            user_min_recv,
+        // the pending swaps a builder puts ahead of the victim move the pair first
+        let (weth_reserve, token_reserve) = self.pending.project(
+            pair_to_sandwich,
+            weth,
             weth_reserve.into(),
             token_reserve.into(),
+            victim.hash,
         );
 
-        if optimal_weth_in.lt(&U256::zero()) {
-            warn!("nothing to sandwithd");
-            return;
+        let max_weth_in = self.inventory.max_frontrun(token);
+        if max_weth_in.is_zero() {
+            return Err(Rejection::NoCapital(token));
         }
 
-        let sandwich_state = self
+        let optimal_weth_in = univ2::calc_sandwich_optima_in(
+            user_amount_in,
+            user_min_recv,
+            weth_reserve,
+            token_reserve,
+            max_weth_in,
+            taxes,
+        );
+
+        let state = self
             .get_sandwitch_state(
                 optimal_weth_in,
                 user_amount_in,
                 user_min_recv,
-                weth_reserve.into(),
-                token_reserve.into(),
+                weth_reserve,
+                token_reserve,
+                taxes,
+                fees.gas_cost,
             )
-            .await;
+            .await?;
+        info!("sandwich target founed for block {}", target_block);
 
-        match sandwich_state {
-            Some(state) => {
-                info!("sandwich target founed");
-            }
-            None => {
-                warn!("sandwich sanity check failed");
-                return;
-            }
+        // capital is locked only once a bundle is submitted, see
+        // `reserve_sandwich`
+        Ok(state)
+    }
+
+    /// Lock the frontrun WETH of a sandwich whose bundle is about to be
+    /// submitted for `target_block`. None when it does not pay or the
+    /// capital is taken by another bundle.
+    pub fn reserve_sandwich(
+        &self,
+        token: Address,
+        state: &SandWichState,
+        target_block: U64,
+    ) -> Option<u64> {
+        if state.profit <= I256::zero() {
+            return None;
         }
+        let reserved = self
+            .inventory
+            .reserve(token, state.optimal_sandwich_weth_in, target_block);
+        if reserved.is_none() {
+            warn!("capital for {:?} taken by another bundle", token);
+        }
+        reserved
     }
 
+    /// The bundle of reservation `id` was submitted: book its expected
+    /// outcome until the next refresh.
+    pub fn sandwich_submitted(&self, id: u64, state: &SandWichState) {
+        // the backrun returns the frontrun WETH with the revenue, gas is paid in ETH
+        self.inventory.apply_optimistic(
+            id,
+            I256::from_raw(state.revenue),
+            -I256::from_raw(state.gas_cost),
+        );
+    }
+
+    /// Submitting the bundle of reservation `id` failed, its capital is free
+    /// again.
+    pub fn sandwich_failed(&self, id: u64) {
+        self.inventory.release(id);
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub async fn get_sandwitch_state(
         &self,
         optimal_sandwich_weth_in: U256,
         user_weth_in: U256,
-        user_min_recv: U256,
+        user_min_recv: MinRecv,
         reserve_weth: U256,
         reserve_token: U256,
-    ) -> Option<SandWichState> {
-        let frontrun_state = PairState::from_tuple(univ2::get_univ2_data_given_in(
+        taxes: TokenTaxes,
+        gas_cost: U256,
+    ) -> Result<SandWichState, Rejection> {
+        univ2::get_sandwitch_state(
             optimal_sandwich_weth_in,
-            reserve_weth,
-            reserve_token,
-        ));
-        let victim_state = PairState::from_tuple(univ2::get_univ2_data_given_in(
             user_weth_in,
-            frontrun_state.new_reserve_a,
-            frontrun_state.new_reserve_b,
-        ));
-        let backrun_state = PairState::from_tuple(univ2::get_univ2_data_given_in(
-            frontrun_state.amount_out,
-            victim_state.new_reserve_b,
-            victim_state.new_reserve_a,
-        ));
-
-        // Sanity check
-        if victim_state.amount_out < user_min_recv {
-            return None;
-        }
-
-        let revenue = backrun_state.amount_out - optimal_sandwich_weth_in;
-        let user_amount_in = user_weth_in;
-        let user_min_recv = user_min_recv;
-        let reserve_state = (reserve_weth, reserve_token);
-
-        let front_run = frontrun_state;
-        let victim = victim_state;
-        let back_run = backrun_state;
-
-        // Return
-        Some(SandWichState {
-            revenue,
-            optimal_sandwich_weth_in,
-            user_amount_in,
             user_min_recv,
-            reserve_state,
-            front_run,
-            victim,
-            back_run,
-        })
+            reserve_weth,
+            reserve_token,
+            taxes,
+            gas_cost,
+        )
     }
 
+    /// Amount of `path[1]` the victim has to receive on the first hop so the
+    /// rest of the path still pays out `amount_out_min`.
     pub async fn get_univ2_exact_weth_token_min_recv(
         &self,
         amount_out_min: U256,
-        path: &Vec<Address>,
-    ) -> U256 {
-        let user_min_recv = amount_out_min;
-
-        for index in (path.capacity() - 1)..1 {
-            let from = path[index];
-            let to = path[index - 1];
+        path: &[Address],
+    ) -> Result<U256, QuoteError> {
+        if path.len() < 2 {
+            return Err(QuoteError::InvalidPath);
+        }
+        if path.len() == 2 {
+            return Ok(amount_out_min);
+        }
 
-            let pair_address = self.get_uni_pair_address(from, to);
-            let (reserve_from, reserve_to) = self.get_univ2_reserve(pair_address, from, to).await;
-            let (a_amount_in, new_reserve_from, new_reserve_to) = univ2::get_univ2_data_given_out(
-                user_min_recv,
-                U256::from(reserve_from),
-                U256::from(reserve_to),
-            );
+        let quote = self
+            .get_amounts_in(amount_out_min, &path[1..], &Dex::uniswap_v2())
+            .await?;
+        Ok(quote.amounts[0])
+    }
 
-            let user_min_recv = a_amount_in;
+    /// Current reserves of every pair along `path`, keyed by pair address.
+    pub async fn get_path_reserves(
+        &self,
+        path: &[Address],
+        dex: &Dex,
+    ) -> HashMap<Address, (U256, U256)> {
+        let mut pairs = Vec::new();
+        for hop in path.windows(2) {
+            // left out, the quote fails with `UnknownPair`
+            if let Some(pair) = self.get_existing_pair(dex, hop[0], hop[1]).await {
+                pairs.push(pair);
+            }
         }
-
-        return user_min_recv;
+        self.fetch_univ2_reserves(&pairs).await;
+        pairs
+            .into_iter()
+            .filter_map(|pair| Some((pair, self.reserve_cache.reserves(pair)?)))
+            .collect()
     }
 
-    pub fn get_uni_pair_address(&self, from: Address, to: Address) -> Address {
-        let (from, to) = univ2::sort_token(from, to);
+    pub async fn get_amounts_out(
+        &self,
+        amount_in: U256,
+        path: &[Address],
+        dex: &Dex,
+    ) -> Result<PathQuote, QuoteError> {
+        let reserves = self.get_path_reserves(path, dex).await;
+        univ2::get_amounts_out(amount_in, path, dex, &reserves)
+    }
 
-        //// TODO remove hard code uniswapv2 factory address
-        let factory = "5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f"
-            .parse::<Address>()
-            .unwrap();
+    pub async fn get_amounts_in(
+        &self,
+        amount_out: U256,
+        path: &[Address],
+        dex: &Dex,
+    ) -> Result<PathQuote, QuoteError> {
+        let reserves = self.get_path_reserves(path, dex).await;
+        univ2::get_amounts_in(amount_out, path, dex, &reserves)
+    }
 
-        //// TODO remove hard code init code
-        let init_code_hash =
-            hex::decode("96e8ac4277198ff8b6f785478aa9a39f403cb768dd02cbee326c3e7da348845f")
-                .unwrap();
+    pub fn get_uni_pair_address(&self, from: Address, to: Address) -> Address {
+        Dex::uniswap_v2().pair_address(from, to)
+    }
 
-        let mut extend_byte_array = from.as_bytes().to_vec();
-        let to_byte_array = to.as_bytes().to_vec();
-        extend_byte_array.extend(to_byte_array);
+    /// The pair of `from` and `to` on `dex`, None when it was never created.
+    /// A failed lookup falls back to the derived address.
+    pub async fn get_existing_pair(
+        &self,
+        dex: &Dex,
+        from: Address,
+        to: Address,
+    ) -> Option<Address> {
+        match self.pairs.lookup(dex, from, to).await {
+            Ok(lookup) => lookup.pair(),
+            Err(e) => {
+                self.metrics.record_rpc_error("getPair");
+                error!("pair lookup of {:?}/{:?} failed: {:?}", from, to, e);
+                Some(dex.pair_address(from, to))
+            }
+        }
+    }
 
-        //// Attention here, ethers-rs: abi encoding not work
-        //// let input = abi::encode(&vec![Token::Address(from), Token::Address(to)]);
-        let salt = keccak256(&extend_byte_array);
+    /// Reserves of the UniswapV2 pair of `from` and `to` in that order, if the
+    /// pair exists.
+    pub async fn get_pair_reserves(&self, from: Address, to: Address) -> Option<(u128, u128)> {
+        let pair = self.get_existing_pair(&Dex::uniswap_v2(), from, to).await?;
+        Some(self.get_univ2_reserve(pair, from, to).await)
+    }
 
-        // let salt2 = hex::decode("4aafb64a36177dc82e7ace74cf60cc655659bc049da9533b5f7a6881bea995c6")
-        //    .unwrap();
+    /// Backruns of `intent` against the same pair on the other forks,
+    /// reading the reserves of every fork's pair first.
+    pub async fn find_arbitrage(&self, intent: &SwapIntent) -> Vec<ArbOpportunity> {
+        let dexes = [Dex::uniswap_v2(), Dex::sushiswap()];
+        let mut pairs = Vec::new();
+        for dex in &dexes {
+            // a fork without the pair has no reserves, `find` skips it
+            if self
+                .get_existing_pair(dex, intent.token_in, intent.token_out)
+                .await
+                .is_some()
+            {
+                self.pool_graph
+                    .add_pool(*dex, intent.token_in, intent.token_out);
+                pairs.push(dex.pair_address(intent.token_in, intent.token_out));
+            }
+        }
+        self.fetch_univ2_reserves(&pairs).await;
+        arbitrage::find(intent, &dexes, self.reserve_cache.as_ref())
+    }
 
-        let pair_address = ethers::core::utils::get_create2_address_from_hash(
-            factory,
-            salt.to_vec(),
-            init_code_hash.to_vec(),
-        );
+    /// Read the reserves of the `pairs` missing from the cache in Multicall3
+    /// batches, into the cache and the pool graph.
+    pub async fn fetch_univ2_reserves(&self, pairs: &[Address]) {
+        let mut missing: Vec<Address> = pairs
+            .iter()
+            .copied()
+            .filter(|pair| self.reserve_cache.reserves(*pair).is_none())
+            .collect();
+        missing.sort();
+        missing.dedup();
+        if missing.is_empty() {
+            return;
+        }
 
-        pair_address
+        let started = Instant::now();
+        let result = self.batcher.get_reserves(&missing).await;
+        let elapsed = started.elapsed();
+        let reserves = match result {
+            Ok(reserves) => reserves,
+            Err(e) => {
+                self.metrics.record_rpc_error("aggregate3");
+                error!(
+                    "getReserves batch of {} pairs failed: {:?}",
+                    missing.len(),
+                    e
+                );
+                return;
+            }
+        };
+        for (pair, reserves) in missing.into_iter().zip(reserves) {
+            self.metrics.record_reserve_lookup(false, Some(elapsed));
+            match reserves {
+                Some((reserve0, reserve1)) => {
+                    self.reserve_cache.insert(pair, (reserve0, reserve1));
+                    self.pool_graph.update_reserves(pair, reserve0, reserve1);
+                }
+                None => {
+                    self.metrics.record_rpc_error("getReserves");
+                    error!("getReserves of {:?} failed", pair);
+                }
+            }
+        }
     }
 
     pub async fn get_univ2_reserve(
@@ -269,8 +782,20 @@ impl<'a> UniswapV2Client {
     ) -> (u128, u128) {
         let (from_, to_) = univ2::sort_token(from, to);
 
-        let pair = IUniswapV2Pair::new(pair_address, Arc::clone(&self.provider));
-        let (reserve0, reserve1, _timestamp) = pair.get_reserves().call().await.unwrap();
+        let (reserve0, reserve1) = match self.reserve_cache.reserves(pair_address) {
+            Some((reserve0, reserve1)) => {
+                self.metrics.record_reserve_lookup(true, None);
+                (reserve0.as_u128(), reserve1.as_u128())
+            }
+            None => {
+                self.fetch_univ2_reserves(&[pair_address]).await;
+                // empty reserves when the read failed, the swap math rejects the pair
+                self.reserve_cache
+                    .reserves(pair_address)
+                    .map(|(reserve0, reserve1)| (reserve0.as_u128(), reserve1.as_u128()))
+                    .unwrap_or_default()
+            }
+        };
 
         if from == from_ {
             (reserve0, reserve1)
@@ -279,3 +804,17 @@ impl<'a> UniswapV2Client {
         }
     }
 }
+
+// indexed pairs of the factories we trade on join the pool graph once they
+// hold reserves, the empty majority of the index stays out of it
+fn add_indexed_pool(graph: &PoolGraph, index: &PoolIndex, pair: Address, reserves: (U256, U256)) {
+    if reserves.0.is_zero() || reserves.1.is_zero() || graph.contains(pair) {
+        return;
+    }
+    let Some(record) = index.get(pair) else {
+        return;
+    };
+    if let Some(dex) = Dex::from_factory(record.factory) {
+        graph.add_pool(dex, record.token0, record.token1);
+    }
+}
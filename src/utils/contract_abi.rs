@@ -1,3 +1,70 @@
 use ethers::prelude::abigen;
 
 abigen!(UniswapV2Router02, "src/contracts/UniswapV2Router02.json");
+
+abigen!(
+    IERC20,
+    r#"[
+        function balanceOf(address account) external view returns (uint256)
+        function transfer(address to, uint256 amount) external returns (bool)
+        function allowance(address owner, address spender) external view returns (uint256)
+    ]"#,
+);
+
+abigen!(
+    IWETH,
+    r#"[
+        function deposit() external payable
+    ]"#,
+);
+
+// SwapRouter, the first V3 router, takes the deadline in every struct
+abigen!(
+    ISwapRouter,
+    r#"[
+        struct ExactInputSingleParams { address tokenIn; address tokenOut; uint24 fee; address recipient; uint256 deadline; uint256 amountIn; uint256 amountOutMinimum; uint160 sqrtPriceLimitX96; }
+        struct ExactInputParams { bytes path; address recipient; uint256 deadline; uint256 amountIn; uint256 amountOutMinimum; }
+        struct ExactOutputSingleParams { address tokenIn; address tokenOut; uint24 fee; address recipient; uint256 deadline; uint256 amountOut; uint256 amountInMaximum; uint160 sqrtPriceLimitX96; }
+        struct ExactOutputParams { bytes path; address recipient; uint256 deadline; uint256 amountOut; uint256 amountInMaximum; }
+        function exactInputSingle(ExactInputSingleParams params) external payable returns (uint256 amountOut)
+        function exactInput(ExactInputParams params) external payable returns (uint256 amountOut)
+        function exactOutputSingle(ExactOutputSingleParams params) external payable returns (uint256 amountIn)
+        function exactOutput(ExactOutputParams params) external payable returns (uint256 amountIn)
+        function multicall(bytes[] data) external payable returns (bytes[] results)
+    ]"#,
+);
+
+// SwapRouter02 moved the deadline to `multicall`
+abigen!(
+    ISwapRouter02,
+    r#"[
+        struct ExactInputSingleParams { address tokenIn; address tokenOut; uint24 fee; address recipient; uint256 amountIn; uint256 amountOutMinimum; uint160 sqrtPriceLimitX96; }
+        struct ExactInputParams { bytes path; address recipient; uint256 amountIn; uint256 amountOutMinimum; }
+        struct ExactOutputSingleParams { address tokenIn; address tokenOut; uint24 fee; address recipient; uint256 amountOut; uint256 amountInMaximum; uint160 sqrtPriceLimitX96; }
+        struct ExactOutputParams { bytes path; address recipient; uint256 amountOut; uint256 amountInMaximum; }
+        function exactInputSingle(ExactInputSingleParams params) external payable returns (uint256 amountOut)
+        function exactInput(ExactInputParams params) external payable returns (uint256 amountOut)
+        function exactOutputSingle(ExactOutputSingleParams params) external payable returns (uint256 amountIn)
+        function exactOutput(ExactOutputParams params) external payable returns (uint256 amountIn)
+        function multicall(uint256 deadline, bytes[] data) external payable returns (bytes[] results)
+        function multicall(bytes32 previousBlockhash, bytes[] data) external payable returns (bytes[] results)
+    ]"#,
+);
+
+abigen!(
+    IUniversalRouter,
+    r#"[
+        function execute(bytes commands, bytes[] inputs) external payable
+        function execute(bytes commands, bytes[] inputs, uint256 deadline) external payable
+    ]"#,
+);
+
+abigen!(
+    IUniswapV2Factory,
+    r#"[
+        function allPairsLength() external view returns (uint256)
+        function allPairs(uint256 index) external view returns (address pair)
+        function getPair(address tokenA, address tokenB) external view returns (address pair)
+        event PairCreated(address indexed token0, address indexed token1, address pair, uint256 pairCount)
+    ]"#,
+);
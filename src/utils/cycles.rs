@@ -0,0 +1,598 @@
+//! Cyclic arbitrage on the graph of known UniV2 pools.
+//!
+//! Tokens are nodes and pools edges, an edge weighs `-ln(fee * reserve_out /
+//! reserve_in)` so a loop whose weights sum below zero returns more than it
+//! takes at the margin. Loops of 2 to `max_len` pools through WETH are
+//! enumerated when a pool is added, only the ones through the new pool, and
+//! after a reserve update only the loops going through an updated pool are
+//! priced again. A token joins at most `max_pools_per_token` pools, which
+//! bounds the enumeration around WETH. Reserves come from the reserve cache
+//! and `Sync` logs, never from a call per evaluation.
+
+use crate::env_store::{get_env_or, EnvError};
+use crate::utils::amm::{self, FEE_DENOMINATOR};
+use crate::utils::univ2::{self, Dex, ReserveCache};
+
+use ethers::prelude::*;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+#[derive(Debug, Clone)]
+pub struct CycleConfig {
+    pub weth: Address,
+    // pools in a loop, at least 2
+    pub max_len: usize,
+    // pools added per token, past it the token's new pools are left out
+    pub max_pools_per_token: usize,
+    // most WETH a loop may start with
+    pub max_amount_in: U256,
+    pub min_profit: U256,
+}
+
+impl CycleConfig {
+    pub fn from_env(weth: Address) -> Result<Self, EnvError> {
+        let max_amount_in: u128 = get_env_or("CYCLE_MAX_WETH_IN_WEI", 10_000_000_000_000_000_000)?;
+        let min_profit: u128 = get_env_or("CYCLE_MIN_PROFIT_WEI", 0)?;
+        Ok(Self {
+            weth,
+            max_len: get_env_or("CYCLE_MAX_LEN", 4)?,
+            max_pools_per_token: get_env_or("CYCLE_MAX_POOLS_PER_TOKEN", 32)?,
+            max_amount_in: max_amount_in.into(),
+            min_profit: min_profit.into(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Pool {
+    dex: Dex,
+    token0: Address,
+    token1: Address,
+    reserve0: U256,
+    reserve1: U256,
+}
+
+impl Pool {
+    fn other(&self, token: Address) -> Address {
+        if token == self.token0 {
+            self.token1
+        } else {
+            self.token0
+        }
+    }
+
+    // (reserve_in, reserve_out) selling `token_in`
+    fn oriented(&self, token_in: Address) -> (U256, U256) {
+        if token_in == self.token0 {
+            (self.reserve0, self.reserve1)
+        } else {
+            (self.reserve1, self.reserve0)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CycleHop {
+    pub pair: Address,
+    pub dex: &'static str,
+    pub token_in: Address,
+    pub token_out: Address,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CycleOpportunity {
+    pub hops: Vec<CycleHop>,
+    // WETH in and out of the loop
+    pub amount_in: U256,
+    pub amount_out: U256,
+    pub profit: U256,
+}
+
+// a loop as (pair, token sold into it) per hop
+type Cycle = Vec<(Address, Address)>;
+
+#[derive(Debug, Default)]
+struct GraphState {
+    pools: HashMap<Address, Pool>,
+    // token -> pairs trading it
+    edges: HashMap<Address, Vec<Address>>,
+    cycles: Vec<Cycle>,
+    // pair -> indices in `cycles`
+    by_pair: HashMap<Address, Vec<usize>>,
+    // pairs with new reserves since the last evaluation
+    dirty: HashSet<Address>,
+}
+
+pub struct PoolGraph {
+    config: CycleConfig,
+    state: RwLock<GraphState>,
+}
+
+impl PoolGraph {
+    pub fn new(config: CycleConfig) -> Self {
+        Self {
+            config,
+            state: RwLock::new(GraphState::default()),
+        }
+    }
+
+    pub fn pool_count(&self) -> usize {
+        self.state.read().unwrap().pools.len()
+    }
+
+    pub fn contains(&self, pair: Address) -> bool {
+        self.state.read().unwrap().pools.contains_key(&pair)
+    }
+
+    /// Track the pair of `token_a` and `token_b` on `dex`, priced once its
+    /// reserves are known. None when either token is in
+    /// `max_pools_per_token` pools already.
+    pub fn add_pool(&self, dex: Dex, token_a: Address, token_b: Address) -> Option<Address> {
+        let pair = dex.pair_address(token_a, token_b);
+        let mut state = self.state.write().unwrap();
+        if state.pools.contains_key(&pair) {
+            return Some(pair);
+        }
+        let full =
+            |token| state.edges.get(&token).map_or(0, Vec::len) >= self.config.max_pools_per_token;
+        if full(token_a) || full(token_b) {
+            return None;
+        }
+        let (token0, token1) = univ2::sort_token(token_a, token_b);
+        state.pools.insert(
+            pair,
+            Pool {
+                dex,
+                token0,
+                token1,
+                reserve0: U256::zero(),
+                reserve1: U256::zero(),
+            },
+        );
+        state.edges.entry(token0).or_default().push(pair);
+        state.edges.entry(token1).or_default().push(pair);
+
+        for cycle in cycles_through(&state, self.config.weth, self.config.max_len, pair) {
+            let i = state.cycles.len();
+            for (pair, _) in &cycle {
+                state.by_pair.entry(*pair).or_default().push(i);
+            }
+            state.cycles.push(cycle);
+        }
+        // its loops are priced with the next evaluation
+        state.dirty.insert(pair);
+        Some(pair)
+    }
+
+    /// New reserves of a tracked pair, `false` for unknown pairs or no change.
+    pub fn update_reserves(&self, pair: Address, reserve0: U256, reserve1: U256) -> bool {
+        let mut state = self.state.write().unwrap();
+        let Some(pool) = state.pools.get_mut(&pair) else {
+            return false;
+        };
+        if (pool.reserve0, pool.reserve1) == (reserve0, reserve1) {
+            return false;
+        }
+        pool.reserve0 = reserve0;
+        pool.reserve1 = reserve1;
+        state.dirty.insert(pair);
+        true
+    }
+
+    /// Pull the reserves of the tracked pairs out of a cache, returns how
+    /// many moved.
+    pub fn sync(&self, reserves: &ReserveCache) -> usize {
+        reserves
+            .entries()
+            .into_iter()
+            .filter(|(pair, (reserve0, reserve1))| {
+                self.update_reserves(*pair, *reserve0, *reserve1)
+            })
+            .count()
+    }
+
+    /// Apply the `Sync` logs of a block, returns how many tracked pairs moved.
+    pub fn apply_logs(&self, logs: &[Log]) -> usize {
+        logs.iter()
+            .filter_map(univ2::decode_sync)
+            .filter(|(pair, reserve0, reserve1)| self.update_reserves(*pair, *reserve0, *reserve1))
+            .count()
+    }
+
+    /// Profitable loops among those touched by updates since the last call,
+    /// most profitable first.
+    pub fn profitable_cycles(&self) -> Vec<CycleOpportunity> {
+        let mut state = self.state.write().unwrap();
+        let mut candidates: Vec<usize> = state
+            .dirty
+            .iter()
+            .filter_map(|pair| state.by_pair.get(pair))
+            .flatten()
+            .copied()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        candidates.sort_unstable();
+        state.dirty.clear();
+
+        let mut found: Vec<CycleOpportunity> = candidates
+            .into_iter()
+            .filter_map(|i| self.evaluate(&state, &state.cycles[i]))
+            .collect();
+        found.sort_by_key(|cycle| std::cmp::Reverse(cycle.profit));
+        found
+    }
+
+    fn evaluate(&self, state: &GraphState, cycle: &Cycle) -> Option<CycleOpportunity> {
+        let pools: Vec<(&Pool, Address)> = cycle
+            .iter()
+            .map(|(pair, token_in)| state.pools.get(pair).map(|pool| (pool, *token_in)))
+            .collect::<Option<_>>()?;
+
+        // cheap test at the margin before any sizing
+        let weight: f64 = pools
+            .iter()
+            .map(|(pool, token_in)| log_weight(pool, *token_in))
+            .sum::<Option<f64>>()?;
+        if weight >= 0.0 {
+            return None;
+        }
+
+        let amount_in = optimal_amount_in(&pools)?.min(self.config.max_amount_in);
+        let amount_out = pools
+            .iter()
+            .try_fold(amount_in, |amount, (pool, token_in)| {
+                let (reserve_in, reserve_out) = pool.oriented(*token_in);
+                amm::swap_given_in(amount, reserve_in, reserve_out, pool.dex.fee_numerator)
+                    .ok()
+                    .map(|outcome| outcome.amount_out)
+            })?;
+        let profit = amount_out.checked_sub(amount_in)?;
+        if profit.is_zero() || profit < self.config.min_profit {
+            return None;
+        }
+
+        Some(CycleOpportunity {
+            hops: pools
+                .iter()
+                .map(|(pool, token_in)| CycleHop {
+                    pair: pool.dex.pair_address(pool.token0, pool.token1),
+                    dex: pool.dex.name,
+                    token_in: *token_in,
+                    token_out: pool.other(*token_in),
+                })
+                .collect(),
+            amount_in,
+            amount_out,
+            profit,
+        })
+    }
+}
+
+fn to_f64(amount: U256) -> f64 {
+    amount.to_string().parse().unwrap_or_default()
+}
+
+fn fee(pool: &Pool) -> f64 {
+    pool.dex.fee_numerator as f64 / FEE_DENOMINATOR as f64
+}
+
+// -ln of the marginal rate selling `token_in`, `None` for an empty pool
+fn log_weight(pool: &Pool, token_in: Address) -> Option<f64> {
+    let (reserve_in, reserve_out) = pool.oriented(token_in);
+    if reserve_in.is_zero() || reserve_out.is_zero() {
+        return None;
+    }
+    Some(-(fee(pool).ln() + to_f64(reserve_out).ln() - to_f64(reserve_in).ln()))
+}
+
+// The loop composes into `out(x) = a x / (b + c x)`, each pool mapping
+// (a, b, c) to (f r_out a, r_in b, r_in c + f a). The best input is
+// `(sqrt(a b) - b) / c`, exact math settles the profit afterwards.
+fn optimal_amount_in(pools: &[(&Pool, Address)]) -> Option<U256> {
+    let (mut a, mut b, mut c) = (1.0f64, 1.0f64, 0.0f64);
+    for (pool, token_in) in pools {
+        let (reserve_in, reserve_out) = pool.oriented(*token_in);
+        let (reserve_in, reserve_out, f) = (to_f64(reserve_in), to_f64(reserve_out), fee(pool));
+        (a, b, c) = (f * reserve_out * a, reserve_in * b, reserve_in * c + f * a);
+    }
+    let amount_in = (a.sqrt() * b.sqrt() - b) / c;
+    if !amount_in.is_finite() || amount_in < 1.0 {
+        return None;
+    }
+    U256::from_dec_str(&format!("{:.0}", amount_in)).ok()
+}
+
+// simple paths of at most `max_len` pools from `from` to `to`, off `start`
+// in between and without `skip`
+fn paths(
+    state: &GraphState,
+    from: Address,
+    to: Address,
+    start: Address,
+    max_len: usize,
+    skip: Address,
+) -> Vec<Cycle> {
+    #[allow(clippy::too_many_arguments)]
+    fn walk(
+        state: &GraphState,
+        token: Address,
+        to: Address,
+        max_len: usize,
+        skip: Address,
+        path: &mut Cycle,
+        visited: &mut HashSet<Address>,
+        paths: &mut Vec<Cycle>,
+    ) {
+        for pair in state.edges.get(&token).into_iter().flatten() {
+            if *pair == skip || path.iter().any(|(used, _)| used == pair) {
+                continue;
+            }
+            let next = state.pools[pair].other(token);
+            path.push((*pair, token));
+            if next == to {
+                paths.push(path.clone());
+            } else if path.len() < max_len && visited.insert(next) {
+                walk(state, next, to, max_len, skip, path, visited, paths);
+                visited.remove(&next);
+            }
+            path.pop();
+        }
+    }
+
+    if from == to {
+        return vec![Vec::new()];
+    }
+    if max_len == 0 {
+        return Vec::new();
+    }
+    let mut paths = Vec::new();
+    let mut visited = HashSet::from([from, start]);
+    walk(
+        state,
+        from,
+        to,
+        max_len,
+        skip,
+        &mut Vec::new(),
+        &mut visited,
+        &mut paths,
+    );
+    paths
+}
+
+// every simple loop of 2..=max_len pools leaving and coming back to `start`
+// that goes through `pair`, in both directions
+fn cycles_through(state: &GraphState, start: Address, max_len: usize, pair: Address) -> Vec<Cycle> {
+    let pool = state.pools[&pair];
+    let mut cycles = Vec::new();
+    for (token_in, token_out) in [(pool.token0, pool.token1), (pool.token1, pool.token0)] {
+        let most = max_len.saturating_sub(1);
+        for head in paths(state, start, token_in, start, most, pair) {
+            let left = most - head.len();
+            for tail in paths(state, token_out, start, start, left, pair) {
+                let mut cycle = head.clone();
+                cycle.push((pair, token_in));
+                cycle.extend(tail);
+                // every token is sold once, the loop closes on `start` only
+                let tokens: HashSet<Address> = cycle.iter().map(|(_, token)| *token).collect();
+                let pairs: HashSet<Address> = cycle.iter().map(|(pair, _)| *pair).collect();
+                if cycle.len() >= 2 && tokens.len() == cycle.len() && pairs.len() == cycle.len() {
+                    cycles.push(cycle);
+                }
+            }
+        }
+    }
+    cycles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weth() -> Address {
+        Address::repeat_byte(0xee)
+    }
+
+    fn graph() -> PoolGraph {
+        PoolGraph::new(CycleConfig {
+            weth: weth(),
+            max_len: 4,
+            max_pools_per_token: 8,
+            max_amount_in: U256::exp10(22),
+            min_profit: U256::zero(),
+        })
+    }
+
+    fn set(graph: &PoolGraph, dex: Dex, a: Address, b: Address, reserve_a: U256, reserve_b: U256) {
+        let pair = graph.add_pool(dex, a, b).unwrap();
+        let (reserve0, reserve1) = if univ2::sort_token(a, b).0 == a {
+            (reserve_a, reserve_b)
+        } else {
+            (reserve_b, reserve_a)
+        };
+        graph.update_reserves(pair, reserve0, reserve1);
+    }
+
+    #[test]
+    fn test_triangle() {
+        let graph = graph();
+        let (usdc, dai) = (Address::repeat_byte(1), Address::repeat_byte(2));
+        let dex = Dex::uniswap_v2();
+        // 1 WETH = 2000 USDC = 2000 DAI, but USDC buys 5% too much DAI
+        set(
+            &graph,
+            dex,
+            weth(),
+            usdc,
+            U256::exp10(21),
+            U256::exp10(24) * 2,
+        );
+        set(
+            &graph,
+            dex,
+            usdc,
+            dai,
+            U256::exp10(24) * 100,
+            U256::exp10(24) * 105,
+        );
+        set(
+            &graph,
+            dex,
+            dai,
+            weth(),
+            U256::exp10(24) * 2,
+            U256::exp10(21),
+        );
+
+        let found = graph.profitable_cycles();
+        assert_eq!(1, found.len());
+        let cycle = &found[0];
+        assert_eq!(3, cycle.hops.len());
+        assert_eq!(weth(), cycle.hops[0].token_in);
+        assert_eq!(weth(), cycle.hops[2].token_out);
+        assert_eq!(usdc, cycle.hops[0].token_out);
+        assert!(cycle.profit > U256::zero());
+
+        // nothing moved, nothing to price again
+        assert!(graph.profitable_cycles().is_empty());
+
+        // the mispricing closes
+        set(
+            &graph,
+            dex,
+            usdc,
+            dai,
+            U256::exp10(24) * 100,
+            U256::exp10(24) * 100,
+        );
+        assert!(graph.profitable_cycles().is_empty());
+    }
+
+    #[test]
+    fn test_two_pool_cycle_from_logs() {
+        let graph = graph();
+        let token = Address::repeat_byte(0xaa);
+        set(
+            &graph,
+            Dex::uniswap_v2(),
+            weth(),
+            token,
+            U256::exp10(21),
+            U256::exp10(24),
+        );
+        set(
+            &graph,
+            Dex::sushiswap(),
+            weth(),
+            token,
+            U256::exp10(21),
+            U256::exp10(24),
+        );
+        assert!(graph.profitable_cycles().is_empty());
+
+        // a big buy on sushiswap leaves the token cheap on uniswap
+        let pair = Dex::sushiswap().pair_address(weth(), token);
+        let (reserve0, reserve1) = if univ2::sort_token(weth(), token).0 == weth() {
+            (U256::exp10(21) * 12 / 10, U256::exp10(24) * 10 / 12)
+        } else {
+            (U256::exp10(24) * 10 / 12, U256::exp10(21) * 12 / 10)
+        };
+        let log = Log {
+            address: pair,
+            topics: vec![H256::from(ethers::utils::keccak256(
+                "Sync(uint112,uint112)",
+            ))],
+            data: ethers::abi::encode(&[
+                ethers::abi::Token::Uint(reserve0),
+                ethers::abi::Token::Uint(reserve1),
+            ])
+            .into(),
+            ..Default::default()
+        };
+        assert_eq!(1, graph.apply_logs(&[log]));
+
+        let found = graph.profitable_cycles();
+        assert_eq!(1, found.len());
+        assert_eq!("uniswapv2", found[0].hops[0].dex);
+        assert_eq!("sushiswap", found[0].hops[1].dex);
+    }
+
+    #[test]
+    fn test_sync_from_reserve_cache() {
+        let graph = graph();
+        let token = Address::repeat_byte(0xaa);
+        let cheap = graph.add_pool(Dex::uniswap_v2(), weth(), token).unwrap();
+        let dear = graph.add_pool(Dex::sushiswap(), weth(), token).unwrap();
+        // no reserves yet, nothing to price
+        assert!(graph.profitable_cycles().is_empty());
+
+        let weth_first = univ2::sort_token(weth(), token).0 == weth();
+        let oriented = |reserve_weth: U256, reserve_token: U256| {
+            if weth_first {
+                (reserve_weth, reserve_token)
+            } else {
+                (reserve_token, reserve_weth)
+            }
+        };
+        let cache = univ2::ReserveCache::default();
+        cache.insert(cheap, oriented(U256::exp10(21), U256::exp10(24)));
+        cache.insert(
+            dear,
+            oriented(U256::exp10(21) * 12 / 10, U256::exp10(24) * 10 / 12),
+        );
+        assert_eq!(2, graph.sync(&cache));
+        assert_eq!(0, graph.sync(&cache));
+        assert_eq!(1, graph.profitable_cycles().len());
+    }
+
+    #[test]
+    fn test_weth_fan_out_is_capped() {
+        let graph = graph();
+        let (usdc, dai) = (Address::repeat_byte(1), Address::repeat_byte(2));
+        let dex = Dex::uniswap_v2();
+        set(
+            &graph,
+            dex,
+            weth(),
+            usdc,
+            U256::exp10(21),
+            U256::exp10(24) * 2,
+        );
+        set(
+            &graph,
+            dex,
+            dai,
+            weth(),
+            U256::exp10(24) * 2,
+            U256::exp10(21),
+        );
+        // a few thousand WETH pairs with a common quote token, each would
+        // close a loop with every other one through it
+        let hub = Address::repeat_byte(3);
+        let mut added = 0;
+        for i in 0..5_000u64 {
+            let token = Address::from_low_u64_be(0x1000 + i);
+            added += graph.add_pool(dex, weth(), token).is_some() as usize;
+            graph.add_pool(Dex::sushiswap(), token, hub);
+        }
+        // the first six filled WETH up to the cap of eight
+        assert_eq!(6, added);
+        assert!(graph.add_pool(dex, weth(), hub).is_none());
+        assert!(graph.state.read().unwrap().cycles.len() < 100);
+
+        // still found, enumerated from the new pool alone
+        set(
+            &graph,
+            dex,
+            usdc,
+            dai,
+            U256::exp10(24) * 100,
+            U256::exp10(24) * 105,
+        );
+        let found = graph.profitable_cycles();
+        assert_eq!(1, found.len());
+        assert_eq!(3, found[0].hops.len());
+    }
+}
@@ -0,0 +1,137 @@
+use crate::utils::fees::FeePolicy;
+use crate::utils::rejection::Rejection;
+
+use ethers::prelude::*;
+use std::sync::Arc;
+
+// post-merge mainnet slot time in seconds
+pub const SLOT_TIME: u64 = 12;
+
+/// Source of chain time. The router checks deadlines against
+/// `block.timestamp`, so everything is derived from the latest head rather
+/// than the local clock.
+pub trait ChainClock: Send + Sync {
+    /// Number and timestamp of the latest head.
+    fn head(&self) -> Option<(U64, U256)>;
+}
+
+impl ChainClock for FeePolicy {
+    fn head(&self) -> Option<(U64, U256)> {
+        self.latest()
+            .map(|fees| (fees.block_number, fees.timestamp))
+    }
+}
+
+/// Pinned head, for tests and offline replays.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedClock {
+    pub number: U64,
+    pub timestamp: U256,
+}
+
+impl ChainClock for FixedClock {
+    fn head(&self) -> Option<(U64, U256)> {
+        Some((self.number, self.timestamp))
+    }
+}
+
+pub struct DeadlineGuard {
+    clock: Arc<dyn ChainClock>,
+    slot_time: u64,
+}
+
+impl DeadlineGuard {
+    pub fn new(clock: Arc<dyn ChainClock>, slot_time: u64) -> Self {
+        Self { clock, slot_time }
+    }
+
+    /// The block our bundles target, the one right after the latest head.
+    pub fn target_block(&self) -> Option<U64> {
+        self.clock.head().map(|(number, _)| number + 1)
+    }
+
+    /// Predicted `block.timestamp` of `block`, assuming no missed slots.
+    pub fn predicted_timestamp(&self, block: U64) -> Option<U256> {
+        let (number, timestamp) = self.clock.head()?;
+        let slots = block.saturating_sub(number).as_u64();
+        Some(timestamp + self.slot_time * slots)
+    }
+
+    /// Reject a victim whose `deadline` has passed by the time `block` is
+    /// mined.
+    pub fn check_at(&self, deadline: U256, block: U64) -> Result<(), Rejection> {
+        let target_timestamp = self
+            .predicted_timestamp(block)
+            .ok_or(Rejection::NoBlockYet)?;
+        if target_timestamp > deadline {
+            return Err(Rejection::DeadlineExceeded {
+                deadline,
+                target_timestamp,
+            });
+        }
+        Ok(())
+    }
+
+    /// Check `deadline` against the next block, returns that block.
+    pub fn check(&self, deadline: U256) -> Result<U64, Rejection> {
+        let block = self.target_block().ok_or(Rejection::NoBlockYet)?;
+        self.check_at(deadline, block)?;
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard() -> DeadlineGuard {
+        DeadlineGuard::new(
+            Arc::new(FixedClock {
+                number: U64::from(100),
+                timestamp: U256::from(1_000),
+            }),
+            SLOT_TIME,
+        )
+    }
+
+    #[test]
+    fn test_predicted_timestamp() {
+        let guard = guard();
+        assert_eq!(Some(U64::from(101)), guard.target_block());
+        assert_eq!(
+            Some(U256::from(1_012)),
+            guard.predicted_timestamp(U64::from(101))
+        );
+        assert_eq!(
+            Some(U256::from(1_036)),
+            guard.predicted_timestamp(U64::from(103))
+        );
+    }
+
+    #[test]
+    fn test_check_deadline() {
+        let guard = guard();
+        assert_eq!(Ok(U64::from(101)), guard.check(U256::from(1_012)));
+        assert_eq!(
+            Err(Rejection::DeadlineExceeded {
+                deadline: U256::from(1_011),
+                target_timestamp: U256::from(1_012),
+            }),
+            guard.check(U256::from(1_011))
+        );
+        assert!(guard.check_at(U256::from(1_020), U64::from(102)).is_err());
+    }
+
+    #[test]
+    fn test_no_head_yet() {
+        struct NoHead;
+        impl ChainClock for NoHead {
+            fn head(&self) -> Option<(U64, U256)> {
+                None
+            }
+        }
+
+        let guard = DeadlineGuard::new(Arc::new(NoHead), SLOT_TIME);
+        assert_eq!(Err(Rejection::NoBlockYet), guard.check(U256::MAX));
+    }
+}
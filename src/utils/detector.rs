@@ -0,0 +1,396 @@
+//! Finds sandwiches that already landed: in one block and on one pair, an
+//! attacker swaps before a victim in the same direction and swaps back
+//! after it.
+
+use crate::utils::backtest::Archive;
+use crate::utils::univ2::{self, SwapFilter};
+
+use ethers::prelude::*;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// A decoded pair `Swap` with the reserves right before it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairSwap {
+    pub pair: Address,
+    pub tx_hash: H256,
+    pub from: Address,
+    pub sender: Address,
+    pub to: Address,
+    pub zero_for_one: bool,
+    pub amount_in: U256,
+    pub amount_out: U256,
+    // (reserve_in, reserve_out) before the swap
+    pub reserves_before: Option<(U256, U256)>,
+}
+
+impl PairSwap {
+    /// `sync` is the pair's `Sync` emitted right before the `Swap`, it
+    /// carries the reserves after it.
+    pub fn new(
+        receipt: &TransactionReceipt,
+        pair: Address,
+        swap: &SwapFilter,
+        sync: Option<(U256, U256)>,
+    ) -> Option<Self> {
+        let zero_for_one = !swap.amount_0_in.is_zero() && !swap.amount_1_out.is_zero();
+        let one_for_zero = !swap.amount_1_in.is_zero() && !swap.amount_0_out.is_zero();
+        if zero_for_one == one_for_zero {
+            return None;
+        }
+
+        let (amount_in, amount_out) = if zero_for_one {
+            (swap.amount_0_in, swap.amount_1_out)
+        } else {
+            (swap.amount_1_in, swap.amount_0_out)
+        };
+        let reserves_before = sync.and_then(|(reserve0, reserve1)| {
+            let before0 = reserve0.checked_sub(swap.amount_0_in)? + swap.amount_0_out;
+            let before1 = reserve1.checked_sub(swap.amount_1_in)? + swap.amount_1_out;
+            Some(if zero_for_one {
+                (before0, before1)
+            } else {
+                (before1, before0)
+            })
+        });
+
+        Some(Self {
+            pair,
+            tx_hash: receipt.transaction_hash,
+            from: receipt.from,
+            sender: swap.sender,
+            to: swap.to,
+            zero_for_one,
+            amount_in,
+            amount_out,
+            reserves_before,
+        })
+    }
+}
+
+/// Swaps of a block in execution order.
+pub fn block_swaps(receipts: &[TransactionReceipt]) -> Vec<PairSwap> {
+    let mut receipts: Vec<&TransactionReceipt> = receipts.iter().collect();
+    receipts.sort_by_key(|receipt| receipt.transaction_index);
+
+    let mut swaps = Vec::new();
+    for receipt in receipts {
+        let mut last_sync: Option<(Address, U256, U256)> = None;
+        for log in &receipt.logs {
+            if let Some(sync) = univ2::decode_sync(log) {
+                last_sync = Some(sync);
+                continue;
+            }
+            if let Some(swap) = univ2::decode_swap(log) {
+                let sync = last_sync
+                    .filter(|(pair, _, _)| *pair == log.address)
+                    .map(|(_, reserve0, reserve1)| (reserve0, reserve1));
+                swaps.extend(PairSwap::new(receipt, log.address, &swap, sync));
+            }
+            last_sync = None;
+        }
+    }
+    swaps
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VictimLoss {
+    pub tx_hash: H256,
+    pub from: Address,
+    pub amount_in: U256,
+    pub amount_out: U256,
+    // what the same swap would have paid on the reserves before the frontrun
+    pub counterfactual_out: Option<U256>,
+}
+
+impl VictimLoss {
+    pub fn loss(&self) -> Option<U256> {
+        self.counterfactual_out
+            .map(|out| out.saturating_sub(self.amount_out))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DetectedSandwich {
+    pub block: U64,
+    pub pair: Address,
+    pub attacker: Address,
+    pub frontrun: H256,
+    pub backrun: H256,
+    pub victims: Vec<VictimLoss>,
+    // in the token the frontrun paid with
+    pub profit: I256,
+}
+
+impl fmt::Display for DetectedSandwich {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "block {} pair {:?} attacker {:?} profit {}",
+            self.block, self.pair, self.attacker, self.profit
+        )?;
+        writeln!(f, "  frontrun {:?}", self.frontrun)?;
+        for victim in &self.victims {
+            match victim.loss() {
+                Some(loss) => writeln!(f, "  victim   {:?} lost {}", victim.tx_hash, loss)?,
+                None => writeln!(f, "  victim   {:?} loss unknown", victim.tx_hash)?,
+            }
+        }
+        write!(f, "  backrun  {:?}", self.backrun)
+    }
+}
+
+// the backrun comes from the same EOA, or from the account the frontrun
+// sent its output to. The pair's `sender` is often a shared router.
+fn same_attacker(front: &PairSwap, back: &PairSwap) -> bool {
+    front.from == back.from || front.to == back.from
+}
+
+/// Sandwiches among the swaps of one block.
+pub fn detect_in_block(block: U64, swaps: &[PairSwap]) -> Vec<DetectedSandwich> {
+    let mut by_pair: BTreeMap<Address, Vec<&PairSwap>> = BTreeMap::new();
+    for swap in swaps {
+        by_pair.entry(swap.pair).or_default().push(swap);
+    }
+
+    let mut detected = Vec::new();
+    for (pair, swaps) in by_pair {
+        let mut i = 0;
+        while i < swaps.len() {
+            let front = swaps[i];
+            let back = (i + 2..swaps.len()).find(|&k| {
+                swaps[k].zero_for_one != front.zero_for_one
+                    && swaps[k].tx_hash != front.tx_hash
+                    && same_attacker(front, swaps[k])
+            });
+            let Some(k) = back else {
+                i += 1;
+                continue;
+            };
+
+            let victims: Vec<VictimLoss> = swaps[i + 1..k]
+                .iter()
+                .filter(|swap| {
+                    swap.zero_for_one == front.zero_for_one
+                        && swap.from != front.from
+                        && swap.tx_hash != front.tx_hash
+                })
+                .map(|swap| VictimLoss {
+                    tx_hash: swap.tx_hash,
+                    from: swap.from,
+                    amount_in: swap.amount_in,
+                    amount_out: swap.amount_out,
+                    counterfactual_out: front.reserves_before.and_then(
+                        |(reserve_in, reserve_out)| {
+                            univ2::get_univ2_data_given_in(swap.amount_in, reserve_in, reserve_out)
+                                .ok()
+                                .map(|outcome| outcome.amount_out)
+                        },
+                    ),
+                })
+                .collect();
+            if victims.is_empty() {
+                i += 1;
+                continue;
+            }
+
+            let back = swaps[k];
+            detected.push(DetectedSandwich {
+                block,
+                pair,
+                attacker: front.from,
+                frontrun: front.tx_hash,
+                backrun: back.tx_hash,
+                victims,
+                profit: I256::from_raw(back.amount_out) - I256::from_raw(front.amount_in),
+            });
+            i = k + 1;
+        }
+    }
+    detected
+}
+
+/// Scan every block with recorded receipts.
+pub fn detect(archive: &Archive) -> Vec<DetectedSandwich> {
+    archive
+        .receipts
+        .iter()
+        .flat_map(|(block, receipts)| detect_in_block(*block, &block_swaps(receipts)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::abi::{encode, Token};
+    use ethers::contract::EthEvent;
+    use ethers::utils::keccak256;
+
+    fn pair() -> Address {
+        Address::repeat_byte(0xee)
+    }
+
+    fn sync_log(reserve0: U256, reserve1: U256) -> Log {
+        Log {
+            address: pair(),
+            topics: vec![H256::from(keccak256("Sync(uint112,uint112)"))],
+            data: encode(&[Token::Uint(reserve0), Token::Uint(reserve1)]).into(),
+            ..Default::default()
+        }
+    }
+
+    fn swap_log(amounts: [U256; 4], to: Address) -> Log {
+        Log {
+            address: pair(),
+            topics: vec![
+                SwapFilter::signature(),
+                H256::from(Address::repeat_byte(0x77)),
+                H256::from(to),
+            ],
+            data: encode(&amounts.map(Token::Uint)).into(),
+            ..Default::default()
+        }
+    }
+
+    // executes a swap on `reserves` and returns its receipt
+    fn swap_receipt(
+        index: u64,
+        from: Address,
+        zero_for_one: bool,
+        amount_in: U256,
+        reserves: &mut (U256, U256),
+    ) -> TransactionReceipt {
+        let (reserve_in, reserve_out) = if zero_for_one {
+            (reserves.0, reserves.1)
+        } else {
+            (reserves.1, reserves.0)
+        };
+        let outcome = univ2::get_univ2_data_given_in(amount_in, reserve_in, reserve_out).unwrap();
+        let (amounts, new_reserves) = if zero_for_one {
+            (
+                [amount_in, U256::zero(), U256::zero(), outcome.amount_out],
+                (outcome.new_reserve_in, outcome.new_reserve_out),
+            )
+        } else {
+            (
+                [U256::zero(), amount_in, outcome.amount_out, U256::zero()],
+                (outcome.new_reserve_out, outcome.new_reserve_in),
+            )
+        };
+        *reserves = new_reserves;
+
+        TransactionReceipt {
+            transaction_hash: H256::from_low_u64_be(index + 1),
+            transaction_index: index.into(),
+            from,
+            logs: vec![sync_log(reserves.0, reserves.1), swap_log(amounts, from)],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_detect_sandwich() {
+        let attacker = Address::repeat_byte(1);
+        let victim = Address::repeat_byte(2);
+        let initial = (U256::exp10(21), U256::exp10(21) * 2);
+        let mut reserves = initial;
+
+        let front = swap_receipt(0, attacker, true, U256::exp10(19), &mut reserves);
+        let victim_swap = swap_receipt(1, victim, true, U256::exp10(19), &mut reserves);
+        let bought = block_swaps(std::slice::from_ref(&front))[0].amount_out;
+        let back = swap_receipt(2, attacker, false, bought, &mut reserves);
+
+        let swaps = block_swaps(&[back, victim_swap, front]);
+        assert_eq!(3, swaps.len());
+        assert_eq!(Some(initial), swaps[0].reserves_before);
+
+        let detected = detect_in_block(U64::from(1), &swaps);
+        assert_eq!(1, detected.len());
+        let sandwich = &detected[0];
+        assert_eq!(attacker, sandwich.attacker);
+        assert_eq!(H256::from_low_u64_be(1), sandwich.frontrun);
+        assert_eq!(H256::from_low_u64_be(3), sandwich.backrun);
+        assert!(sandwich.profit > I256::zero());
+
+        let loss = &sandwich.victims[0];
+        let expected = univ2::get_univ2_data_given_in(U256::exp10(19), initial.0, initial.1)
+            .unwrap()
+            .amount_out;
+        assert_eq!(Some(expected), loss.counterfactual_out);
+        assert!(loss.loss().unwrap() > U256::zero());
+    }
+
+    #[test]
+    fn test_no_sandwich_without_backrun() {
+        let mut reserves = (U256::exp10(21), U256::exp10(21));
+        let receipts = vec![
+            swap_receipt(
+                0,
+                Address::repeat_byte(1),
+                true,
+                U256::exp10(18),
+                &mut reserves,
+            ),
+            swap_receipt(
+                1,
+                Address::repeat_byte(2),
+                true,
+                U256::exp10(18),
+                &mut reserves,
+            ),
+            swap_receipt(
+                2,
+                Address::repeat_byte(3),
+                false,
+                U256::exp10(18),
+                &mut reserves,
+            ),
+        ];
+        assert!(detect_in_block(U64::from(1), &block_swaps(&receipts)).is_empty());
+    }
+
+    #[test]
+    fn test_no_sandwich_across_router_users() {
+        let router = Address::repeat_byte(0x77);
+        let mut reserves = (U256::exp10(21), U256::exp10(21));
+        let receipts: Vec<TransactionReceipt> = (0..3)
+            .map(|i| {
+                swap_receipt(
+                    i,
+                    Address::from_low_u64_be(i + 1),
+                    i < 2,
+                    U256::exp10(18),
+                    &mut reserves,
+                )
+            })
+            .collect();
+        let mut swaps = block_swaps(&receipts);
+        // the router keeps the first output to unwrap it, every swap has
+        // the router as sender
+        swaps[0].to = router;
+        assert!(swaps.iter().all(|swap| swap.sender == router));
+        assert!(detect_in_block(U64::from(1), &swaps).is_empty());
+    }
+
+    #[test]
+    fn test_detect_sandwich_funded_by_frontrun_recipient() {
+        let (attacker, collector) = (Address::repeat_byte(1), Address::repeat_byte(4));
+        let mut reserves = (U256::exp10(21), U256::exp10(21));
+        let receipts = vec![
+            swap_receipt(0, attacker, true, U256::exp10(19), &mut reserves),
+            swap_receipt(
+                1,
+                Address::repeat_byte(2),
+                true,
+                U256::exp10(19),
+                &mut reserves,
+            ),
+            swap_receipt(2, collector, false, U256::exp10(19), &mut reserves),
+        ];
+        let mut swaps = block_swaps(&receipts);
+        swaps[0].to = collector;
+        let detected = detect_in_block(U64::from(1), &swaps);
+        assert_eq!(1, detected.len());
+        assert_eq!(attacker, detected[0].attacker);
+    }
+}
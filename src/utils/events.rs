@@ -0,0 +1,298 @@
+//! What the watcher sees, published once and consumed by every sink: the
+//! opportunity log, the log output and the dashboard. Metrics are counted on
+//! publish, a lagging sink must not lose them.
+
+use crate::utils::api::OpportunityLog;
+use crate::utils::arbitrage::ArbOpportunity;
+use crate::utils::cycles::CycleOpportunity;
+use crate::utils::metrics::Metrics;
+use crate::utils::pricing::{PriceOracle, Valuation};
+use crate::utils::rejection::Rejection;
+use crate::utils::tokens::{self, TokenRegistry};
+use crate::utils::univ2::{Dex, Reserves, SandWichState};
+
+use ethers::prelude::*;
+use log::{info, warn};
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+
+/// A router call evaluated as a sandwich victim.
+#[derive(Debug, Clone)]
+pub struct VictimEvent {
+    pub tx_hash: H256,
+    pub token_in: Address,
+    pub token_out: Address,
+    pub pair: Address,
+    pub amount_in: U256,
+    pub outcome: Result<SandWichState, Rejection>,
+    // what the victim trades, and the sandwich profit when one was found
+    pub amount_in_value: Option<Valuation>,
+    pub profit_value: Option<Valuation>,
+}
+
+impl VictimEvent {
+    pub fn new(
+        tx_hash: H256,
+        token_in: Address,
+        token_out: Address,
+        amount_in: U256,
+        outcome: Result<SandWichState, Rejection>,
+    ) -> Self {
+        Self {
+            tx_hash,
+            token_in,
+            token_out,
+            pair: Dex::uniswap_v2().pair_address(token_in, token_out),
+            amount_in,
+            outcome,
+            amount_in_value: None,
+            profit_value: None,
+        }
+    }
+
+    /// Attach ETH and USD values priced on `reserves`.
+    pub fn valued(mut self, prices: &PriceOracle, reserves: &impl Reserves) -> Self {
+        self.amount_in_value = prices.value_token(self.token_in, self.amount_in, reserves);
+        self.profit_value = self
+            .outcome
+            .as_ref()
+            .ok()
+            .map(|state| prices.value_wei(state.profit, reserves));
+        self
+    }
+}
+
+/// A backrun arbitrage left open by a pending swap.
+#[derive(Debug, Clone)]
+pub struct ArbitrageEvent {
+    pub tx_hash: H256,
+    pub opportunity: ArbOpportunity,
+    pub profit_value: Option<Valuation>,
+}
+
+/// A loop of pools through WETH priced off, found after a block.
+#[derive(Debug, Clone)]
+pub struct CycleEvent {
+    pub block: U64,
+    pub opportunity: CycleOpportunity,
+    pub profit_value: Option<Valuation>,
+}
+
+#[derive(Debug, Clone)]
+pub enum BotEvent {
+    PendingTx(H256),
+    RouterTx {
+        tx_hash: H256,
+        selector: [u8; 4],
+        function: &'static str,
+    },
+    DecodeFailure(H256),
+    Victim(Box<VictimEvent>),
+    Arbitrage(Box<ArbitrageEvent>),
+    Cycle(Box<CycleEvent>),
+    NewBlock {
+        number: U64,
+        timestamp: U256,
+    },
+    // the pending tx subscription ended
+    Disconnected(String),
+}
+
+/// Broadcast channel of [`BotEvent`]s, sinks that lag behind lose the
+/// oldest events.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<BotEvent>,
+    metrics: Arc<Metrics>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize, metrics: Arc<Metrics>) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender, metrics }
+    }
+
+    pub fn publish(&self, event: BotEvent) {
+        count(&self.metrics, &event);
+        // no subscriber is fine, nobody is watching
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<BotEvent> {
+        self.sender.subscribe()
+    }
+}
+
+pub trait EventSink: Send + 'static {
+    fn on_event(&mut self, event: &BotEvent);
+}
+
+fn count(metrics: &Metrics, event: &BotEvent) {
+    match event {
+        BotEvent::PendingTx(_) => metrics.pending_txs.inc(),
+        BotEvent::RouterTx { function, .. } => {
+            metrics.router_txs.with_label_values(&[function]).inc()
+        }
+        BotEvent::DecodeFailure(_) => metrics.decode_failures.inc(),
+        BotEvent::Victim(victim) => metrics.record_outcome(&victim.outcome),
+        BotEvent::Arbitrage(_) => metrics.arbitrages.inc(),
+        BotEvent::Cycle(_) => metrics.cycles.inc(),
+        BotEvent::NewBlock { .. } | BotEvent::Disconnected(_) => {}
+    }
+}
+
+impl EventSink for Arc<OpportunityLog> {
+    fn on_event(&mut self, event: &BotEvent) {
+        if let BotEvent::Victim(victim) = event {
+            if let Ok(state) = &victim.outcome {
+                self.push(victim.tx_hash, state.clone(), victim.profit_value);
+            }
+        }
+    }
+}
+
+/// Writes victims and disconnects to the log, amounts in whole tokens.
+pub struct LogSink {
+    pub tokens: Arc<TokenRegistry>,
+}
+
+fn eth(wei: U256) -> String {
+    format!("{} ETH", tokens::format_units(wei, 18))
+}
+
+impl EventSink for LogSink {
+    fn on_event(&mut self, event: &BotEvent) {
+        match event {
+            BotEvent::Victim(victim) => {
+                let mut swap = format!(
+                    "{:?} {} -> {}",
+                    victim.tx_hash,
+                    self.tokens.format(victim.token_in, victim.amount_in),
+                    self.tokens.info(victim.token_out).symbol
+                );
+                if let Some(value) = &victim.amount_in_value {
+                    swap.push_str(&format!(" [{:.4} ETH {}]", value.eth, value.usd_label()));
+                }
+                match &victim.outcome {
+                    Ok(state) => info!(
+                        "sandwich {}: weth in {}, revenue {}, gas {}, profit {} {}",
+                        swap,
+                        eth(state.optimal_sandwich_weth_in),
+                        eth(state.revenue),
+                        eth(state.gas_cost),
+                        tokens::format_signed_units(state.profit, 18),
+                        victim
+                            .profit_value
+                            .map(|value| value.usd_label())
+                            .unwrap_or_default()
+                    ),
+                    Err(rejection) => warn!(
+                        "victim {} rejected [{}]: {}",
+                        swap,
+                        rejection.reason(),
+                        rejection
+                    ),
+                }
+            }
+            BotEvent::Arbitrage(arbitrage) => {
+                let arb = &arbitrage.opportunity;
+                info!(
+                    "arbitrage after {:?}: {} -> {} via {} on {}, sell on {}, profit {} {}",
+                    arbitrage.tx_hash,
+                    self.tokens.format(arb.base, arb.amount_in),
+                    self.tokens.format(arb.base, arb.amount_out),
+                    self.tokens.info(arb.bridge).symbol,
+                    arb.buy_on,
+                    arb.sell_on,
+                    self.tokens.format(arb.base, arb.profit),
+                    arbitrage
+                        .profit_value
+                        .map(|value| value.usd_label())
+                        .unwrap_or_default()
+                )
+            }
+            BotEvent::Cycle(cycle) => {
+                let opportunity = &cycle.opportunity;
+                let route: Vec<String> = opportunity
+                    .hops
+                    .iter()
+                    .map(|hop| format!("{} ({})", self.tokens.info(hop.token_out).symbol, hop.dex))
+                    .collect();
+                let start = opportunity.hops[0].token_in;
+                info!(
+                    "cycle at block {}: {} -> {} -> {}, profit {} {}",
+                    cycle.block,
+                    self.tokens.format(start, opportunity.amount_in),
+                    route.join(" -> "),
+                    self.tokens.format(start, opportunity.amount_out),
+                    self.tokens.format(start, opportunity.profit),
+                    cycle
+                        .profit_value
+                        .map(|value| value.usd_label())
+                        .unwrap_or_default()
+                )
+            }
+            BotEvent::Disconnected(reason) => warn!("disconnected: {}", reason),
+            _ => {}
+        }
+    }
+}
+
+/// Feed every event of `bus` to `sink` until the bus is dropped.
+pub fn spawn_sink(bus: &EventBus, mut sink: impl EventSink) -> JoinHandle<()> {
+    let mut receiver = bus.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => sink.on_event(&event),
+                Err(RecvError::Lagged(missed)) => warn!("event sink lagged, {} missed", missed),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sinks_share_the_stream() {
+        let metrics = Arc::new(Metrics::new().unwrap());
+        // smaller than what is published, the log sink lags
+        let bus = EventBus::new(1, Arc::clone(&metrics));
+        let log_sink = spawn_sink(
+            &bus,
+            LogSink {
+                tokens: Arc::new(TokenRegistry::bundled()),
+            },
+        );
+
+        bus.publish(BotEvent::PendingTx(H256::zero()));
+        bus.publish(BotEvent::RouterTx {
+            tx_hash: H256::zero(),
+            selector: [0x7f, 0xf3, 0x6a, 0xb5],
+            function: "swapExactETHForTokens",
+        });
+        bus.publish(BotEvent::Victim(Box::new(VictimEvent::new(
+            H256::zero(),
+            Address::repeat_byte(1),
+            Address::repeat_byte(2),
+            U256::one(),
+            Err(Rejection::NoBlockYet),
+        ))));
+        drop(bus);
+
+        log_sink.await.unwrap();
+        assert_eq!(1, metrics.pending_txs.get());
+        assert_eq!(1, metrics.opportunities.get());
+        assert_eq!(
+            1,
+            metrics
+                .rejections
+                .with_label_values(&["no_block_yet"])
+                .get()
+        );
+    }
+}
@@ -0,0 +1,311 @@
+use crate::env_store::{get_env_or, EnvError};
+use crate::utils::rejection::Rejection;
+
+use ethers::prelude::*;
+use log::debug;
+use std::sync::RwLock;
+
+// EIP-1559 constants
+const ELASTICITY_MULTIPLIER: u64 = 2;
+const BASE_FEE_MAX_CHANGE_DENOMINATOR: u64 = 8;
+
+/// Fee knobs, amounts are wei per gas unless named otherwise.
+#[derive(Debug, Clone)]
+pub struct FeeConfig {
+    // frontrun tip = victim tip * (100 + bump) / 100
+    pub frontrun_priority_bump_percent: u64,
+    pub min_priority_fee: u64,
+    pub max_priority_fee: u64,
+    // tip of the backrun, it only has to land after the victim
+    pub backrun_priority_fee: u64,
+    // headroom on max_fee_per_gas so a base fee rise does not strand the tx
+    pub base_fee_multiplier: u64,
+    pub frontrun_gas_limit: u64,
+    pub backrun_gas_limit: u64,
+}
+
+impl Default for FeeConfig {
+    fn default() -> Self {
+        Self {
+            frontrun_priority_bump_percent: 10,
+            min_priority_fee: 1_000_000_000,
+            max_priority_fee: 500_000_000_000,
+            backrun_priority_fee: 0,
+            base_fee_multiplier: 2,
+            frontrun_gas_limit: 150_000,
+            backrun_gas_limit: 150_000,
+        }
+    }
+}
+
+impl FeeConfig {
+    pub fn from_env() -> Result<Self, EnvError> {
+        let default = Self::default();
+        Ok(Self {
+            frontrun_priority_bump_percent: get_env_or(
+                "FEE_FRONTRUN_PRIORITY_BUMP_PERCENT",
+                default.frontrun_priority_bump_percent,
+            )?,
+            min_priority_fee: get_env_or("FEE_MIN_PRIORITY_FEE", default.min_priority_fee)?,
+            max_priority_fee: get_env_or("FEE_MAX_PRIORITY_FEE", default.max_priority_fee)?,
+            backrun_priority_fee: get_env_or(
+                "FEE_BACKRUN_PRIORITY_FEE",
+                default.backrun_priority_fee,
+            )?,
+            base_fee_multiplier: get_env_or(
+                "FEE_BASE_FEE_MULTIPLIER",
+                default.base_fee_multiplier,
+            )?,
+            frontrun_gas_limit: get_env_or("FEE_FRONTRUN_GAS_LIMIT", default.frontrun_gas_limit)?,
+            backrun_gas_limit: get_env_or("FEE_BACKRUN_GAS_LIMIT", default.backrun_gas_limit)?,
+        })
+    }
+}
+
+/// Fee state derived from the latest head.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockFees {
+    pub block_number: U64,
+    pub timestamp: U256,
+    pub base_fee: U256,
+    pub next_base_fee: U256,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxFees {
+    pub max_fee_per_gas: U256,
+    pub max_priority_fee_per_gas: U256,
+    pub gas_limit: U256,
+}
+
+impl TxFees {
+    /// Price actually paid per gas once included under `base_fee`.
+    pub fn effective_gas_price(&self, base_fee: U256) -> U256 {
+        self.max_fee_per_gas
+            .min(base_fee.saturating_add(self.max_priority_fee_per_gas))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SandwichFees {
+    pub frontrun: TxFees,
+    pub backrun: TxFees,
+    // worst case wei spent on gas by both legs at the predicted base fee
+    pub gas_cost: U256,
+}
+
+/// Base fee of the child block as computed by the EIP-1559 update rule.
+pub fn predict_next_base_fee(base_fee: U256, gas_used: U256, gas_limit: U256) -> U256 {
+    let gas_target = gas_limit / ELASTICITY_MULTIPLIER;
+    if gas_target.is_zero() || gas_used == gas_target {
+        return base_fee;
+    }
+
+    if gas_used > gas_target {
+        let delta =
+            base_fee * (gas_used - gas_target) / gas_target / BASE_FEE_MAX_CHANGE_DENOMINATOR;
+        base_fee + delta.max(U256::one())
+    } else {
+        let delta =
+            base_fee * (gas_target - gas_used) / gas_target / BASE_FEE_MAX_CHANGE_DENOMINATOR;
+        base_fee.saturating_sub(delta)
+    }
+}
+
+/// Tip the victim pays per gas on top of `base_fee`, for both legacy and
+/// EIP-1559 transactions.
+pub fn victim_priority_fee(tx: &Transaction, base_fee: U256) -> U256 {
+    match (tx.max_fee_per_gas, tx.max_priority_fee_per_gas) {
+        (Some(max_fee), Some(max_priority)) => max_priority.min(max_fee.saturating_sub(base_fee)),
+        _ => tx.gas_price.unwrap_or_default().saturating_sub(base_fee),
+    }
+}
+
+pub struct FeePolicy {
+    config: FeeConfig,
+    latest: RwLock<Option<BlockFees>>,
+}
+
+impl FeePolicy {
+    pub fn new(config: FeeConfig) -> Self {
+        Self {
+            config,
+            latest: RwLock::new(None),
+        }
+    }
+
+    pub fn config(&self) -> &FeeConfig {
+        &self.config
+    }
+
+    /// Feed a new head, returns the fee state for the block after it.
+    pub fn on_block<T>(&self, block: &Block<T>) -> Option<BlockFees> {
+        let base_fee = block.base_fee_per_gas?;
+        let fees = BlockFees {
+            block_number: block.number?,
+            timestamp: block.timestamp,
+            base_fee,
+            next_base_fee: predict_next_base_fee(base_fee, block.gas_used, block.gas_limit),
+        };
+        debug!(
+            "block {} base fee {} -> next {}",
+            fees.block_number, fees.base_fee, fees.next_base_fee
+        );
+
+        *self.latest.write().unwrap() = Some(fees);
+        Some(fees)
+    }
+
+    pub fn latest(&self) -> Option<BlockFees> {
+        *self.latest.read().unwrap()
+    }
+
+    /// Fees for a frontrun/backrun pair around `victim`, priced against the
+    /// predicted base fee of the next block.
+    pub fn sandwich_fees(&self, victim: &Transaction) -> Result<SandwichFees, Rejection> {
+        let next_base_fee = self.latest().ok_or(Rejection::NoBlockYet)?.next_base_fee;
+        self.sandwich_fees_at(victim, next_base_fee)
+    }
+
+    /// Fails when the capped frontrun tip does not beat the victim's, the
+    /// frontrun would be ordered after it.
+    pub fn sandwich_fees_at(
+        &self,
+        victim: &Transaction,
+        next_base_fee: U256,
+    ) -> Result<SandwichFees, Rejection> {
+        let config = &self.config;
+
+        let victim_tip = victim_priority_fee(victim, next_base_fee);
+        let cap = U256::from(config.max_priority_fee);
+        let frontrun_tip = (victim_tip * (100 + config.frontrun_priority_bump_percent) / 100 + 1)
+            .max(config.min_priority_fee.into())
+            .min(cap);
+        if frontrun_tip <= victim_tip {
+            return Err(Rejection::VictimTipAboveCap { victim_tip, cap });
+        }
+        let backrun_tip = U256::from(config.backrun_priority_fee).min(victim_tip);
+
+        let max_base_fee = next_base_fee * config.base_fee_multiplier;
+        let frontrun = TxFees {
+            max_fee_per_gas: max_base_fee + frontrun_tip,
+            max_priority_fee_per_gas: frontrun_tip,
+            gas_limit: config.frontrun_gas_limit.into(),
+        };
+        let backrun = TxFees {
+            max_fee_per_gas: max_base_fee + backrun_tip,
+            max_priority_fee_per_gas: backrun_tip,
+            gas_limit: config.backrun_gas_limit.into(),
+        };
+
+        let gas_cost = frontrun.effective_gas_price(next_base_fee) * frontrun.gas_limit
+            + backrun.effective_gas_price(next_base_fee) * backrun.gas_limit;
+
+        Ok(SandwichFees {
+            frontrun,
+            backrun,
+            gas_cost,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_predict_next_base_fee() {
+        let base_fee = U256::from(100_000_000_000u64);
+        let gas_limit = U256::from(30_000_000);
+
+        // at target the base fee does not move
+        assert_eq!(
+            base_fee,
+            predict_next_base_fee(base_fee, U256::from(15_000_000), gas_limit)
+        );
+        // full block, +12.5%
+        assert_eq!(
+            U256::from(112_500_000_000u64),
+            predict_next_base_fee(base_fee, gas_limit, gas_limit)
+        );
+        // empty block, -12.5%
+        assert_eq!(
+            U256::from(87_500_000_000u64),
+            predict_next_base_fee(base_fee, U256::zero(), gas_limit)
+        );
+        // tiny base fee still rises by at least 1 wei
+        assert_eq!(
+            U256::from(8),
+            predict_next_base_fee(U256::from(7), U256::from(15_000_001), gas_limit)
+        );
+    }
+
+    #[test]
+    fn test_victim_priority_fee() {
+        let base_fee = U256::from(30);
+
+        let legacy = Transaction {
+            gas_price: Some(U256::from(50)),
+            ..Default::default()
+        };
+        assert_eq!(U256::from(20), victim_priority_fee(&legacy, base_fee));
+
+        let capped = Transaction {
+            max_fee_per_gas: Some(U256::from(35)),
+            max_priority_fee_per_gas: Some(U256::from(10)),
+            ..Default::default()
+        };
+        assert_eq!(U256::from(5), victim_priority_fee(&capped, base_fee));
+    }
+
+    #[test]
+    fn test_sandwich_fees_outbid_victim() {
+        let policy = FeePolicy::new(FeeConfig {
+            min_priority_fee: 0,
+            ..Default::default()
+        });
+        let victim = Transaction {
+            max_fee_per_gas: Some(U256::from(200)),
+            max_priority_fee_per_gas: Some(U256::from(100)),
+            ..Default::default()
+        };
+
+        let fees = policy.sandwich_fees_at(&victim, U256::from(50)).unwrap();
+        assert_eq!(U256::from(111), fees.frontrun.max_priority_fee_per_gas);
+        assert_eq!(U256::from(211), fees.frontrun.max_fee_per_gas);
+        assert_eq!(U256::zero(), fees.backrun.max_priority_fee_per_gas);
+        assert_eq!(U256::from(161 * 150_000 + 50 * 150_000), fees.gas_cost);
+    }
+
+    #[test]
+    fn test_sandwich_fees_victim_tip_above_cap() {
+        let policy = FeePolicy::new(FeeConfig {
+            min_priority_fee: 0,
+            max_priority_fee: 100,
+            ..Default::default()
+        });
+        let victim = |tip: u64| Transaction {
+            max_fee_per_gas: Some(U256::from(1_000)),
+            max_priority_fee_per_gas: Some(U256::from(tip)),
+            ..Default::default()
+        };
+
+        // the bump is clamped but still ahead of the victim
+        let fees = policy
+            .sandwich_fees_at(&victim(95), U256::from(50))
+            .unwrap();
+        assert_eq!(U256::from(100), fees.frontrun.max_priority_fee_per_gas);
+        // at or above the cap the frontrun can't go first
+        for tip in [100, 150] {
+            assert_eq!(
+                Err(Rejection::VictimTipAboveCap {
+                    victim_tip: U256::from(tip),
+                    cap: U256::from(100),
+                }),
+                policy
+                    .sandwich_fees_at(&victim(tip), U256::from(50))
+                    .map(|fees| fees.gas_cost)
+            );
+        }
+    }
+}
@@ -0,0 +1,408 @@
+use crate::env_store::{get_env_or, EnvError};
+use crate::utils::multicall::{CallBatcher, MulticallConfig, MulticallError};
+
+use ethers::prelude::*;
+use ethers::providers::{JsonRpcClient, ProviderError};
+use log::{debug, info};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug)]
+pub enum InventoryError {
+    MulticallError(MulticallError),
+    ProviderError(ProviderError),
+    // `balanceOf` reverted for the token
+    BalanceUnavailable(Address),
+}
+
+impl InventoryError {
+    /// The RPC call that failed, None when the node answered.
+    pub fn rpc_call(&self) -> Option<&'static str> {
+        match self {
+            InventoryError::MulticallError(_) => Some("aggregate3"),
+            InventoryError::ProviderError(_) => Some("eth_getBalance"),
+            InventoryError::BalanceUnavailable(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InventoryConfig {
+    // WETH kept aside, never used for a frontrun
+    pub weth_reserve: U256,
+    // largest WETH amount committed to one token across all in-flight bundles
+    pub max_token_exposure: U256,
+    pub token_exposure_limits: HashMap<Address, U256>,
+}
+
+impl Default for InventoryConfig {
+    fn default() -> Self {
+        Self {
+            weth_reserve: U256::zero(),
+            max_token_exposure: U256::exp10(18) * 10,
+            token_exposure_limits: HashMap::new(),
+        }
+    }
+}
+
+impl InventoryConfig {
+    pub fn from_env() -> Result<Self, EnvError> {
+        let default = Self::default();
+        Ok(Self {
+            weth_reserve: get_env_or("INVENTORY_WETH_RESERVE", default.weth_reserve.as_u128())?
+                .into(),
+            max_token_exposure: get_env_or(
+                "INVENTORY_MAX_TOKEN_EXPOSURE",
+                default.max_token_exposure.as_u128(),
+            )?
+            .into(),
+            token_exposure_limits: parse_token_limits(&get_env_or(
+                "INVENTORY_TOKEN_LIMITS",
+                String::new(),
+            )?)?,
+        })
+    }
+
+    pub fn exposure_limit(&self, token: Address) -> U256 {
+        *self
+            .token_exposure_limits
+            .get(&token)
+            .unwrap_or(&self.max_token_exposure)
+    }
+}
+
+// `token:wei` entries separated by commas
+fn parse_token_limits(val: &str) -> Result<HashMap<Address, U256>, EnvError> {
+    let mut limits = HashMap::new();
+    for entry in val.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let parsed = match entry.split(':').collect::<Vec<&str>>()[..] {
+            [token, wei] => token
+                .parse::<Address>()
+                .ok()
+                .zip(U256::from_dec_str(wei).ok()),
+            _ => None,
+        };
+        let (token, limit) =
+            parsed.ok_or_else(|| EnvError::from(format!("bad token limit: {}", entry)))?;
+        limits.insert(token, limit);
+    }
+    Ok(limits)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Reservation {
+    pub token: Address,
+    pub weth: U256,
+    // block the bundle targets, the reservation lapses after it
+    pub block: U64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Position {
+    pub token: Address,
+    pub balance: U256,
+    pub reserved_weth: U256,
+    pub exposure_limit: U256,
+}
+
+#[derive(Debug, Default)]
+pub struct InventoryState {
+    pub eth_balance: U256,
+    pub weth_balance: U256,
+    pub token_balances: HashMap<Address, U256>,
+    reservations: HashMap<u64, Reservation>,
+    next_reservation: u64,
+}
+
+impl InventoryState {
+    pub fn reserved_weth(&self) -> U256 {
+        self.reservations
+            .values()
+            .fold(U256::zero(), |acc, r| acc + r.weth)
+    }
+
+    pub fn token_exposure(&self, token: Address) -> U256 {
+        self.reservations
+            .values()
+            .filter(|r| r.token == token)
+            .fold(U256::zero(), |acc, r| acc + r.weth)
+    }
+
+    pub fn available_weth(&self, config: &InventoryConfig) -> U256 {
+        self.weth_balance
+            .saturating_sub(config.weth_reserve)
+            .saturating_sub(self.reserved_weth())
+    }
+
+    /// Largest frontrun on `token` we can fund right now.
+    pub fn max_frontrun(&self, config: &InventoryConfig, token: Address) -> U256 {
+        let exposure_left = config
+            .exposure_limit(token)
+            .saturating_sub(self.token_exposure(token));
+        self.available_weth(config).min(exposure_left)
+    }
+
+    pub fn reserve(
+        &mut self,
+        config: &InventoryConfig,
+        token: Address,
+        weth: U256,
+        block: U64,
+    ) -> Option<u64> {
+        if weth > self.max_frontrun(config, token) {
+            return None;
+        }
+
+        let id = self.next_reservation;
+        self.next_reservation += 1;
+        self.reservations
+            .insert(id, Reservation { token, weth, block });
+        Some(id)
+    }
+
+    pub fn release(&mut self, id: u64) -> Option<Reservation> {
+        self.reservations.remove(&id)
+    }
+
+    /// Apply the expected outcome of a submitted bundle before the chain
+    /// confirms it. Its reservation holds until the target block passes, the
+    /// next refresh overwrites these numbers either way.
+    pub fn apply_optimistic(&mut self, id: u64, weth_delta: I256, eth_delta: I256) {
+        if !self.reservations.contains_key(&id) {
+            return;
+        }
+        self.weth_balance = apply_delta(self.weth_balance, weth_delta);
+        self.eth_balance = apply_delta(self.eth_balance, eth_delta);
+    }
+
+    fn expire(&mut self, block: U64) {
+        self.reservations.retain(|_, r| r.block >= block);
+    }
+}
+
+fn apply_delta(balance: U256, delta: I256) -> U256 {
+    if delta.is_negative() {
+        balance.saturating_sub(delta.unsigned_abs())
+    } else {
+        balance.saturating_add(delta.into_raw())
+    }
+}
+
+/// Tracks what the executor holds and what is already promised to in-flight
+/// bundles, so the optimizer never sizes a frontrun we can not pay for.
+pub struct Inventory<P: JsonRpcClient> {
+    provider: Arc<Provider<P>>,
+    config: InventoryConfig,
+    executor: Address,
+    weth: Address,
+    state: Mutex<InventoryState>,
+    batcher: CallBatcher<Provider<P>>,
+}
+
+impl<P: JsonRpcClient + 'static> Inventory<P> {
+    pub fn new(
+        provider: Arc<Provider<P>>,
+        config: InventoryConfig,
+        multicall: MulticallConfig,
+        executor: Address,
+        weth: Address,
+    ) -> Self {
+        Self {
+            batcher: CallBatcher::new(Arc::clone(&provider), multicall),
+            provider,
+            config,
+            executor,
+            weth,
+            state: Mutex::new(InventoryState::default()),
+        }
+    }
+
+    pub fn executor(&self) -> Address {
+        self.executor
+    }
+
+    /// Reload balances from chain at the head `block`. Reservations for
+    /// bundles targeting earlier blocks are dropped.
+    pub async fn refresh(&self, block: U64) -> Result<(), InventoryError> {
+        let eth_balance = self
+            .provider
+            .get_balance(self.executor, None)
+            .await
+            .map_err(InventoryError::ProviderError)?;
+        // WETH first, then the tracked tokens, in one batch
+        let mut tokens = vec![self.weth];
+        tokens.extend(self.state.lock().unwrap().token_balances.keys().cloned());
+        let balances = self
+            .batcher
+            .balances(self.executor, &tokens)
+            .await
+            .map_err(InventoryError::MulticallError)?;
+        let mut token_balances = HashMap::new();
+        for (token, balance) in tokens.iter().zip(balances) {
+            token_balances.insert(
+                *token,
+                balance.ok_or(InventoryError::BalanceUnavailable(*token))?,
+            );
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.eth_balance = eth_balance;
+        state.weth_balance = token_balances.remove(&self.weth).unwrap_or_default();
+        for (token, balance) in token_balances {
+            state.token_balances.insert(token, balance);
+        }
+        state.expire(block + 1);
+        debug!(
+            "inventory at block {}: eth={} weth={} reserved={}",
+            block,
+            state.eth_balance,
+            state.weth_balance,
+            state.reserved_weth()
+        );
+        Ok(())
+    }
+
+    /// Start following the balance of `token`, picked up on the next refresh.
+    pub fn track(&self, token: Address) {
+        let mut state = self.state.lock().unwrap();
+        state.token_balances.entry(token).or_default();
+    }
+
+    pub fn max_frontrun(&self, token: Address) -> U256 {
+        self.state.lock().unwrap().max_frontrun(&self.config, token)
+    }
+
+    pub fn cap_frontrun(&self, token: Address, weth_in: U256) -> U256 {
+        weth_in.min(self.max_frontrun(token))
+    }
+
+    pub fn reserve(&self, token: Address, weth: U256, block: U64) -> Option<u64> {
+        self.track(token);
+        self.state
+            .lock()
+            .unwrap()
+            .reserve(&self.config, token, weth, block)
+    }
+
+    pub fn release(&self, id: u64) {
+        self.state.lock().unwrap().release(id);
+    }
+
+    pub fn apply_optimistic(&self, id: u64, weth_delta: I256, eth_delta: I256) {
+        self.state
+            .lock()
+            .unwrap()
+            .apply_optimistic(id, weth_delta, eth_delta);
+    }
+
+    pub fn positions(&self) -> Vec<Position> {
+        let state = self.state.lock().unwrap();
+        let mut positions = vec![Position {
+            token: self.weth,
+            balance: state.weth_balance,
+            reserved_weth: state.reserved_weth(),
+            exposure_limit: state.weth_balance.saturating_sub(self.config.weth_reserve),
+        }];
+        for (token, balance) in state.token_balances.iter() {
+            positions.push(Position {
+                token: *token,
+                balance: *balance,
+                reserved_weth: state.token_exposure(*token),
+                exposure_limit: self.config.exposure_limit(*token),
+            });
+        }
+        positions
+    }
+
+    pub fn report(&self) {
+        for position in self.positions() {
+            info!(
+                "position {:?}: balance={} reserved_weth={} limit={}",
+                position.token, position.balance, position.reserved_weth, position.exposure_limit
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> InventoryConfig {
+        InventoryConfig {
+            weth_reserve: U256::from(100),
+            max_token_exposure: U256::from(500),
+            token_exposure_limits: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_max_frontrun_capped_by_funds_and_exposure() {
+        let config = config();
+        let token = Address::repeat_byte(1);
+        let mut state = InventoryState {
+            weth_balance: U256::from(400),
+            ..Default::default()
+        };
+        assert_eq!(U256::from(300), state.max_frontrun(&config, token));
+
+        state.weth_balance = U256::from(10_000);
+        assert_eq!(U256::from(500), state.max_frontrun(&config, token));
+    }
+
+    #[test]
+    fn test_reservations_lock_capital() {
+        let config = config();
+        let (token_a, token_b) = (Address::repeat_byte(1), Address::repeat_byte(2));
+        let mut state = InventoryState {
+            weth_balance: U256::from(1_000),
+            ..Default::default()
+        };
+
+        let id = state
+            .reserve(&config, token_a, U256::from(400), U64::from(1))
+            .unwrap();
+        assert_eq!(U256::from(100), state.max_frontrun(&config, token_a));
+        assert_eq!(U256::from(500), state.max_frontrun(&config, token_b));
+        assert!(state
+            .reserve(&config, token_a, U256::from(200), U64::from(1))
+            .is_none());
+
+        state.apply_optimistic(id, I256::from(25), I256::from(-5));
+        assert_eq!(U256::from(1_025), state.weth_balance);
+        assert_eq!(U256::from(100), state.max_frontrun(&config, token_a));
+        state.release(id);
+        assert_eq!(U256::from(500), state.max_frontrun(&config, token_a));
+        // a released or expired reservation has no outcome left to apply
+        state.apply_optimistic(id, I256::from(25), I256::from(-5));
+        assert_eq!(U256::from(1_025), state.weth_balance);
+    }
+
+    #[test]
+    fn test_parse_token_limits() {
+        let limits =
+            parse_token_limits("0xdAC17F958D2ee523a2206206994597C13D831ec7:2000000000000000000, ")
+                .unwrap();
+        let usdt = "0xdAC17F958D2ee523a2206206994597C13D831ec7"
+            .parse::<Address>()
+            .unwrap();
+        assert_eq!(Some(&(U256::exp10(18) * 2)), limits.get(&usdt));
+        assert!(parse_token_limits("0xdAC17F958D2ee523a2206206994597C13D831ec7").is_err());
+    }
+
+    #[test]
+    fn test_reservations_expire() {
+        let config = config();
+        let token = Address::repeat_byte(1);
+        let mut state = InventoryState {
+            weth_balance: U256::from(1_000),
+            ..Default::default()
+        };
+        state.reserve(&config, token, U256::from(400), U64::from(10));
+
+        state.expire(U64::from(10));
+        assert_eq!(U256::from(400), state.reserved_weth());
+        state.expire(U64::from(11));
+        assert_eq!(U256::zero(), state.reserved_weth());
+    }
+}
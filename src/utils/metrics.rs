@@ -0,0 +1,232 @@
+use crate::utils::rejection::Rejection;
+use crate::utils::univ2::SandWichState;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Counters and histograms of the running bot, scraped from `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    pub pending_txs: IntCounter,
+    pub router_txs: IntCounterVec,
+    pub decode_failures: IntCounter,
+    pub reserve_lookups: IntCounterVec,
+    pub reserve_lookup_seconds: Histogram,
+    pub opportunities: IntCounter,
+    pub arbitrages: IntCounter,
+    pub cycles: IntCounter,
+    pub rejections: IntCounterVec,
+    pub estimated_profit_eth: Histogram,
+    pub rpc_errors: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some("unibot".to_string()), None)?;
+
+        let pending_txs = IntCounter::new("pending_txs_total", "Pending txs received")?;
+        let router_txs = IntCounterVec::new(
+            Opts::new("router_txs_total", "Router txs decoded, by function"),
+            &["function"],
+        )?;
+        let decode_failures = IntCounter::new(
+            "decode_failures_total",
+            "Router txs that matched no known function",
+        )?;
+        let reserve_lookups = IntCounterVec::new(
+            Opts::new(
+                "reserve_lookups_total",
+                "Pair reserve lookups, by cache result",
+            ),
+            &["cache"],
+        )?;
+        let reserve_lookup_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "reserve_lookup_seconds",
+                "Latency of reserve lookups going to the node",
+            )
+            .buckets(vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]),
+        )?;
+        let opportunities = IntCounter::new(
+            "opportunities_evaluated_total",
+            "Victims evaluated for a sandwich",
+        )?;
+        let arbitrages = IntCounter::new(
+            "arbitrage_opportunities_total",
+            "Profitable cross-dex backruns found",
+        )?;
+        let cycles = IntCounter::new(
+            "cycle_opportunities_total",
+            "Profitable pool loops through WETH found",
+        )?;
+        let rejections = IntCounterVec::new(
+            Opts::new("rejections_total", "Victims rejected, by reason"),
+            &["reason"],
+        )?;
+        let estimated_profit_eth = Histogram::with_opts(
+            HistogramOpts::new(
+                "estimated_profit_eth",
+                "Estimated profit of found sandwiches after gas, in ETH",
+            )
+            .buckets(vec![-0.01, 0.0, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0]),
+        )?;
+        let rpc_errors = IntCounterVec::new(
+            Opts::new("rpc_errors_total", "Failed RPC calls, by endpoint"),
+            &["endpoint"],
+        )?;
+
+        registry.register(Box::new(pending_txs.clone()))?;
+        registry.register(Box::new(router_txs.clone()))?;
+        registry.register(Box::new(decode_failures.clone()))?;
+        registry.register(Box::new(reserve_lookups.clone()))?;
+        registry.register(Box::new(reserve_lookup_seconds.clone()))?;
+        registry.register(Box::new(opportunities.clone()))?;
+        registry.register(Box::new(arbitrages.clone()))?;
+        registry.register(Box::new(cycles.clone()))?;
+        registry.register(Box::new(rejections.clone()))?;
+        registry.register(Box::new(estimated_profit_eth.clone()))?;
+        registry.register(Box::new(rpc_errors.clone()))?;
+
+        Ok(Self {
+            registry,
+            pending_txs,
+            router_txs,
+            decode_failures,
+            reserve_lookups,
+            reserve_lookup_seconds,
+            opportunities,
+            arbitrages,
+            cycles,
+            rejections,
+            estimated_profit_eth,
+            rpc_errors,
+        })
+    }
+
+    pub fn record_reserve_lookup(&self, cache_hit: bool, latency: Option<Duration>) {
+        let cache = if cache_hit { "hit" } else { "miss" };
+        self.reserve_lookups.with_label_values(&[cache]).inc();
+        if let Some(latency) = latency {
+            self.reserve_lookup_seconds.observe(latency.as_secs_f64());
+        }
+    }
+
+    pub fn record_rpc_error(&self, endpoint: &str) {
+        self.rpc_errors.with_label_values(&[endpoint]).inc();
+    }
+
+    /// Count one evaluated victim and how it ended.
+    pub fn record_outcome(&self, outcome: &Result<SandWichState, Rejection>) {
+        self.opportunities.inc();
+        match outcome {
+            Ok(state) => {
+                let profit = ethers::utils::format_units(state.profit, "ether")
+                    .ok()
+                    .and_then(|eth| eth.parse::<f64>().ok())
+                    .unwrap_or_default();
+                self.estimated_profit_eth.observe(profit);
+            }
+            Err(rejection) => self
+                .rejections
+                .with_label_values(&[rejection.reason()])
+                .inc(),
+        }
+    }
+
+    /// Prometheus text exposition of every metric.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+async fn handle(metrics: Arc<Metrics>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(header::CONTENT_TYPE, TextEncoder::new().format_type())
+            .body(Body::from(metrics.render())),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+    Ok(response.unwrap())
+}
+
+/// Serve `/metrics` on `addr`, returns the bound address (`addr` may use
+/// port 0) and the server task.
+pub fn serve(
+    metrics: Arc<Metrics>,
+    addr: SocketAddr,
+) -> Result<(SocketAddr, JoinHandle<()>), hyper::Error> {
+    let make_service = make_service_fn(move |_conn| {
+        let metrics = Arc::clone(&metrics);
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(Arc::clone(&metrics), req))) }
+    });
+
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    let local_addr = server.local_addr();
+    let handle = tokio::spawn(async move {
+        if let Err(e) = server.await {
+            log::error!("metrics server stopped: {:?}", e);
+        }
+    });
+    Ok((local_addr, handle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    async fn scrape(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_scrape_metrics() {
+        let metrics = Arc::new(Metrics::new().unwrap());
+        metrics.pending_txs.inc_by(3);
+        metrics
+            .router_txs
+            .with_label_values(&["swapExactETHForTokens"])
+            .inc();
+        metrics.record_reserve_lookup(false, Some(Duration::from_millis(20)));
+        metrics.record_outcome(&Err(Rejection::NoBlockYet));
+        metrics.record_rpc_error("eth_getTransactionByHash");
+
+        let (addr, server) = serve(Arc::clone(&metrics), ([127, 0, 0, 1], 0).into()).unwrap();
+        let response = scrape(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.0 200"));
+        assert!(response.contains("unibot_pending_txs_total 3"));
+        assert!(response.contains("unibot_router_txs_total{function=\"swapExactETHForTokens\"} 1"));
+        assert!(response.contains("unibot_reserve_lookups_total{cache=\"miss\"} 1"));
+        assert!(response.contains("unibot_reserve_lookup_seconds_count 1"));
+        assert!(response.contains("unibot_opportunities_evaluated_total 1"));
+        assert!(response.contains("unibot_rejections_total{reason=\"no_block_yet\"} 1"));
+        assert!(
+            response.contains("unibot_rpc_errors_total{endpoint=\"eth_getTransactionByHash\"} 1")
+        );
+
+        assert!(scrape(addr, "/").await.starts_with("HTTP/1.0 404"));
+        server.abort();
+    }
+}
@@ -1,4 +1,29 @@
+pub mod amm;
+pub mod api;
+pub mod arbitrage;
+pub mod backtest;
 pub mod client;
 pub mod contract_abi;
+pub mod cycles;
+pub mod deadline;
 pub mod debug_print;
+pub mod detector;
+pub mod events;
+pub mod fees;
+pub mod inventory;
+pub mod metrics;
+pub mod multicall;
+pub mod nonce;
+pub mod pairs;
+pub mod pending;
+pub mod pools;
+pub mod pricing;
+pub mod rejection;
+pub mod screener;
+pub mod swaps;
+pub mod tokens;
+pub mod tui;
 pub mod univ2;
+pub mod univ3;
+pub mod universal_router;
+pub mod validator;
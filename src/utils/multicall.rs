@@ -0,0 +1,306 @@
+//! Read calls batched into Multicall3 `aggregate3`, one `eth_call` per
+//! `batch_size` calls instead of one each.
+
+use crate::constants::MULTICALL3_ADDRESS;
+use crate::env_store::{get_env_or, EnvError};
+use crate::utils::contract_abi::BalanceOfCall;
+use crate::utils::tokens::{self, TokenInfo};
+use crate::utils::univ2::{GetReservesCall, GetReservesReturn};
+
+use ethers::abi::{AbiDecode, AbiEncode};
+use ethers::prelude::*;
+use ethers::utils::id;
+use std::sync::Arc;
+
+abigen!(
+    IMulticall3,
+    r#"[
+        struct Call3 { address target; bool allowFailure; bytes callData; }
+        struct Call3Result { bool success; bytes returnData; }
+        function aggregate3(Call3[] calls) external payable returns (Call3Result[] returnData)
+    ]"#,
+);
+
+#[derive(Debug)]
+pub enum MulticallError {
+    ContractError(String),
+    LengthMismatch { calls: usize, results: usize },
+}
+
+#[derive(Debug, Clone)]
+pub struct MulticallConfig {
+    pub address: Address,
+    pub batch_size: usize,
+    // a reverting call comes back as None instead of failing its batch
+    pub allow_failure: bool,
+    // block the reads run at, the node's latest when None
+    pub block: Option<BlockNumber>,
+}
+
+impl Default for MulticallConfig {
+    fn default() -> Self {
+        Self {
+            address: MULTICALL3_ADDRESS.parse::<Address>().unwrap(),
+            batch_size: 100,
+            allow_failure: true,
+            block: None,
+        }
+    }
+}
+
+impl MulticallConfig {
+    pub fn from_env() -> Result<Self, EnvError> {
+        let default = Self::default();
+        let block = get_env_or("MULTICALL_BLOCK", String::new())?;
+        Ok(Self {
+            address: get_env_or("MULTICALL_ADDRESS", default.address)?,
+            batch_size: get_env_or("MULTICALL_BATCH_SIZE", default.batch_size)?.max(1),
+            allow_failure: get_env_or("MULTICALL_ALLOW_FAILURE", default.allow_failure)?,
+            block: match block.as_str() {
+                "" => None,
+                block => Some(
+                    block
+                        .parse::<BlockNumber>()
+                        .map_err(|e| format!("invalid value for MULTICALL_BLOCK: {}", e))?,
+                ),
+            },
+        })
+    }
+}
+
+/// A read call: calldata for `target`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadCall {
+    pub target: Address,
+    pub data: Bytes,
+}
+
+impl ReadCall {
+    pub fn new(target: Address, data: impl Into<Bytes>) -> Self {
+        Self {
+            target,
+            data: data.into(),
+        }
+    }
+}
+
+pub struct CallBatcher<M> {
+    multicall: IMulticall3<M>,
+    config: MulticallConfig,
+}
+
+impl<M: Middleware + 'static> CallBatcher<M> {
+    pub fn new(provider: Arc<M>, config: MulticallConfig) -> Self {
+        Self {
+            multicall: IMulticall3::new(config.address, provider),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &MulticallConfig {
+        &self.config
+    }
+
+    /// Return data of every call in order, None for the calls that reverted.
+    pub async fn aggregate(
+        &self,
+        calls: &[ReadCall],
+    ) -> Result<Vec<Option<Bytes>>, MulticallError> {
+        let mut results = Vec::with_capacity(calls.len());
+        for batch in calls.chunks(self.config.batch_size.max(1)) {
+            let call3 = batch
+                .iter()
+                .map(|call| Call3 {
+                    target: call.target,
+                    allow_failure: self.config.allow_failure,
+                    call_data: call.data.clone(),
+                })
+                .collect();
+            let mut aggregate = self.multicall.aggregate_3(call3);
+            if let Some(block) = self.config.block {
+                aggregate = aggregate.block(block);
+            }
+            let returned = aggregate
+                .call()
+                .await
+                .map_err(|e| MulticallError::ContractError(e.to_string()))?;
+            if returned.len() != batch.len() {
+                return Err(MulticallError::LengthMismatch {
+                    calls: batch.len(),
+                    results: returned.len(),
+                });
+            }
+            // (success, returnData) of each call
+            results.extend(
+                returned
+                    .into_iter()
+                    .map(|(success, data)| success.then_some(data)),
+            );
+        }
+        Ok(results)
+    }
+
+    /// `getReserves` of every pair, None for a pair that did not answer.
+    pub async fn get_reserves(
+        &self,
+        pairs: &[Address],
+    ) -> Result<Vec<Option<(U256, U256)>>, MulticallError> {
+        let calls: Vec<ReadCall> = pairs
+            .iter()
+            .map(|pair| ReadCall::new(*pair, GetReservesCall.encode()))
+            .collect();
+        Ok(self
+            .aggregate(&calls)
+            .await?
+            .into_iter()
+            .map(|data| {
+                let reserves = GetReservesReturn::decode(data?).ok()?;
+                Some((reserves.reserve_0.into(), reserves.reserve_1.into()))
+            })
+            .collect())
+    }
+
+    /// `balanceOf(owner)` of every token.
+    pub async fn balances(
+        &self,
+        owner: Address,
+        tokens: &[Address],
+    ) -> Result<Vec<Option<U256>>, MulticallError> {
+        let calls: Vec<ReadCall> = tokens
+            .iter()
+            .map(|token| ReadCall::new(*token, BalanceOfCall { account: owner }.encode()))
+            .collect();
+        Ok(self
+            .aggregate(&calls)
+            .await?
+            .into_iter()
+            .map(|data| U256::decode(data?).ok())
+            .collect())
+    }
+
+    /// ERC20 metadata of every token, None when `decimals` or `symbol` is
+    /// missing.
+    pub async fn token_metadata(
+        &self,
+        tokens: &[Address],
+    ) -> Result<Vec<Option<TokenInfo>>, MulticallError> {
+        let signatures = ["decimals()", "symbol()", "name()"];
+        let calls: Vec<ReadCall> = tokens
+            .iter()
+            .flat_map(|token| {
+                signatures
+                    .iter()
+                    .map(|signature| ReadCall::new(*token, id(signature).to_vec()))
+            })
+            .collect();
+        let results = self.aggregate(&calls).await?;
+        Ok(tokens
+            .iter()
+            .zip(results.chunks(signatures.len()))
+            .map(|(token, data)| {
+                let decimals = U256::decode(data[0].as_ref()?)
+                    .ok()
+                    .filter(|decimals| *decimals <= U256::from(u8::MAX))?;
+                let symbol = tokens::decode_text(data[1].as_ref()?)?;
+                let name = data[2]
+                    .as_ref()
+                    .and_then(|name| tokens::decode_text(name))
+                    .unwrap_or_default();
+                Some(TokenInfo {
+                    address: *token,
+                    symbol,
+                    name,
+                    decimals: decimals.as_u32() as u8,
+                })
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::abi::{self, Token};
+
+    fn returned(results: Vec<Option<Vec<u8>>>) -> Bytes {
+        let results = results
+            .into_iter()
+            .map(|data| {
+                Token::Tuple(vec![
+                    Token::Bool(data.is_some()),
+                    Token::Bytes(data.unwrap_or_default()),
+                ])
+            })
+            .collect();
+        abi::encode(&[Token::Array(results)]).into()
+    }
+
+    fn reserves(reserve0: u64, reserve1: u64) -> Vec<u8> {
+        abi::encode(&[
+            Token::Uint(reserve0.into()),
+            Token::Uint(reserve1.into()),
+            Token::Uint(1_700_000_000u64.into()),
+        ])
+    }
+
+    #[tokio::test]
+    async fn test_reserves_in_batches() {
+        let (provider, mock) = Provider::mocked();
+        let batcher = CallBatcher::new(
+            Arc::new(provider),
+            MulticallConfig {
+                batch_size: 2,
+                ..MulticallConfig::default()
+            },
+        );
+        // responses are served last pushed first
+        mock.push::<Bytes, Bytes>(returned(vec![Some(reserves(5, 6))]))
+            .unwrap();
+        mock.push::<Bytes, Bytes>(returned(vec![Some(reserves(1, 2)), None]))
+            .unwrap();
+
+        let pairs: Vec<Address> = (1..=3).map(Address::from_low_u64_be).collect();
+        let result = batcher.get_reserves(&pairs).await.unwrap();
+        assert_eq!(
+            vec![
+                Some((U256::from(1), U256::from(2))),
+                None,
+                Some((U256::from(5), U256::from(6))),
+            ],
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn test_token_metadata() {
+        let (provider, mock) = Provider::mocked();
+        let batcher = CallBatcher::new(Arc::new(provider), MulticallConfig::default());
+        let mut mkr = [0u8; 32];
+        mkr[..3].copy_from_slice(b"MKR");
+        mock.push::<Bytes, Bytes>(returned(vec![
+            Some(U256::from(18).encode()),
+            Some(mkr.to_vec()),
+            None,
+            // no `decimals`, not an ERC20
+            None,
+            Some("X".to_string().encode()),
+            None,
+        ]))
+        .unwrap();
+
+        let tokens = [Address::repeat_byte(1), Address::repeat_byte(2)];
+        let result = batcher.token_metadata(&tokens).await.unwrap();
+        assert_eq!(
+            vec![
+                Some(TokenInfo {
+                    address: tokens[0],
+                    symbol: "MKR".to_string(),
+                    name: String::new(),
+                    decimals: 18,
+                }),
+                None,
+            ],
+            result
+        );
+    }
+}
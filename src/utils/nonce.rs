@@ -0,0 +1,378 @@
+use ethers::prelude::*;
+use ethers::providers::{JsonRpcClient, ProviderError};
+use log::{debug, warn};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug)]
+pub enum NonceError {
+    EmptyPool,
+    ProviderError(ProviderError),
+    UnknownAccount(Address),
+}
+
+/// A nonce handed out to a strategy task. It stays in flight until it is
+/// either confirmed on chain, released unused or dropped by `reconcile`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NonceLease {
+    pub account: Address,
+    pub nonce: U256,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct InFlight {
+    tx_hash: Option<TxHash>,
+    // last block the tx can be included in, bundles are only valid for their target block
+    valid_until: Option<U64>,
+}
+
+#[derive(Debug, Default)]
+struct AccountState {
+    synced: bool,
+    next: U256,
+    in_flight: BTreeMap<U256, InFlight>,
+}
+
+struct Account {
+    address: Address,
+    state: Mutex<AccountState>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ReconcileReport {
+    pub account: Address,
+    pub confirmed: Vec<U256>,
+    // nonces we handed out that can no longer land: expired bundles and
+    // everything queued behind them
+    pub dropped: Vec<(U256, Option<TxHash>)>,
+    pub next: U256,
+}
+
+/// Hands out nonces for a pool of signing accounts. Every account keeps its
+/// own counter, so independent opportunities can be signed by different
+/// accounts without waiting on each other.
+pub struct NonceManager<P: JsonRpcClient> {
+    provider: Arc<Provider<P>>,
+    accounts: Vec<Account>,
+    cursor: AtomicUsize,
+}
+
+impl<P: JsonRpcClient> NonceManager<P> {
+    pub fn new(provider: Arc<Provider<P>>, accounts: Vec<Address>) -> Self {
+        Self {
+            provider,
+            accounts: accounts
+                .into_iter()
+                .map(|address| Account {
+                    address,
+                    state: Mutex::new(AccountState::default()),
+                })
+                .collect(),
+            cursor: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn accounts(&self) -> Vec<Address> {
+        self.accounts.iter().map(|a| a.address).collect()
+    }
+
+    fn account(&self, address: Address) -> Result<&Account, NonceError> {
+        self.accounts
+            .iter()
+            .find(|a| a.address == address)
+            .ok_or(NonceError::UnknownAccount(address))
+    }
+
+    async fn fetch_count(&self, address: Address, block: BlockNumber) -> Result<U256, NonceError> {
+        self.provider
+            .get_transaction_count(address, Some(block.into()))
+            .await
+            .map_err(NonceError::ProviderError)
+    }
+
+    /// Pull the pending transaction count of every account from the node.
+    /// Local counters only move forward here, nonces already handed out are
+    /// never reissued by a sync.
+    pub async fn sync(&self) -> Result<(), NonceError> {
+        for account in self.accounts.iter() {
+            let pending = self
+                .fetch_count(account.address, BlockNumber::Pending)
+                .await?;
+
+            let mut state = account.state.lock().unwrap();
+            if !state.synced || pending > state.next {
+                debug!(
+                    "nonce sync {:?}: {} -> {}",
+                    account.address, state.next, pending
+                );
+                state.next = pending;
+            }
+            state.synced = true;
+        }
+        Ok(())
+    }
+
+    /// Take the next nonce of the least busy account in the pool.
+    pub async fn acquire(&self) -> Result<NonceLease, NonceError> {
+        if self.accounts.is_empty() {
+            return Err(NonceError::EmptyPool);
+        }
+
+        // round robin start so ties do not always land on the first account
+        let start = self.cursor.fetch_add(1, Ordering::Relaxed) % self.accounts.len();
+        let address = (0..self.accounts.len())
+            .map(|i| &self.accounts[(start + i) % self.accounts.len()])
+            .min_by_key(|a| a.state.lock().unwrap().in_flight.len())
+            .unwrap()
+            .address;
+
+        self.acquire_for(address).await
+    }
+
+    /// Take the next nonce of a specific account.
+    pub async fn acquire_for(&self, address: Address) -> Result<NonceLease, NonceError> {
+        let account = self.account(address)?;
+
+        let synced = account.state.lock().unwrap().synced;
+        if !synced {
+            let pending = self.fetch_count(address, BlockNumber::Pending).await?;
+            let mut state = account.state.lock().unwrap();
+            if !state.synced {
+                state.next = pending;
+                state.synced = true;
+            }
+        }
+
+        let mut state = account.state.lock().unwrap();
+        let nonce = state.next;
+        state.next = nonce + 1;
+        state.in_flight.insert(
+            nonce,
+            InFlight {
+                tx_hash: None,
+                valid_until: None,
+            },
+        );
+
+        Ok(NonceLease {
+            account: address,
+            nonce,
+        })
+    }
+
+    /// Record the transaction signed with `lease`. `valid_until` is the last
+    /// block it can be mined in, `None` for transactions sent to the public
+    /// mempool.
+    pub fn mark_submitted(
+        &self,
+        lease: &NonceLease,
+        tx_hash: TxHash,
+        valid_until: Option<U64>,
+    ) -> Result<(), NonceError> {
+        let account = self.account(lease.account)?;
+        let mut state = account.state.lock().unwrap();
+        state.in_flight.insert(
+            lease.nonce,
+            InFlight {
+                tx_hash: Some(tx_hash),
+                valid_until,
+            },
+        );
+        Ok(())
+    }
+
+    /// Give back a nonce that was never used. Only the most recent nonce can
+    /// be rewound, anything older leaves a gap that `reconcile` closes.
+    pub fn release(&self, lease: &NonceLease) -> Result<(), NonceError> {
+        let account = self.account(lease.account)?;
+        let mut state = account.state.lock().unwrap();
+        state.in_flight.remove(&lease.nonce);
+        if state.next == lease.nonce + 1 {
+            state.next = lease.nonce;
+        }
+        Ok(())
+    }
+
+    /// Bring the local view of every account in line with the chain once
+    /// `block` is the latest block. Handles inclusions, bundles that missed
+    /// their target block and reorgs that un-mine our transactions.
+    pub async fn reconcile(&self, block: U64) -> Result<Vec<ReconcileReport>, NonceError> {
+        let mut reports = vec![];
+        for account in self.accounts.iter() {
+            let mined = self
+                .fetch_count(account.address, BlockNumber::Latest)
+                .await?;
+            let pending = self
+                .fetch_count(account.address, BlockNumber::Pending)
+                .await?;
+            let mut state = account.state.lock().unwrap();
+            reports.push(reconcile_state(
+                account.address,
+                &mut state,
+                mined,
+                pending,
+                block,
+            ));
+        }
+        Ok(reports)
+    }
+}
+
+// `pending` counts the account's transactions in the node's mempool too,
+// including the ones sent outside of this manager
+fn reconcile_state(
+    address: Address,
+    state: &mut AccountState,
+    mined: U256,
+    pending: U256,
+    block: U64,
+) -> ReconcileReport {
+    let mut report = ReconcileReport {
+        account: address,
+        ..Default::default()
+    };
+
+    let unmined = state.in_flight.split_off(&mined);
+    report.confirmed = state.in_flight.keys().cloned().collect();
+    state.in_flight = unmined;
+
+    // walk the remaining nonces in order, the first one that can no longer be
+    // included invalidates everything queued behind it
+    let mut next = mined;
+    let mut broken = false;
+    for (nonce, tx) in std::mem::take(&mut state.in_flight) {
+        let expired = matches!(tx.valid_until, Some(last) if last <= block);
+        if broken || expired || nonce != next {
+            broken = true;
+            report.dropped.push((nonce, tx.tx_hash));
+        } else {
+            state.in_flight.insert(nonce, tx);
+            next = nonce + 1;
+        }
+    }
+
+    if !report.dropped.is_empty() {
+        warn!(
+            "nonce reconcile {:?}: dropped {} in-flight nonces, rewinding {} -> {}",
+            address,
+            report.dropped.len(),
+            state.next,
+            next
+        );
+    }
+
+    // a reorg can lower `mined` below what we already confirmed, rewinding to
+    // `next` lets the un-mined nonces be reused, but never onto a nonce still
+    // waiting in the mempool
+    let next = next.max(pending);
+    state.next = next;
+    state.synced = true;
+    report.next = next;
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager(accounts: Vec<Address>) -> (NonceManager<MockProvider>, MockProvider) {
+        let (provider, mock) = Provider::mocked();
+        (NonceManager::new(Arc::new(provider), accounts), mock)
+    }
+
+    #[tokio::test]
+    async fn test_acquire_is_sequential() {
+        let account = Address::repeat_byte(1);
+        let (manager, mock) = manager(vec![account]);
+        mock.push(U256::from(7)).unwrap();
+
+        let a = manager.acquire().await.unwrap();
+        let b = manager.acquire().await.unwrap();
+        assert_eq!(U256::from(7), a.nonce);
+        assert_eq!(U256::from(8), b.nonce);
+
+        manager.release(&b).unwrap();
+        assert_eq!(U256::from(8), manager.acquire().await.unwrap().nonce);
+    }
+
+    #[tokio::test]
+    async fn test_acquire_spreads_over_pool() {
+        let (first, second) = (Address::repeat_byte(1), Address::repeat_byte(2));
+        let (manager, mock) = manager(vec![first, second]);
+        mock.push(U256::from(3)).unwrap();
+        mock.push(U256::from(5)).unwrap();
+        manager.sync().await.unwrap();
+
+        let a = manager.acquire().await.unwrap();
+        let b = manager.acquire().await.unwrap();
+        assert_ne!(a.account, b.account);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_drops_expired_bundle() {
+        let account = Address::repeat_byte(1);
+        let (manager, mock) = manager(vec![account]);
+        mock.push(U256::from(10)).unwrap();
+
+        let a = manager.acquire().await.unwrap();
+        let b = manager.acquire().await.unwrap();
+        let c = manager.acquire().await.unwrap();
+        manager
+            .mark_submitted(&a, TxHash::repeat_byte(1), None)
+            .unwrap();
+        manager
+            .mark_submitted(&b, TxHash::repeat_byte(2), Some(U64::from(100)))
+            .unwrap();
+        manager
+            .mark_submitted(&c, TxHash::repeat_byte(3), None)
+            .unwrap();
+
+        // `a` got mined, `b` missed its block so `c` can not land either
+        mock.push(U256::from(11)).unwrap();
+        mock.push(U256::from(11)).unwrap();
+        let report = manager.reconcile(U64::from(100)).await.unwrap().remove(0);
+
+        assert_eq!(vec![U256::from(10)], report.confirmed);
+        assert_eq!(2, report.dropped.len());
+        assert_eq!(U256::from(11), report.next);
+        assert_eq!(U256::from(11), manager.acquire().await.unwrap().nonce);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_rewinds_on_reorg() {
+        let account = Address::repeat_byte(1);
+        let mut state = AccountState {
+            synced: true,
+            next: U256::from(12),
+            in_flight: BTreeMap::new(),
+        };
+
+        let report = reconcile_state(
+            account,
+            &mut state,
+            U256::from(10),
+            U256::from(10),
+            U64::from(5),
+        );
+        assert_eq!(U256::from(10), report.next);
+        assert_eq!(U256::from(10), state.next);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_keeps_untracked_pending() {
+        let account = Address::repeat_byte(1);
+        let (manager, mock) = manager(vec![account]);
+        // three transactions sent before the manager started are pending
+        mock.push(U256::from(13)).unwrap();
+        manager.sync().await.unwrap();
+
+        // responses are served last pushed first: latest, then pending
+        mock.push(U256::from(13)).unwrap();
+        mock.push(U256::from(10)).unwrap();
+        let report = manager.reconcile(U64::from(5)).await.unwrap().remove(0);
+        assert!(report.dropped.is_empty());
+        assert_eq!(U256::from(13), report.next);
+        assert_eq!(U256::from(13), manager.acquire().await.unwrap().nonce);
+    }
+}
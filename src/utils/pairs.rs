@@ -0,0 +1,200 @@
+//! Whether the pair a CREATE2 address points at was ever created.
+//!
+//! The pool index answers for free, then the factory's `getPair`, then the
+//! code at the derived address when `getPair` fails. Missing pairs are
+//! remembered for `missing_ttl`, a pair can still be created later.
+
+use crate::utils::contract_abi::IUniswapV2Factory;
+use crate::utils::pools::PoolIndex;
+use crate::utils::univ2::{self, Dex};
+
+use ethers::prelude::*;
+use ethers::providers::{JsonRpcClient, ProviderError};
+use log::debug;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub enum PairError {
+    ContractError(String),
+    ProviderError(ProviderError),
+}
+
+/// Where a pair was confirmed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairSource {
+    Index,
+    Factory,
+    Code,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairLookup {
+    Exists { pair: Address, source: PairSource },
+    // `pair` is the derived address, nothing is deployed there
+    Missing { pair: Address },
+}
+
+impl PairLookup {
+    /// Address of the pair, if it exists.
+    pub fn pair(&self) -> Option<Address> {
+        match self {
+            PairLookup::Exists { pair, .. } => Some(*pair),
+            PairLookup::Missing { .. } => None,
+        }
+    }
+}
+
+pub struct PairValidator<P: JsonRpcClient> {
+    provider: Arc<Provider<P>>,
+    index: Arc<PoolIndex>,
+    missing_ttl: Duration,
+    // a pair is never destroyed, confirmed pairs stay cached
+    confirmed: Mutex<HashMap<Address, PairSource>>,
+    missing: Mutex<HashMap<Address, Instant>>,
+}
+
+impl<P: JsonRpcClient + 'static> PairValidator<P> {
+    pub fn new(provider: Arc<Provider<P>>, index: Arc<PoolIndex>, missing_ttl: Duration) -> Self {
+        Self {
+            provider,
+            index,
+            missing_ttl,
+            confirmed: Mutex::new(HashMap::new()),
+            missing: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Cached answer for the pair of `token_a` and `token_b` on `dex`.
+    pub fn cached(&self, dex: &Dex, token_a: Address, token_b: Address) -> Option<PairLookup> {
+        if let Some(record) = self.index.find(dex.factory, token_a, token_b) {
+            return Some(PairLookup::Exists {
+                pair: record.pair,
+                source: PairSource::Index,
+            });
+        }
+        let pair = dex.pair_address(token_a, token_b);
+        if let Some(source) = self.confirmed.lock().unwrap().get(&pair) {
+            return Some(PairLookup::Exists {
+                pair,
+                source: *source,
+            });
+        }
+        match self.missing.lock().unwrap().get(&pair) {
+            Some(at) if at.elapsed() < self.missing_ttl => Some(PairLookup::Missing { pair }),
+            _ => None,
+        }
+    }
+
+    pub async fn lookup(
+        &self,
+        dex: &Dex,
+        token_a: Address,
+        token_b: Address,
+    ) -> Result<PairLookup, PairError> {
+        if let Some(lookup) = self.cached(dex, token_a, token_b) {
+            return Ok(lookup);
+        }
+
+        let pair = dex.pair_address(token_a, token_b);
+        let (token0, token1) = univ2::sort_token(token_a, token_b);
+        let factory = IUniswapV2Factory::new(dex.factory, Arc::clone(&self.provider));
+        let exists = match factory.get_pair(token0, token1).call().await {
+            Ok(created) => {
+                if !created.is_zero() && created != pair {
+                    // the init code hash of `dex` is wrong, trust neither
+                    return Err(PairError::ContractError(format!(
+                        "getPair returned {:?}, derived {:?}",
+                        created, pair
+                    )));
+                }
+                (!created.is_zero()).then_some(PairSource::Factory)
+            }
+            Err(e) => {
+                debug!("getPair on {:?} failed: {:?}", dex.factory, e);
+                let code = self
+                    .provider
+                    .get_code(pair, None)
+                    .await
+                    .map_err(PairError::ProviderError)?;
+                (!code.is_empty()).then_some(PairSource::Code)
+            }
+        };
+
+        Ok(match exists {
+            Some(source) => {
+                self.confirmed.lock().unwrap().insert(pair, source);
+                PairLookup::Exists { pair, source }
+            }
+            None => {
+                self.missing.lock().unwrap().insert(pair, Instant::now());
+                PairLookup::Missing { pair }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::pools::PairRecord;
+    use ethers::abi::{self, Token};
+
+    fn tokens() -> (Address, Address) {
+        (Address::repeat_byte(1), Address::repeat_byte(2))
+    }
+
+    #[tokio::test]
+    async fn test_missing_pair_is_cached() {
+        let (provider, mock) = Provider::mocked();
+        let validator = PairValidator::new(
+            Arc::new(provider),
+            Arc::new(PoolIndex::in_memory(1)),
+            Duration::from_secs(60),
+        );
+        let dex = Dex::uniswap_v2();
+        let (token_a, token_b) = tokens();
+        mock.push::<Bytes, Bytes>(abi::encode(&[Token::Address(Address::zero())]).into())
+            .unwrap();
+
+        let pair = dex.pair_address(token_a, token_b);
+        let expected = PairLookup::Missing { pair };
+        assert_eq!(
+            expected,
+            validator.lookup(&dex, token_a, token_b).await.unwrap()
+        );
+        // no response left, a second call to the node would fail
+        assert_eq!(
+            expected,
+            validator.lookup(&dex, token_b, token_a).await.unwrap()
+        );
+        assert_eq!(None, expected.pair());
+    }
+
+    #[tokio::test]
+    async fn test_indexed_pair_needs_no_call() {
+        let (provider, _mock) = Provider::mocked();
+        let index = Arc::new(PoolIndex::in_memory(1));
+        let dex = Dex::sushiswap();
+        let (token0, token1) = tokens();
+        let pair = dex.pair_address(token0, token1);
+        index.insert(PairRecord {
+            factory: dex.factory,
+            index: 0,
+            pair,
+            token0,
+            token1,
+            block: 10_794_229,
+        });
+        let validator = PairValidator::new(Arc::new(provider), index, Duration::from_secs(60));
+
+        assert_eq!(
+            PairLookup::Exists {
+                pair,
+                source: PairSource::Index
+            },
+            validator.lookup(&dex, token1, token0).await.unwrap()
+        );
+    }
+}
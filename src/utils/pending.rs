@@ -0,0 +1,379 @@
+//! Reserves a pair is expected to have once the pending swaps ahead of a
+//! victim are mined.
+//!
+//! Pending swaps are kept per pair in the order a builder would include
+//! them: highest priority fee first, but never ahead of an earlier nonce of
+//! the same sender. Mined swaps leave on the next block, replaced ones when
+//! the replacement is seen and the rest after `max_age_blocks`.
+//!
+//! A transaction may swap on several pairs, each swap is tracked under its
+//! hash and they come and go together.
+
+use crate::utils::swaps::SwapAmount;
+use crate::utils::univ2;
+
+use ethers::prelude::*;
+use std::collections::{HashMap, VecDeque};
+use std::sync::RwLock;
+
+/// A swap on one pair, waiting in the mempool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PendingSwap {
+    pub hash: TxHash,
+    pub sender: Address,
+    pub nonce: U256,
+    // tip per gas at the base fee predicted when the swap was seen
+    pub priority_fee: U256,
+    pub pair: Address,
+    pub token_in: Address,
+    // bounds only for a single hop, the router checks the ones of the ends
+    pub amount: SwapAmount,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    swap: PendingSwap,
+    seen_block: U64,
+    // first seen first on equal fees
+    seq: u64,
+}
+
+#[derive(Debug, Default)]
+struct OverlayState {
+    // the swaps of a transaction in the order it makes them
+    entries: HashMap<TxHash, Vec<Entry>>,
+    by_sender_nonce: HashMap<(Address, U256), TxHash>,
+    head: U64,
+    next_seq: u64,
+}
+
+impl OverlayState {
+    fn remove(&mut self, hash: TxHash) -> Vec<PendingSwap> {
+        let Some(entries) = self.entries.remove(&hash) else {
+            return Vec::new();
+        };
+        if let Some(entry) = entries.first() {
+            self.by_sender_nonce
+                .remove(&(entry.swap.sender, entry.swap.nonce));
+        }
+        entries.into_iter().map(|entry| entry.swap).collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct PendingOverlay {
+    state: RwLock<OverlayState>,
+    max_age_blocks: u64,
+}
+
+impl PendingOverlay {
+    pub fn new(max_age_blocks: u64) -> Self {
+        Self {
+            state: RwLock::new(OverlayState::default()),
+            max_age_blocks,
+        }
+    }
+
+    /// Track the swaps of one transaction, in the order it makes them, in
+    /// place of the ones tracked for it before. Returns the swaps of the
+    /// transaction it replaced: same sender and nonce.
+    pub fn insert(&self, swaps: &[PendingSwap]) -> Vec<PendingSwap> {
+        let Some(first) = swaps.first() else {
+            return Vec::new();
+        };
+        let mut state = self.state.write().unwrap();
+        state.remove(first.hash);
+        let replaced = match state.by_sender_nonce.get(&(first.sender, first.nonce)) {
+            Some(hash) => {
+                let hash = *hash;
+                state.remove(hash)
+            }
+            None => Vec::new(),
+        };
+        let mut entries = Vec::with_capacity(swaps.len());
+        for swap in swaps {
+            entries.push(Entry {
+                swap: *swap,
+                seen_block: state.head,
+                seq: state.next_seq,
+            });
+            state.next_seq += 1;
+        }
+        state.entries.insert(first.hash, entries);
+        state
+            .by_sender_nonce
+            .insert((first.sender, first.nonce), first.hash);
+        replaced
+    }
+
+    /// Forget the swaps of a transaction, e.g. once it can no longer be
+    /// mined.
+    pub fn remove(&self, hash: TxHash) -> Vec<PendingSwap> {
+        self.state.write().unwrap().remove(hash)
+    }
+
+    /// Swaps tracked, over all transactions.
+    pub fn len(&self) -> usize {
+        self.state
+            .read()
+            .unwrap()
+            .entries
+            .values()
+            .map(Vec::len)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// New head `block` with its transactions: mined swaps and the ones
+    /// pending for more than `max_age_blocks` are dropped. Returns how many
+    /// swaps left the overlay.
+    pub fn on_block(&self, block: U64, mined: &[TxHash]) -> usize {
+        let mut state = self.state.write().unwrap();
+        state.head = block;
+        let mut removed: usize = mined.iter().map(|hash| state.remove(*hash).len()).sum();
+        // swaps seen before the first block count from it
+        for entry in state.entries.values_mut().flatten() {
+            if entry.seen_block.is_zero() {
+                entry.seen_block = block;
+            }
+        }
+        let expired: Vec<TxHash> = state
+            .entries
+            .iter()
+            .filter(|(_, entries)| {
+                entries
+                    .iter()
+                    .all(|entry| block.as_u64() > entry.seen_block.as_u64() + self.max_age_blocks)
+            })
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            removed += state.remove(hash).len();
+        }
+        removed
+    }
+
+    /// Pending swaps on `pair` in expected inclusion order.
+    pub fn ordered(&self, pair: Address) -> Vec<PendingSwap> {
+        let state = self.state.read().unwrap();
+        let mut by_sender: HashMap<Address, Vec<Entry>> = HashMap::new();
+        for entry in state
+            .entries
+            .values()
+            .flatten()
+            .filter(|e| e.swap.pair == pair)
+        {
+            by_sender.entry(entry.swap.sender).or_default().push(*entry);
+        }
+        let mut queues: Vec<VecDeque<Entry>> = by_sender
+            .into_values()
+            .map(|mut entries| {
+                // the swaps of one transaction keep their order
+                entries.sort_by_key(|entry| (entry.swap.nonce, entry.seq));
+                entries.into()
+            })
+            .collect();
+
+        // the best paying head of a sender queue goes next
+        let mut ordered = Vec::new();
+        while let Some(next) = queues
+            .iter_mut()
+            .filter(|queue| !queue.is_empty())
+            .max_by(|a, b| {
+                let (a, b) = (a[0], b[0]);
+                a.swap
+                    .priority_fee
+                    .cmp(&b.swap.priority_fee)
+                    .then(b.seq.cmp(&a.seq))
+            })
+        {
+            ordered.push(next.pop_front().unwrap().swap);
+        }
+        ordered
+    }
+
+    /// Reserves of `pair`, `(reserve_a, reserve_b)` with `token_a` first,
+    /// after the pending swaps included ahead of `before`. All of them when
+    /// `before` is not tracked.
+    pub fn project(
+        &self,
+        pair: Address,
+        token_a: Address,
+        reserve_a: U256,
+        reserve_b: U256,
+        before: TxHash,
+    ) -> (U256, U256) {
+        let (mut reserve_a, mut reserve_b) = (reserve_a, reserve_b);
+        for swap in self
+            .ordered(pair)
+            .iter()
+            .take_while(|swap| swap.hash != before)
+        {
+            let a_in = swap.token_in == token_a;
+            let (reserve_in, reserve_out) = if a_in {
+                (reserve_a, reserve_b)
+            } else {
+                (reserve_b, reserve_a)
+            };
+            // past its bound the swap reverts and leaves the pair alone
+            let outcome = match swap.amount {
+                SwapAmount::ExactIn {
+                    amount_in,
+                    amount_out_min,
+                } => univ2::get_univ2_data_given_in(amount_in, reserve_in, reserve_out)
+                    .ok()
+                    .filter(|outcome| outcome.amount_out >= amount_out_min),
+                SwapAmount::ExactOut {
+                    amount_out,
+                    amount_in_max,
+                } => univ2::get_univ2_data_given_out(amount_out, reserve_in, reserve_out)
+                    .ok()
+                    .filter(|outcome| outcome.amount_in <= amount_in_max),
+            };
+            let Some(outcome) = outcome else {
+                continue;
+            };
+            (reserve_a, reserve_b) = if a_in {
+                (outcome.new_reserve_in, outcome.new_reserve_out)
+            } else {
+                (outcome.new_reserve_out, outcome.new_reserve_in)
+            };
+        }
+        (reserve_a, reserve_b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exact_in(amount_in: U256, amount_out_min: U256) -> SwapAmount {
+        SwapAmount::ExactIn {
+            amount_in,
+            amount_out_min,
+        }
+    }
+
+    fn swap(hash: u64, sender: u64, nonce: u64, priority_fee: u64) -> PendingSwap {
+        PendingSwap {
+            hash: TxHash::from_low_u64_be(hash),
+            sender: Address::from_low_u64_be(sender),
+            nonce: nonce.into(),
+            priority_fee: priority_fee.into(),
+            pair: Address::repeat_byte(0xaa),
+            token_in: Address::repeat_byte(1),
+            amount: exact_in(U256::exp10(18), U256::zero()),
+        }
+    }
+
+    fn hashes(swaps: &[PendingSwap]) -> Vec<u64> {
+        swaps.iter().map(|s| s.hash.to_low_u64_be()).collect()
+    }
+
+    #[test]
+    fn test_inclusion_order() {
+        let overlay = PendingOverlay::new(3);
+        let pair = Address::repeat_byte(0xaa);
+        // nonce 1 pays most but waits for nonce 0 of the same sender
+        overlay.insert(&[swap(1, 10, 1, 50)]);
+        overlay.insert(&[swap(2, 10, 0, 1)]);
+        overlay.insert(&[swap(3, 20, 7, 3)]);
+        overlay.insert(&[swap(4, 30, 0, 3)]);
+        assert_eq!(vec![3, 4, 2, 1], hashes(&overlay.ordered(pair)));
+
+        // a fee bump of nonce 0 replaces it and pulls both ahead
+        let replaced = overlay.insert(&[swap(5, 10, 0, 10)]);
+        assert_eq!(vec![swap(2, 10, 0, 1)], replaced);
+        assert_eq!(vec![5, 1, 3, 4], hashes(&overlay.ordered(pair)));
+    }
+
+    #[test]
+    fn test_project_and_expire() {
+        let overlay = PendingOverlay::new(2);
+        let pair = Address::repeat_byte(0xaa);
+        let (weth, token) = (Address::repeat_byte(1), Address::repeat_byte(2));
+        let (reserve_weth, reserve_token) = (U256::exp10(20), U256::exp10(24));
+
+        let first = swap(1, 10, 0, 5);
+        // would revert, its bound is more than the pair pays
+        let reverting = PendingSwap {
+            amount: exact_in(U256::exp10(18), U256::exp10(24)),
+            ..swap(2, 20, 0, 4)
+        };
+        let victim = swap(3, 30, 0, 1);
+        overlay.insert(&[first]);
+        overlay.insert(&[reverting]);
+        overlay.insert(&[victim]);
+
+        let outcome =
+            univ2::get_univ2_data_given_in(U256::exp10(18), reserve_weth, reserve_token).unwrap();
+        let projected = overlay.project(pair, weth, reserve_weth, reserve_token, victim.hash);
+        assert_eq!((outcome.new_reserve_in, outcome.new_reserve_out), projected);
+        // oriented the other way round
+        assert_eq!(
+            (outcome.new_reserve_out, outcome.new_reserve_in),
+            overlay.project(pair, token, reserve_token, reserve_weth, victim.hash)
+        );
+
+        assert_eq!(1, overlay.on_block(U64::from(1), &[first.hash]));
+        assert_eq!(
+            (reserve_weth, reserve_token),
+            overlay.project(pair, weth, reserve_weth, reserve_token, victim.hash)
+        );
+        // seen before the first block, they count from block 1
+        assert_eq!(0, overlay.on_block(U64::from(3), &[]));
+        assert_eq!(2, overlay.on_block(U64::from(4), &[]));
+        assert!(overlay.is_empty());
+    }
+
+    #[test]
+    fn test_exact_out_and_swaps_of_one_tx() {
+        let overlay = PendingOverlay::new(2);
+        let (pair, other_pair) = (Address::repeat_byte(0xaa), Address::repeat_byte(0xbb));
+        let weth = Address::repeat_byte(1);
+        let (reserve_weth, reserve_token) = (U256::exp10(20), U256::exp10(24));
+
+        let amount_out = U256::exp10(21);
+        let buy = PendingSwap {
+            amount: SwapAmount::ExactOut {
+                amount_out,
+                amount_in_max: U256::exp10(18) * 2,
+            },
+            ..swap(1, 10, 0, 5)
+        };
+        let elsewhere = PendingSwap {
+            pair: other_pair,
+            ..buy
+        };
+        overlay.insert(&[buy, elsewhere]);
+        assert_eq!(2, overlay.len());
+        assert_eq!(vec![buy], overlay.ordered(pair));
+        assert_eq!(vec![elsewhere], overlay.ordered(other_pair));
+
+        let outcome =
+            univ2::get_univ2_data_given_out(amount_out, reserve_weth, reserve_token).unwrap();
+        assert_eq!(
+            (outcome.new_reserve_in, outcome.new_reserve_out),
+            overlay.project(pair, weth, reserve_weth, reserve_token, TxHash::zero())
+        );
+        // the bound is below what the pair asks for, the swap reverts
+        overlay.insert(&[PendingSwap {
+            amount: SwapAmount::ExactOut {
+                amount_out,
+                amount_in_max: U256::exp10(15),
+            },
+            ..buy
+        }]);
+        assert_eq!(1, overlay.len());
+        assert_eq!(
+            (reserve_weth, reserve_token),
+            overlay.project(pair, weth, reserve_weth, reserve_token, TxHash::zero())
+        );
+
+        assert_eq!(1, overlay.remove(buy.hash).len());
+        assert!(overlay.is_empty());
+    }
+}
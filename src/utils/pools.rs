@@ -0,0 +1,569 @@
+//! Index of the pairs the factories created, walked from `allPairs` once and
+//! kept current from `PairCreated` logs.
+//!
+//! Every new pair is appended to a JSON lines file, so a restart resumes from
+//! the first pair it has not seen.
+
+use crate::env_store::{get_env_or, EnvError};
+use crate::utils::contract_abi::{AllPairsCall, IUniswapV2Factory, PairCreatedFilter};
+use crate::utils::multicall::{CallBatcher, ReadCall};
+use crate::utils::univ2::{self, Token0Call, Token1Call};
+
+use ethers::abi::{AbiDecode, AbiEncode};
+use ethers::contract::EthEvent;
+use ethers::prelude::*;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+#[derive(Debug)]
+pub enum PoolIndexError {
+    EnvError(EnvError),
+    IoError(PathBuf, std::io::Error),
+    JsonError(PathBuf, serde_json::Error),
+    CallError(String),
+    // no `PairCreated` log for a pair `allPairs` returned
+    MissingLog(Address),
+}
+
+/// A pair as its factory created it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PairRecord {
+    pub factory: Address,
+    // position in the factory's `allPairs`
+    pub index: u64,
+    pub pair: Address,
+    pub token0: Address,
+    pub token1: Address,
+    // block of the `PairCreated` log
+    pub block: u64,
+}
+
+#[derive(Debug, Default)]
+struct IndexState {
+    pairs: HashMap<Address, PairRecord>,
+    // (factory, token0, token1) -> pair
+    by_tokens: HashMap<(Address, Address, Address), Address>,
+    by_index: HashMap<(Address, u64), Address>,
+    // every index below is in `pairs`
+    walked: HashMap<Address, u64>,
+    // inserted since the last `save`
+    unsaved: Vec<PairRecord>,
+}
+
+impl IndexState {
+    fn insert(&mut self, record: PairRecord) -> bool {
+        if self.pairs.insert(record.pair, record).is_some() {
+            return false;
+        }
+        self.by_tokens
+            .insert((record.factory, record.token0, record.token1), record.pair);
+        self.by_index
+            .insert((record.factory, record.index), record.pair);
+        let walked = self.walked.entry(record.factory).or_default();
+        while self.by_index.contains_key(&(record.factory, *walked)) {
+            *walked += 1;
+        }
+        true
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PoolIndex {
+    state: RwLock<IndexState>,
+    // where the index is kept between runs
+    path: Option<PathBuf>,
+    // opened on the first `save`, the walk and the `PairCreated`
+    // subscription append to it in turn
+    file: Mutex<Option<File>>,
+    // `allPairs` entries fetched per batch by `sync`
+    batch_size: usize,
+    // blocks per `PairCreated` query of `sync`
+    log_range: u64,
+}
+
+impl PoolIndex {
+    /// Empty index, nothing is persisted.
+    pub fn in_memory(batch_size: usize) -> Self {
+        Self {
+            batch_size: batch_size.max(1),
+            log_range: 10_000,
+            ..Self::default()
+        }
+    }
+
+    /// Pairs saved at `path`, new pairs are appended to it.
+    pub fn load(path: &Path, batch_size: usize) -> Result<Self, PoolIndexError> {
+        let mut index = Self::in_memory(batch_size);
+        if path.exists() {
+            let content = fs::read_to_string(path)
+                .map_err(|e| PoolIndexError::IoError(path.to_path_buf(), e))?;
+            let state = index.state.get_mut().unwrap();
+            let lines: Vec<&str> = content.lines().collect();
+            for (i, line) in lines.iter().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str(line) {
+                    Ok(record) => {
+                        state.insert(record);
+                    }
+                    // a save cut short leaves half a line at the end
+                    Err(e) if i + 1 == lines.len() && !content.ends_with('\n') => {
+                        warn!("dropping the truncated last line of {:?}: {}", path, e);
+                    }
+                    Err(e) => return Err(PoolIndexError::JsonError(path.to_path_buf(), e)),
+                }
+            }
+        }
+        index.path = Some(path.to_path_buf());
+        Ok(index)
+    }
+
+    pub fn from_env() -> Result<Self, PoolIndexError> {
+        let path: PathBuf = get_env_or("POOL_INDEX_PATH", PathBuf::from("pools.jsonl"))
+            .map_err(PoolIndexError::EnvError)?;
+        let batch_size =
+            get_env_or("POOL_INDEX_BATCH_SIZE", 100).map_err(PoolIndexError::EnvError)?;
+        let log_range =
+            get_env_or("POOL_INDEX_LOG_RANGE", 10_000u64).map_err(PoolIndexError::EnvError)?;
+        let mut index = Self::load(&path, batch_size)?;
+        index.log_range = log_range.max(1);
+        Ok(index)
+    }
+
+    /// Add a pair, false if it was already known.
+    pub fn insert(&self, record: PairRecord) -> bool {
+        let mut state = self.state.write().unwrap();
+        let inserted = state.insert(record);
+        if inserted && self.path.is_some() {
+            state.unsaved.push(record);
+        }
+        inserted
+    }
+
+    pub fn get(&self, pair: Address) -> Option<PairRecord> {
+        self.state.read().unwrap().pairs.get(&pair).copied()
+    }
+
+    /// Pair of `token_a` and `token_b` created by `factory`, in either order.
+    pub fn find(&self, factory: Address, token_a: Address, token_b: Address) -> Option<PairRecord> {
+        let (token0, token1) = univ2::sort_token(token_a, token_b);
+        let state = self.state.read().unwrap();
+        let pair = state.by_tokens.get(&(factory, token0, token1))?;
+        state.pairs.get(pair).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.read().unwrap().pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// `allPairs` entries of `factory` below this are all indexed.
+    pub fn walked(&self, factory: Address) -> u64 {
+        self.state
+            .read()
+            .unwrap()
+            .walked
+            .get(&factory)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    // creation block of the last walked pair, pairs announced ahead of the
+    // walk may leave earlier ones behind
+    fn walked_block(&self, factory: Address) -> u64 {
+        let state = self.state.read().unwrap();
+        let walked = state.walked.get(&factory).copied().unwrap_or_default();
+        walked
+            .checked_sub(1)
+            .and_then(|index| state.by_index.get(&(factory, index)))
+            .and_then(|pair| state.pairs.get(pair))
+            .map_or(0, |record| record.block)
+    }
+
+    /// Record the pair a `PairCreated` log announces.
+    pub fn apply_log(&self, log: &Log) -> Option<PairRecord> {
+        let created = ethers::contract::parse_log::<PairCreatedFilter>(log.clone()).ok()?;
+        let record = PairRecord {
+            factory: log.address,
+            index: created.pair_count.checked_sub(U256::one())?.as_u64(),
+            pair: created.pair,
+            token0: created.token_0,
+            token1: created.token_1,
+            block: log.block_number?.as_u64(),
+        };
+        self.insert(record).then_some(record)
+    }
+
+    /// `PairCreated` logs of `factories`, for a subscription.
+    pub fn pair_created_filter(factories: &[Address]) -> Filter {
+        Filter::new()
+            .address(factories.to_vec())
+            .topic0(PairCreatedFilter::signature())
+    }
+
+    /// Walk `allPairs` of `factory` from the first unknown entry to its
+    /// current length, returns how many pairs were added.
+    ///
+    /// The pairs and their tokens are read in Multicall3 batches, the blocks
+    /// they were created at from `PairCreated` logs, scanned forward from
+    /// the last known one.
+    pub async fn sync<M: Middleware + 'static>(
+        &self,
+        provider: Arc<M>,
+        batcher: &CallBatcher<M>,
+        factory: Address,
+    ) -> Result<usize, PoolIndexError> {
+        let length = IUniswapV2Factory::new(factory, Arc::clone(&provider))
+            .all_pairs_length()
+            .call()
+            .await
+            .map_err(|e| PoolIndexError::CallError(e.to_string()))?
+            .as_u64();
+        let mut next = self.walked(factory);
+        if next >= length {
+            return Ok(0);
+        }
+        let head = provider
+            .get_block_number()
+            .await
+            .map_err(|e| PoolIndexError::CallError(e.to_string()))?
+            .as_u64();
+        let mut scan = LogScan {
+            factory,
+            from: self.walked_block(factory),
+            head,
+            range: self.log_range,
+            blocks: HashMap::new(),
+        };
+
+        let mut added = 0;
+        while next < length {
+            let end = length.min(next + self.batch_size as u64);
+            let indexes: Vec<u64> = {
+                let state = self.state.read().unwrap();
+                (next..end)
+                    .filter(|index| !state.by_index.contains_key(&(factory, *index)))
+                    .collect()
+            };
+            let result = self
+                .fetch_pairs(provider.as_ref(), batcher, &mut scan, &indexes)
+                .await;
+            if let Ok(records) = &result {
+                for record in records {
+                    added += self.insert(*record) as usize;
+                }
+            }
+            // checkpoint what the batch added, even when it failed half way
+            self.save()?;
+            result?;
+            info!("indexed {}/{} pairs of {:?}", end, length, factory);
+            next = end;
+        }
+        Ok(added)
+    }
+
+    // `allPairs(index)` and its tokens, batched, and the block of its
+    // `PairCreated` log
+    async fn fetch_pairs<M: Middleware + 'static>(
+        &self,
+        provider: &M,
+        batcher: &CallBatcher<M>,
+        scan: &mut LogScan,
+        indexes: &[u64],
+    ) -> Result<Vec<PairRecord>, PoolIndexError> {
+        if indexes.is_empty() {
+            return Ok(Vec::new());
+        }
+        let call_error = |e| PoolIndexError::CallError(format!("{:?}", e));
+        let calls: Vec<ReadCall> = indexes
+            .iter()
+            .map(|index| {
+                ReadCall::new(
+                    scan.factory,
+                    AllPairsCall {
+                        index: U256::from(*index),
+                    }
+                    .encode(),
+                )
+            })
+            .collect();
+        let pairs = batcher
+            .aggregate(&calls)
+            .await
+            .map_err(call_error)?
+            .into_iter()
+            .zip(indexes)
+            .map(|(data, index)| {
+                data.and_then(|data| Address::decode(data).ok())
+                    .ok_or_else(|| PoolIndexError::CallError(format!("allPairs({}) failed", index)))
+            })
+            .collect::<Result<Vec<Address>, _>>()?;
+
+        let calls: Vec<ReadCall> = pairs
+            .iter()
+            .flat_map(|pair| {
+                [
+                    ReadCall::new(*pair, Token0Call.encode()),
+                    ReadCall::new(*pair, Token1Call.encode()),
+                ]
+            })
+            .collect();
+        let tokens = batcher.aggregate(&calls).await.map_err(call_error)?;
+
+        let mut records = Vec::with_capacity(pairs.len());
+        for ((pair, index), tokens) in pairs.iter().zip(indexes).zip(tokens.chunks(2)) {
+            let token = |data: &Option<Bytes>| {
+                data.as_ref()
+                    .and_then(|data| Address::decode(data).ok())
+                    .ok_or_else(|| PoolIndexError::CallError(format!("tokens of {:?}", pair)))
+            };
+            let block = scan.block(provider, *pair).await?;
+            records.push(PairRecord {
+                factory: scan.factory,
+                index: *index,
+                pair: *pair,
+                token0: token(&tokens[0])?,
+                token1: token(&tokens[1])?,
+                block,
+            });
+        }
+        Ok(records)
+    }
+
+    /// Append the pairs added since the last call to the index file, if any.
+    pub fn save(&self) -> Result<(), PoolIndexError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        // one writer at a time, and the records leave `unsaved` in the
+        // order they are written
+        let mut file = self.file.lock().unwrap();
+        let unsaved = std::mem::take(&mut self.state.write().unwrap().unsaved);
+        if unsaved.is_empty() {
+            return Ok(());
+        }
+        let mut content = String::new();
+        for record in &unsaved {
+            let line = serde_json::to_string(record)
+                .map_err(|e| PoolIndexError::JsonError(path.clone(), e))?;
+            content.push_str(&line);
+            content.push('\n');
+        }
+        let written = match file.as_mut() {
+            Some(file) => file.write_all(content.as_bytes()),
+            None => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|opened| file.insert(opened).write_all(content.as_bytes())),
+        };
+        written.map_err(|e| {
+            // written again by the next save
+            let mut state = self.state.write().unwrap();
+            let newer = std::mem::replace(&mut state.unsaved, unsaved);
+            state.unsaved.extend(newer);
+            PoolIndexError::IoError(path.clone(), e)
+        })
+    }
+}
+
+// `PairCreated` logs of a factory read forward in `range` block windows, up
+// to the head `sync` started at
+struct LogScan {
+    factory: Address,
+    // first block not scanned yet
+    from: u64,
+    head: u64,
+    range: u64,
+    // pair -> creation block, of the logs scanned but not used yet
+    blocks: HashMap<Address, u64>,
+}
+
+impl LogScan {
+    // pairs are walked in creation order, so the log of the next one is
+    // at or after the window the last one was found in
+    async fn block<M: Middleware>(
+        &mut self,
+        provider: &M,
+        pair: Address,
+    ) -> Result<u64, PoolIndexError> {
+        loop {
+            if let Some(block) = self.blocks.remove(&pair) {
+                return Ok(block);
+            }
+            if self.from > self.head {
+                return Err(PoolIndexError::MissingLog(pair));
+            }
+            let to = self.head.min(self.from + self.range - 1);
+            let filter = PoolIndex::pair_created_filter(&[self.factory])
+                .from_block(self.from)
+                .to_block(to);
+            let logs = provider
+                .get_logs(&filter)
+                .await
+                .map_err(|e| PoolIndexError::CallError(e.to_string()))?;
+            for log in logs {
+                let Ok(created) = ethers::contract::parse_log::<PairCreatedFilter>(log.clone())
+                else {
+                    continue;
+                };
+                if let Some(block) = log.block_number {
+                    self.blocks.insert(created.pair, block.as_u64());
+                }
+            }
+            self.from = to + 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::multicall::MulticallConfig;
+    use ethers::abi::{self, Token};
+
+    fn record(index: u64) -> PairRecord {
+        PairRecord {
+            factory: Address::repeat_byte(0xfa),
+            index,
+            pair: Address::from_low_u64_be(100 + index),
+            token0: Address::from_low_u64_be(1),
+            token1: Address::from_low_u64_be(2 + index),
+            block: 10_000 + index,
+        }
+    }
+
+    #[test]
+    fn test_walked_and_round_trip() {
+        let path = std::env::temp_dir().join(format!("unibot-pools-{}.jsonl", std::process::id()));
+        let index = PoolIndex::load(&path, 10).unwrap();
+        let factory = Address::repeat_byte(0xfa);
+
+        // a pair announced ahead of the walk leaves a gap
+        assert!(index.insert(record(0)));
+        assert!(index.insert(record(2)));
+        assert!(!index.insert(record(2)));
+        assert_eq!(1, index.walked(factory));
+        index.save().unwrap();
+        assert!(index.insert(record(1)));
+        assert_eq!(3, index.walked(factory));
+        index.save().unwrap();
+        index.save().unwrap();
+        // only what each save added was appended
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(3, content.lines().count());
+
+        // a save cut short
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"factory\":").unwrap();
+
+        let reloaded = PoolIndex::load(&path, 10).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(3, reloaded.len());
+        assert_eq!(3, reloaded.walked(factory));
+        assert_eq!(
+            Some(record(1)),
+            reloaded.find(
+                factory,
+                Address::from_low_u64_be(3),
+                Address::from_low_u64_be(1)
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_batches_calls_and_scans_logs() {
+        let (provider, mock) = Provider::mocked();
+        let provider = Arc::new(provider);
+        let batcher = CallBatcher::new(Arc::clone(&provider), MulticallConfig::default());
+        let index = PoolIndex::in_memory(10);
+        let factory = Address::repeat_byte(0xfa);
+        let pairs = [record(0), record(1)];
+
+        let aggregated = |results: Vec<Token>| -> Bytes {
+            let results = results
+                .into_iter()
+                .map(|token| {
+                    Token::Tuple(vec![Token::Bool(true), Token::Bytes(abi::encode(&[token]))])
+                })
+                .collect();
+            abi::encode(&[Token::Array(results)]).into()
+        };
+        let created = |record: &PairRecord| Log {
+            address: factory,
+            topics: vec![
+                PairCreatedFilter::signature(),
+                H256::from(record.token0),
+                H256::from(record.token1),
+            ],
+            data: abi::encode(&[
+                Token::Address(record.pair),
+                Token::Uint(U256::from(record.index + 1)),
+            ])
+            .into(),
+            block_number: Some(U64::from(record.block)),
+            ..Default::default()
+        };
+
+        // responses are served last pushed first: allPairsLength, the head,
+        // allPairs, token0 and token1, one log window
+        mock.push::<Vec<Log>, _>(vec![created(&pairs[0]), created(&pairs[1])])
+            .unwrap();
+        mock.push::<Bytes, Bytes>(aggregated(
+            pairs
+                .iter()
+                .flat_map(|record| [Token::Address(record.token0), Token::Address(record.token1)])
+                .collect(),
+        ))
+        .unwrap();
+        mock.push::<Bytes, Bytes>(aggregated(
+            pairs
+                .iter()
+                .map(|record| Token::Address(record.pair))
+                .collect(),
+        ))
+        .unwrap();
+        mock.push::<U64, _>(U64::from(10_001)).unwrap();
+        mock.push::<Bytes, Bytes>(abi::encode(&[Token::Uint(U256::from(2))]).into())
+            .unwrap();
+
+        assert_eq!(2, index.sync(provider, &batcher, factory).await.unwrap());
+        assert_eq!(2, index.walked(factory));
+        assert_eq!(Some(pairs[1]), index.get(pairs[1].pair));
+    }
+
+    #[test]
+    fn test_apply_pair_created_log() {
+        let factory = Address::repeat_byte(0xfa);
+        let (token0, token1) = (Address::repeat_byte(1), Address::repeat_byte(2));
+        let pair = univ2::Dex::uniswap_v2().pair_address(token0, token1);
+        let log = Log {
+            address: factory,
+            topics: vec![
+                PairCreatedFilter::signature(),
+                H256::from(token0),
+                H256::from(token1),
+            ],
+            data: abi::encode(&[Token::Address(pair), Token::Uint(U256::from(1))]).into(),
+            block_number: Some(U64::from(10_008_355)),
+            ..Default::default()
+        };
+
+        let index = PoolIndex::in_memory(10);
+        let record = index.apply_log(&log).unwrap();
+        assert_eq!(0, record.index);
+        assert_eq!(10_008_355, record.block);
+        assert_eq!(Some(record), index.find(factory, token1, token0));
+        assert_eq!(1, index.walked(factory));
+        assert_eq!(None, index.apply_log(&log));
+    }
+}
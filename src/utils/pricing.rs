@@ -0,0 +1,360 @@
+//! ETH and USD valuation from pool reserves.
+//!
+//! A token is priced in WETH on its deepest cached pair with WETH, and WETH
+//! in USD on the deepest cached pair with one of the configured stablecoins.
+//! Prices read from a pair holding less WETH than the configured threshold
+//! are flagged unreliable: a thin pool is cheap to move.
+
+use crate::env_store::{get_env_or, EnvError};
+use crate::utils::tokens::TokenRegistry;
+use crate::utils::univ2::{self, Dex, Reserves};
+
+use ethers::prelude::*;
+use serde::Serialize;
+use std::sync::Arc;
+
+const USDC: &str = "A0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48";
+const USDT: &str = "dAC17F958D2ee523a2206206994597C13D831ec7";
+const DAI: &str = "6B175474E89094C44Da98b954EedeAC495271d0F";
+
+#[derive(Debug, Clone)]
+pub struct PricingConfig {
+    pub weth: Address,
+    // tokens worth one dollar, paired with WETH for the ETH price
+    pub stablecoins: Vec<Address>,
+    // WETH a pair needs for its price to be trusted
+    pub min_weth_liquidity: U256,
+}
+
+impl PricingConfig {
+    pub fn from_env(weth: Address) -> Result<Self, EnvError> {
+        let stablecoins: String = get_env_or("PRICE_STABLECOINS", [USDC, USDT, DAI].join(","))?;
+        let stablecoins = stablecoins
+            .split(',')
+            .map(str::trim)
+            .filter(|token| !token.is_empty())
+            .map(|token| {
+                token
+                    .parse::<Address>()
+                    .map_err(|_| EnvError::from(format!("invalid stablecoin {}", token)))
+            })
+            .collect::<Result<_, _>>()?;
+        let min_weth_liquidity: u128 =
+            get_env_or("PRICE_MIN_WETH_LIQUIDITY_WEI", 10_000_000_000_000_000_000)?;
+        Ok(Self {
+            weth,
+            stablecoins,
+            min_weth_liquidity: min_weth_liquidity.into(),
+        })
+    }
+}
+
+/// `amount` in whole units as a float, for display only.
+pub fn to_float(amount: U256, decimals: u8) -> f64 {
+    ethers::utils::format_units(amount, decimals as u32)
+        .ok()
+        .and_then(|units| units.parse().ok())
+        .unwrap_or_default()
+}
+
+/// Price of one whole token, read from `pair`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Price {
+    pub pair: Address,
+    pub value: f64,
+    pub reliable: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Valuation {
+    pub eth: f64,
+    // unknown until a stablecoin pair has been read
+    pub usd: Option<f64>,
+    // every price involved came from a deep enough pool
+    pub reliable: bool,
+}
+
+impl Valuation {
+    /// `$1234.56`, empty without a USD price, flagged when unreliable.
+    pub fn usd_label(&self) -> String {
+        let mut label = self
+            .usd
+            .map(|usd| format!("${:.2}", usd))
+            .unwrap_or_default();
+        if !self.reliable {
+            label.push_str(" (unreliable)");
+        }
+        label
+    }
+}
+
+pub struct PriceOracle {
+    config: PricingConfig,
+    tokens: Arc<TokenRegistry>,
+    dexes: Vec<Dex>,
+}
+
+impl PriceOracle {
+    pub fn new(config: PricingConfig, tokens: Arc<TokenRegistry>) -> Self {
+        Self {
+            config,
+            tokens,
+            dexes: vec![Dex::uniswap_v2(), Dex::sushiswap()],
+        }
+    }
+
+    pub fn config(&self) -> &PricingConfig {
+        &self.config
+    }
+
+    /// Cached pair of `token` and WETH holding the most WETH, as
+    /// `(pair, token reserve, weth reserve)`.
+    fn deepest_weth_pair(
+        &self,
+        token: Address,
+        reserves: &impl Reserves,
+    ) -> Option<(Address, U256, U256)> {
+        self.dexes
+            .iter()
+            .filter_map(|dex| {
+                let pair = dex.pair_address(token, self.config.weth);
+                let (reserve0, reserve1) = reserves.reserves(pair)?;
+                let (reserve_token, reserve_weth) =
+                    if univ2::sort_token(token, self.config.weth).0 == token {
+                        (reserve0, reserve1)
+                    } else {
+                        (reserve1, reserve0)
+                    };
+                Some((pair, reserve_token, reserve_weth))
+            })
+            .filter(|(_, reserve_token, reserve_weth)| {
+                !reserve_token.is_zero() && !reserve_weth.is_zero()
+            })
+            .max_by_key(|(_, _, reserve_weth)| *reserve_weth)
+    }
+
+    /// ETH per whole `token`.
+    pub fn token_eth_price(&self, token: Address, reserves: &impl Reserves) -> Option<Price> {
+        if token == self.config.weth {
+            return Some(Price {
+                pair: Address::zero(),
+                value: 1.0,
+                reliable: true,
+            });
+        }
+        let (pair, reserve_token, reserve_weth) = self.deepest_weth_pair(token, reserves)?;
+        let decimals = self.tokens.info(token).decimals;
+        Some(Price {
+            pair,
+            value: to_float(reserve_weth, 18) / to_float(reserve_token, decimals),
+            reliable: reserve_weth >= self.config.min_weth_liquidity,
+        })
+    }
+
+    /// USD per ETH, from the deepest stablecoin pair.
+    pub fn eth_usd_price(&self, reserves: &impl Reserves) -> Option<Price> {
+        self.config
+            .stablecoins
+            .iter()
+            .filter_map(|stable| {
+                let (pair, reserve_stable, reserve_weth) =
+                    self.deepest_weth_pair(*stable, reserves)?;
+                let decimals = self.tokens.info(*stable).decimals;
+                Some((
+                    reserve_weth,
+                    Price {
+                        pair,
+                        value: to_float(reserve_stable, decimals) / to_float(reserve_weth, 18),
+                        reliable: reserve_weth >= self.config.min_weth_liquidity,
+                    },
+                ))
+            })
+            .max_by_key(|(reserve_weth, _)| *reserve_weth)
+            .map(|(_, price)| price)
+    }
+
+    /// `reliable` covers the pools the valuation used; a missing stablecoin
+    /// pair only leaves `usd` empty.
+    fn value(&self, eth: f64, reliable: bool, reserves: &impl Reserves) -> Valuation {
+        let usd_price = self.eth_usd_price(reserves);
+        Valuation {
+            eth,
+            usd: usd_price.map(|price| eth * price.value),
+            reliable: reliable && usd_price.is_none_or(|price| price.reliable),
+        }
+    }
+
+    /// Value of a signed wei amount, a profit.
+    pub fn value_wei(&self, wei: I256, reserves: &impl Reserves) -> Valuation {
+        let eth = to_float(wei.unsigned_abs(), 18);
+        let eth = if wei.is_negative() { -eth } else { eth };
+        self.value(eth, true, reserves)
+    }
+
+    /// Value of `amount` of `token`, `None` without a cached WETH pair.
+    pub fn value_token(
+        &self,
+        token: Address,
+        amount: U256,
+        reserves: &impl Reserves,
+    ) -> Option<Valuation> {
+        let price = self.token_eth_price(token, reserves)?;
+        let decimals = self.tokens.info(token).decimals;
+        Some(self.value(
+            to_float(amount, decimals) * price.value,
+            price.reliable,
+            reserves,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn weth() -> Address {
+        "C02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"
+            .parse::<Address>()
+            .unwrap()
+    }
+
+    fn oracle() -> PriceOracle {
+        PriceOracle::new(
+            PricingConfig {
+                weth: weth(),
+                stablecoins: vec![USDC.parse().unwrap()],
+                min_weth_liquidity: U256::exp10(19),
+            },
+            Arc::new(TokenRegistry::bundled()),
+        )
+    }
+
+    // reserves in (reserve0, reserve1) order of the pair
+    fn insert(
+        reserves: &mut HashMap<Address, (U256, U256)>,
+        dex: Dex,
+        token: Address,
+        reserve_token: U256,
+        reserve_weth: U256,
+    ) {
+        let pair = dex.pair_address(token, weth());
+        let value = if univ2::sort_token(token, weth()).0 == token {
+            (reserve_token, reserve_weth)
+        } else {
+            (reserve_weth, reserve_token)
+        };
+        reserves.insert(pair, value);
+    }
+
+    #[test]
+    fn test_deepest_pool_wins() {
+        let oracle = oracle();
+        let token = Address::repeat_byte(0xaa);
+        let mut reserves = HashMap::new();
+        // 1 token = 0.001 ETH on uniswap, 0.002 ETH on a thinner sushiswap
+        insert(
+            &mut reserves,
+            Dex::uniswap_v2(),
+            token,
+            U256::exp10(24),
+            U256::exp10(21),
+        );
+        insert(
+            &mut reserves,
+            Dex::sushiswap(),
+            token,
+            U256::exp10(21),
+            U256::exp10(18) * 2,
+        );
+
+        let price = oracle.token_eth_price(token, &reserves).unwrap();
+        assert_eq!(Dex::uniswap_v2().pair_address(token, weth()), price.pair);
+        assert!((price.value - 0.001).abs() < 1e-12);
+        assert!(price.reliable);
+
+        // USDC has 6 decimals, 2000 USDC per ETH
+        let usdc: Address = USDC.parse().unwrap();
+        insert(
+            &mut reserves,
+            Dex::uniswap_v2(),
+            usdc,
+            U256::from(200_000_000_000u64),
+            U256::exp10(20),
+        );
+        let valuation = oracle
+            .value_token(token, U256::exp10(18) * 500, &reserves)
+            .unwrap();
+        assert!((valuation.eth - 0.5).abs() < 1e-9);
+        assert!((valuation.usd.unwrap() - 1000.0).abs() < 1e-6);
+        assert!(valuation.reliable);
+
+        let loss = oracle.value_wei(I256::from(-1_000_000_000_000_000_000i64), &reserves);
+        assert!((loss.usd.unwrap() + 2000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_thin_pool_is_unreliable() {
+        let oracle = oracle();
+        let token = Address::repeat_byte(0xbb);
+        let mut reserves = HashMap::new();
+        insert(
+            &mut reserves,
+            Dex::uniswap_v2(),
+            token,
+            U256::exp10(20),
+            U256::exp10(18),
+        );
+
+        let valuation = oracle
+            .value_token(token, U256::exp10(18), &reserves)
+            .unwrap();
+        assert!(!valuation.reliable);
+        // no stablecoin pair cached yet
+        assert_eq!(None, valuation.usd);
+        assert_eq!(" (unreliable)", valuation.usd_label());
+        assert_eq!(
+            None,
+            oracle.value_token(Address::repeat_byte(0xcc), U256::one(), &reserves)
+        );
+    }
+
+    #[test]
+    fn test_missing_usd_price_is_not_unreliable() {
+        let oracle = oracle();
+        let token = Address::repeat_byte(0xdd);
+        let mut reserves = HashMap::new();
+        insert(
+            &mut reserves,
+            Dex::uniswap_v2(),
+            token,
+            U256::exp10(24),
+            U256::exp10(21),
+        );
+
+        // deep WETH pool, no stablecoin pair cached
+        let valuation = oracle
+            .value_token(token, U256::exp10(18), &reserves)
+            .unwrap();
+        assert!(valuation.reliable);
+        assert_eq!(None, valuation.usd);
+        assert_eq!("", valuation.usd_label());
+
+        let profit = oracle.value_wei(I256::from(1_000_000_000_000_000_000i64), &reserves);
+        assert!(profit.reliable);
+        assert_eq!(None, profit.usd);
+
+        // a thin stablecoin pair still taints the USD figure
+        let usdc: Address = USDC.parse().unwrap();
+        insert(
+            &mut reserves,
+            Dex::uniswap_v2(),
+            usdc,
+            U256::from(2_000_000_000u64),
+            U256::exp10(18),
+        );
+        let profit = oracle.value_wei(I256::from(1_000_000_000_000_000_000i64), &reserves);
+        assert!(!profit.reliable);
+        assert!((profit.usd.unwrap() - 2000.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,84 @@
+use crate::utils::amm::AmmError;
+use crate::utils::screener::ScreenReason;
+use crate::utils::univ2::QuoteError;
+use crate::utils::validator::VictimCheck;
+
+use ethers::prelude::*;
+use std::fmt;
+
+/// Why a pending swap did not turn into an opportunity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    DeadlineExceeded {
+        deadline: U256,
+        target_timestamp: U256,
+    },
+    NoBlockYet,
+    // the frontrun may not tip more than the cap, so it can't go first
+    VictimTipAboveCap {
+        victim_tip: U256,
+        cap: U256,
+    },
+    InvalidVictim(VictimCheck),
+    TokenScreen(ScreenReason),
+    Quote(QuoteError),
+    Amm(AmmError),
+    Unprofitable,
+    NoCapital(Address),
+    SanityCheckFailed,
+}
+
+impl Rejection {
+    /// Short stable label, used to group rejections in logs and reports.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Rejection::DeadlineExceeded { .. } => "deadline_exceeded",
+            Rejection::NoBlockYet => "no_block_yet",
+            Rejection::VictimTipAboveCap { .. } => "victim_tip_above_cap",
+            Rejection::InvalidVictim(check) => check.name(),
+            Rejection::TokenScreen(_) => "token_screen",
+            Rejection::Quote(_) => "quote_failed",
+            Rejection::Amm(_) => "amm_math",
+            Rejection::Unprofitable => "unprofitable",
+            Rejection::NoCapital(_) => "no_capital",
+            Rejection::SanityCheckFailed => "sanity_check_failed",
+        }
+    }
+
+    /// Whether the victim can still execute, only then does it move the
+    /// pool for other strategies.
+    pub fn victim_may_land(&self) -> bool {
+        !matches!(
+            self,
+            Rejection::DeadlineExceeded { .. } | Rejection::InvalidVictim(_)
+        )
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejection::DeadlineExceeded {
+                deadline,
+                target_timestamp,
+            } => write!(
+                f,
+                "deadline {} passed by target block time {}",
+                deadline, target_timestamp
+            ),
+            Rejection::NoBlockYet => write!(f, "no block seen yet, can't price gas"),
+            Rejection::VictimTipAboveCap { victim_tip, cap } => write!(
+                f,
+                "victim tips {} per gas, the frontrun can't outbid it under {}",
+                victim_tip, cap
+            ),
+            Rejection::InvalidVictim(check) => write!(f, "invalid victim: {}", check),
+            Rejection::TokenScreen(reason) => write!(f, "token failed screening: {}", reason),
+            Rejection::Quote(e) => write!(f, "quote failed: {}", e),
+            Rejection::Amm(e) => write!(f, "swap math failed: {}", e),
+            Rejection::Unprofitable => write!(f, "backrun returns less weth than frontrun"),
+            Rejection::NoCapital(token) => write!(f, "no capital left for {:?}", token),
+            Rejection::SanityCheckFailed => write!(f, "sandwich sanity check failed"),
+        }
+    }
+}
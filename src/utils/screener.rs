@@ -0,0 +1,512 @@
+use crate::env_store::{get_env_or, EnvError};
+use crate::utils::contract_abi::{BalanceOfCall, DepositCall, TransferCall};
+use crate::utils::univ2::{self, TokenTaxes, BPS};
+
+use ethers::abi::{AbiDecode, AbiEncode};
+use ethers::prelude::*;
+use ethers::providers::JsonRpcClient;
+use log::debug;
+use revm::db::{CacheDB, EthersDB};
+use revm::primitives::{
+    AccountInfo, Address as EvmAddress, Bytes as EvmBytes, EVMError, ExecutionResult, TransactTo,
+    U256 as EvmU256,
+};
+use revm::Evm;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+abigen!(
+    IUniswapV2Pair,
+    r#"[
+        function getReserves() external view returns (uint112 reserve0, uint112 reserve1, uint32 blockTimestampLast)
+        function swap(uint amount0Out, uint amount1Out, address to, bytes calldata data) external
+    ]"#,
+);
+
+const SIM_GAS_LIMIT: u64 = 1_000_000;
+
+#[derive(Debug, Clone)]
+pub struct ScreenerConfig {
+    // WETH spent on the probe buy, big enough to trip max-tx limits that
+    // would also stop a real frontrun
+    pub probe_weth: U256,
+    // highest buy or sell tax we still trade, in basis points
+    pub max_tax_bps: u64,
+    pub verdict_ttl: Duration,
+    // known taxes that skip the simulation, e.g. for tokens it misjudges
+    pub tax_overrides: HashMap<Address, TokenTaxes>,
+}
+
+impl Default for ScreenerConfig {
+    fn default() -> Self {
+        Self {
+            probe_weth: U256::exp10(17),
+            max_tax_bps: 500,
+            verdict_ttl: Duration::from_secs(600),
+            tax_overrides: HashMap::new(),
+        }
+    }
+}
+
+impl ScreenerConfig {
+    pub fn from_env() -> Result<Self, EnvError> {
+        let default = Self::default();
+        Ok(Self {
+            probe_weth: get_env_or("SCREENER_PROBE_WETH", default.probe_weth.as_u128())?.into(),
+            max_tax_bps: get_env_or("SCREENER_MAX_TAX_BPS", default.max_tax_bps)?,
+            verdict_ttl: Duration::from_secs(get_env_or(
+                "SCREENER_VERDICT_TTL_SECS",
+                default.verdict_ttl.as_secs(),
+            )?),
+            tax_overrides: parse_tax_overrides(&get_env_or(
+                "SCREENER_TAX_OVERRIDES",
+                String::new(),
+            )?)?,
+        })
+    }
+}
+
+// `token:buy_bps:sell_bps` entries separated by commas
+fn parse_tax_overrides(val: &str) -> Result<HashMap<Address, TokenTaxes>, EnvError> {
+    let mut overrides = HashMap::new();
+    for entry in val.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let parts: Vec<&str> = entry.split(':').collect();
+        let parsed = match parts[..] {
+            [token, buy, sell] => token
+                .parse::<Address>()
+                .ok()
+                .zip(buy.parse::<u64>().ok().filter(|bps| *bps <= BPS))
+                .zip(sell.parse::<u64>().ok().filter(|bps| *bps <= BPS)),
+            _ => None,
+        };
+        let ((token, buy_tax_bps), sell_tax_bps) =
+            parsed.ok_or_else(|| EnvError::from(format!("bad tax override: {}", entry)))?;
+        overrides.insert(
+            token,
+            TokenTaxes {
+                buy_tax_bps,
+                sell_tax_bps,
+            },
+        );
+    }
+    Ok(overrides)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScreenReason {
+    NoLiquidity,
+    BuyReverted(String),
+    SellReverted(String),
+    Blacklisted(String),
+    MaxTxLimit(String),
+    HighTax { buy_tax_bps: u64, sell_tax_bps: u64 },
+    SimulationError(String),
+    // the node failed to serve the fork, says nothing about the token
+    RpcError(String),
+}
+
+impl fmt::Display for ScreenReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScreenReason::NoLiquidity => write!(f, "pair has no liquidity"),
+            ScreenReason::BuyReverted(reason) => write!(f, "buy reverted: {}", reason),
+            ScreenReason::SellReverted(reason) => write!(f, "sell reverted: {}", reason),
+            ScreenReason::Blacklisted(reason) => write!(f, "executor blacklisted: {}", reason),
+            ScreenReason::MaxTxLimit(reason) => write!(f, "max tx limit: {}", reason),
+            ScreenReason::HighTax {
+                buy_tax_bps,
+                sell_tax_bps,
+            } => write!(
+                f,
+                "transfer tax too high: buy {}bps sell {}bps",
+                buy_tax_bps, sell_tax_bps
+            ),
+            ScreenReason::SimulationError(reason) => write!(f, "simulation failed: {}", reason),
+            ScreenReason::RpcError(reason) => write!(f, "fork lookup failed: {}", reason),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenVerdict {
+    Safe(TokenTaxes),
+    Rejected(ScreenReason),
+}
+
+/// Measured result of a probe buy followed by a full sell.
+#[derive(Debug, Clone, Copy)]
+struct ProbeResult {
+    buy_tax_bps: u64,
+    sell_tax_bps: u64,
+}
+
+/// Decides whether a token is safe to sandwich by buying and immediately
+/// selling it through its WETH pair in a local EVM forked from the latest
+/// block. The executor address is the trader, so blacklists aimed at it show
+/// up as failed transfers.
+pub struct TokenScreener<P: JsonRpcClient> {
+    prober: Arc<Prober<P>>,
+    config: ScreenerConfig,
+    verdicts: Mutex<HashMap<Address, (TokenVerdict, Instant)>>,
+}
+
+impl<P: JsonRpcClient + 'static> TokenScreener<P> {
+    pub fn new(
+        provider: Arc<Provider<P>>,
+        config: ScreenerConfig,
+        executor: Address,
+        weth: Address,
+    ) -> Self {
+        Self {
+            prober: Arc::new(Prober {
+                provider,
+                executor,
+                weth,
+                amount: config.probe_weth,
+            }),
+            config,
+            verdicts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn cached(&self, token: Address) -> Option<TokenVerdict> {
+        let verdicts = self.verdicts.lock().unwrap();
+        match verdicts.get(&token) {
+            Some((verdict, at)) if at.elapsed() < self.config.verdict_ttl => Some(verdict.clone()),
+            _ => None,
+        }
+    }
+
+    /// Verdict for `token` traded through `pair`, simulated at most once per
+    /// `verdict_ttl`. The simulation blocks on its RPC reads, it runs on the
+    /// blocking pool.
+    pub async fn screen(&self, token: Address, pair: Address) -> TokenVerdict {
+        if let Some(taxes) = self.config.tax_overrides.get(&token) {
+            return TokenVerdict::Safe(*taxes);
+        }
+        if let Some(verdict) = self.cached(token) {
+            return verdict;
+        }
+
+        let prober = Arc::clone(&self.prober);
+        let probed = tokio::task::spawn_blocking(move || prober.probe(token, pair))
+            .await
+            .unwrap_or_else(|e| Err(ScreenReason::SimulationError(e.to_string())));
+        let verdict = match probed {
+            // nothing gets through a full tax, whatever the limit
+            Ok(probe)
+                if probe.buy_tax_bps.max(probe.sell_tax_bps)
+                    > self.config.max_tax_bps.min(BPS - 1) =>
+            {
+                TokenVerdict::Rejected(ScreenReason::HighTax {
+                    buy_tax_bps: probe.buy_tax_bps,
+                    sell_tax_bps: probe.sell_tax_bps,
+                })
+            }
+            Ok(probe) => TokenVerdict::Safe(TokenTaxes {
+                buy_tax_bps: probe.buy_tax_bps,
+                sell_tax_bps: probe.sell_tax_bps,
+            }),
+            Err(reason) => TokenVerdict::Rejected(reason),
+        };
+        debug!("token {:?} screened: {:?}", token, verdict);
+        if matches!(verdict, TokenVerdict::Rejected(ScreenReason::RpcError(_))) {
+            return verdict;
+        }
+
+        self.verdicts
+            .lock()
+            .unwrap()
+            .insert(token, (verdict.clone(), Instant::now()));
+        verdict
+    }
+}
+
+// what a probe needs, moved onto a blocking thread
+struct Prober<P: JsonRpcClient> {
+    provider: Arc<Provider<P>>,
+    executor: Address,
+    weth: Address,
+    // WETH spent on the probe buy
+    amount: U256,
+}
+
+impl<P: JsonRpcClient + 'static> Prober<P> {
+    fn probe(&self, token: Address, pair: Address) -> Result<ProbeResult, ScreenReason> {
+        let db = EthersDB::new(Arc::clone(&self.provider), None)
+            .ok_or_else(|| ScreenReason::RpcError("can't fork latest block".to_string()))?;
+        let mut sim = Simulation::new(CacheDB::new(db), self.executor, self.amount)?;
+
+        let reserves = sim
+            .call(pair, GetReservesCall {}.encode())
+            .and_then(|out| {
+                GetReservesReturn::decode(out)
+                    .map_err(|e| ScreenReason::SimulationError(e.to_string()))
+            })?;
+        let (reserve0, reserve1) = (reserves.reserve_0, reserves.reserve_1);
+        let weth_is_token0 = univ2::sort_token(self.weth, token).0 == self.weth;
+        let (reserve_weth, reserve_token) = if weth_is_token0 {
+            (U256::from(reserve0), U256::from(reserve1))
+        } else {
+            (U256::from(reserve1), U256::from(reserve0))
+        };
+        if reserve_weth.is_zero() || reserve_token.is_zero() {
+            return Err(ScreenReason::NoLiquidity);
+        }
+
+        // buy: wrap, pay the pair and pull the quoted tokens out of it
+        let probe = self.amount;
+        sim.commit_value(self.weth, DepositCall {}.encode(), probe)?
+            .map_err(ScreenReason::SimulationError)?;
+        sim.commit(
+            self.weth,
+            TransferCall {
+                to: pair,
+                amount: probe,
+            }
+            .encode(),
+        )?
+        .map_err(ScreenReason::SimulationError)?;
+
+        let buy = univ2::get_univ2_data_given_in(probe, reserve_weth, reserve_token)
+            .map_err(|_| ScreenReason::NoLiquidity)?;
+        let token_out = buy.amount_out;
+        if token_out.is_zero() {
+            return Err(ScreenReason::NoLiquidity);
+        }
+        let balance_before = sim.balance_of(token, self.executor)?;
+        sim.commit(pair, swap_call(weth_is_token0, token_out, self.executor))?
+            .map_err(|reason| classify_revert(reason, ScreenReason::BuyReverted))?;
+        let received = sim
+            .balance_of(token, self.executor)?
+            .saturating_sub(balance_before);
+        if received.is_zero() {
+            return Err(ScreenReason::BuyReverted("nothing received".to_string()));
+        }
+
+        // sell: send everything back and swap what actually reached the pair
+        let (reserve_weth, reserve_token) = (buy.new_reserve_in, buy.new_reserve_out);
+        sim.commit(
+            token,
+            TransferCall {
+                to: pair,
+                amount: received,
+            }
+            .encode(),
+        )?
+        .map_err(|reason| classify_revert(reason, ScreenReason::SellReverted))?;
+        let delivered = sim.balance_of(token, pair)?.saturating_sub(reserve_token);
+
+        let weth_out = univ2::get_univ2_data_given_in(delivered, reserve_token, reserve_weth)
+            .map_err(|e| ScreenReason::SellReverted(e.to_string()))?
+            .amount_out;
+        sim.commit(pair, swap_call(!weth_is_token0, weth_out, self.executor))?
+            .map_err(|reason| classify_revert(reason, ScreenReason::SellReverted))?;
+
+        Ok(ProbeResult {
+            buy_tax_bps: tax_bps(token_out, received),
+            sell_tax_bps: tax_bps(received, delivered),
+        })
+    }
+}
+
+/// Share of `expected` lost on the way, in basis points.
+fn tax_bps(expected: U256, actual: U256) -> u64 {
+    if expected.is_zero() {
+        return 0;
+    }
+    (expected.saturating_sub(actual) * BPS / expected).as_u64()
+}
+
+fn swap_call(out_is_token1: bool, amount_out: U256, to: Address) -> Vec<u8> {
+    let (amount_0_out, amount_1_out) = if out_is_token1 {
+        (U256::zero(), amount_out)
+    } else {
+        (amount_out, U256::zero())
+    };
+    SwapCall {
+        amount_0_out,
+        amount_1_out,
+        to,
+        data: Bytes::new(),
+    }
+    .encode()
+}
+
+// Revert strings are the only hint on why a transfer failed, match the
+// wording commonly used by anti-bot and max-tx guards.
+fn classify_revert(reason: String, fallback: fn(String) -> ScreenReason) -> ScreenReason {
+    let lower = reason.to_lowercase();
+    if lower.contains("blacklist") || lower.contains("bot") || lower.contains("blocked") {
+        ScreenReason::Blacklisted(reason)
+    } else if lower.contains("max") || lower.contains("limit") {
+        ScreenReason::MaxTxLimit(reason)
+    } else {
+        fallback(reason)
+    }
+}
+
+// Error(string) selector
+const REVERT_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+
+fn revert_reason(output: &[u8]) -> String {
+    if output.len() > 4 && output[..4] == REVERT_SELECTOR {
+        if let Ok(reason) = String::decode(&output[4..]) {
+            return reason;
+        }
+    }
+    format!("0x{}", hex::encode(output))
+}
+
+// state the fork could not fetch is an RPC failure, anything else a broken
+// simulation
+fn evm_error(e: EVMError<ProviderError>) -> ScreenReason {
+    match e {
+        EVMError::Database(e) => ScreenReason::RpcError(e.to_string()),
+        e => ScreenReason::SimulationError(format!("{:?}", e)),
+    }
+}
+
+fn evm_address(address: Address) -> EvmAddress {
+    EvmAddress::from(address.0)
+}
+
+struct Simulation<P: JsonRpcClient + 'static> {
+    evm: Evm<'static, (), CacheDB<EthersDB<Provider<P>>>>,
+}
+
+impl<P: JsonRpcClient + 'static> Simulation<P> {
+    fn new(
+        mut db: CacheDB<EthersDB<Provider<P>>>,
+        trader: Address,
+        eth: U256,
+    ) -> Result<Self, ScreenReason> {
+        let account = db
+            .load_account(evm_address(trader))
+            .map_err(|e| ScreenReason::RpcError(e.to_string()))?;
+        account.info = AccountInfo {
+            balance: account.info.balance + EvmU256::from_limbs(eth.0),
+            ..account.info.clone()
+        };
+
+        let evm = Evm::builder()
+            .with_db(db)
+            .modify_tx_env(|tx| {
+                tx.caller = evm_address(trader);
+                tx.gas_limit = SIM_GAS_LIMIT;
+                tx.gas_price = EvmU256::ZERO;
+            })
+            .build();
+        Ok(Self { evm })
+    }
+
+    // the outer error is a failed simulation, the inner one a reverted call
+    fn execute(
+        &mut self,
+        to: Address,
+        data: Vec<u8>,
+        value: U256,
+        commit: bool,
+    ) -> Result<Result<Vec<u8>, String>, ScreenReason> {
+        let tx = self.evm.tx_mut();
+        tx.transact_to = TransactTo::Call(evm_address(to));
+        tx.data = EvmBytes::from(data);
+        tx.value = EvmU256::from_limbs(value.0);
+
+        let result = if commit {
+            self.evm.transact_commit().map_err(evm_error)?
+        } else {
+            self.evm.transact().map_err(evm_error)?.result
+        };
+
+        Ok(match result {
+            ExecutionResult::Success { output, .. } => Ok(output.into_data().to_vec()),
+            ExecutionResult::Revert { output, .. } => Err(revert_reason(&output)),
+            ExecutionResult::Halt { reason, .. } => Err(format!("halted: {:?}", reason)),
+        })
+    }
+
+    fn call(&mut self, to: Address, data: Vec<u8>) -> Result<Vec<u8>, ScreenReason> {
+        self.execute(to, data, U256::zero(), false)?
+            .map_err(ScreenReason::SimulationError)
+    }
+
+    fn commit(&mut self, to: Address, data: Vec<u8>) -> Result<Result<(), String>, ScreenReason> {
+        self.execute(to, data, U256::zero(), true)
+            .map(|result| result.map(|_| ()))
+    }
+
+    fn commit_value(
+        &mut self,
+        to: Address,
+        data: Vec<u8>,
+        value: U256,
+    ) -> Result<Result<(), String>, ScreenReason> {
+        self.execute(to, data, value, true)
+            .map(|result| result.map(|_| ()))
+    }
+
+    fn balance_of(&mut self, token: Address, account: Address) -> Result<U256, ScreenReason> {
+        let out = self.call(token, BalanceOfCall { account }.encode())?;
+        U256::decode(out).map_err(|e| ScreenReason::SimulationError(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tax_bps() {
+        assert_eq!(0, tax_bps(U256::from(1_000), U256::from(1_000)));
+        assert_eq!(500, tax_bps(U256::from(1_000), U256::from(950)));
+        assert_eq!(BPS, tax_bps(U256::from(1_000), U256::zero()));
+        assert_eq!(0, tax_bps(U256::zero(), U256::zero()));
+    }
+
+    #[test]
+    fn test_parse_tax_overrides() {
+        let overrides =
+            parse_tax_overrides("0xdAC17F958D2ee523a2206206994597C13D831ec7:300:500, ").unwrap();
+        assert_eq!(
+            Some(&TokenTaxes {
+                buy_tax_bps: 300,
+                sell_tax_bps: 500
+            }),
+            overrides.get(
+                &"0xdAC17F958D2ee523a2206206994597C13D831ec7"
+                    .parse::<Address>()
+                    .unwrap()
+            )
+        );
+        assert!(parse_tax_overrides("0xdAC17F958D2ee523a2206206994597C13D831ec7:300").is_err());
+        assert!(
+            parse_tax_overrides("0xdAC17F958D2ee523a2206206994597C13D831ec7:10000:10000").is_ok()
+        );
+        assert!(
+            parse_tax_overrides("0xdAC17F958D2ee523a2206206994597C13D831ec7:300:10001").is_err()
+        );
+    }
+
+    #[test]
+    fn test_classify_revert() {
+        let fallback = ScreenReason::SellReverted;
+        assert_eq!(
+            ScreenReason::Blacklisted("Blacklisted".to_string()),
+            classify_revert("Blacklisted".to_string(), fallback)
+        );
+        assert_eq!(
+            ScreenReason::MaxTxLimit("Transfer amount exceeds the maxTxAmount.".to_string()),
+            classify_revert(
+                "Transfer amount exceeds the maxTxAmount.".to_string(),
+                fallback
+            )
+        );
+        assert_eq!(
+            ScreenReason::SellReverted("TRANSFER_FAILED".to_string()),
+            classify_revert("TRANSFER_FAILED".to_string(), fallback)
+        );
+    }
+}
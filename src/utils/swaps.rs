@@ -0,0 +1,377 @@
+//! Router calls decoded into one swap shape, whichever router and pool
+//! version they go through.
+
+use crate::utils::contract_abi::{i_swap_router, i_swap_router_02};
+use crate::IUniswapV2Router02Calls;
+
+use ethers::abi::AbiDecode;
+use ethers::prelude::*;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Protocol {
+    V2,
+    V3,
+}
+
+/// The side the caller fixed and the slippage bound on the other one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum SwapAmount {
+    ExactIn {
+        amount_in: U256,
+        amount_out_min: U256,
+    },
+    ExactOut {
+        amount_out: U256,
+        amount_in_max: U256,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NormalizedSwap {
+    // router function the swap was decoded from
+    pub function: &'static str,
+    pub protocol: Protocol,
+    // tokens in trading order, the first one is sold
+    pub path: Vec<Address>,
+    // V3 fee tier of each hop in hundredths of a bip, empty for V2
+    pub fees: Vec<u32>,
+    pub amount: SwapAmount,
+    pub recipient: Address,
+    // SwapRouter02 swaps only get one through `multicall`
+    pub deadline: Option<U256>,
+}
+
+impl NormalizedSwap {
+    pub fn token_in(&self) -> Address {
+        self.path.first().copied().unwrap_or_default()
+    }
+
+    pub fn token_out(&self) -> Address {
+        self.path.last().copied().unwrap_or_default()
+    }
+}
+
+/// Tokens and fee tiers of a packed V3 path, `token (fee token)*`.
+pub fn decode_v3_path(path: &[u8]) -> Option<(Vec<Address>, Vec<u32>)> {
+    const HOP: usize = 23;
+    if path.len() < 20 + HOP || !(path.len() - 20).is_multiple_of(HOP) {
+        return None;
+    }
+    let mut tokens = vec![Address::from_slice(&path[..20])];
+    let mut fees = Vec::new();
+    for hop in path[20..].chunks(HOP) {
+        fees.push(u32::from_be_bytes([0, hop[0], hop[1], hop[2]]));
+        tokens.push(Address::from_slice(&hop[3..]));
+    }
+    Some((tokens, fees))
+}
+
+// exact output paths are packed from the token bought back to the one sold
+fn decode_v3_path_reversed(path: &[u8]) -> Option<(Vec<Address>, Vec<u32>)> {
+    let (mut tokens, mut fees) = decode_v3_path(path)?;
+    tokens.reverse();
+    fees.reverse();
+    Some((tokens, fees))
+}
+
+fn exact_in(amount_in: U256, amount_out_min: U256) -> SwapAmount {
+    SwapAmount::ExactIn {
+        amount_in,
+        amount_out_min,
+    }
+}
+
+fn exact_out(amount_out: U256, amount_in_max: U256) -> SwapAmount {
+    SwapAmount::ExactOut {
+        amount_out,
+        amount_in_max,
+    }
+}
+
+/// A UniswapV2Router02 swap, `value` is the ETH sent with the call.
+pub fn decode_v2(input: &[u8], value: U256) -> Option<NormalizedSwap> {
+    use IUniswapV2Router02Calls::*;
+
+    let (function, path, amount, recipient, deadline) =
+        match IUniswapV2Router02Calls::decode(input).ok()? {
+            SwapExactTokensForTokens(c) => (
+                "swapExactTokensForTokens",
+                c.path,
+                exact_in(c.amount_in, c.amount_out_min),
+                c.to,
+                c.deadline,
+            ),
+            SwapTokensForExactTokens(c) => (
+                "swapTokensForExactTokens",
+                c.path,
+                exact_out(c.amount_out, c.amount_in_max),
+                c.to,
+                c.deadline,
+            ),
+            SwapExactETHForTokens(c) => (
+                "swapExactETHForTokens",
+                c.path,
+                exact_in(value, c.amount_out_min),
+                c.to,
+                c.deadline,
+            ),
+            SwapExactETHForTokensSupportingFeeOnTransferTokens(c) => (
+                "swapExactETHForTokensSupportingFeeOnTransferTokens",
+                c.path,
+                exact_in(value, c.amount_out_min),
+                c.to,
+                c.deadline,
+            ),
+            SwapETHForExactTokens(c) => (
+                "swapETHForExactTokens",
+                c.path,
+                exact_out(c.amount_out, value),
+                c.to,
+                c.deadline,
+            ),
+            SwapExactTokensForETH(c) => (
+                "swapExactTokensForETH",
+                c.path,
+                exact_in(c.amount_in, c.amount_out_min),
+                c.to,
+                c.deadline,
+            ),
+            SwapTokensForExactETH(c) => (
+                "swapTokensForExactETH",
+                c.path,
+                exact_out(c.amount_out, c.amount_in_max),
+                c.to,
+                c.deadline,
+            ),
+        };
+    if path.len() < 2 {
+        return None;
+    }
+    Some(NormalizedSwap {
+        function,
+        protocol: Protocol::V2,
+        path,
+        fees: vec![],
+        amount,
+        recipient,
+        deadline: Some(deadline),
+    })
+}
+
+fn v3_swap(
+    function: &'static str,
+    (path, fees): (Vec<Address>, Vec<u32>),
+    amount: SwapAmount,
+    recipient: Address,
+    deadline: Option<U256>,
+) -> NormalizedSwap {
+    NormalizedSwap {
+        function,
+        protocol: Protocol::V3,
+        path,
+        fees,
+        amount,
+        recipient,
+        deadline,
+    }
+}
+
+fn decode_swap_router(input: &[u8]) -> Option<Vec<NormalizedSwap>> {
+    use i_swap_router::*;
+
+    let swap = match ISwapRouterCalls::decode(input).ok()? {
+        ISwapRouterCalls::ExactInputSingle(ExactInputSingleCall { params: p }) => v3_swap(
+            "exactInputSingle",
+            (vec![p.token_in, p.token_out], vec![p.fee]),
+            exact_in(p.amount_in, p.amount_out_minimum),
+            p.recipient,
+            Some(p.deadline),
+        ),
+        ISwapRouterCalls::ExactInput(ExactInputCall { params: p }) => v3_swap(
+            "exactInput",
+            decode_v3_path(&p.path)?,
+            exact_in(p.amount_in, p.amount_out_minimum),
+            p.recipient,
+            Some(p.deadline),
+        ),
+        ISwapRouterCalls::ExactOutputSingle(ExactOutputSingleCall { params: p }) => v3_swap(
+            "exactOutputSingle",
+            (vec![p.token_in, p.token_out], vec![p.fee]),
+            exact_out(p.amount_out, p.amount_in_maximum),
+            p.recipient,
+            Some(p.deadline),
+        ),
+        ISwapRouterCalls::ExactOutput(ExactOutputCall { params: p }) => v3_swap(
+            "exactOutput",
+            decode_v3_path_reversed(&p.path)?,
+            exact_out(p.amount_out, p.amount_in_maximum),
+            p.recipient,
+            Some(p.deadline),
+        ),
+        ISwapRouterCalls::Multicall(MulticallCall { data }) => {
+            return Some(data.iter().flat_map(|call| decode_v3(call)).collect())
+        }
+    };
+    Some(vec![swap])
+}
+
+fn decode_swap_router_02(input: &[u8], deadline: Option<U256>) -> Option<Vec<NormalizedSwap>> {
+    use i_swap_router_02::*;
+
+    let swap = match ISwapRouter02Calls::decode(input).ok()? {
+        ISwapRouter02Calls::ExactInputSingle(ExactInputSingleCall { params: p }) => v3_swap(
+            "exactInputSingle",
+            (vec![p.token_in, p.token_out], vec![p.fee]),
+            exact_in(p.amount_in, p.amount_out_minimum),
+            p.recipient,
+            deadline,
+        ),
+        ISwapRouter02Calls::ExactInput(ExactInputCall { params: p }) => v3_swap(
+            "exactInput",
+            decode_v3_path(&p.path)?,
+            exact_in(p.amount_in, p.amount_out_minimum),
+            p.recipient,
+            deadline,
+        ),
+        ISwapRouter02Calls::ExactOutputSingle(ExactOutputSingleCall { params: p }) => v3_swap(
+            "exactOutputSingle",
+            (vec![p.token_in, p.token_out], vec![p.fee]),
+            exact_out(p.amount_out, p.amount_in_maximum),
+            p.recipient,
+            deadline,
+        ),
+        ISwapRouter02Calls::ExactOutput(ExactOutputCall { params: p }) => v3_swap(
+            "exactOutput",
+            decode_v3_path_reversed(&p.path)?,
+            exact_out(p.amount_out, p.amount_in_maximum),
+            p.recipient,
+            deadline,
+        ),
+        ISwapRouter02Calls::Multicall(MulticallCall { deadline, data }) => {
+            return Some(
+                data.iter()
+                    .filter_map(|call| decode_swap_router_02(call, Some(deadline)))
+                    .flatten()
+                    .collect(),
+            )
+        }
+        ISwapRouter02Calls::MulticallWithPreviousBlockhash(
+            MulticallWithPreviousBlockhashCall { data, .. },
+        ) => {
+            return Some(
+                data.iter()
+                    .filter_map(|call| decode_swap_router_02(call, deadline))
+                    .flatten()
+                    .collect(),
+            )
+        }
+    };
+    Some(vec![swap])
+}
+
+/// The V3 swaps of a SwapRouter or SwapRouter02 call, `multicall` batches
+/// may hold several. Empty for anything else.
+pub fn decode_v3(input: &[u8]) -> Vec<NormalizedSwap> {
+    decode_swap_router(input)
+        .or_else(|| decode_swap_router_02(input, None))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::abi::AbiEncode;
+
+    fn packed_path(tokens: &[Address], fees: &[u32]) -> Bytes {
+        let mut path = tokens[0].as_bytes().to_vec();
+        for (fee, token) in fees.iter().zip(&tokens[1..]) {
+            path.extend_from_slice(&fee.to_be_bytes()[1..]);
+            path.extend_from_slice(token.as_bytes());
+        }
+        path.into()
+    }
+
+    // selectors of the deployed routers
+    #[test]
+    fn test_selectors() {
+        let selector = |call: Vec<u8>| hex::encode(&call[..4]);
+        let params = i_swap_router::ExactInputSingleParams::default();
+        assert_eq!(
+            "414bf389",
+            selector(i_swap_router::ExactInputSingleCall { params }.encode())
+        );
+        let params = i_swap_router::ExactOutputParams::default();
+        assert_eq!(
+            "f28c0498",
+            selector(i_swap_router::ExactOutputCall { params }.encode())
+        );
+        let params = i_swap_router_02::ExactInputSingleParams::default();
+        assert_eq!(
+            "04e45aaf",
+            selector(i_swap_router_02::ExactInputSingleCall { params }.encode())
+        );
+        let params = i_swap_router_02::ExactOutputParams::default();
+        assert_eq!(
+            "09b81346",
+            selector(i_swap_router_02::ExactOutputCall { params }.encode())
+        );
+        assert_eq!(
+            "5ae401dc",
+            selector(i_swap_router_02::MulticallCall::default().encode())
+        );
+    }
+
+    #[test]
+    fn test_decode_v3() {
+        let (weth, usdc, dai) = (
+            Address::repeat_byte(1),
+            Address::repeat_byte(2),
+            Address::repeat_byte(3),
+        );
+        let recipient = Address::repeat_byte(9);
+
+        // exact output paths run backwards
+        let call = i_swap_router::ExactOutputCall {
+            params: i_swap_router::ExactOutputParams {
+                path: packed_path(&[dai, usdc, weth], &[100, 500]),
+                recipient,
+                deadline: U256::from(1_700_000_000),
+                amount_out: U256::exp10(21),
+                amount_in_maximum: U256::exp10(18),
+            },
+        };
+        let swaps = decode_v3(&call.encode());
+        assert_eq!(1, swaps.len());
+        assert_eq!(vec![weth, usdc, dai], swaps[0].path);
+        assert_eq!(vec![500, 100], swaps[0].fees);
+        assert_eq!(exact_out(U256::exp10(21), U256::exp10(18)), swaps[0].amount);
+        assert_eq!(Some(U256::from(1_700_000_000)), swaps[0].deadline);
+
+        // SwapRouter02 batches carry the deadline for every swap
+        let single = i_swap_router_02::ExactInputSingleCall {
+            params: i_swap_router_02::ExactInputSingleParams {
+                token_in: weth,
+                token_out: usdc,
+                fee: 3000,
+                recipient,
+                amount_in: U256::exp10(18),
+                amount_out_minimum: U256::from(1_900_000_000u64),
+                sqrt_price_limit_x96: U256::zero(),
+            },
+        };
+        let call = i_swap_router_02::MulticallCall {
+            deadline: U256::from(42),
+            data: vec![single.encode().into(), vec![0xde, 0xad].into()],
+        };
+        let swaps = decode_v3(&call.encode());
+        assert_eq!(1, swaps.len());
+        assert_eq!("exactInputSingle", swaps[0].function);
+        assert_eq!((weth, usdc), (swaps[0].token_in(), swaps[0].token_out()));
+        assert_eq!(vec![3000], swaps[0].fees);
+        assert_eq!(Some(U256::from(42)), swaps[0].deadline);
+
+        assert_eq!(None, decode_v3_path(&[0u8; 42]));
+        assert!(decode_v3(&[0xde, 0xad, 0xbe, 0xef]).is_empty());
+    }
+}
@@ -0,0 +1,298 @@
+//! Token symbols and decimals for readable amounts.
+//!
+//! The registry starts from the bundled token list, adds the tokens cached by
+//! earlier runs and fetches the rest from the token contract.
+
+use crate::env_store::{get_env_or, EnvError};
+use crate::utils::multicall::CallBatcher;
+
+use ethers::{abi::AbiDecode, prelude::*, utils::id};
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+const BUNDLED_LIST: &str = include_str!("tokenlist.json");
+
+#[derive(Debug)]
+pub enum TokenError {
+    EnvError(EnvError),
+    IoError(PathBuf, std::io::Error),
+    JsonError(PathBuf, serde_json::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenInfo {
+    pub address: Address,
+    pub symbol: String,
+    pub name: String,
+    pub decimals: u8,
+}
+
+impl TokenInfo {
+    /// Placeholder for a token whose metadata could not be read.
+    pub fn unknown(address: Address) -> Self {
+        let hex = format!("{:?}", address);
+        Self {
+            address,
+            symbol: format!("{}..{}", &hex[..6], &hex[hex.len() - 4..]),
+            name: String::new(),
+            decimals: 18,
+        }
+    }
+
+    /// `amount` in whole tokens, `1.5 WETH`.
+    pub fn format(&self, amount: U256) -> String {
+        format!("{} {}", format_units(amount, self.decimals), self.symbol)
+    }
+}
+
+/// `amount` scaled down by `decimals`, without trailing zeros.
+pub fn format_units(amount: U256, decimals: u8) -> String {
+    let formatted = ethers::utils::format_units(amount, decimals as u32).unwrap_or_default();
+    match formatted.split_once('.') {
+        Some((whole, fraction)) => {
+            let fraction = fraction.trim_end_matches('0');
+            if fraction.is_empty() {
+                whole.to_string()
+            } else {
+                format!("{}.{}", whole, fraction)
+            }
+        }
+        None => formatted,
+    }
+}
+
+/// [`format_units`] of a signed amount.
+pub fn format_signed_units(amount: I256, decimals: u8) -> String {
+    let units = format_units(amount.unsigned_abs(), decimals);
+    if amount.is_negative() {
+        format!("-{}", units)
+    } else {
+        units
+    }
+}
+
+// token list layout, https://tokenlists.org
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TokenList {
+    tokens: Vec<TokenInfo>,
+}
+
+// ERC20 `symbol()` and `name()` return a string, a few old tokens (MKR)
+// return a bytes32
+pub(crate) fn decode_text(data: &[u8]) -> Option<String> {
+    if let Ok(text) = String::decode(data) {
+        return Some(text);
+    }
+    if data.len() == 32 {
+        let end = data.iter().position(|b| *b == 0).unwrap_or(32);
+        return String::from_utf8(data[..end].to_vec()).ok();
+    }
+    None
+}
+
+async fn call<M: Middleware>(provider: &M, token: Address, signature: &str) -> Option<Bytes> {
+    let tx = TransactionRequest::new()
+        .to(token)
+        .data(id(signature).to_vec());
+    provider.call(&tx.into(), None).await.ok()
+}
+
+/// Token metadata, shared between tasks.
+#[derive(Debug, Default)]
+pub struct TokenRegistry {
+    tokens: RwLock<HashMap<Address, TokenInfo>>,
+    // where fetched tokens are cached between runs
+    path: Option<PathBuf>,
+    // held from the snapshot to the rename, concurrent saves would share
+    // the tmp file and an older snapshot could land last
+    save_lock: Mutex<()>,
+}
+
+impl TokenRegistry {
+    /// Only the bundled token list, nothing is persisted.
+    pub fn bundled() -> Self {
+        let list: TokenList = serde_json::from_str(BUNDLED_LIST).unwrap();
+        let registry = Self::default();
+        registry.extend(list.tokens);
+        registry
+    }
+
+    /// Bundled tokens plus the ones cached at `path`, new tokens are written
+    /// back to it.
+    pub fn load(path: &Path) -> Result<Self, TokenError> {
+        let mut registry = Self::bundled();
+        if path.exists() {
+            let content =
+                fs::read_to_string(path).map_err(|e| TokenError::IoError(path.to_path_buf(), e))?;
+            let list: TokenList = serde_json::from_str(&content)
+                .map_err(|e| TokenError::JsonError(path.to_path_buf(), e))?;
+            registry.extend(list.tokens);
+        }
+        registry.path = Some(path.to_path_buf());
+        Ok(registry)
+    }
+
+    pub fn from_env() -> Result<Self, TokenError> {
+        let path: PathBuf = get_env_or("TOKEN_CACHE_PATH", PathBuf::from("tokens.json"))
+            .map_err(TokenError::EnvError)?;
+        Self::load(&path)
+    }
+
+    pub fn extend(&self, tokens: impl IntoIterator<Item = TokenInfo>) {
+        let mut map = self.tokens.write().unwrap();
+        for token in tokens {
+            map.insert(token.address, token);
+        }
+    }
+
+    pub fn get(&self, token: Address) -> Option<TokenInfo> {
+        self.tokens.read().unwrap().get(&token).cloned()
+    }
+
+    /// Known metadata or the [`TokenInfo::unknown`] placeholder.
+    pub fn info(&self, token: Address) -> TokenInfo {
+        self.get(token).unwrap_or_else(|| TokenInfo::unknown(token))
+    }
+
+    pub fn format(&self, token: Address, amount: U256) -> String {
+        self.info(token).format(amount)
+    }
+
+    /// Metadata of `token`, read from the contract the first time it is seen.
+    pub async fn resolve<M: Middleware>(&self, provider: &M, token: Address) -> TokenInfo {
+        if let Some(info) = self.get(token) {
+            return info;
+        }
+
+        let decimals = call(provider, token, "decimals()")
+            .await
+            .and_then(|data| U256::decode(data).ok())
+            .filter(|decimals| *decimals <= U256::from(u8::MAX));
+        let symbol = call(provider, token, "symbol()")
+            .await
+            .and_then(|data| decode_text(&data));
+        let (Some(decimals), Some(symbol)) = (decimals, symbol) else {
+            // not cached, the token may not be deployed yet
+            debug!("no ERC20 metadata for {:?}", token);
+            return TokenInfo::unknown(token);
+        };
+        let name = call(provider, token, "name()")
+            .await
+            .and_then(|data| decode_text(&data))
+            .unwrap_or_default();
+
+        let info = TokenInfo {
+            address: token,
+            symbol,
+            name,
+            decimals: decimals.as_u32() as u8,
+        };
+        self.extend([info.clone()]);
+        if let Err(e) = self.save() {
+            error!("saving token cache failed: {:?}", e);
+        }
+        info
+    }
+
+    /// Metadata of every token not known yet, read in Multicall3 batches.
+    /// Tokens without metadata are left out, like in `resolve`.
+    pub async fn resolve_all<M: Middleware + 'static>(
+        &self,
+        batcher: &CallBatcher<M>,
+        tokens: &[Address],
+    ) {
+        let mut unknown: Vec<Address> = tokens
+            .iter()
+            .copied()
+            .filter(|token| self.get(*token).is_none())
+            .collect();
+        unknown.sort();
+        unknown.dedup();
+        if unknown.is_empty() {
+            return;
+        }
+        match batcher.token_metadata(&unknown).await {
+            Ok(infos) => self.extend(infos.into_iter().flatten()),
+            Err(e) => {
+                error!("token metadata batch failed: {:?}", e);
+                return;
+            }
+        }
+        if let Err(e) = self.save() {
+            error!("saving token cache failed: {:?}", e);
+        }
+    }
+
+    /// Write every known token to the cache file, if any.
+    pub fn save(&self) -> Result<(), TokenError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let _saving = self.save_lock.lock().unwrap();
+        let mut tokens: Vec<TokenInfo> = self.tokens.read().unwrap().values().cloned().collect();
+        tokens.sort_by_key(|token| token.address);
+        let content = serde_json::to_string_pretty(&TokenList { tokens })
+            .map_err(|e| TokenError::JsonError(path.clone(), e))?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, content)
+            .and_then(|_| fs::rename(&tmp, path))
+            .map_err(|e| TokenError::IoError(path.clone(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::abi::AbiEncode;
+
+    #[test]
+    fn test_format() {
+        let registry = TokenRegistry::bundled();
+        let usdc = "A0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48"
+            .parse::<Address>()
+            .unwrap();
+        assert_eq!("1.5 USDC", registry.format(usdc, U256::from(1_500_000)));
+
+        let weth = "C02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"
+            .parse::<Address>()
+            .unwrap();
+        assert_eq!("10 WETH", registry.format(weth, U256::exp10(19)));
+
+        let unknown = registry.info(Address::repeat_byte(0xab));
+        assert_eq!("0xabab..abab", unknown.symbol);
+    }
+
+    #[test]
+    fn test_decode_text() {
+        assert_eq!(
+            Some("PEPE".to_string()),
+            decode_text(&"PEPE".to_string().encode())
+        );
+        let mut mkr = [0u8; 32];
+        mkr[..3].copy_from_slice(b"MKR");
+        assert_eq!(Some("MKR".to_string()), decode_text(&mkr));
+        assert_eq!(None, decode_text(&[1, 2, 3]));
+    }
+
+    #[test]
+    fn test_cache_round_trip() {
+        let path = std::env::temp_dir().join(format!("unibot-tokens-{}.json", std::process::id()));
+        let registry = TokenRegistry::load(&path).unwrap();
+        let token = TokenInfo {
+            address: Address::repeat_byte(7),
+            symbol: "SEVEN".to_string(),
+            name: "Seven".to_string(),
+            decimals: 9,
+        };
+        registry.extend([token.clone()]);
+        registry.save().unwrap();
+
+        let reloaded = TokenRegistry::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(Some(token), reloaded.get(Address::repeat_byte(7)));
+    }
+}
@@ -0,0 +1,547 @@
+//! Terminal dashboard of the watcher, one more subscriber of the event bus.
+//!
+//! Keys: `/` edits the token/pair filter, `Enter` applies it, `Esc` clears
+//! it and `q` quits.
+
+use crate::utils::events::BotEvent;
+use crate::utils::tokens::{self, TokenRegistry};
+use crate::utils::univ2;
+
+use ethers::prelude::*;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Row, Table};
+use ratatui::Frame;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::{self, error::TryRecvError};
+
+const MAX_VICTIMS: usize = 200;
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+// a head older than this means the node stopped feeding us
+const STALE_AFTER: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VictimRow {
+    pub tx_hash: H256,
+    pub token: Address,
+    pub symbol: String,
+    pub pair: Address,
+    pub amount_in: U256,
+    // amount_in in whole tokens with its symbol
+    pub amount: String,
+    pub slippage_bps: Option<U256>,
+    pub optimal_in: Option<U256>,
+    pub revenue: Option<U256>,
+    pub profit: Option<I256>,
+    // "ok" or the rejection reason
+    pub status: &'static str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    Waiting,
+    Ok,
+    Stale,
+    Down,
+}
+
+pub struct DashboardState {
+    weth: Address,
+    tokens: Arc<TokenRegistry>,
+    pending_total: u64,
+    pending_window: VecDeque<Instant>,
+    router_hits: BTreeMap<String, u64>,
+    decode_failures: u64,
+    victims: VecDeque<VictimRow>,
+    last_block: Option<(U64, Instant)>,
+    disconnected: Option<String>,
+    found: u64,
+    rejected: u64,
+    arbitrages: u64,
+    revenue: U256,
+    gas_cost: U256,
+    profit: I256,
+    profit_usd: f64,
+    // found sandwiches valued with a thin pool
+    unreliable: u64,
+    pub filter: String,
+    pub editing: bool,
+}
+
+impl DashboardState {
+    pub fn new(weth: Address, tokens: Arc<TokenRegistry>) -> Self {
+        Self {
+            weth,
+            tokens,
+            pending_total: 0,
+            pending_window: VecDeque::new(),
+            router_hits: BTreeMap::new(),
+            decode_failures: 0,
+            victims: VecDeque::new(),
+            last_block: None,
+            disconnected: None,
+            found: 0,
+            rejected: 0,
+            arbitrages: 0,
+            revenue: U256::zero(),
+            gas_cost: U256::zero(),
+            profit: I256::zero(),
+            profit_usd: 0.0,
+            unreliable: 0,
+            filter: String::new(),
+            editing: false,
+        }
+    }
+
+    pub fn apply(&mut self, event: &BotEvent, now: Instant) {
+        match event {
+            BotEvent::PendingTx(_) => {
+                self.pending_total += 1;
+                self.pending_window.push_back(now);
+            }
+            BotEvent::RouterTx {
+                selector, function, ..
+            } => {
+                let key = format!("0x{} {}", hex::encode(selector), function);
+                *self.router_hits.entry(key).or_default() += 1;
+            }
+            BotEvent::DecodeFailure(_) => self.decode_failures += 1,
+            BotEvent::NewBlock { number, .. } => {
+                self.last_block = Some((*number, now));
+                self.disconnected = None;
+            }
+            BotEvent::Disconnected(reason) => self.disconnected = Some(reason.clone()),
+            BotEvent::Arbitrage(arbitrage) => {
+                self.arbitrages += 1;
+                if let Some(value) = &arbitrage.profit_value {
+                    self.profit_usd += value.usd.unwrap_or_default();
+                    if !value.reliable {
+                        self.unreliable += 1;
+                    }
+                }
+            }
+            BotEvent::Cycle(cycle) => {
+                self.arbitrages += 1;
+                if let Some(value) = &cycle.profit_value {
+                    self.profit_usd += value.usd.unwrap_or_default();
+                    if !value.reliable {
+                        self.unreliable += 1;
+                    }
+                }
+            }
+            BotEvent::Victim(victim) => {
+                let token = if victim.token_in == self.weth {
+                    victim.token_out
+                } else {
+                    victim.token_in
+                };
+                let mut row = VictimRow {
+                    tx_hash: victim.tx_hash,
+                    token,
+                    symbol: self.tokens.info(token).symbol,
+                    pair: victim.pair,
+                    amount_in: victim.amount_in,
+                    amount: match &victim.amount_in_value {
+                        Some(value) if value.usd.is_some() => format!(
+                            "{} ({})",
+                            self.tokens.format(victim.token_in, victim.amount_in),
+                            value.usd_label()
+                        ),
+                        _ => self.tokens.format(victim.token_in, victim.amount_in),
+                    },
+                    slippage_bps: None,
+                    optimal_in: None,
+                    revenue: None,
+                    profit: None,
+                    status: "ok",
+                };
+                match &victim.outcome {
+                    Ok(state) => {
+                        self.found += 1;
+                        self.revenue += state.revenue;
+                        self.gas_cost += state.gas_cost;
+                        self.profit += state.profit;
+                        if let Some(value) = &victim.profit_value {
+                            self.profit_usd += value.usd.unwrap_or_default();
+                            if !value.reliable {
+                                self.unreliable += 1;
+                            }
+                        }
+                        // slack the victim left against the untouched pair
+                        row.slippage_bps = univ2::get_univ2_data_given_in(
+                            state.user_amount_in,
+                            state.reserve_state.0,
+                            state.reserve_state.1,
+                        )
+                        .ok()
+                        .filter(|quote| !quote.amount_out.is_zero())
+                        .map(|quote| {
+                            quote.amount_out.saturating_sub(state.user_min_recv) * univ2::BPS
+                                / quote.amount_out
+                        });
+                        row.optimal_in = Some(state.optimal_sandwich_weth_in);
+                        row.revenue = Some(state.revenue);
+                        row.profit = Some(state.profit);
+                    }
+                    Err(rejection) => {
+                        self.rejected += 1;
+                        row.status = rejection.reason();
+                    }
+                }
+                if self.victims.len() == MAX_VICTIMS {
+                    self.victims.pop_back();
+                }
+                self.victims.push_front(row);
+            }
+        }
+        while self
+            .pending_window
+            .front()
+            .is_some_and(|seen| now.duration_since(*seen) > RATE_WINDOW)
+        {
+            self.pending_window.pop_front();
+        }
+    }
+
+    /// Pending txs per second over the last few seconds.
+    pub fn pending_rate(&self) -> f64 {
+        self.pending_window.len() as f64 / RATE_WINDOW.as_secs_f64()
+    }
+
+    pub fn health(&self, now: Instant) -> Health {
+        if self.disconnected.is_some() {
+            return Health::Down;
+        }
+        match self.last_block {
+            None => Health::Waiting,
+            Some((_, seen)) if now.duration_since(seen) > STALE_AFTER => Health::Stale,
+            Some(_) => Health::Ok,
+        }
+    }
+
+    /// Victims matching the filter on token symbol, token or pair address,
+    /// newest first.
+    pub fn visible_victims(&self) -> impl Iterator<Item = &VictimRow> {
+        let filter = self.filter.to_lowercase();
+        self.victims.iter().filter(move |row| {
+            filter.is_empty()
+                || row.symbol.to_lowercase().contains(&filter)
+                || format!("{:?}", row.token).contains(&filter)
+                || format!("{:?}", row.pair).contains(&filter)
+        })
+    }
+
+    /// Handle a key press, `false` when the dashboard should close.
+    pub fn on_key(&mut self, code: KeyCode) -> bool {
+        if self.editing {
+            match code {
+                KeyCode::Char(c) => self.filter.push(c),
+                KeyCode::Backspace => {
+                    self.filter.pop();
+                }
+                KeyCode::Enter => self.editing = false,
+                KeyCode::Esc => {
+                    self.filter.clear();
+                    self.editing = false;
+                }
+                _ => {}
+            }
+            return true;
+        }
+        match code {
+            KeyCode::Char('q') => return false,
+            KeyCode::Char('/') => self.editing = true,
+            KeyCode::Esc => self.filter.clear(),
+            _ => {}
+        }
+        true
+    }
+}
+
+fn eth(wei: U256) -> String {
+    tokens::format_units(wei, 18)
+}
+
+fn draw(frame: &mut Frame, state: &DashboardState, now: Instant) {
+    let [top, hits, victims, footer] = Layout::vertical([
+        Constraint::Length(7),
+        Constraint::Length(10),
+        Constraint::Min(5),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+    let [mempool, health, pnl] = Layout::horizontal([Constraint::Ratio(1, 3); 3]).areas(top);
+
+    frame.render_widget(
+        Paragraph::new(vec![
+            Line::from(format!("pending txs    {}", state.pending_total)),
+            Line::from(format!("rate           {:.1}/s", state.pending_rate())),
+            Line::from(format!("decode misses  {}", state.decode_failures)),
+        ])
+        .block(Block::bordered().title("Mempool")),
+        mempool,
+    );
+
+    let (status, color) = match state.health(now) {
+        Health::Waiting => ("WAITING", Color::Yellow),
+        Health::Ok => ("OK", Color::Green),
+        Health::Stale => ("STALE", Color::Yellow),
+        Health::Down => ("DOWN", Color::Red),
+    };
+    let mut lines = vec![Line::from(status).style(Style::new().fg(color).bold())];
+    if let Some((number, seen)) = state.last_block {
+        lines.push(Line::from(format!(
+            "block {} ({}s ago)",
+            number,
+            now.duration_since(seen).as_secs()
+        )));
+    }
+    if let Some(reason) = &state.disconnected {
+        lines.push(Line::from(reason.as_str()));
+    }
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title("Connection")),
+        health,
+    );
+
+    frame.render_widget(
+        Paragraph::new(vec![
+            Line::from(format!(
+                "found {} / rejected {} / arbitrage {}",
+                state.found, state.rejected, state.arbitrages
+            )),
+            Line::from(format!("revenue  {} ETH", eth(state.revenue))),
+            Line::from(format!("gas      {} ETH", eth(state.gas_cost))),
+            Line::from(format!(
+                "profit   {} ETH",
+                tokens::format_signed_units(state.profit, 18)
+            )),
+            Line::from(format!(
+                "         ${:.2} ({} unreliable)",
+                state.profit_usd, state.unreliable
+            )),
+        ])
+        .block(Block::bordered().title("PnL (estimated)")),
+        pnl,
+    );
+
+    let rows = state
+        .router_hits
+        .iter()
+        .map(|(function, count)| Row::new(vec![function.clone(), count.to_string()]));
+    frame.render_widget(
+        Table::new(rows, [Constraint::Min(40), Constraint::Length(10)])
+            .header(Row::new(vec!["selector", "hits"]).bold())
+            .block(Block::bordered().title("Router hits")),
+        hits,
+    );
+
+    let rows = state.visible_victims().map(|row| {
+        let dash = || "-".to_string();
+        Row::new(vec![
+            format!("{:?}", row.tx_hash),
+            row.symbol.clone(),
+            row.amount.clone(),
+            row.slippage_bps
+                .map(|bps| format!("{:.2}%", bps.as_u64() as f64 / 100.0))
+                .unwrap_or_else(dash),
+            row.optimal_in.map(eth).unwrap_or_else(dash),
+            row.revenue.map(eth).unwrap_or_else(dash),
+            row.status.to_string(),
+        ])
+    });
+    frame.render_widget(
+        Table::new(
+            rows,
+            [
+                Constraint::Length(66),
+                Constraint::Length(14),
+                Constraint::Length(34),
+                Constraint::Length(9),
+                Constraint::Length(14),
+                Constraint::Length(14),
+                Constraint::Min(10),
+            ],
+        )
+        .header(
+            Row::new(vec![
+                "victim",
+                "token",
+                "amount in",
+                "slippage",
+                "frontrun ETH",
+                "revenue ETH",
+                "status",
+            ])
+            .bold(),
+        )
+        .block(Block::bordered().title("Candidate victims")),
+        victims,
+    );
+
+    let help = if state.editing {
+        format!("filter: {}_  (enter apply, esc clear)", state.filter)
+    } else if state.filter.is_empty() {
+        "/ filter by token or pair   q quit".to_string()
+    } else {
+        format!("filter: {}  (/ edit, esc clear, q quit)", state.filter)
+    };
+    frame.render_widget(Paragraph::new(help), footer);
+}
+
+/// Run the dashboard on the current terminal until `q`, blocking.
+pub fn run(
+    mut receiver: broadcast::Receiver<BotEvent>,
+    weth: Address,
+    tokens: Arc<TokenRegistry>,
+) -> std::io::Result<()> {
+    let mut terminal = ratatui::init();
+    let mut state = DashboardState::new(weth, tokens);
+
+    let result = loop {
+        loop {
+            match receiver.try_recv() {
+                Ok(event) => state.apply(&event, Instant::now()),
+                Err(TryRecvError::Lagged(_)) => continue,
+                Err(TryRecvError::Empty) | Err(TryRecvError::Closed) => break,
+            }
+        }
+
+        let now = Instant::now();
+        if let Err(e) = terminal.draw(|frame| draw(frame, &state, now)) {
+            break Err(e);
+        }
+
+        match event::poll(Duration::from_millis(200)) {
+            Ok(true) => match event::read() {
+                Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                    if !state.on_key(key.code) {
+                        break Ok(());
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => break Err(e),
+            },
+            Ok(false) => {}
+            Err(e) => break Err(e),
+        }
+    };
+
+    ratatui::restore();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::events::VictimEvent;
+    use crate::utils::rejection::Rejection;
+    use crate::utils::univ2::TokenTaxes;
+
+    fn weth() -> Address {
+        Address::repeat_byte(0xee)
+    }
+
+    fn victim(token: Address, outcome: Result<univ2::SandWichState, Rejection>) -> BotEvent {
+        BotEvent::Victim(Box::new(VictimEvent::new(
+            H256::random(),
+            weth(),
+            token,
+            U256::exp10(19),
+            outcome,
+        )))
+    }
+
+    #[test]
+    fn test_apply_events() {
+        let now = Instant::now();
+        let mut state = DashboardState::new(weth(), Arc::new(TokenRegistry::bundled()));
+        assert_eq!(Health::Waiting, state.health(now));
+
+        state.apply(&BotEvent::PendingTx(H256::zero()), now);
+        state.apply(
+            &BotEvent::RouterTx {
+                tx_hash: H256::zero(),
+                selector: [0x7f, 0xf3, 0x6a, 0xb5],
+                function: "swapExactETHForTokens",
+            },
+            now,
+        );
+        state.apply(
+            &BotEvent::NewBlock {
+                number: 1.into(),
+                timestamp: U256::zero(),
+            },
+            now,
+        );
+        assert_eq!(
+            Some(&1),
+            state.router_hits.get("0x7ff36ab5 swapExactETHForTokens")
+        );
+        assert_eq!(Health::Ok, state.health(now));
+        assert_eq!(Health::Stale, state.health(now + STALE_AFTER * 2));
+
+        // rate only counts the recent window
+        state.apply(&BotEvent::PendingTx(H256::zero()), now + RATE_WINDOW * 2);
+        assert_eq!(2, state.pending_total);
+        assert_eq!(0.1, state.pending_rate());
+
+        let sandwich = univ2::get_sandwitch_state(
+            U256::exp10(18),
+            U256::exp10(19),
+            univ2::MinRecv::PairOut(U256::exp10(21) * 170),
+            U256::exp10(20),
+            U256::exp10(24) * 2,
+            TokenTaxes::default(),
+            U256::exp10(15),
+        )
+        .unwrap();
+        state.apply(
+            &victim(Address::repeat_byte(0xab), Ok(sandwich.clone())),
+            now,
+        );
+        state.apply(
+            &victim(Address::repeat_byte(0xcd), Err(Rejection::NoBlockYet)),
+            now,
+        );
+        assert_eq!((1, 1), (state.found, state.rejected));
+        assert_eq!(sandwich.profit, state.profit);
+
+        let row = state.visible_victims().last().unwrap();
+        assert_eq!(Address::repeat_byte(0xab), row.token);
+        assert!(row.slippage_bps.unwrap() > U256::zero());
+    }
+
+    #[test]
+    fn test_filter_keys() {
+        let now = Instant::now();
+        let mut state = DashboardState::new(weth(), Arc::new(TokenRegistry::bundled()));
+        state.apply(
+            &victim(Address::repeat_byte(0xab), Err(Rejection::NoBlockYet)),
+            now,
+        );
+        state.apply(
+            &victim(Address::repeat_byte(0xcd), Err(Rejection::NoBlockYet)),
+            now,
+        );
+        assert_eq!(2, state.visible_victims().count());
+
+        assert!(state.on_key(KeyCode::Char('/')));
+        for c in "0xABAB".chars() {
+            state.on_key(KeyCode::Char(c));
+        }
+        state.on_key(KeyCode::Enter);
+        assert!(!state.editing);
+        let visible: Vec<_> = state.visible_victims().collect();
+        assert_eq!(1, visible.len());
+        assert_eq!(Address::repeat_byte(0xab), visible[0].token);
+
+        state.on_key(KeyCode::Esc);
+        assert_eq!(2, state.visible_victims().count());
+        assert!(!state.on_key(KeyCode::Char('q')));
+    }
+}
@@ -1,25 +1,74 @@
+use crate::utils::amm::{self, AmmError, SwapOutcome};
+use crate::utils::rejection::Rejection;
+
 use ethers::prelude::*;
 use ethers::types::Address;
 use ethers::utils::keccak256;
-use hex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::RwLock;
 
 abigen!(
     IUniswapV2Pair,
     r#"[
         function getReserves() external view returns (uint112 reserve0, uint112 reserve1, uint32 blockTimestampLast)
+        function token0() external view returns (address)
+        function token1() external view returns (address)
+        event Swap(address indexed sender, uint amount0In, uint amount1In, uint amount0Out, uint amount1Out, address indexed to)
+        event Sync(uint112 reserve0, uint112 reserve1)
     ]"#,
 );
 
-#[derive(Clone)]
+pub const BPS: u64 = 10_000;
+
+/// Transfer taxes of a fee-on-transfer token, in basis points of the amount
+/// sent. Buy tax is taken on the way out of the pair, sell tax on the way in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TokenTaxes {
+    pub buy_tax_bps: u64,
+    pub sell_tax_bps: u64,
+}
+
+/// The victim's bound on what it buys from the pair we sandwich, and what
+/// the router compares it with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MinRecv {
+    // `swapExactETHForTokens`: the pair's output, before the transfer tax
+    PairOut(U256),
+    // `...SupportingFeeOnTransferTokens`: what reached the recipient
+    Received(U256),
+}
+
+impl MinRecv {
+    pub fn amount(&self) -> U256 {
+        match self {
+            MinRecv::PairOut(amount) | MinRecv::Received(amount) => *amount,
+        }
+    }
+
+    // buy tax taken before the router looks at the victim's output
+    fn tax_bps(&self, taxes: TokenTaxes) -> u64 {
+        match self {
+            MinRecv::PairOut(_) => 0,
+            MinRecv::Received(_) => taxes.buy_tax_bps,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct PairState {
     pub amount_out: U256,
     pub new_reserve_a: U256,
     pub new_reserve_b: U256,
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct SandWichState {
     pub revenue: U256,
+    // wei spent on gas by frontrun and backrun, profit = revenue - gas_cost
+    pub gas_cost: U256,
+    pub profit: I256,
     pub optimal_sandwich_weth_in: U256,
     pub user_amount_in: U256,
     pub user_min_recv: U256,
@@ -32,46 +81,98 @@ pub struct SandWichState {
     pub back_run: PairState,
 }
 
-impl PairState {
-    pub fn from_tuple(t: (U256, U256, U256)) -> Self {
+impl From<SwapOutcome> for PairState {
+    fn from(outcome: SwapOutcome) -> Self {
         Self {
-            amount_out: t.0,
-            new_reserve_a: t.1,
-            new_reserve_b: t.2,
+            amount_out: outcome.amount_out,
+            new_reserve_a: outcome.new_reserve_in,
+            new_reserve_b: outcome.new_reserve_out,
         }
     }
 }
 
-pub fn get_uni_pair_address(from: Address, to: Address) -> Address {
-    let (from, to) = sort_token(from, to);
+/// A UniswapV2 deployment: fork factories differ in address, pair init code
+/// and swap fee.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dex {
+    pub name: &'static str,
+    pub factory: Address,
+    pub init_code_hash: H256,
+    // swap fee as in `amountIn * fee_numerator / 1000`, 997 for a 0.3% fee
+    pub fee_numerator: u64,
+}
 
-    //// TODO remove hard code uniswapv2 factory address
-    let factory = "5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f"
-        .parse::<Address>()
-        .unwrap();
+impl Dex {
+    pub fn uniswap_v2() -> Self {
+        Self {
+            name: "uniswapv2",
+            factory: "5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f"
+                .parse::<Address>()
+                .unwrap(),
+            init_code_hash: "96e8ac4277198ff8b6f785478aa9a39f403cb768dd02cbee326c3e7da348845f"
+                .parse::<H256>()
+                .unwrap(),
+            fee_numerator: amm::DEFAULT_FEE_NUMERATOR,
+        }
+    }
 
-    //// TODO remove hard code init code
-    let init_code_hash =
-        hex::decode("96e8ac4277198ff8b6f785478aa9a39f403cb768dd02cbee326c3e7da348845f").unwrap();
+    pub fn sushiswap() -> Self {
+        Self {
+            name: "sushiswap",
+            factory: "C0AEe478e3658e2610c5F7A4A2E1777cE9e4f2Ac"
+                .parse::<Address>()
+                .unwrap(),
+            init_code_hash: "e18a34eb0e04b04f7a0ac29a6e80748dca96319b42c520b21d6d2f7fba4f8a2d"
+                .parse::<H256>()
+                .unwrap(),
+            fee_numerator: amm::DEFAULT_FEE_NUMERATOR,
+        }
+    }
 
-    let mut extend_byte_array = from.as_bytes().to_vec();
-    let to_byte_array = to.as_bytes().to_vec();
-    extend_byte_array.extend(to_byte_array);
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Self::uniswap_v2(), Self::sushiswap()]
+            .into_iter()
+            .find(|dex| dex.name == name)
+    }
 
-    //// Attention here, ethers-rs: abi encoding not work
-    //// let input = abi::encode(&vec![Token::Address(from), Token::Address(to)]);
-    let salt = keccak256(&extend_byte_array);
+    pub fn from_factory(factory: Address) -> Option<Self> {
+        [Self::uniswap_v2(), Self::sushiswap()]
+            .into_iter()
+            .find(|dex| dex.factory == factory)
+    }
 
-    let salt2 =
-        hex::decode("4aafb64a36177dc82e7ace74cf60cc655659bc049da9533b5f7a6881bea995c6").unwrap();
+    pub fn pair_address(&self, from: Address, to: Address) -> Address {
+        let (from, to) = sort_token(from, to);
 
-    let pair_address = ethers::core::utils::get_create2_address_from_hash(
-        factory,
-        salt.to_vec(),
-        init_code_hash.to_vec(),
-    );
+        let mut extend_byte_array = from.as_bytes().to_vec();
+        let to_byte_array = to.as_bytes().to_vec();
+        extend_byte_array.extend(to_byte_array);
 
-    pair_address
+        //// Attention here, ethers-rs: abi encoding not work
+        //// let input = abi::encode(&vec![Token::Address(from), Token::Address(to)]);
+        let salt = keccak256(&extend_byte_array);
+
+        ethers::core::utils::get_create2_address_from_hash(
+            self.factory,
+            salt,
+            self.init_code_hash.as_bytes(),
+        )
+    }
+}
+
+/// `Swap` emitted by a pair, `None` for any other log.
+pub fn decode_swap(log: &Log) -> Option<SwapFilter> {
+    ethers::contract::parse_log::<SwapFilter>(log.clone()).ok()
+}
+
+/// `Sync` emitted by a pair: `(pair, reserve0, reserve1)`.
+pub fn decode_sync(log: &Log) -> Option<(Address, U256, U256)> {
+    let sync = ethers::contract::parse_log::<SyncFilter>(log.clone()).ok()?;
+    Some((log.address, sync.reserve_0.into(), sync.reserve_1.into()))
+}
+
+pub fn get_uni_pair_address(from: Address, to: Address) -> Address {
+    Dex::uniswap_v2().pair_address(from, to)
 }
 
 pub fn sort_token(from: Address, to: Address) -> (Address, Address) {
@@ -82,71 +183,418 @@ pub fn sort_token(from: Address, to: Address) -> (Address, Address) {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QuoteError {
+    InvalidPath,
+    UnknownPair(Address),
+    Amm(AmmError),
+}
+
+impl From<AmmError> for QuoteError {
+    fn from(e: AmmError) -> Self {
+        QuoteError::Amm(e)
+    }
+}
+
+impl fmt::Display for QuoteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QuoteError::InvalidPath => write!(f, "INVALID_PATH"),
+            QuoteError::UnknownPair(pair) => write!(f, "no reserves for pair {:?}", pair),
+            QuoteError::Amm(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Where quotes read pair reserves from, keyed by pair address and in
+/// `(reserve0, reserve1)` order like `getReserves`.
+pub trait Reserves {
+    fn reserves(&self, pair: Address) -> Option<(U256, U256)>;
+}
+
+impl Reserves for HashMap<Address, (U256, U256)> {
+    fn reserves(&self, pair: Address) -> Option<(U256, U256)> {
+        self.get(&pair).cloned()
+    }
+}
+
+/// Reserves read from the node, shared between tasks and dropped every
+/// block.
+#[derive(Debug, Default)]
+pub struct ReserveCache {
+    reserves: RwLock<HashMap<Address, (U256, U256)>>,
+}
+
+impl ReserveCache {
+    pub fn insert(&self, pair: Address, reserves: (U256, U256)) {
+        self.reserves.write().unwrap().insert(pair, reserves);
+    }
+
+    pub fn clear(&self) {
+        self.reserves.write().unwrap().clear();
+    }
+
+    /// Every cached pair with its reserves.
+    pub fn entries(&self) -> Vec<(Address, (U256, U256))> {
+        self.reserves
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(pair, reserves)| (*pair, *reserves))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.reserves.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Reserves for ReserveCache {
+    fn reserves(&self, pair: Address) -> Option<(U256, U256)> {
+        self.reserves.read().unwrap().get(&pair).cloned()
+    }
+}
+
+/// One pair along a quoted path, with its reserves before and after the swap.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Hop {
+    pub pair: Address,
+    pub token_in: Address,
+    pub token_out: Address,
+    pub amount_in: U256,
+    pub amount_out: U256,
+    pub reserve_in: U256,
+    pub reserve_out: U256,
+    pub new_reserve_in: U256,
+    pub new_reserve_out: U256,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PathQuote {
+    // same layout as the router's `amounts`, one entry per path token
+    pub amounts: Vec<U256>,
+    pub hops: Vec<Hop>,
+}
+
+/// `UniswapV2Library.getAmountOut`
+pub fn get_amount_out(
+    amount_in: U256,
+    reserve_in: U256,
+    reserve_out: U256,
+    dex: &Dex,
+) -> Result<U256, QuoteError> {
+    let outcome = amm::swap_given_in(amount_in, reserve_in, reserve_out, dex.fee_numerator)?;
+    Ok(outcome.amount_out)
+}
+
+/// `UniswapV2Library.getAmountIn`
+pub fn get_amount_in(
+    amount_out: U256,
+    reserve_in: U256,
+    reserve_out: U256,
+    dex: &Dex,
+) -> Result<U256, QuoteError> {
+    let outcome = amm::swap_given_out(amount_out, reserve_in, reserve_out, dex.fee_numerator)?;
+    Ok(outcome.amount_in)
+}
+
+fn hop_reserves(
+    token_in: Address,
+    token_out: Address,
+    dex: &Dex,
+    reserves: &impl Reserves,
+) -> Result<(Address, U256, U256), QuoteError> {
+    let pair = dex.pair_address(token_in, token_out);
+    let (reserve0, reserve1) = reserves
+        .reserves(pair)
+        .ok_or(QuoteError::UnknownPair(pair))?;
+    if sort_token(token_in, token_out).0 == token_in {
+        Ok((pair, reserve0, reserve1))
+    } else {
+        Ok((pair, reserve1, reserve0))
+    }
+}
+
+fn build_hop(
+    pair: Address,
+    path: &[Address],
+    i: usize,
+    amounts: &[U256],
+    reserve_in: U256,
+    reserve_out: U256,
+) -> Hop {
+    Hop {
+        pair,
+        token_in: path[i],
+        token_out: path[i + 1],
+        amount_in: amounts[i],
+        amount_out: amounts[i + 1],
+        reserve_in,
+        reserve_out,
+        new_reserve_in: reserve_in + amounts[i],
+        new_reserve_out: reserve_out - amounts[i + 1],
+    }
+}
+
+/// `UniswapV2Library.getAmountsOut`, plus the reserves of every hop.
+pub fn get_amounts_out(
+    amount_in: U256,
+    path: &[Address],
+    dex: &Dex,
+    reserves: &impl Reserves,
+) -> Result<PathQuote, QuoteError> {
+    if path.len() < 2 {
+        return Err(QuoteError::InvalidPath);
+    }
+
+    let mut amounts = vec![U256::zero(); path.len()];
+    let mut pair_reserves = Vec::with_capacity(path.len() - 1);
+    amounts[0] = amount_in;
+    for i in 0..path.len() - 1 {
+        let (pair, reserve_in, reserve_out) = hop_reserves(path[i], path[i + 1], dex, reserves)?;
+        amounts[i + 1] = get_amount_out(amounts[i], reserve_in, reserve_out, dex)?;
+        pair_reserves.push((pair, reserve_in, reserve_out));
+    }
+
+    let hops = pair_reserves
+        .into_iter()
+        .enumerate()
+        .map(|(i, (pair, reserve_in, reserve_out))| {
+            build_hop(pair, path, i, &amounts, reserve_in, reserve_out)
+        })
+        .collect();
+    Ok(PathQuote { amounts, hops })
+}
+
+/// `UniswapV2Library.getAmountsIn`, plus the reserves of every hop.
+pub fn get_amounts_in(
+    amount_out: U256,
+    path: &[Address],
+    dex: &Dex,
+    reserves: &impl Reserves,
+) -> Result<PathQuote, QuoteError> {
+    if path.len() < 2 {
+        return Err(QuoteError::InvalidPath);
+    }
+
+    let mut amounts = vec![U256::zero(); path.len()];
+    let mut pair_reserves = vec![(Address::zero(), U256::zero(), U256::zero()); path.len() - 1];
+    amounts[path.len() - 1] = amount_out;
+    for i in (1..path.len()).rev() {
+        let (pair, reserve_in, reserve_out) = hop_reserves(path[i - 1], path[i], dex, reserves)?;
+        amounts[i - 1] = get_amount_in(amounts[i], reserve_in, reserve_out, dex)?;
+        pair_reserves[i - 1] = (pair, reserve_in, reserve_out);
+    }
+
+    let hops = pair_reserves
+        .into_iter()
+        .enumerate()
+        .map(|(i, (pair, reserve_in, reserve_out))| {
+            build_hop(pair, path, i, &amounts, reserve_in, reserve_out)
+        })
+        .collect();
+    Ok(PathQuote { amounts, hops })
+}
+
+/// Amount of `from` to send for `user_min_recv` of `to`, with the 0.3% fee.
 pub fn get_univ2_data_given_out(
     user_min_recv: U256,
     reserve_from: U256,
     reserve_to: U256,
-) -> (U256, U256, U256) {
-    // Use checked_sub to avoid underflow
-    let mut new_reserve_to = match reserve_to.checked_sub(user_min_recv) {
-        Some(result) => result,
-        None => return (U256::zero(), reserve_from, reserve_to), // return early with appropriate values
-    };
+) -> Result<SwapOutcome, AmmError> {
+    amm::swap_given_out(
+        user_min_recv,
+        reserve_from,
+        reserve_to,
+        amm::DEFAULT_FEE_NUMERATOR,
+    )
+}
 
-    let numerator = reserve_from * user_min_recv * 1000;
-    let denominator = reserve_to * 997;
-    let a_amount_in = numerator / denominator + 1;
+/// What arrives of `amount` after a `tax_bps` transfer tax, nothing past
+/// `BPS`.
+pub fn apply_tax(amount: U256, tax_bps: u64) -> U256 {
+    amount - amount * tax_bps.min(BPS) / BPS
+}
 
-    let (mut new_reserve_from, ok) = reserve_from.overflowing_add(a_amount_in);
-    if ok {
-        new_reserve_from = U256::MAX;
+/// Amount needed before a `tax_bps` transfer tax so that `amount` arrives,
+/// none when the tax takes it all.
+pub fn remove_tax(amount: U256, tax_bps: u64) -> Result<U256, AmmError> {
+    if tax_bps == 0 {
+        return Ok(amount);
     }
-
-    (a_amount_in, new_reserve_from, new_reserve_to)
+    if tax_bps >= BPS {
+        return Err(AmmError::ZeroOutput);
+    }
+    let kept = U256::from(BPS - tax_bps);
+    amount
+        .checked_mul(U256::from(BPS))
+        .and_then(|amount| amount.checked_add(kept - 1))
+        .map(|amount| amount / kept)
+        .ok_or(AmmError::Overflow)
 }
 
-// need write test
-pub fn get_univ2_data_given_in(
-    amountA_in: U256,
+/// `get_univ2_data_given_in` for tokens taxing the transfer into the pair
+/// (`tax_in_bps`) or out to the recipient (`tax_out_bps`). The amount out is
+/// what the recipient actually holds afterwards.
+pub fn get_univ2_data_given_in_taxed(
+    amount_in: U256,
     reserve_a: U256,
     reserve_b: U256,
-) -> (U256, U256, U256) {
-    let amount_in_with_fee = amountA_in * 997;
-    let numerator = amount_in_with_fee * reserve_b;
-    let denominator = amount_in_with_fee + (reserve_a * 1000);
-    let amountB_out = numerator / denominator;
+    tax_in_bps: u64,
+    tax_out_bps: u64,
+) -> Result<SwapOutcome, AmmError> {
+    let delivered = apply_tax(amount_in, tax_in_bps);
+    let outcome = get_univ2_data_given_in(delivered, reserve_a, reserve_b)?;
+    Ok(SwapOutcome {
+        amount_in,
+        amount_out: apply_tax(outcome.amount_out, tax_out_bps),
+        ..outcome
+    })
+}
 
-    let (mut new_reserve_b, ok) = reserve_b.overflowing_sub(amountB_out);
-    if !ok {
-        new_reserve_b = U256::from(1);
-    }
+/// `get_univ2_data_given_out` for taxed tokens, `user_min_recv` is what the
+/// recipient has to end up with and the amount in is what the sender sends.
+pub fn get_univ2_data_given_out_taxed(
+    user_min_recv: U256,
+    reserve_from: U256,
+    reserve_to: U256,
+    tax_in_bps: u64,
+    tax_out_bps: u64,
+) -> Result<SwapOutcome, AmmError> {
+    let outcome = get_univ2_data_given_out(
+        remove_tax(user_min_recv, tax_out_bps)?,
+        reserve_from,
+        reserve_to,
+    )?;
+    Ok(SwapOutcome {
+        amount_in: remove_tax(outcome.amount_in, tax_in_bps)?,
+        amount_out: user_min_recv,
+        ..outcome
+    })
+}
 
-    let (mut new_reserve_a, ok) = reserve_a.overflowing_add(amountA_in);
-    if !ok {
-        new_reserve_a = U256::MAX;
-    }
-    (amountB_out, new_reserve_a, new_reserve_b)
+/// Amount of `b` bought with `amount_a_in` of `a`, with the 0.3% fee.
+pub fn get_univ2_data_given_in(
+    amount_a_in: U256,
+    reserve_a: U256,
+    reserve_b: U256,
+) -> Result<SwapOutcome, AmmError> {
+    amm::swap_given_in(
+        amount_a_in,
+        reserve_a,
+        reserve_b,
+        amm::DEFAULT_FEE_NUMERATOR,
+    )
 }
 
 pub fn calc_sandwich_optima_in(
     user_amount_in: U256,
-    user_min_recv_token: U256,
+    user_min_recv_token: MinRecv,
     reserve_weth: U256,
     reserve_token: U256,
+    max_weth_in: U256,
+    taxes: TokenTaxes,
 ) -> U256 {
+    // a frontrun the pair can't take leaves the victim nothing
     let call_f = |amountIn: U256| -> U256 {
-        let frontrunState = get_univ2_data_given_in(amountIn, reserve_weth, reserve_token);
-        let victimState = get_univ2_data_given_in(user_amount_in, frontrunState.1, frontrunState.2);
-        victimState.0
+        get_univ2_data_given_in_taxed(amountIn, reserve_weth, reserve_token, 0, taxes.buy_tax_bps)
+            .and_then(|frontrunState| {
+                get_univ2_data_given_in_taxed(
+                    user_amount_in,
+                    frontrunState.new_reserve_in,
+                    frontrunState.new_reserve_out,
+                    0,
+                    user_min_recv_token.tax_bps(taxes),
+                )
+            })
+            .map(|victimState| victimState.amount_out)
+            .unwrap_or_default()
     };
 
     // FIXME: ge function with U256
-    let pass_f = |amountOut: U256| -> bool { amountOut.ge(&user_min_recv_token) };
+    let pass_f = |amountOut: U256| -> bool { amountOut.ge(&user_min_recv_token.amount()) };
 
-    let optimal_weth_in = binary_search(U256::from(0), U256::from(100), call_f, pass_f);
+    let optimal_weth_in = binary_search(U256::from(0), max_weth_in, call_f, pass_f);
     optimal_weth_in
 }
 
+/// Frontrun, victim and backrun applied in order to the `(weth, token)`
+/// reserves, priced with `gas_cost` for the two bundle transactions.
+pub fn get_sandwitch_state(
+    optimal_sandwich_weth_in: U256,
+    user_weth_in: U256,
+    user_min_recv: MinRecv,
+    reserve_weth: U256,
+    reserve_token: U256,
+    taxes: TokenTaxes,
+    gas_cost: U256,
+) -> Result<SandWichState, Rejection> {
+    let frontrun_state = PairState::from(
+        get_univ2_data_given_in_taxed(
+            optimal_sandwich_weth_in,
+            reserve_weth,
+            reserve_token,
+            0,
+            taxes.buy_tax_bps,
+        )
+        .map_err(Rejection::Amm)?,
+    );
+    let victim_state = PairState::from(
+        get_univ2_data_given_in_taxed(
+            user_weth_in,
+            frontrun_state.new_reserve_a,
+            frontrun_state.new_reserve_b,
+            0,
+            taxes.buy_tax_bps,
+        )
+        .map_err(Rejection::Amm)?,
+    );
+    let backrun_state = PairState::from(
+        get_univ2_data_given_in_taxed(
+            frontrun_state.amount_out,
+            victim_state.new_reserve_b,
+            victim_state.new_reserve_a,
+            taxes.sell_tax_bps,
+            0,
+        )
+        .map_err(Rejection::Amm)?,
+    );
+
+    // Sanity check, against the amount the router compares with the bound
+    let victim_out = match user_min_recv {
+        MinRecv::PairOut(_) => frontrun_state
+            .new_reserve_b
+            .saturating_sub(victim_state.new_reserve_b),
+        MinRecv::Received(_) => victim_state.amount_out,
+    };
+    if victim_out < user_min_recv.amount() {
+        return Err(Rejection::SanityCheckFailed);
+    }
+
+    let revenue = backrun_state
+        .amount_out
+        .checked_sub(optimal_sandwich_weth_in)
+        .ok_or(Rejection::Unprofitable)?;
+    let profit = I256::from_raw(revenue) - I256::from_raw(gas_cost);
+
+    Ok(SandWichState {
+        revenue,
+        gas_cost,
+        profit,
+        optimal_sandwich_weth_in,
+        user_amount_in: user_weth_in,
+        user_min_recv: user_min_recv.amount(),
+        reserve_state: (reserve_weth, reserve_token),
+        front_run: frontrun_state,
+        victim: victim_state,
+        back_run: backrun_state,
+    })
+}
+
 pub fn binary_search<F, G>(mut left: U256, mut right: U256, cal_func: F, pass_func: G) -> U256
 where
     F: Fn(U256) -> U256,
@@ -207,13 +655,318 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_decode_swap() {
+        let (pair, sender, to) = (
+            Address::repeat_byte(1),
+            Address::repeat_byte(2),
+            Address::repeat_byte(3),
+        );
+        let mut data = [0u8; 128];
+        U256::from(5).to_big_endian(&mut data[..32]);
+        U256::from(7).to_big_endian(&mut data[96..]);
+        let log = Log {
+            address: pair,
+            topics: vec![SwapFilter::signature(), sender.into(), to.into()],
+            data: data.to_vec().into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            Some(SwapFilter {
+                sender,
+                amount_0_in: U256::from(5),
+                amount_1_in: U256::zero(),
+                amount_0_out: U256::zero(),
+                amount_1_out: U256::from(7),
+                to,
+            }),
+            decode_swap(&log)
+        );
+
+        // a Sync from the same pair is not a swap
+        let sync = Log {
+            topics: vec![SyncFilter::signature()],
+            data: data[..64].to_vec().into(),
+            ..log
+        };
+        assert_eq!(None, decode_swap(&sync));
+    }
+
+    #[test]
+    fn test_decode_sync() {
+        let pair = Address::repeat_byte(1);
+        let mut data = [0u8; 64];
+        U256::from(5).to_big_endian(&mut data[..32]);
+        U256::from(7).to_big_endian(&mut data[32..]);
+        let log = Log {
+            address: pair,
+            topics: vec![H256::from(keccak256("Sync(uint112,uint112)"))],
+            data: data.to_vec().into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            Some((pair, U256::from(5), U256::from(7))),
+            decode_sync(&log)
+        );
+
+        let mut other = log;
+        other.topics = vec![H256::zero()];
+        assert_eq!(None, decode_sync(&other));
+    }
+
     #[test]
     fn test_get_univ2_data_given_out() {
-        let res: (U256, U256, U256) = (U256::from(13), U256::from(1246), U256::from(23300));
+        let res = SwapOutcome {
+            amount_in: U256::from(13),
+            amount_out: U256::from(233),
+            new_reserve_in: U256::from(1246),
+            new_reserve_out: U256::from(23300),
+        };
         assert_eq!(
-            res,
+            Ok(res),
             get_univ2_data_given_out(U256::from(233), U256::from(1233), U256::from(23533))
         );
+        assert_eq!(
+            Err(AmmError::InsufficientLiquidity),
+            get_univ2_data_given_out(U256::from(233), U256::from(1233), U256::from(233))
+        );
+    }
+
+    #[test]
+    fn test_get_univ2_data_given_in() {
+        let out = get_univ2_data_given_in(
+            U256::from(1_000),
+            U256::from(1_000_000),
+            U256::from(2_000_000),
+        )
+        .unwrap();
+        // reserves move by exactly the swapped amounts
+        assert_eq!(U256::from(1_001_000), out.new_reserve_in);
+        assert_eq!(U256::from(2_000_000) - out.amount_out, out.new_reserve_out);
+    }
+
+    #[test]
+    fn test_get_univ2_data_given_in_taxed() {
+        let (reserve_a, reserve_b) = (U256::from(1_000_000), U256::from(2_000_000));
+        let untaxed = get_univ2_data_given_in(U256::from(1_000), reserve_a, reserve_b).unwrap();
+        assert_eq!(
+            Ok(untaxed),
+            get_univ2_data_given_in_taxed(U256::from(1_000), reserve_a, reserve_b, 0, 0)
+        );
+
+        // 10% sell tax: only 900 reach the pair
+        assert_eq!(
+            get_univ2_data_given_in(U256::from(900), reserve_a, reserve_b)
+                .unwrap()
+                .new_reserve_in,
+            get_univ2_data_given_in_taxed(U256::from(1_000), reserve_a, reserve_b, 1_000, 0)
+                .unwrap()
+                .new_reserve_in
+        );
+
+        // 10% buy tax: the recipient gets 90% of what the pair sends
+        let taxed =
+            get_univ2_data_given_in_taxed(U256::from(1_000), reserve_a, reserve_b, 0, 1_000)
+                .unwrap();
+        assert_eq!(
+            untaxed.amount_out - untaxed.amount_out / 10,
+            taxed.amount_out
+        );
+    }
+
+    #[test]
+    fn test_min_recv_tax_only_for_received() {
+        let (reserve_weth, reserve_token) = (U256::exp10(20), U256::exp10(24));
+        let taxes = TokenTaxes {
+            buy_tax_bps: 1_000,
+            sell_tax_bps: 0,
+        };
+        let victim_in = U256::exp10(18);
+        // between what the pair sends the victim and what survives the tax
+        let pair_out = get_univ2_data_given_in(victim_in, reserve_weth, reserve_token)
+            .unwrap()
+            .amount_out;
+        let bound = pair_out * 95 / 100;
+        let state = |min_recv| {
+            get_sandwitch_state(
+                U256::exp10(16),
+                victim_in,
+                min_recv,
+                reserve_weth,
+                reserve_token,
+                taxes,
+                U256::zero(),
+            )
+        };
+
+        // the taxed frontrun does not pay, but the victim still lands
+        assert!(matches!(
+            state(MinRecv::PairOut(bound)),
+            Err(Rejection::Unprofitable)
+        ));
+        assert!(matches!(
+            state(MinRecv::Received(bound)),
+            Err(Rejection::SanityCheckFailed)
+        ));
+    }
+
+    #[test]
+    fn test_remove_tax() {
+        assert_eq!(Ok(U256::from(1_000)), remove_tax(U256::from(1_000), 0));
+        assert_eq!(Ok(U256::from(1_112)), remove_tax(U256::from(1_000), 1_000));
+        assert!(
+            apply_tax(remove_tax(U256::from(1_000), 1_000).unwrap(), 1_000) >= U256::from(1_000)
+        );
+
+        // a full tax lets nothing through and nothing makes up for it
+        assert_eq!(U256::zero(), apply_tax(U256::from(1_000), BPS));
+        assert_eq!(U256::zero(), apply_tax(U256::from(1_000), BPS * 2));
+        assert_eq!(
+            Err(AmmError::ZeroOutput),
+            remove_tax(U256::from(1_000), BPS)
+        );
+        assert!(get_univ2_data_given_out_taxed(
+            U256::from(1_000),
+            U256::exp10(20),
+            U256::exp10(20),
+            0,
+            BPS
+        )
+        .is_err());
+    }
+
+    fn test_path() -> (Vec<Address>, HashMap<Address, (U256, U256)>) {
+        let weth = "C02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"
+            .parse::<Address>()
+            .unwrap();
+        let usdc = "A0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48"
+            .parse::<Address>()
+            .unwrap();
+        let usdt = "dAC17F958D2ee523a2206206994597C13D831ec7"
+            .parse::<Address>()
+            .unwrap();
+
+        let dex = Dex::uniswap_v2();
+        let mut reserves = HashMap::new();
+        // usdc < weth, reserve0 is usdc
+        reserves.insert(
+            dex.pair_address(weth, usdc),
+            (U256::from(50_000_000_000_000u64), U256::exp10(22) * 2),
+        );
+        // usdc < usdt, reserve0 is usdc
+        reserves.insert(
+            dex.pair_address(usdc, usdt),
+            (
+                U256::from(8_000_000_000_000u64),
+                U256::from(8_100_000_000_000u64),
+            ),
+        );
+        (vec![weth, usdc, usdt], reserves)
+    }
+
+    #[test]
+    fn test_get_amount_out_and_in() {
+        let dex = Dex::uniswap_v2();
+        // values from UniswapV2Library tests
+        assert_eq!(
+            Ok(U256::from(1)),
+            get_amount_out(U256::from(2), U256::from(100), U256::from(100), &dex)
+        );
+        assert_eq!(
+            Ok(U256::from(2)),
+            get_amount_in(U256::from(1), U256::from(100), U256::from(100), &dex)
+        );
+        assert_eq!(
+            Err(QuoteError::Amm(AmmError::ZeroInput)),
+            get_amount_out(U256::zero(), U256::from(100), U256::from(100), &dex)
+        );
+        assert_eq!(
+            Err(QuoteError::Amm(AmmError::InsufficientLiquidity)),
+            get_amount_in(U256::from(100), U256::from(100), U256::from(100), &dex)
+        );
+    }
+
+    #[test]
+    fn test_get_amounts_out_and_in_round_trip() {
+        let dex = Dex::uniswap_v2();
+        let (path, reserves) = test_path();
+
+        let out = get_amounts_out(U256::exp10(18), &path, &dex, &reserves).unwrap();
+        assert_eq!(3, out.amounts.len());
+        assert_eq!(2, out.hops.len());
+        assert_eq!(out.amounts[1], out.hops[0].amount_out);
+        assert_eq!(out.amounts[1], out.hops[1].amount_in);
+        assert_eq!(
+            U256::exp10(22) * 2 + U256::exp10(18),
+            out.hops[0].new_reserve_in
+        );
+
+        // asking for the quoted output never needs more than the original input
+        let back = get_amounts_in(out.amounts[2], &path, &dex, &reserves).unwrap();
+        assert_eq!(out.amounts[2], back.amounts[2]);
+        assert!(back.amounts[0] <= U256::exp10(18));
+        assert!(
+            get_amounts_out(back.amounts[0], &path, &dex, &reserves)
+                .unwrap()
+                .amounts[2]
+                >= out.amounts[2]
+        );
+
+        assert_eq!(
+            Err(QuoteError::InvalidPath),
+            get_amounts_in(U256::one(), &path[..1], &dex, &reserves)
+        );
+    }
+
+    // Parity with the deployed router, run against a mainnet fork:
+    // LOCAL_RPC_URL=http://127.0.0.1:8545 cargo test -- --ignored
+    #[tokio::test]
+    #[ignore]
+    async fn test_get_amounts_parity_with_router() {
+        abigen!(
+            IRouterQuote,
+            r#"[
+                function getAmountsOut(uint amountIn, address[] memory path) public view returns (uint[] memory amounts)
+                function getAmountsIn(uint amountOut, address[] memory path) public view returns (uint[] memory amounts)
+            ]"#,
+        );
+
+        let url = std::env::var("LOCAL_RPC_URL").unwrap();
+        let provider = std::sync::Arc::new(Provider::<Http>::try_from(url).unwrap());
+        let router = IRouterQuote::new(
+            "7a250d5630B4cF539739dF2C5dAcb4c659F2488D"
+                .parse::<Address>()
+                .unwrap(),
+            provider.clone(),
+        );
+
+        let dex = Dex::uniswap_v2();
+        let (path, _) = test_path();
+        let mut reserves = HashMap::new();
+        for hop in path.windows(2) {
+            let pair_address = dex.pair_address(hop[0], hop[1]);
+            let pair = IUniswapV2Pair::new(pair_address, provider.clone());
+            let (reserve0, reserve1, _) = pair.get_reserves().call().await.unwrap();
+            reserves.insert(pair_address, (U256::from(reserve0), U256::from(reserve1)));
+        }
+
+        let amount_in = U256::exp10(18);
+        let expected = router
+            .get_amounts_out(amount_in, path.clone())
+            .call()
+            .await
+            .unwrap();
+        let quote = get_amounts_out(amount_in, &path, &dex, &reserves).unwrap();
+        assert_eq!(expected, quote.amounts);
+
+        let amount_out = U256::from(1_000_000_000u64);
+        let expected = router
+            .get_amounts_in(amount_out, path.clone())
+            .call()
+            .await
+            .unwrap();
+        let quote = get_amounts_in(amount_out, &path, &dex, &reserves).unwrap();
+        assert_eq!(expected, quote.amounts);
     }
 
     // generate pub fn binary_search<F, G>(left: U256, right: U256, cal_func: F, pass_func: G) -> U256  test
@@ -0,0 +1,922 @@
+//! Uniswap V3 pool math, ported from the core `FullMath`, `TickMath`,
+//! `SqrtPriceMath`, `SwapMath` and `TickBitmap` libraries with the same
+//! rounding, so a simulated swap moves the same wei as the pool.
+//!
+//! Prices are `sqrt(token1 / token0)` as Q64.96 fixed point, amounts follow
+//! the pool's sign convention: positive is paid into the pool.
+
+use ethers::prelude::*;
+use std::collections::HashMap;
+use std::fmt;
+
+pub const MIN_TICK: i32 = -887272;
+pub const MAX_TICK: i32 = 887272;
+// fees are in hundredths of a bip, 3000 is 0.3%
+pub const FEE_PIPS_DENOMINATOR: u32 = 1_000_000;
+
+// `getSqrtRatioAtTick` multiplies these in for every set bit of |tick|,
+// the first replaces 2^128 for bit 0
+const TICK_FACTORS: [(u32, u128); 19] = [
+    (0x2, 0xfff97272373d413259a46990580e213a),
+    (0x4, 0xfff2e50f5f656932ef12357cf3c7fdcc),
+    (0x8, 0xffe5caca7e10e4e61c3624eaa0941cd0),
+    (0x10, 0xffcb9843d60f6159c9db58835c926644),
+    (0x20, 0xff973b41fa98c081472e6896dfb254c0),
+    (0x40, 0xff2ea16466c96a3843ec78b326b52861),
+    (0x80, 0xfe5dee046a99a2a811c461f1969c3053),
+    (0x100, 0xfcbe86c7900a88aedcffc83b479aa3a4),
+    (0x200, 0xf987a7253ac413176f2b074cf7815e54),
+    (0x400, 0xf3392b0822b70005940c7a398e4b70f3),
+    (0x800, 0xe7159475a2c29b7443b29c7fa6e889d9),
+    (0x1000, 0xd097f3bdfd2022b8845ad8f792aa5825),
+    (0x2000, 0xa9f746462d870fdf8a65dc1f90e061e5),
+    (0x4000, 0x70d869a156d2a1b890bb3df62baf32f7),
+    (0x8000, 0x31be135f97d08fd981231505542fcfa6),
+    (0x10000, 0x9aa508b5b7a84e1c677de54f3e99bc9),
+    (0x20000, 0x5d6af8dedb81196699c329225ee604),
+    (0x40000, 0x2216e584f5fa1ea926041bedfe98),
+    (0x80000, 0x48a170391f7dc42444e8fa2),
+];
+const TICK_BIT_0: u128 = 0xfffcb933bd6fad37aa2d162d1a594001;
+
+/// The revert a pool or library would hit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum V3Error {
+    TickOutOfRange,
+    PriceOutOfRange,
+    PriceLimit,
+    ZeroAmount,
+    ZeroLiquidity,
+    // an exact output swap ran out of liquidity before filling
+    InsufficientLiquidity,
+    LiquidityUnderflow,
+    LiquidityOverflow,
+    Overflow,
+}
+
+impl fmt::Display for V3Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            V3Error::TickOutOfRange => write!(f, "T"),
+            V3Error::PriceOutOfRange => write!(f, "R"),
+            V3Error::PriceLimit => write!(f, "SPL"),
+            V3Error::ZeroAmount => write!(f, "AS"),
+            V3Error::ZeroLiquidity => write!(f, "ZERO_LIQUIDITY"),
+            V3Error::InsufficientLiquidity => write!(f, "INSUFFICIENT_LIQUIDITY"),
+            V3Error::LiquidityUnderflow => write!(f, "LS"),
+            V3Error::LiquidityOverflow => write!(f, "LA"),
+            V3Error::Overflow => write!(f, "OVERFLOW"),
+        }
+    }
+}
+
+pub fn min_sqrt_ratio() -> U256 {
+    U256::from(4295128739u64)
+}
+
+pub fn max_sqrt_ratio() -> U256 {
+    U256::from_dec_str("1461446703485210103287273052203988822378723970342").unwrap()
+}
+
+fn q96() -> U256 {
+    U256::one() << 96
+}
+
+fn max_uint160() -> U256 {
+    (U256::one() << 160) - 1
+}
+
+fn to_uint160(value: U256) -> Result<U256, V3Error> {
+    if value > max_uint160() {
+        return Err(V3Error::Overflow);
+    }
+    Ok(value)
+}
+
+/// `FullMath.mulDiv`: `a * b / denominator` rounded down, without
+/// overflowing on the intermediate product.
+pub fn mul_div(a: U256, b: U256, denominator: U256) -> Result<U256, V3Error> {
+    if denominator.is_zero() {
+        return Err(V3Error::Overflow);
+    }
+    U256::try_from(a.full_mul(b) / U512::from(denominator)).map_err(|_| V3Error::Overflow)
+}
+
+/// `FullMath.mulDivRoundingUp`.
+pub fn mul_div_rounding_up(a: U256, b: U256, denominator: U256) -> Result<U256, V3Error> {
+    let result = mul_div(a, b, denominator)?;
+    if (a.full_mul(b) % U512::from(denominator)).is_zero() {
+        Ok(result)
+    } else {
+        result.checked_add(U256::one()).ok_or(V3Error::Overflow)
+    }
+}
+
+// `UnsafeMath.divRoundingUp`, `b` is never zero
+fn div_rounding_up(a: U256, b: U256) -> U256 {
+    let (quotient, remainder) = a.div_mod(b);
+    if remainder.is_zero() {
+        quotient
+    } else {
+        quotient + 1
+    }
+}
+
+/// `TickMath.getSqrtRatioAtTick`: `sqrt(1.0001^tick)` as Q64.96.
+pub fn get_sqrt_ratio_at_tick(tick: i32) -> Result<U256, V3Error> {
+    let abs_tick = tick.unsigned_abs();
+    if abs_tick > MAX_TICK as u32 {
+        return Err(V3Error::TickOutOfRange);
+    }
+
+    let mut ratio = if abs_tick & 0x1 != 0 {
+        U256::from(TICK_BIT_0)
+    } else {
+        U256::one() << 128
+    };
+    for (bit, factor) in TICK_FACTORS {
+        if abs_tick & bit != 0 {
+            ratio = (ratio * U256::from(factor)) >> 128;
+        }
+    }
+    if tick > 0 {
+        ratio = U256::MAX / ratio;
+    }
+
+    // Q128.128 to Q64.96, rounding up
+    let rounding = if (ratio & U256::from(u32::MAX)).is_zero() {
+        U256::zero()
+    } else {
+        U256::one()
+    };
+    Ok((ratio >> 32) + rounding)
+}
+
+/// `TickMath.getTickAtSqrtRatio`: the greatest tick whose ratio is at most
+/// `sqrt_price_x96`.
+pub fn get_tick_at_sqrt_ratio(sqrt_price_x96: U256) -> Result<i32, V3Error> {
+    if sqrt_price_x96 < min_sqrt_ratio() || sqrt_price_x96 >= max_sqrt_ratio() {
+        return Err(V3Error::PriceOutOfRange);
+    }
+    // ratio(low) <= sqrt_price_x96 < ratio(high)
+    let (mut low, mut high) = (MIN_TICK, MAX_TICK);
+    while high - low > 1 {
+        let middle = low + (high - low) / 2;
+        if get_sqrt_ratio_at_tick(middle)? <= sqrt_price_x96 {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+    Ok(low)
+}
+
+fn next_sqrt_price_from_amount0_rounding_up(
+    sqrt_price_x96: U256,
+    liquidity: u128,
+    amount: U256,
+    add: bool,
+) -> Result<U256, V3Error> {
+    if amount.is_zero() {
+        return Ok(sqrt_price_x96);
+    }
+    let numerator1 = U256::from(liquidity) << 96;
+    let (product, overflow) = amount.overflowing_mul(sqrt_price_x96);
+
+    if add {
+        if !overflow {
+            let (denominator, overflow) = numerator1.overflowing_add(product);
+            if !overflow {
+                return mul_div_rounding_up(numerator1, sqrt_price_x96, denominator);
+            }
+        }
+        // L / (L / sqrtP + amount), less precise but never overflows
+        let denominator = (numerator1 / sqrt_price_x96)
+            .checked_add(amount)
+            .ok_or(V3Error::Overflow)?;
+        Ok(div_rounding_up(numerator1, denominator))
+    } else {
+        if overflow || numerator1 <= product {
+            return Err(V3Error::Overflow);
+        }
+        to_uint160(mul_div_rounding_up(
+            numerator1,
+            sqrt_price_x96,
+            numerator1 - product,
+        )?)
+    }
+}
+
+fn next_sqrt_price_from_amount1_rounding_down(
+    sqrt_price_x96: U256,
+    liquidity: u128,
+    amount: U256,
+    add: bool,
+) -> Result<U256, V3Error> {
+    let liquidity = U256::from(liquidity);
+    if add {
+        let quotient = if amount <= max_uint160() {
+            (amount << 96) / liquidity
+        } else {
+            mul_div(amount, q96(), liquidity)?
+        };
+        to_uint160(
+            sqrt_price_x96
+                .checked_add(quotient)
+                .ok_or(V3Error::Overflow)?,
+        )
+    } else {
+        let quotient = if amount <= max_uint160() {
+            div_rounding_up(amount << 96, liquidity)
+        } else {
+            mul_div_rounding_up(amount, q96(), liquidity)?
+        };
+        if sqrt_price_x96 <= quotient {
+            return Err(V3Error::Overflow);
+        }
+        Ok(sqrt_price_x96 - quotient)
+    }
+}
+
+/// `SqrtPriceMath.getNextSqrtPriceFromInput`.
+pub fn get_next_sqrt_price_from_input(
+    sqrt_price_x96: U256,
+    liquidity: u128,
+    amount_in: U256,
+    zero_for_one: bool,
+) -> Result<U256, V3Error> {
+    if sqrt_price_x96.is_zero() {
+        return Err(V3Error::PriceOutOfRange);
+    }
+    if liquidity == 0 {
+        return Err(V3Error::ZeroLiquidity);
+    }
+    if zero_for_one {
+        next_sqrt_price_from_amount0_rounding_up(sqrt_price_x96, liquidity, amount_in, true)
+    } else {
+        next_sqrt_price_from_amount1_rounding_down(sqrt_price_x96, liquidity, amount_in, true)
+    }
+}
+
+/// `SqrtPriceMath.getNextSqrtPriceFromOutput`.
+pub fn get_next_sqrt_price_from_output(
+    sqrt_price_x96: U256,
+    liquidity: u128,
+    amount_out: U256,
+    zero_for_one: bool,
+) -> Result<U256, V3Error> {
+    if sqrt_price_x96.is_zero() {
+        return Err(V3Error::PriceOutOfRange);
+    }
+    if liquidity == 0 {
+        return Err(V3Error::ZeroLiquidity);
+    }
+    if zero_for_one {
+        next_sqrt_price_from_amount1_rounding_down(sqrt_price_x96, liquidity, amount_out, false)
+    } else {
+        next_sqrt_price_from_amount0_rounding_up(sqrt_price_x96, liquidity, amount_out, false)
+    }
+}
+
+/// `SqrtPriceMath.getAmount0Delta`: token0 between two prices.
+pub fn get_amount0_delta(
+    sqrt_ratio_a_x96: U256,
+    sqrt_ratio_b_x96: U256,
+    liquidity: u128,
+    round_up: bool,
+) -> Result<U256, V3Error> {
+    let (lower, upper) = if sqrt_ratio_a_x96 > sqrt_ratio_b_x96 {
+        (sqrt_ratio_b_x96, sqrt_ratio_a_x96)
+    } else {
+        (sqrt_ratio_a_x96, sqrt_ratio_b_x96)
+    };
+    if lower.is_zero() {
+        return Err(V3Error::PriceOutOfRange);
+    }
+    let numerator1 = U256::from(liquidity) << 96;
+    let numerator2 = upper - lower;
+    if round_up {
+        Ok(div_rounding_up(
+            mul_div_rounding_up(numerator1, numerator2, upper)?,
+            lower,
+        ))
+    } else {
+        Ok(mul_div(numerator1, numerator2, upper)? / lower)
+    }
+}
+
+/// `SqrtPriceMath.getAmount1Delta`: token1 between two prices.
+pub fn get_amount1_delta(
+    sqrt_ratio_a_x96: U256,
+    sqrt_ratio_b_x96: U256,
+    liquidity: u128,
+    round_up: bool,
+) -> Result<U256, V3Error> {
+    let (lower, upper) = if sqrt_ratio_a_x96 > sqrt_ratio_b_x96 {
+        (sqrt_ratio_b_x96, sqrt_ratio_a_x96)
+    } else {
+        (sqrt_ratio_a_x96, sqrt_ratio_b_x96)
+    };
+    if round_up {
+        mul_div_rounding_up(U256::from(liquidity), upper - lower, q96())
+    } else {
+        mul_div(U256::from(liquidity), upper - lower, q96())
+    }
+}
+
+/// One `SwapMath.computeSwapStep` within a single liquidity range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwapStep {
+    pub sqrt_price_next_x96: U256,
+    pub amount_in: U256,
+    pub amount_out: U256,
+    pub fee_amount: U256,
+}
+
+/// `SwapMath.computeSwapStep`, `amount_remaining` is positive for exact
+/// input and negative for exact output.
+pub fn compute_swap_step(
+    sqrt_price_current_x96: U256,
+    sqrt_price_target_x96: U256,
+    liquidity: u128,
+    amount_remaining: I256,
+    fee_pips: u32,
+) -> Result<SwapStep, V3Error> {
+    let zero_for_one = sqrt_price_current_x96 >= sqrt_price_target_x96;
+    let exact_in = !amount_remaining.is_negative();
+    let remaining = amount_remaining.unsigned_abs();
+    let fee_complement = U256::from(FEE_PIPS_DENOMINATOR - fee_pips);
+    let (current, target) = (sqrt_price_current_x96, sqrt_price_target_x96);
+
+    let mut amount_in = U256::zero();
+    let mut amount_out = U256::zero();
+    let next = if exact_in {
+        let remaining_less_fee =
+            mul_div(remaining, fee_complement, U256::from(FEE_PIPS_DENOMINATOR))?;
+        amount_in = if zero_for_one {
+            get_amount0_delta(target, current, liquidity, true)?
+        } else {
+            get_amount1_delta(current, target, liquidity, true)?
+        };
+        if remaining_less_fee >= amount_in {
+            target
+        } else {
+            get_next_sqrt_price_from_input(current, liquidity, remaining_less_fee, zero_for_one)?
+        }
+    } else {
+        amount_out = if zero_for_one {
+            get_amount1_delta(target, current, liquidity, false)?
+        } else {
+            get_amount0_delta(current, target, liquidity, false)?
+        };
+        if remaining >= amount_out {
+            target
+        } else {
+            get_next_sqrt_price_from_output(current, liquidity, remaining, zero_for_one)?
+        }
+    };
+
+    // amounts computed against the target are reused when it was reached
+    let max = next == target;
+    if zero_for_one {
+        if !max || !exact_in {
+            amount_in = get_amount0_delta(next, current, liquidity, true)?;
+        }
+        if !max || exact_in {
+            amount_out = get_amount1_delta(next, current, liquidity, false)?;
+        }
+    } else {
+        if !max || !exact_in {
+            amount_in = get_amount1_delta(current, next, liquidity, true)?;
+        }
+        if !max || exact_in {
+            amount_out = get_amount0_delta(current, next, liquidity, false)?;
+        }
+    }
+    if !exact_in && amount_out > remaining {
+        amount_out = remaining;
+    }
+
+    let fee_amount = if exact_in && next != target {
+        // the remainder is all fee when the input is used up
+        remaining - amount_in
+    } else {
+        mul_div_rounding_up(amount_in, U256::from(fee_pips), fee_complement)?
+    };
+
+    Ok(SwapStep {
+        sqrt_price_next_x96: next,
+        amount_in,
+        amount_out,
+        fee_amount,
+    })
+}
+
+/// `LiquidityMath.addDelta`.
+pub fn add_delta(liquidity: u128, delta: i128) -> Result<u128, V3Error> {
+    if delta < 0 {
+        liquidity
+            .checked_sub(delta.unsigned_abs())
+            .ok_or(V3Error::LiquidityUnderflow)
+    } else {
+        liquidity
+            .checked_add(delta as u128)
+            .ok_or(V3Error::LiquidityOverflow)
+    }
+}
+
+/// A swap run through the pool and the pool state right after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct V3SwapOutcome {
+    // what the pool receives (positive) or pays out (negative)
+    pub amount0: I256,
+    pub amount1: I256,
+    pub sqrt_price_x96: U256,
+    pub tick: i32,
+    pub liquidity: u128,
+}
+
+/// The state of a pool a swap depends on. Ticks the bitmap does not hold
+/// are taken as uninitialized, so the words a swap crosses must be loaded
+/// for the result to be exact.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct V3Pool {
+    pub sqrt_price_x96: U256,
+    pub tick: i32,
+    // in range at the current tick
+    pub liquidity: u128,
+    pub fee: u32,
+    pub tick_spacing: i32,
+    // word position -> initialized ticks, `TickBitmap`
+    pub bitmap: HashMap<i16, U256>,
+    // initialized tick -> liquidityNet
+    pub ticks: HashMap<i32, i128>,
+}
+
+// word and bit of a compressed tick in the bitmap
+fn position(compressed: i32) -> (i16, u8) {
+    ((compressed >> 8) as i16, (compressed & 0xff) as u8)
+}
+
+impl V3Pool {
+    pub fn new(
+        sqrt_price_x96: U256,
+        liquidity: u128,
+        fee: u32,
+        tick_spacing: i32,
+    ) -> Result<Self, V3Error> {
+        Ok(Self {
+            sqrt_price_x96,
+            tick: get_tick_at_sqrt_ratio(sqrt_price_x96)?,
+            liquidity,
+            fee,
+            tick_spacing,
+            bitmap: HashMap::new(),
+            ticks: HashMap::new(),
+        })
+    }
+
+    /// Record the `liquidityNet` of `tick`, as read from `ticks(tick)`.
+    pub fn set_tick(&mut self, tick: i32, liquidity_net: i128) {
+        let (word, bit) = position(tick / self.tick_spacing);
+        let entry = self.bitmap.entry(word).or_default();
+        if liquidity_net == 0 {
+            *entry &= !(U256::one() << bit);
+            self.ticks.remove(&tick);
+        } else {
+            *entry |= U256::one() << bit;
+            self.ticks.insert(tick, liquidity_net);
+        }
+    }
+
+    /// Mint `liquidity` between two initializable ticks.
+    pub fn add_position(
+        &mut self,
+        tick_lower: i32,
+        tick_upper: i32,
+        liquidity: u128,
+    ) -> Result<(), V3Error> {
+        let delta = i128::try_from(liquidity).map_err(|_| V3Error::LiquidityOverflow)?;
+        let lower = self.ticks.get(&tick_lower).copied().unwrap_or_default();
+        let upper = self.ticks.get(&tick_upper).copied().unwrap_or_default();
+        self.set_tick(
+            tick_lower,
+            lower.checked_add(delta).ok_or(V3Error::LiquidityOverflow)?,
+        );
+        self.set_tick(
+            tick_upper,
+            upper.checked_sub(delta).ok_or(V3Error::LiquidityOverflow)?,
+        );
+        if (tick_lower..tick_upper).contains(&self.tick) {
+            self.liquidity = add_delta(self.liquidity, delta)?;
+        }
+        Ok(())
+    }
+
+    /// `TickBitmap.nextInitializedTickWithinOneWord`: the next initialized
+    /// tick at or left of `tick` (`lte`) or right of it, or the word edge.
+    pub fn next_initialized_tick_within_one_word(&self, tick: i32, lte: bool) -> (i32, bool) {
+        let spacing = self.tick_spacing;
+        let mut compressed = tick / spacing;
+        if tick < 0 && tick % spacing != 0 {
+            // round towards negative infinity
+            compressed -= 1;
+        }
+
+        if lte {
+            let (word, bit) = position(compressed);
+            let mask = (U256::one() << bit) - 1 + (U256::one() << bit);
+            let masked = self.bitmap.get(&word).copied().unwrap_or_default() & mask;
+            if masked.is_zero() {
+                ((compressed - bit as i32) * spacing, false)
+            } else {
+                let most_significant = masked.bits() as i32 - 1;
+                (
+                    (compressed - (bit as i32 - most_significant)) * spacing,
+                    true,
+                )
+            }
+        } else {
+            let (word, bit) = position(compressed + 1);
+            let mask = !((U256::one() << bit) - 1);
+            let masked = self.bitmap.get(&word).copied().unwrap_or_default() & mask;
+            if masked.is_zero() {
+                ((compressed + 1 + (255 - bit as i32)) * spacing, false)
+            } else {
+                let least_significant = masked.trailing_zeros() as i32;
+                (
+                    (compressed + 1 + (least_significant - bit as i32)) * spacing,
+                    true,
+                )
+            }
+        }
+    }
+
+    /// `UniswapV3Pool.swap` without moving any token. `amount_specified` is
+    /// positive for exact input and negative for exact output, the price
+    /// limit defaults to the routers' one tick short of the bound.
+    pub fn swap(
+        &self,
+        zero_for_one: bool,
+        amount_specified: I256,
+        sqrt_price_limit_x96: Option<U256>,
+    ) -> Result<V3SwapOutcome, V3Error> {
+        if amount_specified.is_zero() {
+            return Err(V3Error::ZeroAmount);
+        }
+        let limit = sqrt_price_limit_x96.unwrap_or_else(|| {
+            if zero_for_one {
+                min_sqrt_ratio() + 1
+            } else {
+                max_sqrt_ratio() - 1
+            }
+        });
+        let limit_valid = if zero_for_one {
+            limit < self.sqrt_price_x96 && limit > min_sqrt_ratio()
+        } else {
+            limit > self.sqrt_price_x96 && limit < max_sqrt_ratio()
+        };
+        if !limit_valid {
+            return Err(V3Error::PriceLimit);
+        }
+
+        let exact_in = !amount_specified.is_negative();
+        let mut remaining = amount_specified;
+        let mut calculated = I256::zero();
+        let mut sqrt_price = self.sqrt_price_x96;
+        let mut tick = self.tick;
+        let mut liquidity = self.liquidity;
+
+        let signed = |amount: U256| I256::try_from(amount).map_err(|_| V3Error::Overflow);
+        while !remaining.is_zero() && sqrt_price != limit {
+            let start = sqrt_price;
+            let (tick_next, initialized) =
+                self.next_initialized_tick_within_one_word(tick, zero_for_one);
+            let tick_next = tick_next.clamp(MIN_TICK, MAX_TICK);
+            let sqrt_price_next = get_sqrt_ratio_at_tick(tick_next)?;
+            let past_limit = if zero_for_one {
+                sqrt_price_next < limit
+            } else {
+                sqrt_price_next > limit
+            };
+            let target = if past_limit { limit } else { sqrt_price_next };
+
+            let step = compute_swap_step(sqrt_price, target, liquidity, remaining, self.fee)?;
+            sqrt_price = step.sqrt_price_next_x96;
+            if exact_in {
+                remaining -= signed(step.amount_in + step.fee_amount)?;
+                calculated -= signed(step.amount_out)?;
+            } else {
+                remaining += signed(step.amount_out)?;
+                calculated += signed(step.amount_in + step.fee_amount)?;
+            }
+
+            if sqrt_price == sqrt_price_next {
+                if initialized {
+                    let net = self.ticks.get(&tick_next).copied().unwrap_or_default();
+                    liquidity = add_delta(liquidity, if zero_for_one { -net } else { net })?;
+                }
+                tick = if zero_for_one {
+                    tick_next - 1
+                } else {
+                    tick_next
+                };
+            } else if sqrt_price != start {
+                tick = get_tick_at_sqrt_ratio(sqrt_price)?;
+            }
+        }
+
+        let (amount0, amount1) = if zero_for_one == exact_in {
+            (amount_specified - remaining, calculated)
+        } else {
+            (calculated, amount_specified - remaining)
+        };
+        Ok(V3SwapOutcome {
+            amount0,
+            amount1,
+            sqrt_price_x96: sqrt_price,
+            tick,
+            liquidity,
+        })
+    }
+
+    /// What `amount_in` buys, with the state after the swap.
+    pub fn swap_given_in(
+        &self,
+        zero_for_one: bool,
+        amount_in: U256,
+    ) -> Result<(U256, V3SwapOutcome), V3Error> {
+        let amount = I256::try_from(amount_in).map_err(|_| V3Error::Overflow)?;
+        let outcome = self.swap(zero_for_one, amount, None)?;
+        let paid = if zero_for_one {
+            outcome.amount1
+        } else {
+            outcome.amount0
+        };
+        Ok((paid.unsigned_abs(), outcome))
+    }
+
+    /// What buying exactly `amount_out` costs, with the state after the swap.
+    pub fn swap_given_out(
+        &self,
+        zero_for_one: bool,
+        amount_out: U256,
+    ) -> Result<(U256, V3SwapOutcome), V3Error> {
+        let amount = I256::try_from(amount_out).map_err(|_| V3Error::Overflow)?;
+        let outcome = self.swap(zero_for_one, -amount, None)?;
+        let (received, cost) = if zero_for_one {
+            (outcome.amount1, outcome.amount0)
+        } else {
+            (outcome.amount0, outcome.amount1)
+        };
+        // the router reverts when the pool could not fill the whole output
+        if received.unsigned_abs() != amount_out {
+            return Err(V3Error::InsufficientLiquidity);
+        }
+        Ok((cost.unsigned_abs(), outcome))
+    }
+
+    /// Move the pool to the state a simulated swap left it in.
+    pub fn apply(&mut self, outcome: &V3SwapOutcome) {
+        self.sqrt_price_x96 = outcome.sqrt_price_x96;
+        self.tick = outcome.tick;
+        self.liquidity = outcome.liquidity;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(value: &str) -> U256 {
+        U256::from_dec_str(value).unwrap()
+    }
+
+    fn int(value: &str) -> I256 {
+        I256::from_dec_str(value).unwrap()
+    }
+
+    // encodePriceSqrt of the reference tests
+    fn price_1_1() -> U256 {
+        q96()
+    }
+
+    // vectors from TickMath.spec.ts
+    #[test]
+    fn test_tick_math() {
+        assert_eq!(min_sqrt_ratio(), get_sqrt_ratio_at_tick(MIN_TICK).unwrap());
+        assert_eq!(
+            dec("4295343490"),
+            get_sqrt_ratio_at_tick(MIN_TICK + 1).unwrap()
+        );
+        assert_eq!(q96(), get_sqrt_ratio_at_tick(0).unwrap());
+        assert_eq!(
+            dec("1461373636630004318706518188784493106690254656249"),
+            get_sqrt_ratio_at_tick(MAX_TICK - 1).unwrap()
+        );
+        assert_eq!(max_sqrt_ratio(), get_sqrt_ratio_at_tick(MAX_TICK).unwrap());
+        assert_eq!(
+            Err(V3Error::TickOutOfRange),
+            get_sqrt_ratio_at_tick(MAX_TICK + 1)
+        );
+
+        assert_eq!(MIN_TICK, get_tick_at_sqrt_ratio(min_sqrt_ratio()).unwrap());
+        assert_eq!(
+            MIN_TICK + 1,
+            get_tick_at_sqrt_ratio(dec("4295343490")).unwrap()
+        );
+        assert_eq!(
+            MAX_TICK - 1,
+            get_tick_at_sqrt_ratio(max_sqrt_ratio() - 1).unwrap()
+        );
+        assert_eq!(
+            Err(V3Error::PriceOutOfRange),
+            get_tick_at_sqrt_ratio(max_sqrt_ratio())
+        );
+    }
+
+    // vectors from SqrtPriceMath.spec.ts
+    #[test]
+    fn test_sqrt_price_math() {
+        let price_121_100 = dec("87150978765690771352898345369");
+        let liquidity = 10u128.pow(18);
+        assert_eq!(
+            dec("90909090909090910"),
+            get_amount0_delta(price_1_1(), price_121_100, liquidity, true).unwrap()
+        );
+        assert_eq!(
+            dec("90909090909090909"),
+            get_amount0_delta(price_1_1(), price_121_100, liquidity, false).unwrap()
+        );
+        assert_eq!(
+            dec("100000000000000000"),
+            get_amount1_delta(price_1_1(), price_121_100, liquidity, true).unwrap()
+        );
+        assert_eq!(
+            dec("99999999999999999"),
+            get_amount1_delta(price_1_1(), price_121_100, liquidity, false).unwrap()
+        );
+
+        let tenth = U256::exp10(17);
+        assert_eq!(
+            price_121_100,
+            get_next_sqrt_price_from_input(price_1_1(), 10u128.pow(18), tenth, false).unwrap()
+        );
+        assert_eq!(
+            dec("72025602285694852357767227579"),
+            get_next_sqrt_price_from_input(price_1_1(), 10u128.pow(18), tenth, true).unwrap()
+        );
+        assert_eq!(
+            dec("88031291682515930659493278152"),
+            get_next_sqrt_price_from_output(price_1_1(), 10u128.pow(18), tenth, false).unwrap()
+        );
+        assert_eq!(
+            dec("71305346262837903834189555302"),
+            get_next_sqrt_price_from_output(price_1_1(), 10u128.pow(18), tenth, true).unwrap()
+        );
+    }
+
+    // vectors from SwapMath.spec.ts
+    #[test]
+    fn test_compute_swap_step() {
+        let liquidity = 2 * 10u128.pow(18);
+        let one = int("1000000000000000000");
+
+        // exact in capped at the target, one for zero
+        let target = dec("79623317895830914510487008059");
+        let step = compute_swap_step(price_1_1(), target, liquidity, one, 600).unwrap();
+        assert_eq!(target, step.sqrt_price_next_x96);
+        assert_eq!(dec("9975124224178055"), step.amount_in);
+        assert_eq!(dec("5988667735148"), step.fee_amount);
+        assert_eq!(dec("9925619580021728"), step.amount_out);
+
+        // exact out capped at the target, one for zero
+        let step = compute_swap_step(price_1_1(), target, liquidity, -one, 600).unwrap();
+        assert_eq!(target, step.sqrt_price_next_x96);
+        assert_eq!(dec("9975124224178055"), step.amount_in);
+        assert_eq!(dec("5988667735148"), step.fee_amount);
+        assert_eq!(dec("9925619580021728"), step.amount_out);
+
+        // exact in fully spent, one for zero
+        let target = dec("250541448375047931186501464011");
+        let step = compute_swap_step(price_1_1(), target, liquidity, one, 600).unwrap();
+        assert!(step.sqrt_price_next_x96 < target);
+        assert_eq!(dec("999400000000000000"), step.amount_in);
+        assert_eq!(dec("600000000000000"), step.fee_amount);
+        assert_eq!(dec("666399946655997866"), step.amount_out);
+
+        // exact out fully received, one for zero
+        let target = dec("792281625142643375935439503360");
+        let step = compute_swap_step(price_1_1(), target, liquidity, -one, 600).unwrap();
+        assert!(step.sqrt_price_next_x96 < target);
+        assert_eq!(dec("2000000000000000000"), step.amount_in);
+        assert_eq!(dec("1200720432259356"), step.fee_amount);
+        assert_eq!(dec("1000000000000000000"), step.amount_out);
+
+        // amount out capped at the desired amount
+        let step = compute_swap_step(
+            dec("417332158212080721273783715441582"),
+            dec("1452870262520218020823638996"),
+            159344665391607089467575320103,
+            int("-1"),
+            1,
+        )
+        .unwrap();
+        assert_eq!(
+            (U256::one(), U256::one(), U256::one()),
+            (step.amount_in, step.fee_amount, step.amount_out)
+        );
+        assert_eq!(
+            dec("417332158212080721273783715441581"),
+            step.sqrt_price_next_x96
+        );
+
+        // target price of 1 uses partial input
+        let step = compute_swap_step(
+            U256::from(2),
+            U256::one(),
+            1,
+            int("3915081100057732413702495386755767"),
+            1,
+        )
+        .unwrap();
+        assert_eq!(dec("39614081257132168796771975168"), step.amount_in);
+        assert_eq!(dec("39614120871253040049813"), step.fee_amount);
+        assert_eq!(U256::zero(), step.amount_out);
+        assert_eq!(U256::one(), step.sqrt_price_next_x96);
+
+        // the whole input taken as fee
+        let step = compute_swap_step(
+            U256::from(2413),
+            dec("79887613182836312"),
+            1985041575832132834610021537970,
+            int("10"),
+            1872,
+        )
+        .unwrap();
+        assert_eq!(U256::zero(), step.amount_in);
+        assert_eq!(U256::from(10), step.fee_amount);
+        assert_eq!(U256::zero(), step.amount_out);
+        assert_eq!(U256::from(2413), step.sqrt_price_next_x96);
+
+        // intermediate insufficient liquidity, exact out both ways
+        let price = dec("20282409603651670423947251286016");
+        let target = price * 11 / 10;
+        let step = compute_swap_step(price, target, 1024, int("-4"), 3000).unwrap();
+        assert_eq!(U256::zero(), step.amount_out);
+        assert_eq!(target, step.sqrt_price_next_x96);
+        assert_eq!(U256::from(26215), step.amount_in);
+        assert_eq!(U256::from(79), step.fee_amount);
+
+        let target = price * 9 / 10;
+        let step = compute_swap_step(price, target, 1024, int("-263000"), 3000).unwrap();
+        assert_eq!(U256::from(26214), step.amount_out);
+        assert_eq!(target, step.sqrt_price_next_x96);
+        assert_eq!(U256::one(), step.amount_in);
+        assert_eq!(U256::one(), step.fee_amount);
+    }
+
+    #[test]
+    fn test_swap_across_ticks() {
+        // full range at 1:1 behaves like a V2 pair holding L of each token
+        let liquidity = 10u128.pow(21);
+        let mut pool = V3Pool::new(price_1_1(), 0, 3000, 60).unwrap();
+        pool.add_position(-887220, 887220, liquidity).unwrap();
+        assert_eq!(liquidity, pool.liquidity);
+
+        let amount_in = U256::exp10(18);
+        let (amount_out, outcome) = pool.swap_given_in(true, amount_in).unwrap();
+        let v2 = crate::utils::amm::swap_given_in(
+            amount_in,
+            U256::from(liquidity),
+            U256::from(liquidity),
+            997,
+        )
+        .unwrap()
+        .amount_out;
+        assert!(amount_out <= v2 && v2 - amount_out < U256::from(10));
+        assert_eq!(I256::try_from(amount_in).unwrap(), outcome.amount0);
+        assert!(outcome.tick < 0);
+
+        // buying the same amount back costs at least what was sold for it
+        let (cost, _) = pool.swap_given_out(true, amount_out).unwrap();
+        assert!(cost >= amount_in && cost - amount_in < U256::from(10));
+
+        // a narrow position below the price adds liquidity once crossed
+        pool.add_position(-1200, -600, liquidity).unwrap();
+        assert_eq!(
+            (-600, true),
+            pool.next_initialized_tick_within_one_word(-1, true)
+        );
+        let (_, outcome) = pool.swap_given_in(true, U256::exp10(19) * 5).unwrap();
+        assert!(outcome.tick < -600 && outcome.tick >= -1200);
+        assert_eq!(2 * liquidity, outcome.liquidity);
+
+        pool.apply(&outcome);
+        assert_eq!(outcome.tick, pool.tick);
+        assert_eq!(
+            Err(V3Error::PriceLimit),
+            pool.swap(false, I256::one(), Some(pool.sqrt_price_x96))
+        );
+    }
+}
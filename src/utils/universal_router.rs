@@ -0,0 +1,630 @@
+//! Universal Router `execute` calls: one byte per command in `commands`, one
+//! ABI encoded input per command in `inputs`. The swaps come out normalized
+//! with the wraps, unwraps and sweeps around them folded in, so a victim
+//! reads the same as through the V2 router.
+
+use crate::utils::contract_abi::{
+    ExecuteCall, ExecuteWithCommandsAndInputsCall, IUniversalRouterCalls,
+};
+use crate::utils::swaps::{self, NormalizedSwap, Protocol, SwapAmount};
+
+use ethers::abi::{self, AbiDecode, ParamType, Token};
+use ethers::prelude::*;
+use std::collections::HashMap;
+
+pub const V3_SWAP_EXACT_IN: u8 = 0x00;
+pub const V3_SWAP_EXACT_OUT: u8 = 0x01;
+pub const PERMIT2_TRANSFER_FROM: u8 = 0x02;
+pub const SWEEP: u8 = 0x04;
+pub const V2_SWAP_EXACT_IN: u8 = 0x08;
+pub const V2_SWAP_EXACT_OUT: u8 = 0x09;
+pub const PERMIT2_PERMIT: u8 = 0x0a;
+pub const WRAP_ETH: u8 = 0x0b;
+pub const UNWRAP_WETH: u8 = 0x0c;
+pub const EXECUTE_SUB_PLAN: u8 = 0x21;
+
+const COMMAND_TYPE_MASK: u8 = 0x3f;
+// a failing command does not revert the whole call
+const FLAG_ALLOW_REVERT: u8 = 0x80;
+
+/// Recipient standing for the caller.
+pub fn msg_sender() -> Address {
+    Address::from_low_u64_be(1)
+}
+
+/// Recipient standing for the router itself.
+pub fn address_this() -> Address {
+    Address::from_low_u64_be(2)
+}
+
+/// Amount standing for the router's whole balance of the token.
+pub fn contract_balance() -> U256 {
+    U256::one() << 255
+}
+
+#[derive(Debug)]
+pub enum UniversalRouterError {
+    NotExecute,
+    LengthMismatch { commands: usize, inputs: usize },
+    AbiError(u8, abi::Error),
+    InvalidPath(u8),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    V3SwapExactIn {
+        recipient: Address,
+        amount_in: U256,
+        amount_out_min: U256,
+        // trading order, as decoded by `swaps::decode_v3_path`
+        path: Vec<Address>,
+        fees: Vec<u32>,
+        payer_is_user: bool,
+    },
+    V3SwapExactOut {
+        recipient: Address,
+        amount_out: U256,
+        amount_in_max: U256,
+        path: Vec<Address>,
+        fees: Vec<u32>,
+        payer_is_user: bool,
+    },
+    V2SwapExactIn {
+        recipient: Address,
+        amount_in: U256,
+        amount_out_min: U256,
+        path: Vec<Address>,
+        payer_is_user: bool,
+    },
+    V2SwapExactOut {
+        recipient: Address,
+        amount_out: U256,
+        amount_in_max: U256,
+        path: Vec<Address>,
+        payer_is_user: bool,
+    },
+    WrapEth {
+        recipient: Address,
+        amount_min: U256,
+    },
+    UnwrapWeth {
+        recipient: Address,
+        amount_min: U256,
+    },
+    Permit2Permit {
+        token: Address,
+        amount: U256,
+        spender: Address,
+    },
+    Permit2TransferFrom {
+        token: Address,
+        recipient: Address,
+        amount: U256,
+    },
+    Sweep {
+        token: Address,
+        recipient: Address,
+        amount_min: U256,
+    },
+    // any other command type, nothing a swap depends on
+    Other(u8),
+}
+
+/// Decoded `execute` call, commands in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Execution {
+    pub commands: Vec<Command>,
+    pub deadline: Option<U256>,
+}
+
+fn uint(token: &Token) -> U256 {
+    token.clone().into_uint().unwrap_or_default()
+}
+
+fn address(token: &Token) -> Address {
+    token.clone().into_address().unwrap_or_default()
+}
+
+fn boolean(token: &Token) -> bool {
+    token.clone().into_bool().unwrap_or_default()
+}
+
+fn swap_params(path: ParamType) -> Vec<ParamType> {
+    vec![
+        ParamType::Address,
+        ParamType::Uint(256),
+        ParamType::Uint(256),
+        path,
+        ParamType::Bool,
+    ]
+}
+
+fn decode_command(command: u8, input: &[u8]) -> Result<Command, UniversalRouterError> {
+    let kind = command & COMMAND_TYPE_MASK;
+    let decode = |params: &[ParamType]| {
+        abi::decode(params, input).map_err(|e| UniversalRouterError::AbiError(command, e))
+    };
+
+    let command = match kind {
+        V3_SWAP_EXACT_IN | V3_SWAP_EXACT_OUT => {
+            let t = decode(&swap_params(ParamType::Bytes))?;
+            let packed = t[3].clone().into_bytes().unwrap_or_default();
+            let (mut path, mut fees) =
+                swaps::decode_v3_path(&packed).ok_or(UniversalRouterError::InvalidPath(command))?;
+            if kind == V3_SWAP_EXACT_IN {
+                Command::V3SwapExactIn {
+                    recipient: address(&t[0]),
+                    amount_in: uint(&t[1]),
+                    amount_out_min: uint(&t[2]),
+                    path,
+                    fees,
+                    payer_is_user: boolean(&t[4]),
+                }
+            } else {
+                // exact output paths are packed from the token bought
+                path.reverse();
+                fees.reverse();
+                Command::V3SwapExactOut {
+                    recipient: address(&t[0]),
+                    amount_out: uint(&t[1]),
+                    amount_in_max: uint(&t[2]),
+                    path,
+                    fees,
+                    payer_is_user: boolean(&t[4]),
+                }
+            }
+        }
+        V2_SWAP_EXACT_IN | V2_SWAP_EXACT_OUT => {
+            let t = decode(&swap_params(ParamType::Array(Box::new(ParamType::Address))))?;
+            let path: Vec<Address> = t[3]
+                .clone()
+                .into_array()
+                .unwrap_or_default()
+                .iter()
+                .map(address)
+                .collect();
+            if path.len() < 2 {
+                return Err(UniversalRouterError::InvalidPath(command));
+            }
+            if kind == V2_SWAP_EXACT_IN {
+                Command::V2SwapExactIn {
+                    recipient: address(&t[0]),
+                    amount_in: uint(&t[1]),
+                    amount_out_min: uint(&t[2]),
+                    path,
+                    payer_is_user: boolean(&t[4]),
+                }
+            } else {
+                Command::V2SwapExactOut {
+                    recipient: address(&t[0]),
+                    amount_out: uint(&t[1]),
+                    amount_in_max: uint(&t[2]),
+                    path,
+                    payer_is_user: boolean(&t[4]),
+                }
+            }
+        }
+        WRAP_ETH | UNWRAP_WETH => {
+            let t = decode(&[ParamType::Address, ParamType::Uint(256)])?;
+            let (recipient, amount_min) = (address(&t[0]), uint(&t[1]));
+            if kind == WRAP_ETH {
+                Command::WrapEth {
+                    recipient,
+                    amount_min,
+                }
+            } else {
+                Command::UnwrapWeth {
+                    recipient,
+                    amount_min,
+                }
+            }
+        }
+        PERMIT2_PERMIT => {
+            // ((token, amount, expiration, nonce), spender, sigDeadline), signature
+            let details = ParamType::Tuple(vec![
+                ParamType::Address,
+                ParamType::Uint(160),
+                ParamType::Uint(48),
+                ParamType::Uint(48),
+            ]);
+            let permit = ParamType::Tuple(vec![details, ParamType::Address, ParamType::Uint(256)]);
+            let t = decode(&[permit, ParamType::Bytes])?;
+            let permit = t[0].clone().into_tuple().unwrap_or_default();
+            let details = permit[0].clone().into_tuple().unwrap_or_default();
+            Command::Permit2Permit {
+                token: address(&details[0]),
+                amount: uint(&details[1]),
+                spender: address(&permit[1]),
+            }
+        }
+        PERMIT2_TRANSFER_FROM => {
+            let t = decode(&[ParamType::Address, ParamType::Address, ParamType::Uint(160)])?;
+            Command::Permit2TransferFrom {
+                token: address(&t[0]),
+                recipient: address(&t[1]),
+                amount: uint(&t[2]),
+            }
+        }
+        SWEEP => {
+            let t = decode(&[ParamType::Address, ParamType::Address, ParamType::Uint(256)])?;
+            Command::Sweep {
+                token: address(&t[0]),
+                recipient: address(&t[1]),
+                amount_min: uint(&t[2]),
+            }
+        }
+        other => Command::Other(other),
+    };
+    Ok(command)
+}
+
+/// Commands of an `execute` call. A command flagged to allow reverts that
+/// does not decode is skipped, the router would skip it too.
+pub fn decode_commands(
+    commands: &[u8],
+    inputs: &[Bytes],
+) -> Result<Vec<Command>, UniversalRouterError> {
+    if commands.len() != inputs.len() {
+        return Err(UniversalRouterError::LengthMismatch {
+            commands: commands.len(),
+            inputs: inputs.len(),
+        });
+    }
+    let mut decoded = Vec::with_capacity(commands.len());
+    for (command, input) in commands.iter().zip(inputs) {
+        match decode_command(*command, input) {
+            Ok(command) => decoded.push(command),
+            Err(_) if command & FLAG_ALLOW_REVERT != 0 => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(decoded)
+}
+
+pub fn decode_execute(input: &[u8]) -> Result<Execution, UniversalRouterError> {
+    let (commands, inputs, deadline) = match IUniversalRouterCalls::decode(input) {
+        Ok(IUniversalRouterCalls::Execute(ExecuteCall { commands, inputs })) => {
+            (commands, inputs, None)
+        }
+        // abigen names the deadline overload after the parameters it shares
+        Ok(IUniversalRouterCalls::ExecuteWithCommandsAndInputs(
+            ExecuteWithCommandsAndInputsCall {
+                commands,
+                inputs,
+                deadline,
+            },
+        )) => (commands, inputs, Some(deadline)),
+        Err(_) => return Err(UniversalRouterError::NotExecute),
+    };
+    Ok(Execution {
+        commands: decode_commands(&commands, &inputs)?,
+        deadline,
+    })
+}
+
+/// The swaps of `execution` sent by `sender` with `value` wei to `router`.
+/// Recipients are resolved, amounts taken from the router balance are
+/// bounded by what earlier commands left there, and an unwrap or sweep of a
+/// swap's output moves its recipient and tightens its slippage bound.
+pub fn normalize(
+    execution: &Execution,
+    sender: Address,
+    router: Address,
+    value: U256,
+    weth: Address,
+) -> Vec<NormalizedSwap> {
+    let resolve = |recipient: Address| {
+        if recipient == msg_sender() {
+            sender
+        } else if recipient == address_this() {
+            router
+        } else {
+            recipient
+        }
+    };
+    // least the router holds of each token between commands
+    let mut balances: HashMap<Address, U256> = HashMap::new();
+    let mut swaps: Vec<NormalizedSwap> = Vec::new();
+
+    for command in &execution.commands {
+        let (function, protocol, path, fees, amount, recipient) = match command.clone() {
+            Command::WrapEth {
+                recipient,
+                amount_min,
+            } => {
+                let amount = if amount_min == contract_balance() {
+                    value
+                } else {
+                    amount_min
+                };
+                if resolve(recipient) == router {
+                    *balances.entry(weth).or_default() += amount;
+                }
+                continue;
+            }
+            Command::UnwrapWeth {
+                recipient,
+                amount_min,
+            } => {
+                settle(&mut swaps, router, weth, resolve(recipient), amount_min);
+                continue;
+            }
+            Command::Sweep {
+                token,
+                recipient,
+                amount_min,
+            } => {
+                settle(&mut swaps, router, token, resolve(recipient), amount_min);
+                continue;
+            }
+            Command::Permit2Permit { .. }
+            | Command::Permit2TransferFrom { .. }
+            | Command::Other(_) => continue,
+            Command::V2SwapExactIn {
+                recipient,
+                amount_in,
+                amount_out_min,
+                path,
+                ..
+            } => (
+                "V2_SWAP_EXACT_IN",
+                Protocol::V2,
+                path,
+                vec![],
+                SwapAmount::ExactIn {
+                    amount_in,
+                    amount_out_min,
+                },
+                recipient,
+            ),
+            Command::V2SwapExactOut {
+                recipient,
+                amount_out,
+                amount_in_max,
+                path,
+                ..
+            } => (
+                "V2_SWAP_EXACT_OUT",
+                Protocol::V2,
+                path,
+                vec![],
+                SwapAmount::ExactOut {
+                    amount_out,
+                    amount_in_max,
+                },
+                recipient,
+            ),
+            Command::V3SwapExactIn {
+                recipient,
+                amount_in,
+                amount_out_min,
+                path,
+                fees,
+                ..
+            } => (
+                "V3_SWAP_EXACT_IN",
+                Protocol::V3,
+                path,
+                fees,
+                SwapAmount::ExactIn {
+                    amount_in,
+                    amount_out_min,
+                },
+                recipient,
+            ),
+            Command::V3SwapExactOut {
+                recipient,
+                amount_out,
+                amount_in_max,
+                path,
+                fees,
+                ..
+            } => (
+                "V3_SWAP_EXACT_OUT",
+                Protocol::V3,
+                path,
+                fees,
+                SwapAmount::ExactOut {
+                    amount_out,
+                    amount_in_max,
+                },
+                recipient,
+            ),
+        };
+
+        let mut swap = NormalizedSwap {
+            function,
+            protocol,
+            path,
+            fees,
+            amount,
+            recipient: resolve(recipient),
+            deadline: execution.deadline,
+        };
+        let token_in = swap.token_in();
+        if let SwapAmount::ExactIn { amount_in, .. } = &mut swap.amount {
+            if *amount_in == contract_balance() {
+                *amount_in = balances.remove(&token_in).unwrap_or_default();
+            }
+        }
+        if swap.recipient == router {
+            let received = match swap.amount {
+                SwapAmount::ExactIn { amount_out_min, .. } => amount_out_min,
+                SwapAmount::ExactOut { amount_out, .. } => amount_out,
+            };
+            *balances.entry(swap.token_out()).or_default() += received;
+        }
+        swaps.push(swap);
+    }
+    swaps
+}
+
+// `token` the router holds leaves for `recipient`, at least `amount_min`:
+// the last swap that paid it to the router really pays `recipient`
+fn settle(
+    swaps: &mut [NormalizedSwap],
+    router: Address,
+    token: Address,
+    recipient: Address,
+    amount_min: U256,
+) {
+    let Some(swap) = swaps
+        .iter_mut()
+        .rev()
+        .find(|swap| swap.recipient == router && swap.token_out() == token)
+    else {
+        return;
+    };
+    swap.recipient = recipient;
+    if let SwapAmount::ExactIn { amount_out_min, .. } = &mut swap.amount {
+        *amount_out_min = (*amount_out_min).max(amount_min);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::abi::AbiEncode;
+
+    fn weth() -> Address {
+        Address::repeat_byte(0xee)
+    }
+
+    fn execute(commands: &[u8], inputs: Vec<Vec<Token>>) -> Vec<u8> {
+        ExecuteWithCommandsAndInputsCall {
+            commands: commands.to_vec().into(),
+            inputs: inputs
+                .iter()
+                .map(|tokens| abi::encode(tokens).into())
+                .collect(),
+            deadline: U256::from(1_700_000_000),
+        }
+        .encode()
+    }
+
+    #[test]
+    fn test_wrap_and_v2_swap() {
+        let (sender, router, token) = (
+            Address::repeat_byte(1),
+            Address::repeat_byte(2),
+            Address::repeat_byte(3),
+        );
+        let input = execute(
+            &[WRAP_ETH, V2_SWAP_EXACT_IN],
+            vec![
+                vec![
+                    Token::Address(address_this()),
+                    Token::Uint(contract_balance()),
+                ],
+                vec![
+                    Token::Address(msg_sender()),
+                    Token::Uint(contract_balance()),
+                    Token::Uint(U256::from(500)),
+                    Token::Array(vec![Token::Address(weth()), Token::Address(token)]),
+                    Token::Bool(false),
+                ],
+            ],
+        );
+        assert_eq!("3593564c", hex::encode(&input[..4]));
+
+        let execution = decode_execute(&input).unwrap();
+        assert_eq!(Some(U256::from(1_700_000_000)), execution.deadline);
+        let swaps = normalize(&execution, sender, router, U256::exp10(18), weth());
+        assert_eq!(1, swaps.len());
+        assert_eq!(Protocol::V2, swaps[0].protocol);
+        assert_eq!(vec![weth(), token], swaps[0].path);
+        assert_eq!(sender, swaps[0].recipient);
+        assert_eq!(
+            SwapAmount::ExactIn {
+                amount_in: U256::exp10(18),
+                amount_out_min: U256::from(500),
+            },
+            swaps[0].amount
+        );
+    }
+
+    #[test]
+    fn test_v3_swap_unwrapped_to_sender() {
+        let (sender, router, token) = (
+            Address::repeat_byte(1),
+            Address::repeat_byte(2),
+            Address::repeat_byte(3),
+        );
+        let mut path = token.as_bytes().to_vec();
+        path.extend_from_slice(&3000u32.to_be_bytes()[1..]);
+        path.extend_from_slice(weth().as_bytes());
+        let permit = Token::Tuple(vec![
+            Token::Tuple(vec![
+                Token::Address(token),
+                Token::Uint(U256::from(1000)),
+                Token::Uint(U256::from(1_700_000_000)),
+                Token::Uint(U256::zero()),
+            ]),
+            Token::Address(router),
+            Token::Uint(U256::from(1_700_000_000)),
+        ]);
+        let input = execute(
+            &[PERMIT2_PERMIT, V3_SWAP_EXACT_IN, UNWRAP_WETH, 0x80 | SWEEP],
+            vec![
+                vec![permit, Token::Bytes(vec![0; 65])],
+                vec![
+                    Token::Address(address_this()),
+                    Token::Uint(U256::from(1000)),
+                    Token::Uint(U256::from(40)),
+                    Token::Bytes(path),
+                    Token::Bool(true),
+                ],
+                vec![Token::Address(msg_sender()), Token::Uint(U256::from(50))],
+                // a broken input the router is allowed to skip
+                vec![Token::Bool(true)],
+            ],
+        );
+
+        let execution = decode_execute(&input).unwrap();
+        assert_eq!(
+            Command::Permit2Permit {
+                token,
+                amount: U256::from(1000),
+                spender: router,
+            },
+            execution.commands[0]
+        );
+        assert_eq!(3, execution.commands.len());
+        let swaps = normalize(&execution, sender, router, U256::zero(), weth());
+        assert_eq!(1, swaps.len());
+        assert_eq!(vec![3000], swaps[0].fees);
+        assert_eq!((token, weth()), (swaps[0].token_in(), swaps[0].token_out()));
+        // the unwrap pays the sender and its bound is the tighter one
+        assert_eq!(sender, swaps[0].recipient);
+        assert_eq!(
+            SwapAmount::ExactIn {
+                amount_in: U256::from(1000),
+                amount_out_min: U256::from(50),
+            },
+            swaps[0].amount
+        );
+
+        assert!(matches!(
+            decode_commands(&[WRAP_ETH], &[]),
+            Err(UniversalRouterError::LengthMismatch { .. })
+        ));
+        assert!(matches!(
+            decode_execute(&[0xde, 0xad, 0xbe, 0xef]),
+            Err(UniversalRouterError::NotExecute)
+        ));
+    }
+
+    #[test]
+    fn test_sub_plan_is_not_a_v3_swap() {
+        // 0x21 & 0x1f would read as V3_SWAP_EXACT_OUT
+        let input = execute(
+            &[EXECUTE_SUB_PLAN],
+            vec![vec![
+                Token::Bytes(vec![V2_SWAP_EXACT_IN]),
+                Token::Array(vec![Token::Bytes(vec![0; 32])]),
+            ]],
+        );
+        let execution = decode_execute(&input).unwrap();
+        assert_eq!(vec![Command::Other(EXECUTE_SUB_PLAN)], execution.commands);
+    }
+}
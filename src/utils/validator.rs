@@ -0,0 +1,287 @@
+use crate::utils::contract_abi::IERC20;
+
+use ethers::prelude::*;
+use ethers::providers::JsonRpcClient;
+use std::fmt;
+use std::sync::Arc;
+
+/// The check a pending victim failed, it would never make it into a block
+/// as we expect.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VictimCheck {
+    BadSignature(String),
+    SenderMismatch { recovered: Address, from: Address },
+    NonceNotNext { nonce: U256, next: U256 },
+    FeeCapBelowBaseFee { max_fee: U256, base_fee: U256 },
+    InsufficientEth { needed: U256, balance: U256 },
+    InsufficientToken { needed: U256, balance: U256 },
+    InsufficientAllowance { needed: U256, allowance: U256 },
+    // the RPC call that failed and why
+    LookupFailed(&'static str, String),
+}
+
+impl VictimCheck {
+    pub fn name(&self) -> &'static str {
+        match self {
+            VictimCheck::BadSignature(_) => "bad_signature",
+            VictimCheck::SenderMismatch { .. } => "sender_mismatch",
+            VictimCheck::NonceNotNext { .. } => "nonce_not_next",
+            VictimCheck::FeeCapBelowBaseFee { .. } => "fee_cap_below_base_fee",
+            VictimCheck::InsufficientEth { .. } => "insufficient_eth",
+            VictimCheck::InsufficientToken { .. } => "insufficient_token",
+            VictimCheck::InsufficientAllowance { .. } => "insufficient_allowance",
+            VictimCheck::LookupFailed(..) => "lookup_failed",
+        }
+    }
+
+    /// The transaction cannot be included in any later block: it does not
+    /// recover to its sender or its nonce was used already.
+    pub fn never_mined(&self) -> bool {
+        match self {
+            VictimCheck::BadSignature(_) | VictimCheck::SenderMismatch { .. } => true,
+            VictimCheck::NonceNotNext { nonce, next } => nonce < next,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for VictimCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VictimCheck::BadSignature(e) => write!(f, "signature does not recover: {}", e),
+            VictimCheck::SenderMismatch { recovered, from } => {
+                write!(f, "signature recovers to {:?}, not {:?}", recovered, from)
+            }
+            VictimCheck::NonceNotNext { nonce, next } => {
+                write!(f, "nonce {} is not next in line ({})", nonce, next)
+            }
+            VictimCheck::FeeCapBelowBaseFee { max_fee, base_fee } => {
+                write!(
+                    f,
+                    "fee cap {} below predicted base fee {}",
+                    max_fee, base_fee
+                )
+            }
+            VictimCheck::InsufficientEth { needed, balance } => {
+                write!(f, "needs {} wei, holds {}", needed, balance)
+            }
+            VictimCheck::InsufficientToken { needed, balance } => {
+                write!(f, "needs {} token in, holds {}", needed, balance)
+            }
+            VictimCheck::InsufficientAllowance { needed, allowance } => {
+                write!(f, "needs {} router allowance, has {}", needed, allowance)
+            }
+            VictimCheck::LookupFailed(call, e) => write!(f, "{} failed: {}", call, e),
+        }
+    }
+}
+
+/// Token side of a swap paying with an ERC20: what the router will pull from
+/// the sender.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenIn {
+    pub token: Address,
+    pub amount: U256,
+}
+
+// fee cap of the tx, legacy transactions pay gas_price whatever the base fee
+fn max_fee_per_gas(tx: &Transaction) -> U256 {
+    tx.max_fee_per_gas.or(tx.gas_price).unwrap_or_default()
+}
+
+pub fn check_signature(tx: &Transaction) -> Result<(), VictimCheck> {
+    let recovered = tx
+        .recover_from()
+        .map_err(|e| VictimCheck::BadSignature(e.to_string()))?;
+    if recovered != tx.from {
+        return Err(VictimCheck::SenderMismatch {
+            recovered,
+            from: tx.from,
+        });
+    }
+    Ok(())
+}
+
+pub fn check_nonce(tx: &Transaction, next: U256) -> Result<(), VictimCheck> {
+    if tx.nonce != next {
+        return Err(VictimCheck::NonceNotNext {
+            nonce: tx.nonce,
+            next,
+        });
+    }
+    Ok(())
+}
+
+pub fn check_fee_cap(tx: &Transaction, next_base_fee: U256) -> Result<(), VictimCheck> {
+    let max_fee = max_fee_per_gas(tx);
+    if max_fee < next_base_fee {
+        return Err(VictimCheck::FeeCapBelowBaseFee {
+            max_fee,
+            base_fee: next_base_fee,
+        });
+    }
+    Ok(())
+}
+
+pub fn check_eth_balance(tx: &Transaction, balance: U256) -> Result<(), VictimCheck> {
+    let needed = tx
+        .value
+        .saturating_add(tx.gas.saturating_mul(max_fee_per_gas(tx)));
+    if balance < needed {
+        return Err(VictimCheck::InsufficientEth { needed, balance });
+    }
+    Ok(())
+}
+
+/// Filters out pending router calls that can never be mined: bad
+/// signatures, stuck nonces, underpriced fees or senders that can't pay.
+pub struct VictimValidator<P: JsonRpcClient> {
+    provider: Arc<Provider<P>>,
+    router: Address,
+}
+
+impl<P: JsonRpcClient + 'static> VictimValidator<P> {
+    pub fn new(provider: Arc<Provider<P>>, router: Address) -> Self {
+        Self { provider, router }
+    }
+
+    /// Run every check, cheapest first, and report the first that fails.
+    pub async fn validate(
+        &self,
+        tx: &Transaction,
+        next_base_fee: U256,
+        token_in: Option<TokenIn>,
+    ) -> Result<(), VictimCheck> {
+        check_signature(tx)?;
+        check_fee_cap(tx, next_base_fee)?;
+
+        let next = self
+            .provider
+            .get_transaction_count(tx.from, Some(BlockNumber::Latest.into()))
+            .await
+            .map_err(|e| VictimCheck::LookupFailed("eth_getTransactionCount", e.to_string()))?;
+        check_nonce(tx, next)?;
+
+        let balance = self
+            .provider
+            .get_balance(tx.from, None)
+            .await
+            .map_err(|e| VictimCheck::LookupFailed("eth_getBalance", e.to_string()))?;
+        check_eth_balance(tx, balance)?;
+
+        if let Some(token_in) = token_in {
+            let erc20 = IERC20::new(token_in.token, Arc::clone(&self.provider));
+            let balance = erc20
+                .balance_of(tx.from)
+                .call()
+                .await
+                .map_err(|e| VictimCheck::LookupFailed("balanceOf", e.to_string()))?;
+            if balance < token_in.amount {
+                return Err(VictimCheck::InsufficientToken {
+                    needed: token_in.amount,
+                    balance,
+                });
+            }
+
+            let allowance = erc20
+                .allowance(tx.from, self.router)
+                .call()
+                .await
+                .map_err(|e| VictimCheck::LookupFailed("allowance", e.to_string()))?;
+            if allowance < token_in.amount {
+                return Err(VictimCheck::InsufficientAllowance {
+                    needed: token_in.amount,
+                    allowance,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::signers::{LocalWallet, Signer};
+
+    #[test]
+    fn test_check_signature() {
+        let wallet = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+            .parse::<LocalWallet>()
+            .unwrap()
+            .with_chain_id(1u64);
+        let request = TransactionRequest::new()
+            .to(Address::repeat_byte(2))
+            .value(1)
+            .nonce(0)
+            .gas(21_000)
+            .gas_price(1)
+            .chain_id(1u64);
+        let typed: ethers::types::transaction::eip2718::TypedTransaction = request.into();
+        let signature = wallet.sign_transaction_sync(&typed).unwrap();
+
+        let mut tx = Transaction {
+            from: wallet.address(),
+            to: Some(Address::repeat_byte(2)),
+            value: U256::one(),
+            gas: U256::from(21_000),
+            gas_price: Some(U256::one()),
+            chain_id: Some(U256::one()),
+            r: signature.r,
+            s: signature.s,
+            v: signature.v.into(),
+            ..Default::default()
+        };
+        assert_eq!(Ok(()), check_signature(&tx));
+
+        tx.from = Address::repeat_byte(3);
+        assert!(matches!(
+            check_signature(&tx),
+            Err(VictimCheck::SenderMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_check_eth_balance_and_fee_cap() {
+        let tx = Transaction {
+            value: U256::from(1_000),
+            gas: U256::from(100),
+            max_fee_per_gas: Some(U256::from(10)),
+            max_priority_fee_per_gas: Some(U256::from(1)),
+            ..Default::default()
+        };
+
+        assert_eq!(Ok(()), check_eth_balance(&tx, U256::from(2_000)));
+        assert_eq!(
+            Err(VictimCheck::InsufficientEth {
+                needed: U256::from(2_000),
+                balance: U256::from(1_999)
+            }),
+            check_eth_balance(&tx, U256::from(1_999))
+        );
+
+        assert_eq!(Ok(()), check_fee_cap(&tx, U256::from(10)));
+        assert!(check_fee_cap(&tx, U256::from(11)).is_err());
+    }
+
+    #[test]
+    fn test_check_nonce_gap() {
+        let tx = Transaction {
+            nonce: U256::from(7),
+            ..Default::default()
+        };
+        assert_eq!(Ok(()), check_nonce(&tx, U256::from(7)));
+        // an earlier nonce of the sender is still pending, the victim waits
+        assert_eq!(
+            Err(VictimCheck::NonceNotNext {
+                nonce: U256::from(7),
+                next: U256::from(5)
+            }),
+            check_nonce(&tx, U256::from(5))
+        );
+        assert!(!check_nonce(&tx, U256::from(5)).unwrap_err().never_mined());
+        // already replaced or mined
+        assert!(check_nonce(&tx, U256::from(8)).unwrap_err().never_mined());
+    }
+}